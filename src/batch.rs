@@ -0,0 +1,246 @@
+//! Atomic multi-key batches with preconditions.
+
+use std::time::Duration;
+
+/// A set of writes applied atomically, optionally guarded by preconditions.
+///
+/// Preconditions play the role of Redis `WATCH`: they are all checked before
+/// any write is applied, regardless of the order they were added in, and the
+/// batch is rolled back without writing anything if one of them fails. Writes
+/// are then applied in the order they were added.
+///
+/// The whole batch is sent to PostgreSQL in a single round-trip, as one
+/// multi-statement query: preconditions are checked in SQL, and each write
+/// is its own statement so that several writes to the same key apply in
+/// order.
+///
+/// Execute a batch with [`Store::execute_batch`](crate::Store::execute_batch).
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use pgkv::{Batch, BatchResult, Store};
+///
+/// let store = Store::connect("postgresql://localhost/mydb")?;
+///
+/// let batch = Batch::new()
+///     .if_version("account:1", 7)
+///     .if_absent("transfer:42")
+///     .put("account:1", b"90")
+///     .put("transfer:42", b"done")
+///     .increment("transfers", 1);
+///
+/// match store.execute_batch(&batch)? {
+///     BatchResult::Committed(results) => println!("applied {} writes", results.len()),
+///     BatchResult::Failed { precondition } => println!("{:?} did not hold", precondition),
+/// }
+/// # Ok::<(), pgkv::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub(crate) preconditions: Vec<Precondition>,
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+/// A write queued in a [`Batch`].
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOp {
+    Put {
+        key: String,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    Delete {
+        key: String,
+    },
+    Increment {
+        key: String,
+        delta: i64,
+    },
+}
+
+impl Batch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a write of `value` to `key`.
+    pub fn put(mut self, key: impl Into<String>, value: impl AsRef<[u8]>) -> Self {
+        self.ops.push(BatchOp::Put {
+            key: key.into(),
+            value: value.as_ref().to_vec(),
+            ttl: None,
+        });
+        self
+    }
+
+    /// Queues a write of `value` to `key` that expires after `ttl`.
+    pub fn put_ex(
+        mut self,
+        key: impl Into<String>,
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Self {
+        self.ops.push(BatchOp::Put {
+            key: key.into(),
+            value: value.as_ref().to_vec(),
+            ttl: Some(ttl),
+        });
+        self
+    }
+
+    /// Queues a delete of `key`.
+    pub fn delete(mut self, key: impl Into<String>) -> Self {
+        self.ops.push(BatchOp::Delete { key: key.into() });
+        self
+    }
+
+    /// Queues an increment of the numeric value at `key`.
+    pub fn increment(mut self, key: impl Into<String>, delta: i64) -> Self {
+        self.ops.push(BatchOp::Increment {
+            key: key.into(),
+            delta,
+        });
+        self
+    }
+
+    /// Requires that `key` does not exist (or has expired).
+    pub fn if_absent(mut self, key: impl Into<String>) -> Self {
+        self.preconditions
+            .push(Precondition::Absent { key: key.into() });
+        self
+    }
+
+    /// Requires that `key` currently holds `value`.
+    ///
+    /// The comparison runs in SQL, so on a store that compresses values a
+    /// compressed value only matches if compressing `value` with the current
+    /// settings gives the same bytes. Not supported on a store that encrypts
+    /// values.
+    pub fn if_equals(mut self, key: impl Into<String>, value: impl AsRef<[u8]>) -> Self {
+        self.preconditions.push(Precondition::Equals {
+            key: key.into(),
+            value: value.as_ref().to_vec(),
+        });
+        self
+    }
+
+    /// Requires that `key` is currently at `version`.
+    ///
+    /// See [`Store::get_versioned`](crate::Store::get_versioned).
+    pub fn if_version(mut self, key: impl Into<String>, version: u64) -> Self {
+        self.preconditions.push(Precondition::Version {
+            key: key.into(),
+            version,
+        });
+        self
+    }
+
+    /// Returns the number of queued writes.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if no writes are queued.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// A condition that must hold for a [`Batch`] to be applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Precondition {
+    /// The key must not exist.
+    Absent {
+        /// The key to check.
+        key: String,
+    },
+    /// The key must hold exactly this value.
+    Equals {
+        /// The key to check.
+        key: String,
+        /// The expected value.
+        value: Vec<u8>,
+    },
+    /// The key must be at this version.
+    Version {
+        /// The key to check.
+        key: String,
+        /// The expected version.
+        version: u64,
+    },
+}
+
+impl Precondition {
+    /// Returns the key this precondition checks.
+    pub fn key(&self) -> &str {
+        match self {
+            Precondition::Absent { key }
+            | Precondition::Equals { key, .. }
+            | Precondition::Version { key, .. } => key,
+        }
+    }
+}
+
+/// Result of executing a [`Batch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchResult {
+    /// All preconditions held and every write was applied; holds one result
+    /// per write, in the order they were added.
+    Committed(Vec<OpResult>),
+    /// A precondition did not hold; nothing was written.
+    Failed {
+        /// The first precondition that did not hold.
+        precondition: Precondition,
+    },
+}
+
+impl BatchResult {
+    /// Returns `true` if the batch was applied.
+    #[inline]
+    pub fn is_committed(&self) -> bool {
+        matches!(self, BatchResult::Committed(_))
+    }
+}
+
+/// Result of a single write in a committed [`Batch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpResult {
+    /// A put was applied.
+    Put {
+        /// The key's new version.
+        version: u64,
+    },
+    /// A delete was applied.
+    Delete {
+        /// Whether the key existed.
+        existed: bool,
+    },
+    /// An increment was applied.
+    Increment {
+        /// The value after the increment.
+        value: i64,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_builder() {
+        let batch = Batch::new()
+            .if_absent("a")
+            .if_version("b", 3)
+            .put("a", b"1")
+            .put_ex("b", b"2", Duration::from_secs(60))
+            .delete("c")
+            .increment("d", 5);
+
+        assert_eq!(batch.len(), 4);
+        assert_eq!(batch.preconditions.len(), 2);
+        assert_eq!(batch.preconditions[1].key(), "b");
+        assert!(Batch::new().is_empty());
+    }
+}
@@ -0,0 +1,587 @@
+//! Two-tier caching with an in-process LRU in front of PostgreSQL.
+//!
+//! Entries are invalidated across processes via `LISTEN`/`NOTIFY`: a trigger on
+//! the table publishes the key of every changed row, and each [`CachedStore`]
+//! drains those notifications before serving reads from memory.
+
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::{Client, NoTls};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::{KeyType, LocalCacheConfig};
+use crate::error::{Error, Result};
+use crate::key;
+use crate::store::Store;
+use crate::types::{CacheStats, Stats};
+
+/// Maximum length of a PostgreSQL channel name in bytes.
+const MAX_CHANNEL_LENGTH: usize = 63;
+
+/// How often the listener thread checks whether its `CachedStore` was dropped.
+const LISTEN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A [`Store`] wrapper that keeps hot keys in an in-process LRU cache.
+///
+/// Reads are served from memory while the entry is fresh. Writes made through
+/// any `Store` pointed at the same table (in this or another process) evict
+/// the key from every `CachedStore`. Invalidations are received on a
+/// dedicated `LISTEN` connection owned by a background thread, so checking for
+/// them never costs a round-trip.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use pgkv::{CachedStore, LocalCacheConfig, Store};
+/// use std::time::Duration;
+///
+/// let store = Store::connect("postgresql://localhost/mydb")?;
+/// let cached = CachedStore::new(
+///     &store,
+///     LocalCacheConfig::new().max_entries(10_000).ttl(Duration::from_secs(30)),
+/// )?;
+///
+/// cached.set("config:theme", b"dark")?;
+/// let theme = cached.get("config:theme")?;  // Fetched from PostgreSQL
+/// let theme = cached.get("config:theme")?;  // Served from memory
+///
+/// println!("Hit ratio: {:.2}", cached.cache_stats().hit_ratio());
+/// # Ok::<(), pgkv::Error>(())
+/// ```
+pub struct CachedStore<'a> {
+    store: &'a Store,
+    invalidations_rx: Receiver<Result<Invalidation>>,
+    flush_tx: Sender<u64>,
+    flushes: Cell<u64>,
+    shutdown: Arc<AtomicBool>,
+    cache: RefCell<Lru>,
+    config: LocalCacheConfig,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+    invalidations: Cell<u64>,
+}
+
+impl<'a> CachedStore<'a> {
+    /// Creates a cached wrapper around `store`.
+    ///
+    /// This installs the change notification triggers on the table (if missing)
+    /// and opens a second connection, owned by a background thread, to listen
+    /// for invalidations.
+    pub fn new(store: &'a Store, config: LocalCacheConfig) -> Result<Self> {
+        config.validate()?;
+
+        let channel = channel_name(&store.config().table_name);
+        store.create_notify_trigger(&channel)?;
+
+        let mut listener = Client::connect(&store.config().connection_string, NoTls)
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let pid: i32 = listener.query_one("SELECT pg_backend_pid()", &[])?.get(0);
+        let flush_channel = format!("pgkv_flush_{}", pid);
+        let listen_sql = format!(
+            "LISTEN \"{}\"; LISTEN \"{}\"",
+            channel.replace('"', "\"\""),
+            flush_channel
+        );
+        listener.batch_execute(&listen_sql)?;
+
+        let (tx, invalidations_rx) = mpsc::channel();
+        let (flush_tx, flush_rx) = mpsc::channel();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread_shutdown = Arc::clone(&shutdown);
+        let mut listener = Listener {
+            client: listener,
+            tx,
+            flush_rx,
+            flush_channel,
+        };
+        thread::Builder::new()
+            .name("pgkv-listener".into())
+            .spawn(move || listener.run(&thread_shutdown))?;
+
+        Ok(Self {
+            store,
+            invalidations_rx,
+            flush_tx,
+            flushes: Cell::new(0),
+            shutdown,
+            cache: RefCell::new(Lru::new(config.max_entries, config.max_bytes)),
+            config,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            invalidations: Cell::new(0),
+        })
+    }
+
+    /// Gets a value by key, serving it from memory when possible.
+    ///
+    /// Returns `None` if the key doesn't exist or has expired. Missing keys
+    /// are not cached.
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.sync()?;
+
+        if let Some(value) = self.cache.borrow_mut().get(key, Instant::now()) {
+            self.hits.set(self.hits.get() + 1);
+            return Ok(Some(value));
+        }
+
+        self.misses.set(self.misses.get() + 1);
+
+        match self.store.get_entry(key)? {
+            Some(entry) => {
+                let mut ttl = self.config.ttl;
+                if self.store.config().ttl_enabled() {
+                    if let Some(remaining) = entry.expires_at.map(remaining_ttl) {
+                        ttl = ttl.min(remaining);
+                    }
+                }
+
+                let expires_at = Instant::now() + ttl;
+                self.cache
+                    .borrow_mut()
+                    .insert(key.to_string(), entry.value.clone(), expires_at);
+                Ok(Some(entry.value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Sets a value for a key and drops any cached copy.
+    pub fn set(&self, key: &str, value: impl AsRef<[u8]>) -> Result<()> {
+        self.store.set(key, value)?;
+        self.invalidate(key);
+        Ok(())
+    }
+
+    /// Sets a value with an expiration time (TTL) and drops any cached copy.
+    pub fn set_ex(&self, key: &str, value: impl AsRef<[u8]>, ttl: Duration) -> Result<()> {
+        self.store.set_ex(key, value, ttl)?;
+        self.invalidate(key);
+        Ok(())
+    }
+
+    /// Deletes a key and drops any cached copy.
+    ///
+    /// Returns `true` if the key was deleted, `false` if it didn't exist.
+    pub fn delete(&self, key: &str) -> Result<bool> {
+        let deleted = self.store.delete(key)?;
+        self.invalidate(key);
+        Ok(deleted)
+    }
+
+    /// Drops the cached copy of a key, if any.
+    pub fn invalidate(&self, key: &str) {
+        if self.cache.borrow_mut().remove(key) {
+            self.invalidations.set(self.invalidations.get() + 1);
+        }
+    }
+
+    /// Drops the cached copy of the key named in a change notification.
+    fn invalidate_notified(&self, payload: &str) {
+        if self.store.config().key_type == KeyType::Text {
+            self.invalidate(payload);
+            return;
+        }
+
+        match key::parse_hex_key(payload) {
+            // Keys that aren't UTF-8 can't be cached
+            Some(bytes) => {
+                if let Ok(key) = String::from_utf8(bytes) {
+                    self.invalidate(&key);
+                }
+            }
+            None => self.invalidate_all(),
+        }
+    }
+
+    /// Drops all cached entries.
+    pub fn invalidate_all(&self) {
+        let removed = self.cache.borrow_mut().clear();
+        self.invalidations.set(self.invalidations.get() + removed);
+    }
+
+    /// Applies pending invalidations received from PostgreSQL.
+    ///
+    /// This runs automatically before every read; call it explicitly to
+    /// release memory held by stale entries between reads.
+    ///
+    /// If the listener connection is lost, the local cache is flushed and an
+    /// error is returned, since remote writes can no longer be observed.
+    pub fn sync(&self) -> Result<()> {
+        loop {
+            match self.invalidations_rx.try_recv() {
+                Ok(invalidation) => {
+                    self.apply(invalidation)?;
+                }
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => return Err(self.listener_stopped()),
+            }
+        }
+    }
+
+    /// Waits until every change committed before this call has been received,
+    /// and applies the resulting invalidations.
+    ///
+    /// Notifications arrive asynchronously, so a read right after a write
+    /// made elsewhere (or directly through [`CachedStore::store`]) may still
+    /// be served from memory. Call this to make such writes visible. It costs
+    /// a round-trip on the listener connection and waits for the listener
+    /// thread, which polls for requests every 250 ms.
+    pub fn flush_invalidations(&self) -> Result<()> {
+        let id = self.flushes.get() + 1;
+        self.flushes.set(id);
+
+        if self.flush_tx.send(id).is_err() {
+            return Err(self.listener_stopped());
+        }
+
+        loop {
+            match self.invalidations_rx.recv() {
+                Ok(invalidation) => {
+                    if self.apply(invalidation)? == Some(id) {
+                        return self.sync();
+                    }
+                }
+                Err(_) => return Err(self.listener_stopped()),
+            }
+        }
+    }
+
+    /// Applies one message from the listener, returning the flush it
+    /// completes, if any.
+    fn apply(&self, invalidation: Result<Invalidation>) -> Result<Option<u64>> {
+        match invalidation {
+            Ok(Invalidation::Key(key)) => self.invalidate_notified(&key),
+            Ok(Invalidation::All) => self.invalidate_all(),
+            Ok(Invalidation::Flushed(id)) => return Ok(Some(id)),
+            Err(e) => {
+                self.invalidate_all();
+                return Err(e);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Flushes the local cache after the listener thread has gone away.
+    fn listener_stopped(&self) -> Error {
+        self.invalidate_all();
+        Error::Connection("invalidation listener stopped".into())
+    }
+
+    /// Returns hit/miss counters and the current size of the local cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        let cache = self.cache.borrow();
+        CacheStats {
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+            evictions: cache.evictions,
+            invalidations: self.invalidations.get(),
+            entries: cache.entries.len() as u64,
+            size_bytes: cache.size_bytes as u64,
+        }
+    }
+
+    /// Gets statistics about the underlying table.
+    pub fn stats(&self) -> Result<Stats> {
+        self.store.stats()
+    }
+
+    /// Returns a reference to the underlying store.
+    ///
+    /// Writes made directly through the store are picked up as invalidations
+    /// on the next read once their notification arrives; see
+    /// [`CachedStore::flush_invalidations`].
+    pub fn store(&self) -> &Store {
+        self.store
+    }
+}
+
+impl Drop for CachedStore<'_> {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+/// An invalidation received from the listener connection.
+enum Invalidation {
+    /// A single key was written or deleted.
+    Key(String),
+    /// The table was truncated.
+    All,
+    /// Every change committed before the flush request with this ID has been
+    /// forwarded.
+    Flushed(u64),
+}
+
+/// The background side of a [`CachedStore`]: a `LISTEN` connection that
+/// forwards change notifications and answers flush requests.
+struct Listener {
+    client: Client,
+    tx: Sender<Result<Invalidation>>,
+    flush_rx: Receiver<u64>,
+    /// A channel only this connection listens on, used to answer flushes.
+    flush_channel: String,
+}
+
+impl Listener {
+    /// Forwards notifications until `shutdown` is set.
+    fn run(&mut self, shutdown: &AtomicBool) {
+        while !shutdown.load(Ordering::Relaxed) {
+            if !self.request_flushes() || !self.forward_notifications() {
+                return;
+            }
+
+            if self.client.is_closed() {
+                let _ = self
+                    .tx
+                    .send(Err(Error::Connection("listener connection closed".into())));
+                return;
+            }
+        }
+    }
+
+    /// Sends a notification to itself for each pending flush request.
+    ///
+    /// PostgreSQL delivers notifications in commit order, so by the time one
+    /// comes back every change committed before it has been received.
+    ///
+    /// Returns `false` if the listener should stop.
+    fn request_flushes(&mut self) -> bool {
+        while let Ok(id) = self.flush_rx.try_recv() {
+            let sql = format!("NOTIFY \"{}\", '{}'", self.flush_channel, id);
+            if let Err(e) = self.client.batch_execute(&sql) {
+                let _ = self.tx.send(Err(e.into()));
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Forwards notifications received within one poll interval.
+    ///
+    /// Returns `false` if the listener should stop.
+    fn forward_notifications(&mut self) -> bool {
+        let mut notifications = self.client.notifications();
+        let mut iter = notifications.timeout_iter(LISTEN_POLL_INTERVAL);
+
+        loop {
+            let invalidation = match iter.next() {
+                Ok(Some(notification)) if notification.channel() == self.flush_channel => {
+                    match notification.payload().parse() {
+                        Ok(id) => Ok(Invalidation::Flushed(id)),
+                        Err(_) => continue,
+                    }
+                }
+                Ok(Some(notification)) => match notification.payload() {
+                    "" => Ok(Invalidation::All),
+                    key => Ok(Invalidation::Key(key.to_string())),
+                },
+                Ok(None) => return true,
+                Err(e) => Err(e.into()),
+            };
+
+            let failed = invalidation.is_err();
+            if self.tx.send(invalidation).is_err() || failed {
+                return false;
+            }
+        }
+    }
+}
+
+/// Returns the notification channel used for a table.
+fn channel_name(table_name: &str) -> String {
+    let mut channel = format!("pgkv_{}", table_name);
+    if channel.len() > MAX_CHANNEL_LENGTH {
+        let mut end = MAX_CHANNEL_LENGTH;
+        while !channel.is_char_boundary(end) {
+            end -= 1;
+        }
+        channel.truncate(end);
+    }
+    channel
+}
+
+/// Returns the time remaining until `expires_at`, or zero if it has passed.
+fn remaining_ttl(expires_at: SystemTime) -> Duration {
+    expires_at
+        .duration_since(SystemTime::now())
+        .unwrap_or(Duration::ZERO)
+}
+
+/// A cached value with its local expiry and recency tick.
+struct CacheEntry {
+    value: Vec<u8>,
+    expires_at: Instant,
+    tick: u64,
+}
+
+/// A least-recently-used map bounded by entry count and total bytes.
+struct Lru {
+    entries: HashMap<String, CacheEntry>,
+    /// Keys ordered from least to most recently used.
+    recency: BTreeMap<u64, String>,
+    next_tick: u64,
+    size_bytes: usize,
+    max_entries: usize,
+    max_bytes: usize,
+    evictions: u64,
+}
+
+impl Lru {
+    fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_tick: 0,
+            size_bytes: 0,
+            max_entries,
+            max_bytes,
+            evictions: 0,
+        }
+    }
+
+    /// Returns a fresh value and marks it as most recently used.
+    fn get(&mut self, key: &str, now: Instant) -> Option<Vec<u8>> {
+        let entry = self.entries.get(key)?;
+        if entry.expires_at <= now {
+            self.remove(key);
+            return None;
+        }
+
+        let tick = self.bump();
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.tick);
+        self.recency.insert(tick, key.to_string());
+        entry.tick = tick;
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: Vec<u8>, expires_at: Instant) {
+        self.remove(&key);
+
+        let size = key.len() + value.len();
+        if size > self.max_bytes {
+            return;
+        }
+
+        while self.entries.len() >= self.max_entries || self.size_bytes + size > self.max_bytes {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.size_bytes -= oldest.len() + evicted.value.len();
+                self.evictions += 1;
+            }
+        }
+
+        let tick = self.bump();
+        self.recency.insert(tick, key.clone());
+        self.size_bytes += size;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                expires_at,
+                tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.recency.remove(&entry.tick);
+                self.size_bytes -= key.len() + entry.value.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn clear(&mut self) -> u64 {
+        let removed = self.entries.len() as u64;
+        self.entries.clear();
+        self.recency.clear();
+        self.size_bytes = 0;
+        removed
+    }
+
+    fn bump(&mut self) -> u64 {
+        self.next_tick += 1;
+        self.next_tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn later() -> Instant {
+        Instant::now() + Duration::from_secs(60)
+    }
+
+    #[test]
+    fn test_channel_name() {
+        assert_eq!(channel_name("kv_store"), "pgkv_kv_store");
+
+        let long = "t".repeat(63);
+        assert_eq!(channel_name(&long).len(), MAX_CHANNEL_LENGTH);
+
+        let multibyte = "é".repeat(40);
+        let channel = channel_name(&multibyte);
+        assert!(channel.len() <= MAX_CHANNEL_LENGTH);
+        assert!(channel.starts_with("pgkv_é"));
+    }
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let mut lru = Lru::new(2, 1024);
+        lru.insert("a".into(), b"1".to_vec(), later());
+        lru.insert("b".into(), b"2".to_vec(), later());
+
+        // Touch "a" so that "b" becomes the eviction candidate
+        assert_eq!(lru.get("a", Instant::now()), Some(b"1".to_vec()));
+        lru.insert("c".into(), b"3".to_vec(), later());
+
+        assert!(lru.get("b", Instant::now()).is_none());
+        assert!(lru.get("a", Instant::now()).is_some());
+        assert!(lru.get("c", Instant::now()).is_some());
+        assert_eq!(lru.evictions, 1);
+    }
+
+    #[test]
+    fn test_lru_respects_byte_limit() {
+        let mut lru = Lru::new(100, 10);
+        lru.insert("a".into(), vec![0; 4], later());
+        lru.insert("b".into(), vec![0; 4], later());
+        assert_eq!(lru.size_bytes, 10);
+
+        lru.insert("c".into(), vec![0; 4], later());
+        assert_eq!(lru.entries.len(), 2);
+        assert!(lru.size_bytes <= 10);
+
+        // Values larger than the whole cache are never stored
+        lru.insert("huge".into(), vec![0; 100], later());
+        assert!(lru.get("huge", Instant::now()).is_none());
+    }
+
+    #[test]
+    fn test_lru_expiry_and_removal() {
+        let mut lru = Lru::new(10, 1024);
+        lru.insert("stale".into(), b"v".to_vec(), Instant::now());
+        assert!(lru.get("stale", Instant::now()).is_none());
+        assert_eq!(lru.size_bytes, 0);
+
+        lru.insert("a".into(), b"v".to_vec(), later());
+        assert!(lru.remove("a"));
+        assert!(!lru.remove("a"));
+
+        lru.insert("b".into(), b"v".to_vec(), later());
+        assert_eq!(lru.clear(), 1);
+        assert!(lru.recency.is_empty());
+    }
+}
@@ -0,0 +1,299 @@
+//! Value codecs for [`TypedStore`](crate::TypedStore).
+//!
+//! This module is only available when the `serde` feature is enabled. JSON is
+//! always available; the other codecs are enabled with the `msgpack`, `cbor`,
+//! `bincode` and `postcard` features.
+//!
+//! # Value format
+//!
+//! JSON values are stored as plain JSON so they stay readable from SQL and
+//! other clients. Values written by the binary codecs start with a two-byte
+//! header, `0xC1` followed by the codec's [`Codec::ID`]. `0xC1` can never start
+//! a UTF-8 (and so JSON) value, so decoding with the wrong codec is reported as
+//! an [`Error::Serialization`] instead of producing garbage.
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::error::{Error, Result};
+
+/// First byte of every value written by a tagged codec.
+const HEADER_MAGIC: u8 = 0xC1;
+
+/// A serialization format for [`TypedStore`](crate::TypedStore) values.
+///
+/// Implement this to plug in your own format. Identifiers `0..128` are
+/// reserved for the codecs provided by pgkv.
+///
+/// # Example
+///
+/// ```rust
+/// use pgkv::{Codec, Error, Result};
+/// use serde::{Serialize, de::DeserializeOwned};
+///
+/// /// Indented JSON, for values that people read in `psql`.
+/// struct PrettyJson;
+///
+/// impl Codec for PrettyJson {
+///     const ID: Option<u8> = Some(200);
+///     const NAME: &'static str = "pretty-json";
+///
+///     fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+///         serde_json::to_vec_pretty(value).map_err(|e| Error::Serialization(e.to_string()))
+///     }
+///
+///     fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+///         serde_json::from_slice(bytes).map_err(|e| Error::Serialization(e.to_string()))
+///     }
+/// }
+/// ```
+pub trait Codec {
+    /// Identifier written in the value header, or `None` to store values untagged.
+    const ID: Option<u8>;
+
+    /// Human-readable name, used in error messages.
+    const NAME: &'static str;
+
+    /// Serializes a value, without the header.
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>>;
+
+    /// Deserializes a value, without the header.
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// Serializes a value with `C`, adding the codec header if it has one.
+pub(crate) fn encode_value<C: Codec, T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    match C::ID {
+        Some(id) => {
+            let mut bytes = vec![HEADER_MAGIC, id];
+            bytes.extend_from_slice(&C::encode(value)?);
+            Ok(bytes)
+        }
+        None => C::encode(value),
+    }
+}
+
+/// Deserializes a value with `C`, checking that it was written by `C`.
+pub(crate) fn decode_value<C: Codec, T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    let found = match bytes {
+        [HEADER_MAGIC, id, ..] => Some(*id),
+        _ => None,
+    };
+
+    if found != C::ID {
+        return Err(Error::Serialization(format!(
+            "value was encoded as {} but decoded as {}",
+            found.map_or("untagged data", codec_name),
+            C::NAME
+        )));
+    }
+
+    match found {
+        Some(_) => C::decode(&bytes[2..]),
+        None => C::decode(bytes),
+    }
+}
+
+/// Returns the name of a built-in codec by identifier.
+fn codec_name(id: u8) -> &'static str {
+    match id {
+        1 => "msgpack",
+        2 => "cbor",
+        3 => "bincode",
+        4 => "postcard",
+        _ => "an unknown codec",
+    }
+}
+
+fn serialization_error(e: impl std::fmt::Display) -> Error {
+    Error::Serialization(e.to_string())
+}
+
+/// JSON via `serde_json`. Values are stored untagged.
+///
+/// This is the default codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    const ID: Option<u8> = None;
+    const NAME: &'static str = "json";
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(serialization_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(serialization_error)
+    }
+}
+
+/// MessagePack via `rmp-serde`, with struct fields encoded by name.
+#[cfg(feature = "msgpack")]
+#[cfg_attr(docsrs, doc(cfg(feature = "msgpack")))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    const ID: Option<u8> = Some(1);
+    const NAME: &'static str = "msgpack";
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(serialization_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(bytes).map_err(serialization_error)
+    }
+}
+
+/// CBOR via `ciborium`.
+#[cfg(feature = "cbor")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cbor")))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    const ID: Option<u8> = Some(2);
+    const NAME: &'static str = "cbor";
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).map_err(serialization_error)?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        ciborium::from_reader(bytes).map_err(serialization_error)
+    }
+}
+
+/// bincode 2 with the standard configuration.
+#[cfg(feature = "bincode")]
+#[cfg_attr(docsrs, doc(cfg(feature = "bincode")))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    const ID: Option<u8> = Some(3);
+    const NAME: &'static str = "bincode";
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        bincode::serde::encode_to_vec(value, bincode::config::standard())
+            .map_err(serialization_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        let (value, _) = bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map_err(serialization_error)?;
+        Ok(value)
+    }
+}
+
+/// postcard, a compact format for constrained environments.
+#[cfg(feature = "postcard")]
+#[cfg_attr(docsrs, doc(cfg(feature = "postcard")))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    const ID: Option<u8> = Some(4);
+    const NAME: &'static str = "postcard";
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        postcard::to_allocvec(value).map_err(serialization_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        postcard::from_bytes(bytes).map_err(serialization_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+
+    fn user() -> User {
+        User {
+            name: "Alice".into(),
+            age: 30,
+            tags: vec!["admin".into()],
+        }
+    }
+
+    fn roundtrip<C: Codec>() {
+        let bytes = encode_value::<C, _>(&user()).unwrap();
+        assert_eq!(bytes.first() == Some(&HEADER_MAGIC), C::ID.is_some());
+        assert_eq!(decode_value::<C, User>(&bytes).unwrap(), user());
+    }
+
+    #[test]
+    fn test_json_is_untagged() {
+        roundtrip::<Json>();
+        let bytes = encode_value::<Json, _>(&user()).unwrap();
+        assert_eq!(bytes, serde_json::to_vec(&user()).unwrap());
+    }
+
+    #[test]
+    fn test_tag_mismatch() {
+        struct Fake;
+        impl Codec for Fake {
+            const ID: Option<u8> = Some(2);
+            const NAME: &'static str = "fake";
+            fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+                Json::encode(value)
+            }
+            fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+                Json::decode(bytes)
+            }
+        }
+
+        roundtrip::<Fake>();
+
+        let tagged = encode_value::<Fake, _>(&user()).unwrap();
+        let err = decode_value::<Json, User>(&tagged).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "serialization error: value was encoded as cbor but decoded as json"
+        );
+
+        let untagged = encode_value::<Json, _>(&user()).unwrap();
+        let err = decode_value::<Fake, User>(&untagged).unwrap_err();
+        assert!(err.to_string().contains("untagged data"));
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_roundtrip() {
+        roundtrip::<MessagePack>();
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_roundtrip() {
+        roundtrip::<Cbor>();
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode_roundtrip() {
+        roundtrip::<Bincode>();
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn test_postcard_roundtrip() {
+        roundtrip::<Postcard>();
+    }
+}
@@ -0,0 +1,283 @@
+//! Transparent value compression.
+//!
+//! Enable it with [`Config::compression`](crate::Config::compression). zstd
+//! requires the `zstd` feature and LZ4 the `lz4` feature.
+//!
+//! # Value format
+//!
+//! Compressed values start with a six-byte header: `0xC0`, the algorithm
+//! identifier, and the uncompressed length as a little-endian `u32`. The
+//! row's `encoding` column marks compressed values, so only those are
+//! decompressed: other values are returned as stored, whatever bytes they
+//! start with. Data written before compression was enabled (or after it was
+//! disabled) stays readable, and every store decompresses values whatever its
+//! own setting.
+
+use std::borrow::Cow;
+
+use crate::error::{Error, Result};
+
+/// First byte of every compressed value.
+const HEADER_MAGIC: u8 = 0xC0;
+
+/// Length of the header in front of compressed data.
+const HEADER_LEN: usize = 6;
+
+/// Bit set in a row's `encoding` column when its value is compressed.
+pub(crate) const ENCODING_FLAG: i16 = 1;
+
+const ZSTD_ID: u8 = 1;
+const LZ4_ID: u8 = 2;
+
+/// Compression applied to values before they are written.
+///
+/// Values shorter than `min_size` bytes, and values that don't get smaller,
+/// are stored uncompressed.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use pgkv::{Compression, Config};
+///
+/// let config = Config::new("postgresql://localhost/mydb")
+///     .compression(Compression::Zstd { level: 3, min_size: 1024 });
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Store values as they are.
+    #[default]
+    None,
+
+    /// Zstandard, for the best ratio. Requires the `zstd` feature.
+    Zstd {
+        /// Compression level, from `1` (fastest) to `22` (smallest).
+        level: i32,
+        /// Minimum value size in bytes to compress.
+        min_size: usize,
+    },
+
+    /// LZ4, for the lowest CPU cost. Requires the `lz4` feature.
+    Lz4 {
+        /// Minimum value size in bytes to compress.
+        min_size: usize,
+    },
+}
+
+impl Compression {
+    /// Returns the algorithm's name.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd { .. } => "zstd",
+            Compression::Lz4 { .. } => "lz4",
+        }
+    }
+
+    /// Returns whether the algorithm was compiled in.
+    pub(crate) fn is_available(&self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Zstd { .. } => cfg!(feature = "zstd"),
+            Compression::Lz4 { .. } => cfg!(feature = "lz4"),
+        }
+    }
+}
+
+/// Compresses a value with `compression`, adding the header.
+///
+/// Returns the value borrowed and unchanged if it is below the algorithm's
+/// threshold or compressing it doesn't save space, so an owned result is
+/// always compressed.
+pub(crate) fn compress(compression: Compression, value: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let (id, min_size) = match compression {
+        Compression::None => return Ok(Cow::Borrowed(value)),
+        Compression::Zstd { min_size, .. } => (ZSTD_ID, min_size),
+        Compression::Lz4 { min_size } => (LZ4_ID, min_size),
+    };
+
+    let Ok(len) = u32::try_from(value.len()) else {
+        return Ok(Cow::Borrowed(value));
+    };
+    if value.len() < min_size {
+        return Ok(Cow::Borrowed(value));
+    }
+
+    let data = compress_data(compression, value)?;
+    let mut compressed = Vec::with_capacity(HEADER_LEN + data.len());
+    compressed.extend_from_slice(&[HEADER_MAGIC, id]);
+    compressed.extend_from_slice(&len.to_le_bytes());
+    compressed.extend_from_slice(&data);
+
+    if compressed.len() < value.len() {
+        Ok(Cow::Owned(compressed))
+    } else {
+        Ok(Cow::Borrowed(value))
+    }
+}
+
+/// Compresses a value with `compression`, without the header.
+fn compress_data(compression: Compression, value: &[u8]) -> Result<Vec<u8>> {
+    #[cfg(feature = "zstd")]
+    if let Compression::Zstd { level, .. } = compression {
+        return zstd::bulk::compress(value, level).map_err(Error::Io);
+    }
+    #[cfg(feature = "lz4")]
+    if let Compression::Lz4 { .. } = compression {
+        return Ok(lz4_flex::block::compress(value));
+    }
+
+    // Only reached when the algorithm's feature is disabled
+    let _ = value;
+    Err(unavailable(compression.name()))
+}
+
+/// Decompresses a value written by [`compress`].
+pub(crate) fn decompress(value: &[u8]) -> Result<Vec<u8>> {
+    let (id, len) = match value {
+        [HEADER_MAGIC, id @ (ZSTD_ID | LZ4_ID), a, b, c, d, ..] => {
+            (*id, u32::from_le_bytes([*a, *b, *c, *d]) as usize)
+        }
+        _ => return Err(corrupt("missing compression header")),
+    };
+
+    let decompressed = decompress_data(id, &value[HEADER_LEN..], len)?;
+    if decompressed.len() != len {
+        return Err(corrupt(format!(
+            "expected {} bytes, got {}",
+            len,
+            decompressed.len()
+        )));
+    }
+
+    Ok(decompressed)
+}
+
+/// Decompresses data written by the algorithm with identifier `id`.
+fn decompress_data(id: u8, data: &[u8], len: usize) -> Result<Vec<u8>> {
+    #[cfg(feature = "zstd")]
+    if id == ZSTD_ID {
+        return zstd::bulk::decompress(data, len).map_err(corrupt);
+    }
+    #[cfg(feature = "lz4")]
+    if id == LZ4_ID {
+        return lz4_flex::block::decompress(data, len).map_err(corrupt);
+    }
+
+    // Only reached when the algorithm's feature is disabled
+    let _ = (data, len);
+    Err(unavailable(if id == ZSTD_ID { "zstd" } else { "lz4" }))
+}
+
+/// SQL condition that is true when the `encoding` column marks a compressed value.
+pub(crate) fn compressed_sql(encoding: &str) -> String {
+    format!("(({} & {}) <> 0)", encoding, ENCODING_FLAG)
+}
+
+/// SQL expression for the uncompressed length in bytes of the value in
+/// `column`, whose encoding is in the `encoding` column.
+pub(crate) fn uncompressed_len_sql(column: &str, encoding: &str) -> String {
+    format!(
+        r#"CASE WHEN {1} THEN
+            get_byte({0}, 2)::bigint
+            | (get_byte({0}, 3)::bigint << 8)
+            | (get_byte({0}, 4)::bigint << 16)
+            | (get_byte({0}, 5)::bigint << 24)
+        ELSE octet_length({0})::bigint END"#,
+        column,
+        compressed_sql(encoding)
+    )
+}
+
+/// Error for an algorithm whose feature is disabled.
+pub(crate) fn unavailable(algorithm: &str) -> Error {
+    Error::Config(format!(
+        "{0} compression requires the `{0}` feature",
+        algorithm
+    ))
+}
+
+fn corrupt(e: impl std::fmt::Display) -> Error {
+    Error::Query(format!("failed to decompress value: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text() -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog ".repeat(50)
+    }
+
+    #[test]
+    fn test_none_and_threshold() {
+        let value = text();
+        assert!(matches!(
+            compress(Compression::None, &value).unwrap(),
+            Cow::Borrowed(_)
+        ));
+
+        let compression = Compression::Lz4 {
+            min_size: value.len() + 1,
+        };
+        assert!(matches!(
+            compress(compression, &value).unwrap(),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn test_missing_header() {
+        for value in [&b""[..], b"plain", b"\xC0\x01", b"\xC0\x09abcdef"] {
+            assert!(matches!(decompress(value), Err(Error::Query(_))));
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_roundtrip() {
+        let value = text();
+        let compressed = compress(
+            Compression::Zstd {
+                level: 3,
+                min_size: 0,
+            },
+            &value,
+        )
+        .unwrap();
+        assert!(compressed.len() < value.len());
+        assert_eq!(&compressed[..2], &[HEADER_MAGIC, ZSTD_ID]);
+        assert_eq!(decompress(&compressed).unwrap(), value);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_lz4_roundtrip() {
+        let value = text();
+        let compressed = compress(Compression::Lz4 { min_size: 0 }, &value).unwrap();
+        assert!(compressed.len() < value.len());
+        assert_eq!(
+            u32::from_le_bytes(compressed[2..6].try_into().unwrap()) as usize,
+            value.len()
+        );
+        assert_eq!(decompress(&compressed).unwrap(), value);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_incompressible_values_are_stored_raw() {
+        let value: Vec<u8> = (0..=255).collect();
+        let compressed = compress(Compression::Lz4 { min_size: 0 }, &value).unwrap();
+        assert!(matches!(compressed, Cow::Borrowed(_)));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_corrupt_value() {
+        let value = text();
+        let mut compressed = compress(Compression::Lz4 { min_size: 0 }, &value)
+            .unwrap()
+            .into_owned();
+        compressed.truncate(HEADER_LEN + 4);
+        assert!(matches!(decompress(&compressed), Err(Error::Query(_))));
+    }
+}
@@ -1,7 +1,19 @@
 //! Configuration types for pgkv.
 
+use std::time::Duration;
+
+use crate::compression::{self, Compression};
+#[cfg(feature = "encryption")]
+use crate::encryption::{Encryptor, KeyProvider};
 use crate::{Error, Result};
 
+/// Largest number of partitions accepted by [`Config::partitions`].
+const MAX_PARTITIONS: u32 = 1024;
+
+/// Largest chunk size accepted by [`Config::chunk_size`] (1GB, PostgreSQL's
+/// limit for a `BYTEA` value).
+const MAX_CHUNK_SIZE: usize = 1024 * 1024 * 1024;
+
 /// The type of table to use for storage.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum TableType {
@@ -28,6 +40,79 @@ impl TableType {
             TableType::Regular => "",
         }
     }
+
+    /// Returns the persistence keyword of `ALTER TABLE ... SET`.
+    pub(crate) fn persistence_keyword(&self) -> &'static str {
+        match self {
+            TableType::Unlogged => "UNLOGGED",
+            TableType::Regular => "LOGGED",
+        }
+    }
+
+    /// Returns the table type for a `pg_class.relpersistence` value.
+    pub(crate) fn from_persistence(persistence: &str) -> Self {
+        match persistence {
+            "u" => TableType::Unlogged,
+            _ => TableType::Regular,
+        }
+    }
+}
+
+/// The column type used to store keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyType {
+    /// `TEXT` keys - readable from SQL, and compatible with every `&str` API.
+    #[default]
+    Text,
+
+    /// `BYTEA` keys - for hashes, UUID bytes and composite binary keys.
+    ///
+    /// Keys are compared and ordered byte by byte. `&str` keys are stored as
+    /// their UTF-8 bytes, so the whole API keeps working; use the methods
+    /// taking an [`AsKey`](crate::AsKey) key and [`Store::keys_bytes`](crate::Store::keys_bytes)
+    /// or [`Store::scan_bytes`](crate::Store::scan_bytes) for keys that aren't UTF-8.
+    Bytes,
+}
+
+impl KeyType {
+    /// Returns the SQL type of the key column.
+    #[inline]
+    pub fn sql_type(&self) -> &'static str {
+        match self {
+            KeyType::Text => "TEXT",
+            KeyType::Bytes => "BYTEA",
+        }
+    }
+}
+
+/// The column type used to store values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueType {
+    /// `BYTEA` values - any bytes, with optional compression and encryption.
+    #[default]
+    Bytes,
+
+    /// `JSONB` values - every value must be JSON text.
+    ///
+    /// Enables the `json_*` operations on [`Store`](crate::Store) and
+    /// [`ScanOptions::json_contains`](crate::ScanOptions::json_contains).
+    /// Values are returned as PostgreSQL normalizes them: without
+    /// insignificant whitespace, with object keys sorted and deduplicated.
+    /// Compression, encryption and the byte operations (`append`,
+    /// `get_range`, `set_range`, `strlen` and `put_stream`) are not supported,
+    /// and `TypedStore` values must use the JSON codec without schema versions.
+    Jsonb,
+}
+
+impl ValueType {
+    /// Returns the SQL type of the value column.
+    #[inline]
+    pub fn sql_type(&self) -> &'static str {
+        match self {
+            ValueType::Bytes => "BYTEA",
+            ValueType::Jsonb => "JSONB",
+        }
+    }
 }
 
 /// Strategy for handling expired keys.
@@ -85,9 +170,22 @@ pub struct Config {
     /// Type of table to create.
     pub(crate) table_type: TableType,
 
+    /// Column type of the key.
+    pub(crate) key_type: KeyType,
+
+    /// Column type of the value.
+    pub(crate) value_type: ValueType,
+
+    /// Whether to create a GIN index on `JSONB` values.
+    pub(crate) json_index: bool,
+
     /// Whether to automatically create the table if it doesn't exist.
     pub(crate) auto_create_table: bool,
 
+    /// Whether to apply pending schema migrations on connect; unset follows
+    /// `auto_create_table`.
+    pub(crate) auto_migrate: Option<bool>,
+
     /// Strategy for cleaning up expired keys.
     pub(crate) ttl_cleanup_strategy: TtlCleanupStrategy,
 
@@ -105,6 +203,32 @@ pub struct Config {
 
     /// Application name for PostgreSQL connection.
     pub(crate) application_name: Option<String>,
+
+    /// Whether to record every write in a history table.
+    pub(crate) history: bool,
+
+    /// How long history records are kept by `prune_history()`.
+    pub(crate) history_retention: Option<Duration>,
+
+    /// Compression applied to written values.
+    pub(crate) compression: Compression,
+
+    /// Size of the chunks written by `put_stream()`.
+    pub(crate) chunk_size: usize,
+
+    /// Number of hash partitions of the table, if it is partitioned.
+    pub(crate) partitions: Option<u32>,
+
+    /// Number of connections used by per-partition maintenance.
+    pub(crate) maintenance_workers: usize,
+
+    /// Encryption applied to written values.
+    #[cfg(feature = "encryption")]
+    pub(crate) encryption: Option<Encryptor>,
+
+    /// Whether reads reject values that aren't encrypted.
+    #[cfg(feature = "encryption")]
+    pub(crate) reject_unencrypted: bool,
 }
 
 impl Default for Config {
@@ -113,13 +237,27 @@ impl Default for Config {
             connection_string: String::new(),
             table_name: "kv_store".to_string(),
             table_type: TableType::Unlogged,
+            key_type: KeyType::Text,
+            value_type: ValueType::Bytes,
+            json_index: false,
             auto_create_table: true,
+            auto_migrate: None,
             ttl_cleanup_strategy: TtlCleanupStrategy::OnRead,
             max_key_length: 1024,              // 1KB max key
             max_value_size: 100 * 1024 * 1024, // 100MB max value
             schema: None,
             connect_timeout_secs: 10,
             application_name: None,
+            history: false,
+            history_retention: None,
+            compression: Compression::None,
+            chunk_size: 1024 * 1024, // 1MB chunks
+            partitions: None,
+            maintenance_workers: 1,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            #[cfg(feature = "encryption")]
+            reject_unencrypted: false,
         }
     }
 }
@@ -151,20 +289,116 @@ impl Config {
 
     /// Sets the table type (Unlogged or Regular).
     ///
+    /// This only applies when the table is created; switch an existing table
+    /// with [`Store::set_table_type`](crate::Store::set_table_type).
+    ///
     /// Default: [`TableType::Unlogged`]
     pub fn table_type(mut self, table_type: TableType) -> Self {
         self.table_type = table_type;
         self
     }
 
+    /// Sets the column type used to store keys.
+    ///
+    /// This only applies when the table is created; it must match the type of
+    /// an existing table's `key` column.
+    ///
+    /// Default: [`KeyType::Text`]
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, KeyType};
+    ///
+    /// let config = Config::new("postgresql://localhost/mydb")
+    ///     .table_name("blobs_by_hash")
+    ///     .key_type(KeyType::Bytes);
+    /// ```
+    pub fn key_type(mut self, key_type: KeyType) -> Self {
+        self.key_type = key_type;
+        self
+    }
+
+    /// Sets the column type used to store values.
+    ///
+    /// Like [`Config::key_type`], this only applies when the table is created.
+    ///
+    /// Default: [`ValueType::Bytes`]
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, ValueType};
+    ///
+    /// let config = Config::new("postgresql://localhost/mydb")
+    ///     .table_name("documents")
+    ///     .value_type(ValueType::Jsonb)
+    ///     .json_index(true);
+    /// ```
+    pub fn value_type(mut self, value_type: ValueType) -> Self {
+        self.value_type = value_type;
+        self
+    }
+
+    /// Sets whether to create a GIN index on `JSONB` values, which speeds up
+    /// [`ScanOptions::json_contains`](crate::ScanOptions::json_contains) filters.
+    ///
+    /// The index is created with the table, or on connect if the table
+    /// already exists. Requires [`ValueType::Jsonb`].
+    ///
+    /// Default: `false`
+    pub fn json_index(mut self, enabled: bool) -> Self {
+        self.json_index = enabled;
+        self
+    }
+
     /// Sets whether to automatically create the table.
     ///
+    /// When disabled, [`Store::with_config`](crate::Store::with_config)
+    /// checks the existing table instead, and fails with
+    /// [`Error::TableNotFound`](crate::Error::TableNotFound) if it is missing
+    /// or [`Error::SchemaMismatch`](crate::Error::SchemaMismatch) if its
+    /// columns, indexes, history triggers or table type don't match this
+    /// configuration. An older table is then only migrated if
+    /// [`Config::auto_migrate`] is enabled explicitly.
+    ///
     /// Default: `true`
     pub fn auto_create_table(mut self, auto_create: bool) -> Self {
         self.auto_create_table = auto_create;
         self
     }
 
+    /// Sets whether to upgrade an existing table to the current schema on connect.
+    ///
+    /// Tables created by older versions of pgkv may lack columns that newer
+    /// versions use. With this enabled, pending migrations are applied when
+    /// the store connects; otherwise apply them with
+    /// [`Store::migrate`](crate::Store::migrate), and preview them with
+    /// [`Store::pending_migrations`](crate::Store::pending_migrations).
+    ///
+    /// Default: the value of [`Config::auto_create_table`], so a store that
+    /// must not create the table doesn't alter it either unless this is
+    /// enabled explicitly.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, Store};
+    ///
+    /// // Migrations are reviewed and applied by a deploy step instead
+    /// let config = Config::new("postgresql://localhost/mydb").auto_migrate(false);
+    /// let store = Store::with_config(config)?;
+    ///
+    /// for migration in store.pending_migrations()? {
+    ///     println!("{}", migration);
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn auto_migrate(mut self, enabled: bool) -> Self {
+        self.auto_migrate = Some(enabled);
+        self
+    }
+
     /// Sets the strategy for cleaning up expired keys.
     ///
     /// - [`TtlCleanupStrategy::OnRead`] (default): Automatically delete expired keys when accessed
@@ -225,14 +459,242 @@ impl Config {
         self
     }
 
+    /// Sets whether every set and delete is recorded in a history table.
+    ///
+    /// When enabled, the table `{table_name}_history` and the triggers that
+    /// populate it are created alongside the main table, enabling
+    /// [`Store::history`](crate::Store::history) and
+    /// [`Store::get_as_of`](crate::Store::get_as_of).
+    ///
+    /// Default: `false`
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Config;
+    /// use std::time::Duration;
+    ///
+    /// let config = Config::new("postgresql://localhost/mydb")
+    ///     .history(true)
+    ///     .history_retention(Duration::from_secs(30 * 24 * 3600)); // 30 days
+    /// ```
+    pub fn history(mut self, enabled: bool) -> Self {
+        self.history = enabled;
+        self
+    }
+
+    /// Sets how long history records are kept.
+    ///
+    /// Records older than this are deleted by
+    /// [`Store::prune_history`](crate::Store::prune_history).
+    ///
+    /// Default: `None` (keep forever)
+    pub fn history_retention(mut self, retention: Duration) -> Self {
+        self.history_retention = Some(retention);
+        self
+    }
+
+    /// Sets the compression applied to values before they are written.
+    ///
+    /// Reads decompress transparently, including values written before
+    /// compression was enabled or with a different algorithm. Increments and
+    /// the byte operations ([`Store::append`](crate::Store::append),
+    /// [`Store::set_range`](crate::Store::set_range), ...) write uncompressed
+    /// values, and `append`, `prepend` and `set_range` fail with
+    /// [`Error::InvalidValue`] on a compressed value.
+    ///
+    /// Default: [`Compression::None`]
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Compression, Config};
+    ///
+    /// // Compress values of 4KB or more with LZ4
+    /// let config = Config::new("postgresql://localhost/mydb")
+    ///     .compression(Compression::Lz4 { min_size: 4096 });
+    /// ```
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the size of the chunks written by [`Store::put_stream`](crate::Store::put_stream).
+    ///
+    /// Readers fetch one chunk at a time, so this bounds the memory used to
+    /// read or write a streamed value. Changing it doesn't affect values
+    /// already written.
+    ///
+    /// Default: 1MB
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Config;
+    ///
+    /// let config = Config::new("postgresql://localhost/mydb")
+    ///     .chunk_size(8 * 1024 * 1024); // 8MB
+    /// ```
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = size;
+        self
+    }
+
+    /// Hash-partitions the table on `key` into `count` partitions.
+    ///
+    /// Each partition is a separate table, `{table_name}_p0` to
+    /// `{table_name}_p{count - 1}`, of the configured [`TableType`] and with
+    /// its own indexes, so large tables can be vacuumed and analyzed a
+    /// partition at a time. This only applies when the table is created; an
+    /// existing table keeps its layout.
+    ///
+    /// Default: not partitioned
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Config;
+    ///
+    /// let config = Config::new("postgresql://localhost/mydb")
+    ///     .table_name("sessions")
+    ///     .partitions(16)
+    ///     .maintenance_workers(4);
+    /// ```
+    pub fn partitions(mut self, count: u32) -> Self {
+        self.partitions = Some(count);
+        self
+    }
+
+    /// Sets how many partitions [`Store::vacuum`](crate::Store::vacuum),
+    /// [`Store::analyze`](crate::Store::analyze),
+    /// [`Store::cleanup_expired`](crate::Store::cleanup_expired) and
+    /// [`Store::stats`](crate::Store::stats) process at once.
+    ///
+    /// With more than one worker, these open an extra connection per worker
+    /// for the duration of the call. Inside a transaction they always use
+    /// the store's own connection.
+    ///
+    /// Default: `1`
+    pub fn maintenance_workers(mut self, workers: usize) -> Self {
+        self.maintenance_workers = workers;
+        self
+    }
+
+    /// Encrypts values with keys from `provider` before they are written.
+    ///
+    /// Values are encrypted with XChaCha20-Poly1305 after compression, with
+    /// the key name as associated data. Reads decrypt transparently, and values
+    /// written before encryption was enabled are returned as stored unless
+    /// [`Config::reject_unencrypted`] is enabled.
+    /// Operations that compute the new value in SQL (increments,
+    /// [`Store::append`](crate::Store::append), `prepend` and `set_range`)
+    /// and [`Store::put_stream`](crate::Store::put_stream), whose chunks
+    /// aren't encrypted, return [`Error::Config`](crate::Error::Config)
+    /// instead of writing plaintext. See [`Store::reencrypt_all`](crate::Store::reencrypt_all)
+    /// for key rotation.
+    ///
+    /// Default: disabled
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, Keyring};
+    ///
+    /// # let key = [0u8; 32];
+    /// let config = Config::new("postgresql://localhost/mydb")
+    ///     .encryption(Keyring::new(1, key));
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub fn encryption(mut self, provider: impl KeyProvider + 'static) -> Self {
+        self.encryption = Some(Encryptor::new(provider));
+        self
+    }
+
+    /// Sets whether reads reject values that aren't encrypted.
+    ///
+    /// A value's `encoding` column says whether it is encrypted. Without this
+    /// option, anyone who can write to the table can replace a ciphertext
+    /// with plaintext of their choosing and clear the flag, and the store
+    /// returns it as if it had written it (a downgrade attack). With it, such
+    /// values fail with [`Error::Encryption`](crate::Error::Encryption).
+    ///
+    /// Enable it once every value is encrypted: with this option set,
+    /// [`Store::reencrypt_all`](crate::Store::reencrypt_all) also encrypts
+    /// values written before encryption was enabled. Requires
+    /// [`Config::encryption`].
+    ///
+    /// Default: `false`
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, Keyring, Store};
+    ///
+    /// # let key = [0u8; 32];
+    /// let config = Config::new("postgresql://localhost/mydb")
+    ///     .encryption(Keyring::new(1, key))
+    ///     .reject_unencrypted(true);
+    /// let store = Store::with_config(config)?;
+    /// store.reencrypt_all()?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub fn reject_unencrypted(mut self, enabled: bool) -> Self {
+        self.reject_unencrypted = enabled;
+        self
+    }
+
     /// Returns the fully qualified table name (with schema if set).
     pub(crate) fn qualified_table_name(&self) -> String {
+        self.qualified_name(&self.table_name)
+    }
+
+    /// Returns the fully qualified name of the history table.
+    pub(crate) fn qualified_history_table_name(&self) -> String {
+        self.qualified_name(&format!("{}_history", self.table_name))
+    }
+
+    /// Returns the fully qualified name of the table holding streamed value chunks.
+    pub(crate) fn qualified_chunks_table_name(&self) -> String {
+        self.qualified_name(&format!("{}_chunks", self.table_name))
+    }
+
+    /// Returns the fully qualified name of the table recording secondary indexes.
+    pub(crate) fn qualified_indexes_table_name(&self) -> String {
+        self.qualified_name(&format!("{}_indexes", self.table_name))
+    }
+
+    /// Returns the fully qualified name of the sequence that generates row versions.
+    pub(crate) fn qualified_version_seq_name(&self) -> String {
+        self.qualified_name(&format!("{}_version_seq", self.table_name))
+    }
+
+    /// Returns the fully qualified name of the table recording schema versions.
+    pub(crate) fn qualified_schema_version_table_name(&self) -> String {
+        self.qualified_name("pgkv_schema_version")
+    }
+
+    /// Returns the fully qualified name of partition `index` of the table.
+    pub(crate) fn qualified_partition_name(&self, index: u32) -> String {
+        self.qualified_name(&format!("{}_p{}", self.table_name, index))
+    }
+
+    /// Returns a database object name qualified with the configured schema.
+    pub(crate) fn qualified_name(&self, name: &str) -> String {
         match &self.schema {
-            Some(schema) => format!("{}.{}", quote_ident(schema), quote_ident(&self.table_name)),
-            None => quote_ident(&self.table_name),
+            Some(schema) => format!("{}.{}", quote_ident(schema), quote_ident(name)),
+            None => quote_ident(name),
         }
     }
 
+    /// Returns whether pending migrations are applied on connect.
+    #[inline]
+    pub(crate) fn migrate_on_connect(&self) -> bool {
+        self.auto_migrate.unwrap_or(self.auto_create_table)
+    }
+
     /// Returns whether TTL expiration checking is enabled.
     #[inline]
     pub(crate) fn ttl_enabled(&self) -> bool {
@@ -273,6 +735,147 @@ impl Config {
             ));
         }
 
+        if self.chunk_size == 0 || self.chunk_size > MAX_CHUNK_SIZE {
+            return Err(Error::Config(format!(
+                "chunk_size must be between 1 and {} bytes",
+                MAX_CHUNK_SIZE
+            )));
+        }
+
+        if let Some(count) = self.partitions {
+            if !(2..=MAX_PARTITIONS).contains(&count) {
+                return Err(Error::Config(format!(
+                    "partitions must be between 2 and {}",
+                    MAX_PARTITIONS
+                )));
+            }
+        }
+
+        if self.maintenance_workers == 0 {
+            return Err(Error::Config(
+                "maintenance_workers must be greater than 0".into(),
+            ));
+        }
+
+        if !self.compression.is_available() {
+            return Err(compression::unavailable(self.compression.name()));
+        }
+
+        if self.value_type == ValueType::Jsonb && self.compression != Compression::None {
+            return Err(Error::Config(
+                "compression is not supported with ValueType::Jsonb".into(),
+            ));
+        }
+
+        if self.json_index && self.value_type != ValueType::Jsonb {
+            return Err(Error::Config("json_index requires ValueType::Jsonb".into()));
+        }
+
+        #[cfg(feature = "encryption")]
+        if self.encryption.is_some() && self.value_type == ValueType::Jsonb {
+            return Err(Error::Config(
+                "encryption is not supported with ValueType::Jsonb".into(),
+            ));
+        }
+
+        #[cfg(feature = "encryption")]
+        if self.reject_unencrypted && self.encryption.is_none() {
+            return Err(Error::Config(
+                "reject_unencrypted requires Config::encryption".into(),
+            ));
+        }
+
+        #[cfg(feature = "encryption")]
+        if let Some(ref encryptor) = self.encryption {
+            if !encryptor.has_current_key() {
+                return Err(Error::Config(format!(
+                    "key provider has no key for current key ID {}",
+                    encryptor.current_key_id()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Configuration for the in-process cache used by [`CachedStore`](crate::CachedStore).
+///
+/// # Example
+///
+/// ```rust
+/// use pgkv::LocalCacheConfig;
+/// use std::time::Duration;
+///
+/// let config = LocalCacheConfig::new()
+///     .max_entries(10_000)
+///     .max_bytes(64 * 1024 * 1024) // 64MB
+///     .ttl(Duration::from_secs(30));
+/// ```
+#[derive(Debug, Clone)]
+pub struct LocalCacheConfig {
+    /// Maximum number of entries held in memory.
+    pub(crate) max_entries: usize,
+
+    /// Maximum total size of cached keys and values in bytes.
+    pub(crate) max_bytes: usize,
+
+    /// How long an entry may be served from memory before it is refetched.
+    pub(crate) ttl: Duration,
+}
+
+impl Default for LocalCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 1024,
+            max_bytes: 16 * 1024 * 1024, // 16MB
+            ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+impl LocalCacheConfig {
+    /// Creates a new local cache configuration with default limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of cached entries.
+    ///
+    /// Default: `1024`
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Sets the maximum total size of cached keys and values in bytes.
+    ///
+    /// Default: `16777216` (16MB)
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Sets how long an entry may be served from memory.
+    ///
+    /// Entries are also bounded by the key's own expiration time.
+    ///
+    /// Default: `60` seconds
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Validates the configuration.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.max_entries == 0 {
+            return Err(Error::Config("max_entries must be greater than 0".into()));
+        }
+
+        if self.max_bytes == 0 {
+            return Err(Error::Config("max_bytes must be greater than 0".into()));
+        }
+
         Ok(())
     }
 }
@@ -284,6 +887,13 @@ fn quote_ident(ident: &str) -> String {
     format!("\"{}\"", escaped)
 }
 
+/// Quotes a string literal for safe use in SQL.
+pub(crate) fn quote_literal(value: &str) -> String {
+    // PostgreSQL literal quoting: double any existing single quotes
+    let escaped = value.replace('\'', "''");
+    format!("'{}'", escaped)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -294,9 +904,15 @@ mod tests {
         assert_eq!(config.table_name, "kv_store");
         assert_eq!(config.table_type, TableType::Unlogged);
         assert!(config.auto_create_table);
+        assert!(config.migrate_on_connect());
         assert_eq!(config.ttl_cleanup_strategy, TtlCleanupStrategy::OnRead);
         assert!(config.ttl_enabled());
         assert!(config.cleanup_on_read());
+
+        // Migrating on connect follows auto_create_table unless set
+        let config = Config::new("postgresql://localhost/test").auto_create_table(false);
+        assert!(!config.migrate_on_connect());
+        assert!(config.auto_migrate(true).migrate_on_connect());
     }
 
     #[test]
@@ -305,6 +921,7 @@ mod tests {
             .table_name("custom_table")
             .table_type(TableType::Regular)
             .auto_create_table(false)
+            .auto_migrate(false)
             .ttl_cleanup_strategy(TtlCleanupStrategy::Manual)
             .max_key_length(2048)
             .max_value_size(1024)
@@ -314,6 +931,7 @@ mod tests {
         assert_eq!(config.table_name, "custom_table");
         assert_eq!(config.table_type, TableType::Regular);
         assert!(!config.auto_create_table);
+        assert!(!config.migrate_on_connect());
         assert_eq!(config.ttl_cleanup_strategy, TtlCleanupStrategy::Manual);
         assert!(config.ttl_enabled());
         assert!(!config.cleanup_on_read());
@@ -323,6 +941,65 @@ mod tests {
         assert_eq!(config.application_name, Some("my_app".to_string()));
     }
 
+    #[test]
+    fn test_history_config() {
+        let config = Config::new("postgresql://localhost/test");
+        assert!(!config.history);
+        assert!(config.history_retention.is_none());
+
+        let config = Config::new("postgresql://localhost/test")
+            .table_name("flags")
+            .history(true)
+            .history_retention(Duration::from_secs(3600));
+        assert!(config.history);
+        assert_eq!(config.history_retention, Some(Duration::from_secs(3600)));
+        assert_eq!(config.qualified_history_table_name(), "\"flags_history\"");
+    }
+
+    #[test]
+    fn test_compression_config() {
+        let config = Config::new("postgresql://localhost/test");
+        assert_eq!(config.compression, Compression::None);
+        assert!(config.validate().is_ok());
+
+        let config = Config::new("postgresql://localhost/test").compression(Compression::Zstd {
+            level: 3,
+            min_size: 1024,
+        });
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "zstd"));
+
+        let config = Config::new("postgresql://localhost/test")
+            .compression(Compression::Lz4 { min_size: 0 });
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "lz4"));
+    }
+
+    #[test]
+    fn test_chunk_size_config() {
+        let config = Config::new("postgresql://localhost/test").table_name("blobs");
+        assert_eq!(config.chunk_size, 1024 * 1024);
+        assert_eq!(config.qualified_chunks_table_name(), "\"blobs_chunks\"");
+
+        let config = config.chunk_size(0);
+        assert!(config.validate().is_err());
+
+        let config = config.chunk_size(MAX_CHUNK_SIZE + 1);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_partitions_config() {
+        let config = Config::new("postgresql://localhost/test").table_name("big");
+        assert_eq!(config.partitions, None);
+        assert_eq!(config.maintenance_workers, 1);
+        assert_eq!(config.qualified_partition_name(3), "\"big_p3\"");
+
+        assert!(config.clone().partitions(16).validate().is_ok());
+        for count in [0, 1, MAX_PARTITIONS + 1] {
+            assert!(config.clone().partitions(count).validate().is_err());
+        }
+        assert!(config.maintenance_workers(0).validate().is_err());
+    }
+
     #[test]
     fn test_ttl_cleanup_strategies() {
         let config = Config::new("postgresql://localhost/test")
@@ -352,16 +1029,84 @@ mod tests {
         assert_eq!(config.qualified_table_name(), "\"my_schema\".\"my_table\"");
     }
 
+    #[test]
+    fn test_qualified_version_seq_name() {
+        let config = Config::new("postgresql://localhost/test").table_name("my_table");
+        assert_eq!(
+            config.qualified_version_seq_name(),
+            "\"my_table_version_seq\""
+        );
+
+        let config = Config::new("postgresql://localhost/test")
+            .table_name("my_table")
+            .schema("my_schema");
+        assert_eq!(
+            config.qualified_version_seq_name(),
+            "\"my_schema\".\"my_table_version_seq\""
+        );
+    }
+
     #[test]
     fn test_quote_ident() {
         assert_eq!(quote_ident("simple"), "\"simple\"");
         assert_eq!(quote_ident("has\"quote"), "\"has\"\"quote\"");
     }
 
+    #[test]
+    fn test_quote_literal() {
+        assert_eq!(quote_literal("simple"), "'simple'");
+        assert_eq!(quote_literal("it's"), "'it''s'");
+    }
+
+    #[test]
+    fn test_local_cache_config() {
+        let config = LocalCacheConfig::new();
+        assert_eq!(config.max_entries, 1024);
+        assert!(config.validate().is_ok());
+
+        let config = LocalCacheConfig::new()
+            .max_entries(10)
+            .max_bytes(4096)
+            .ttl(Duration::from_secs(5));
+        assert_eq!(config.max_entries, 10);
+        assert_eq!(config.max_bytes, 4096);
+        assert_eq!(config.ttl, Duration::from_secs(5));
+
+        assert!(LocalCacheConfig::new().max_entries(0).validate().is_err());
+        assert!(LocalCacheConfig::new().max_bytes(0).validate().is_err());
+    }
+
     #[test]
     fn test_table_type_sql() {
         assert_eq!(TableType::Unlogged.sql_keyword(), "UNLOGGED");
         assert_eq!(TableType::Regular.sql_keyword(), "");
+        assert_eq!(TableType::Regular.persistence_keyword(), "LOGGED");
+        assert_eq!(TableType::from_persistence("u"), TableType::Unlogged);
+        assert_eq!(TableType::from_persistence("p"), TableType::Regular);
+    }
+
+    #[test]
+    fn test_key_type() {
+        let config = Config::new("postgresql://localhost/test");
+        assert_eq!(config.key_type, KeyType::Text);
+        assert_eq!(config.key_type.sql_type(), "TEXT");
+
+        let config = config.key_type(KeyType::Bytes);
+        assert_eq!(config.key_type.sql_type(), "BYTEA");
+    }
+
+    #[test]
+    fn test_value_type() {
+        let config = Config::new("postgresql://localhost/test");
+        assert_eq!(config.value_type, ValueType::Bytes);
+        assert!(config.clone().json_index(true).validate().is_err());
+
+        let config = config.value_type(ValueType::Jsonb).json_index(true);
+        assert_eq!(config.value_type.sql_type(), "JSONB");
+        assert!(config.validate().is_ok());
+
+        let compressed = config.compression(Compression::Lz4 { min_size: 0 });
+        assert!(compressed.validate().is_err());
     }
 
     #[test]
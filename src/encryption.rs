@@ -0,0 +1,316 @@
+//! Client-side value encryption.
+//!
+//! Enable it with [`Config::encryption`](crate::Config::encryption), which
+//! requires the `encryption` feature. Values are encrypted with
+//! XChaCha20-Poly1305 after compression, and the key name is bound as
+//! associated data, so a ciphertext copied to another key fails to decrypt.
+//!
+//! # Value format
+//!
+//! Encrypted values start with a 30-byte header: `0xF5`, the format version
+//! (`1`), the key ID as a little-endian `u32`, and the 24-byte nonce. The
+//! ciphertext and the 16-byte authentication tag follow. The row's `encoding`
+//! column marks encrypted values, so other values are returned as stored,
+//! whatever bytes they start with.
+//!
+//! # Downgrade attacks
+//!
+//! Because the flag lives next to the value, anyone who can write to the
+//! table can replace a ciphertext with plaintext and clear the flag, and an
+//! encrypting store returns that plaintext as if it had written it. Enable
+//! [`Config::reject_unencrypted`](crate::Config::reject_unencrypted) to make
+//! reads of unencrypted values fail with
+//! [`Error::Encryption`](crate::Error::Encryption) instead.
+
+use crate::error::Error;
+use crate::key::display_key;
+
+#[cfg(feature = "encryption")]
+use crate::error::Result;
+#[cfg(feature = "encryption")]
+use std::{collections::HashMap, fmt, sync::Arc};
+
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, OsRng, Payload},
+};
+
+/// First byte of every encrypted value.
+#[cfg(feature = "encryption")]
+const HEADER_MAGIC: u8 = 0xF5;
+
+/// Version of the encrypted value format.
+#[cfg(feature = "encryption")]
+const FORMAT_VERSION: u8 = 1;
+
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 24;
+#[cfg(feature = "encryption")]
+const TAG_LEN: usize = 16;
+
+/// Length of the header in front of the ciphertext.
+#[cfg(feature = "encryption")]
+const HEADER_LEN: usize = 6 + NONCE_LEN;
+
+/// Bit set in a row's `encoding` column when its value is encrypted.
+pub(crate) const ENCODING_FLAG: i16 = 2;
+
+/// Returns whether `value` has the header of an encrypted value.
+#[cfg(feature = "encryption")]
+fn is_encrypted(value: &[u8]) -> bool {
+    value.len() >= HEADER_LEN + TAG_LEN && value[0] == HEADER_MAGIC && value[1] == FORMAT_VERSION
+}
+
+/// Returns the ID of the key that encrypted `value`.
+#[cfg(feature = "encryption")]
+fn key_id(value: &[u8]) -> Option<u32> {
+    is_encrypted(value).then(|| u32::from_le_bytes([value[2], value[3], value[4], value[5]]))
+}
+
+/// SQL condition that is true when the `encoding` column marks an encrypted value.
+pub(crate) fn encrypted_sql(encoding: &str) -> String {
+    format!("(({} & {}) <> 0)", encoding, ENCODING_FLAG)
+}
+
+/// Error for a value that could not be encrypted or decrypted.
+pub(crate) fn encryption_error(key: &[u8], reason: impl Into<String>) -> Error {
+    Error::Encryption {
+        key: display_key(key),
+        reason: reason.into(),
+    }
+}
+
+/// A source of 256-bit encryption keys, identified by number.
+///
+/// New values are encrypted with [`KeyProvider::current_key_id`]. Every value
+/// records the ID of the key that encrypted it, so keys can be rotated by
+/// changing the current ID while still returning the old keys from
+/// [`KeyProvider::key`] until [`Store::reencrypt_all`](crate::Store::reencrypt_all)
+/// has run (and the history retention has passed, if history is enabled).
+///
+/// Implement this to fetch keys from a KMS or secret manager, or use [`Keyring`].
+#[cfg(feature = "encryption")]
+#[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+pub trait KeyProvider: Send + Sync {
+    /// Returns the ID of the key used to encrypt new values.
+    fn current_key_id(&self) -> u32;
+
+    /// Returns the key with the given ID, or `None` if it is unknown.
+    fn key(&self, id: u32) -> Option<[u8; 32]>;
+}
+
+/// A [`KeyProvider`] holding keys in memory.
+///
+/// # Example
+///
+/// ```rust
+/// use pgkv::Keyring;
+///
+/// # let (old_key, new_key) = ([1u8; 32], [2u8; 32]);
+/// // Encrypt with key 2; key 1 is only used to decrypt older values
+/// let keyring = Keyring::new(2, new_key).retired_key(1, old_key);
+/// ```
+#[cfg(feature = "encryption")]
+#[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+#[derive(Clone)]
+pub struct Keyring {
+    current: u32,
+    keys: HashMap<u32, [u8; 32]>,
+}
+
+#[cfg(feature = "encryption")]
+impl Keyring {
+    /// Creates a keyring that encrypts with `key`, identified by `id`.
+    pub fn new(id: u32, key: [u8; 32]) -> Self {
+        Self {
+            current: id,
+            keys: HashMap::from([(id, key)]),
+        }
+    }
+
+    /// Adds a key that is only used to decrypt values written before a rotation.
+    pub fn retired_key(mut self, id: u32, key: [u8; 32]) -> Self {
+        self.keys.entry(id).or_insert(key);
+        self
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl KeyProvider for Keyring {
+    fn current_key_id(&self) -> u32 {
+        self.current
+    }
+
+    fn key(&self, id: u32) -> Option<[u8; 32]> {
+        self.keys.get(&id).copied()
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<_> = self.keys.keys().collect();
+        ids.sort();
+        f.debug_struct("Keyring")
+            .field("current", &self.current)
+            .field("key_ids", &ids)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Encrypts and decrypts values with keys from a [`KeyProvider`].
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub(crate) struct Encryptor {
+    provider: Arc<dyn KeyProvider>,
+}
+
+#[cfg(feature = "encryption")]
+impl Encryptor {
+    pub(crate) fn new(provider: impl KeyProvider + 'static) -> Self {
+        Self {
+            provider: Arc::new(provider),
+        }
+    }
+
+    /// Returns the ID of the key that encrypts new values.
+    pub(crate) fn current_key_id(&self) -> u32 {
+        self.provider.current_key_id()
+    }
+
+    /// Returns whether the provider has the key that encrypts new values.
+    pub(crate) fn has_current_key(&self) -> bool {
+        self.provider.key(self.current_key_id()).is_some()
+    }
+
+    /// Encrypts the value stored at `key` with the current key.
+    pub(crate) fn encrypt(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let id = self.provider.current_key_id();
+        let cipher = self.cipher(id).ok_or_else(|| {
+            Error::Config(format!("key provider has no key for current key ID {}", id))
+        })?;
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value,
+                    aad: key,
+                },
+            )
+            .map_err(|_| encryption_error(key, "failed to encrypt value"))?;
+
+        let mut encrypted = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        encrypted.extend_from_slice(&[HEADER_MAGIC, FORMAT_VERSION]);
+        encrypted.extend_from_slice(&id.to_le_bytes());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    /// Decrypts the value stored at `key`, which must be encrypted.
+    pub(crate) fn decrypt(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let id = key_id(value).ok_or_else(|| encryption_error(key, "value is not encrypted"))?;
+        let cipher = self
+            .cipher(id)
+            .ok_or_else(|| encryption_error(key, format!("unknown key ID {}", id)))?;
+
+        let nonce = XNonce::from_slice(&value[6..HEADER_LEN]);
+        cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &value[HEADER_LEN..],
+                    aad: key,
+                },
+            )
+            .map_err(|_| encryption_error(key, "authentication failed"))
+    }
+
+    fn cipher(&self, id: u32) -> Option<XChaCha20Poly1305> {
+        let key = self.provider.key(id)?;
+        Some(XChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl fmt::Debug for Encryptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryptor")
+            .field("current_key_id", &self.provider.current_key_id())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+
+    fn encryptor() -> Encryptor {
+        Encryptor::new(Keyring::new(1, [7; 32]))
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let encryptor = encryptor();
+        let encrypted = encryptor.encrypt(b"session:1", b"secret").unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert_eq!(key_id(&encrypted), Some(1));
+        assert_eq!(encrypted.len(), HEADER_LEN + b"secret".len() + TAG_LEN);
+        assert_eq!(
+            encryptor.decrypt(b"session:1", &encrypted).unwrap(),
+            b"secret"
+        );
+
+        // Random nonces make every ciphertext different
+        assert_ne!(
+            encryptor.encrypt(b"session:1", b"secret").unwrap(),
+            encrypted
+        );
+    }
+
+    #[test]
+    fn test_key_name_is_bound() {
+        let encryptor = encryptor();
+        let encrypted = encryptor.encrypt(b"session:1", b"secret").unwrap();
+
+        let err = encryptor.decrypt(b"session:2", &encrypted).unwrap_err();
+        assert!(matches!(err, Error::Encryption { ref key, .. } if key == "session:2"));
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let encryptor = encryptor();
+        let mut encrypted = encryptor.encrypt(b"k", b"secret").unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert!(encryptor.decrypt(b"k", &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = encryptor();
+        let encrypted = old.encrypt(b"k", b"secret").unwrap();
+
+        let rotated = Encryptor::new(Keyring::new(2, [9; 32]).retired_key(1, [7; 32]));
+        assert_eq!(rotated.decrypt(b"k", &encrypted).unwrap(), b"secret");
+        assert_eq!(key_id(&rotated.encrypt(b"k", b"secret").unwrap()), Some(2));
+
+        let err = Encryptor::new(Keyring::new(2, [9; 32]))
+            .decrypt(b"k", &encrypted)
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown key ID 1"));
+    }
+
+    #[test]
+    fn test_keyring_debug_hides_keys() {
+        let keyring = Keyring::new(2, [9; 32]).retired_key(1, [7; 32]);
+        assert_eq!(
+            format!("{:?}", keyring),
+            "Keyring { current: 2, key_ids: [1, 2], .. }"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 //! Error types for pgkv operations.
 
+use postgres::error::SqlState;
 use std::fmt;
 
 /// Result type alias for pgkv operations.
@@ -38,6 +39,24 @@ pub enum Error {
         key: String,
     },
 
+    /// The value stored at the key is not a number.
+    NotNumeric {
+        /// The key holding the non-numeric value.
+        key: String,
+    },
+
+    /// A value could not be encrypted or decrypted.
+    ///
+    /// Returned when a value was encrypted with a key the key provider doesn't
+    /// know, or fails authentication because it was modified or copied from
+    /// another key.
+    Encryption {
+        /// The key holding the value.
+        key: String,
+        /// What went wrong.
+        reason: String,
+    },
+
     /// The key has expired.
     Expired {
         /// The key that has expired.
@@ -47,12 +66,31 @@ pub enum Error {
     /// A transaction error occurred.
     Transaction(String),
 
+    /// A transaction was aborted because of a concurrent update or deadlock.
+    ///
+    /// The operation can be retried.
+    Conflict(String),
+
     /// The table does not exist and auto-creation is disabled.
     TableNotFound {
         /// The name of the missing table.
         table: String,
     },
 
+    /// The table doesn't match the configuration.
+    ///
+    /// Returned on connect when auto-creation is disabled and the table lacks
+    /// columns or indexes, has columns of the wrong type, or is logged when
+    /// the configuration expects an unlogged table (or the reverse).
+    SchemaMismatch {
+        /// The name of the table.
+        table: String,
+        /// What the configuration expects, one entry per difference.
+        expected: Vec<String>,
+        /// What the table has instead, matching `expected` entry by entry.
+        found: Vec<String>,
+    },
+
     /// Configuration error.
     Config(String),
 
@@ -84,11 +122,30 @@ impl fmt::Display for Error {
             Error::CasMismatch { key } => {
                 write!(f, "compare-and-swap failed for key: {}", key)
             }
+            Error::NotNumeric { key } => write!(f, "value is not numeric for key: {}", key),
+            Error::Encryption { key, reason } => {
+                write!(f, "encryption error for key {}: {}", key, reason)
+            }
             Error::Expired { key } => write!(f, "key has expired: {}", key),
             Error::Transaction(msg) => write!(f, "transaction error: {}", msg),
+            Error::Conflict(msg) => write!(f, "transaction conflict: {}", msg),
             Error::TableNotFound { table } => {
                 write!(f, "table not found: {}", table)
             }
+            Error::SchemaMismatch {
+                table,
+                expected,
+                found,
+            } => {
+                write!(f, "schema mismatch in table {}: ", table)?;
+                for (i, (expected, found)) in expected.iter().zip(found).enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "expected {}, found {}", expected, found)?;
+                }
+                Ok(())
+            }
             Error::Config(msg) => write!(f, "configuration error: {}", msg),
             Error::Io(e) => write!(f, "I/O error: {}", e),
             #[cfg(feature = "serde")]
@@ -101,6 +158,11 @@ impl From<postgres::Error> for Error {
     fn from(err: postgres::Error) -> Self {
         if err.is_closed() {
             Error::Connection(err.to_string())
+        } else if matches!(
+            err.code(),
+            Some(&SqlState::T_R_SERIALIZATION_FAILURE | &SqlState::T_R_DEADLOCK_DETECTED)
+        ) {
+            Error::Conflict(err.to_string())
         } else {
             Error::Query(err.to_string())
         }
@@ -138,12 +200,21 @@ impl Error {
         matches!(self, Error::Connection(_))
     }
 
+    /// Returns `true` if this error is a transaction conflict.
+    #[inline]
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, Error::Conflict(_))
+    }
+
     /// Returns `true` if this error is recoverable (can retry).
     #[inline]
     pub fn is_recoverable(&self) -> bool {
         matches!(
             self,
-            Error::Connection(_) | Error::CasMismatch { .. } | Error::Expired { .. }
+            Error::Connection(_)
+                | Error::CasMismatch { .. }
+                | Error::Expired { .. }
+                | Error::Conflict(_)
         )
     }
 }
@@ -163,6 +234,31 @@ mod tests {
             key: "test".to_string(),
         };
         assert!(err.to_string().contains("compare-and-swap"));
+
+        let err = Error::NotNumeric {
+            key: "test".to_string(),
+        };
+        assert_eq!(err.to_string(), "value is not numeric for key: test");
+
+        let err = Error::Encryption {
+            key: "test".to_string(),
+            reason: "authentication failed".to_string(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "encryption error for key test: authentication failed"
+        );
+
+        let err = Error::SchemaMismatch {
+            table: "kv".to_string(),
+            expected: vec!["column tags of type text[]".into(), "UNLOGGED table".into()],
+            found: vec!["no column tags".into(), "LOGGED table".into()],
+        };
+        assert_eq!(
+            err.to_string(),
+            "schema mismatch in table kv: expected column tags of type text[], found no column \
+             tags; expected UNLOGGED table, found LOGGED table"
+        );
     }
 
     #[test]
@@ -183,5 +279,9 @@ mod tests {
         let conn = Error::Connection("test".to_string());
         assert!(conn.is_connection());
         assert!(conn.is_recoverable());
+
+        let conflict = Error::Conflict("test".to_string());
+        assert!(conflict.is_conflict());
+        assert!(conflict.is_recoverable());
     }
 }
@@ -0,0 +1,187 @@
+//! Secondary indexes over values.
+//!
+//! [`Store::create_index`](crate::Store::create_index) creates a PostgreSQL
+//! expression index on the table, so the database keeps it up to date on
+//! every write, and records the expression in the companion table
+//! `{table_name}_indexes`. [`Store::find_by`](crate::Store::find_by) reads the
+//! expression back from that table, so every store pointed at the table can
+//! query an index whichever store created it.
+//!
+//! Indexed expressions are compared as text. JSON path indexes on `BYTEA`
+//! values parse each value as UTF-8 JSON; values that aren't JSON have no
+//! entry in the index.
+
+use crate::config::{ValueType, quote_literal};
+use crate::error::{Error, Result};
+
+/// Maximum length of a PostgreSQL identifier in bytes.
+const MAX_IDENTIFIER_LEN: usize = 63;
+
+/// What a secondary index extracts from each value.
+///
+/// # Example
+///
+/// ```rust
+/// use pgkv::IndexExpr;
+///
+/// // The `user_id` field of JSON values
+/// let by_user = IndexExpr::json_path(&["user_id"]);
+///
+/// // Any SQL expression over the `value` column
+/// let by_length = IndexExpr::sql("octet_length(value)");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexExpr {
+    /// The JSON at a path inside the value, as text.
+    ///
+    /// The path lists object keys and array indexes from the top of the
+    /// document. Strings are indexed without their quotes, so `"alice"` is
+    /// found with `find_by(index, "alice")`.
+    JsonPath(Vec<String>),
+
+    /// A SQL expression over the `value` and `key` columns, indexed as text.
+    ///
+    /// The expression is inserted into the index definition as written, so
+    /// it must be trusted and `IMMUTABLE`.
+    Sql(String),
+}
+
+impl IndexExpr {
+    /// Indexes the JSON at `path` inside each value.
+    pub fn json_path(path: &[&str]) -> Self {
+        IndexExpr::JsonPath(path.iter().map(|s| s.to_string()).collect())
+    }
+
+    /// Indexes a custom SQL expression.
+    pub fn sql(expr: impl Into<String>) -> Self {
+        IndexExpr::Sql(expr.into())
+    }
+
+    /// Returns the indexed SQL expression for a table of `value_type` values.
+    ///
+    /// `try_jsonb` is the qualified name of the function created by
+    /// [`try_jsonb_function_sql`].
+    pub(crate) fn to_sql(&self, value_type: ValueType, try_jsonb: &str) -> Result<String> {
+        match self {
+            IndexExpr::JsonPath(path) => {
+                if path.is_empty() {
+                    return Err(Error::Config("JSON path index needs a path".into()));
+                }
+                let path = text_array_literal(path);
+                Ok(match value_type {
+                    ValueType::Jsonb => format!("(value #>> {}::text[])", path),
+                    ValueType::Bytes => format!("({}(value) #>> {}::text[])", try_jsonb, path),
+                })
+            }
+            IndexExpr::Sql(expr) => {
+                if expr.trim().is_empty() {
+                    return Err(Error::Config("index expression cannot be empty".into()));
+                }
+                Ok(format!("(({})::text)", expr))
+            }
+        }
+    }
+}
+
+/// Returns the name of the PostgreSQL index backing index `name` on `table_name`.
+///
+/// Fails unless `name` is lowercase letters, digits and underscores, starting
+/// with a letter, and the full name fits in a PostgreSQL identifier.
+pub(crate) fn index_name(table_name: &str, name: &str) -> Result<String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !valid {
+        return Err(Error::Config(format!(
+            "invalid index name {:?}: use lowercase letters, digits and underscores",
+            name
+        )));
+    }
+
+    let index = format!("{}_ix_{}", table_name, name);
+    if index.len() > MAX_IDENTIFIER_LEN {
+        return Err(Error::Config(format!(
+            "index name {:?} is too long for table {:?}",
+            name, table_name
+        )));
+    }
+    Ok(index)
+}
+
+/// Returns the SQL creating `function`, which parses a `BYTEA` value as JSON
+/// and returns `NULL` when it isn't.
+pub(crate) fn try_jsonb_function_sql(function: &str) -> String {
+    format!(
+        r#"
+        SELECT pg_advisory_xact_lock(hashtext('pgkv_try_jsonb'));
+
+        CREATE OR REPLACE FUNCTION {function}(value BYTEA) RETURNS JSONB AS $$
+        BEGIN
+            RETURN convert_from(value, 'UTF8')::jsonb;
+        EXCEPTION WHEN OTHERS THEN
+            RETURN NULL;
+        END;
+        $$ LANGUAGE plpgsql IMMUTABLE STRICT PARALLEL SAFE;
+        "#,
+    )
+}
+
+/// Returns `path` as a quoted PostgreSQL `text[]` literal.
+fn text_array_literal(path: &[String]) -> String {
+    let elements: Vec<String> = path
+        .iter()
+        .map(|s| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    quote_literal(&format!("{{{}}}", elements.join(",")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_path_sql() {
+        let expr = IndexExpr::json_path(&["user", "id"]);
+        assert_eq!(
+            expr.to_sql(ValueType::Jsonb, "f").unwrap(),
+            r#"(value #>> '{"user","id"}'::text[])"#
+        );
+        assert_eq!(
+            expr.to_sql(ValueType::Bytes, "f").unwrap(),
+            r#"(f(value) #>> '{"user","id"}'::text[])"#
+        );
+
+        let quoted = IndexExpr::json_path(&[r#"it's "x""#]);
+        assert_eq!(
+            quoted.to_sql(ValueType::Jsonb, "f").unwrap(),
+            r#"(value #>> '{"it''s \"x\""}'::text[])"#
+        );
+
+        assert!(
+            IndexExpr::json_path(&[])
+                .to_sql(ValueType::Jsonb, "f")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_sql_expr() {
+        let expr = IndexExpr::sql("octet_length(value)");
+        assert_eq!(
+            expr.to_sql(ValueType::Bytes, "f").unwrap(),
+            "((octet_length(value))::text)"
+        );
+        assert!(IndexExpr::sql("  ").to_sql(ValueType::Bytes, "f").is_err());
+    }
+
+    #[test]
+    fn test_index_name() {
+        assert_eq!(index_name("kv", "by_user").unwrap(), "kv_ix_by_user");
+
+        for name in ["", "1st", "ByUser", "by-user", "by user"] {
+            assert!(index_name("kv", name).is_err(), "{:?}", name);
+        }
+        assert!(index_name("kv", &"a".repeat(60)).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+//! Values in `BYTEA` or `JSONB` columns.
+//!
+//! The store handles values as bytes whatever the column type. With
+//! [`ValueType::Jsonb`](crate::ValueType::Jsonb) those bytes are JSON text,
+//! which these wrappers send and receive in the `jsonb` binary format: a
+//! version byte (`1`) followed by the text.
+
+use std::error::Error as StdError;
+
+use bytes::BytesMut;
+use postgres::types::{FromSql, IsNull, ToSql, Type, to_sql_checked};
+
+/// Version byte of the `jsonb` binary format.
+const JSONB_VERSION: u8 = 1;
+
+type BoxError = Box<dyn StdError + Sync + Send>;
+
+/// A value bound as a parameter for the value column.
+#[derive(Debug)]
+pub(crate) struct ValueParam<'a>(pub(crate) &'a [u8]);
+
+impl ToSql for ValueParam<'_> {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError> {
+        if *ty == Type::JSONB {
+            std::str::from_utf8(self.0)?;
+            out.extend_from_slice(&[JSONB_VERSION]);
+        }
+        out.extend_from_slice(self.0);
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::BYTEA || *ty == Type::JSONB
+    }
+
+    to_sql_checked!();
+}
+
+/// A value read from the value column.
+#[derive(Debug)]
+pub(crate) struct StoredValue(pub(crate) Vec<u8>);
+
+impl<'a> FromSql<'a> for StoredValue {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, BoxError> {
+        if *ty == Type::JSONB {
+            return match raw.split_first() {
+                Some((&JSONB_VERSION, text)) => Ok(StoredValue(text.to_vec())),
+                _ => Err("unsupported jsonb format version".into()),
+            };
+        }
+        Ok(StoredValue(raw.to_vec()))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::BYTEA || *ty == Type::JSONB
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jsonb_wire_format() {
+        let mut out = BytesMut::new();
+        ValueParam(br#"{"a":1}"#)
+            .to_sql(&Type::JSONB, &mut out)
+            .unwrap();
+        assert_eq!(&out[..], b"\x01{\"a\":1}");
+
+        let value = StoredValue::from_sql(&Type::JSONB, &out).unwrap();
+        assert_eq!(value.0, br#"{"a":1}"#);
+        assert!(StoredValue::from_sql(&Type::JSONB, b"\x02{}").is_err());
+    }
+
+    #[test]
+    fn test_bytea_passes_through() {
+        let mut out = BytesMut::new();
+        ValueParam(&[0xff, 0x00])
+            .to_sql(&Type::BYTEA, &mut out)
+            .unwrap();
+        assert_eq!(&out[..], &[0xff, 0x00]);
+        assert_eq!(
+            StoredValue::from_sql(&Type::BYTEA, &out).unwrap().0,
+            [0xff, 0x00]
+        );
+        assert!(!<ValueParam<'_> as ToSql>::accepts(&Type::TEXT));
+    }
+}
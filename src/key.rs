@@ -0,0 +1,462 @@
+//! Text, binary and structured keys.
+
+use std::fmt::{Display, Write};
+use std::str::FromStr;
+
+/// A key, as the bytes stored in the key column.
+///
+/// Implemented for text (`str`, `String`) and binary (`[u8]`, `Vec<u8>`,
+/// `[u8; N]`) keys. With [`KeyType::Text`](crate::KeyType::Text), binary keys
+/// must be valid UTF-8.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use pgkv::{Config, KeyType, Store};
+///
+/// let config = Config::new("postgresql://localhost/mydb")
+///     .table_name("objects")
+///     .key_type(KeyType::Bytes);
+/// let store = Store::with_config(config)?;
+///
+/// let digest: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+/// store.set(&digest, b"object data")?;
+/// assert!(store.exists(&digest)?);
+/// # Ok::<(), pgkv::Error>(())
+/// ```
+pub trait AsKey {
+    /// Returns the key's bytes.
+    fn key_bytes(&self) -> &[u8];
+}
+
+impl AsKey for str {
+    fn key_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsKey for String {
+    fn key_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsKey for [u8] {
+    fn key_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsKey for Vec<u8> {
+    fn key_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<const N: usize> AsKey for [u8; N] {
+    fn key_bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<K: AsKey + ?Sized> AsKey for &K {
+    fn key_bytes(&self) -> &[u8] {
+        (**self).key_bytes()
+    }
+}
+
+/// A structured key, rendered to and parsed from a string key.
+///
+/// Used as the key type of `KeyedStore` (with the `serde` feature). Implement it by
+/// hand, or declare the key with [`kv_key!`](crate::kv_key).
+///
+/// # Example
+///
+/// ```rust
+/// use pgkv::KvKey;
+///
+/// #[derive(Debug, PartialEq)]
+/// struct SessionKey(u64);
+///
+/// impl KvKey for SessionKey {
+///     fn to_key(&self) -> String {
+///         format!("session:{}", self.0)
+///     }
+///
+///     fn from_key(key: &str) -> Option<Self> {
+///         key.strip_prefix("session:")?.parse().ok().map(SessionKey)
+///     }
+///
+///     fn key_prefix() -> String {
+///         "session:".to_string()
+///     }
+/// }
+///
+/// assert_eq!(SessionKey(7).to_key(), "session:7");
+/// assert_eq!(SessionKey::from_key("session:7"), Some(SessionKey(7)));
+/// ```
+pub trait KvKey: Sized {
+    /// Renders the key.
+    fn to_key(&self) -> String;
+
+    /// Parses a key rendered by [`KvKey::to_key`], returning `None` if `key`
+    /// is not a key of this type.
+    fn from_key(key: &str) -> Option<Self>;
+
+    /// Returns the prefix shared by every key of this type, used to scan them.
+    ///
+    /// Defaults to the empty prefix, which scans all keys.
+    fn key_prefix() -> String {
+        String::new()
+    }
+}
+
+impl KvKey for String {
+    fn to_key(&self) -> String {
+        self.clone()
+    }
+
+    fn from_key(key: &str) -> Option<Self> {
+        Some(key.to_string())
+    }
+}
+
+/// Declares a struct implementing [`KvKey`](crate::KvKey).
+///
+/// The struct body lists the key's segments in order: string literals are
+/// written as they are and `name: Type` declares a public field, rendered
+/// with [`Display`](std::fmt::Display) and parsed with
+/// [`FromStr`](std::str::FromStr). Segments are joined with `:`, and `:` and
+/// `%` in field values are percent-encoded so every key parses back.
+///
+/// # Example
+///
+/// ```rust
+/// use pgkv::{KvKey, kv_key};
+///
+/// kv_key! {
+///     /// A user's profile section.
+///     #[derive(Debug, Clone, PartialEq)]
+///     pub struct ProfileKey {
+///         "user", user_id: u64, "profile", section: String,
+///     }
+/// }
+///
+/// let key = ProfileKey { user_id: 42, section: "a:b".to_string() };
+/// assert_eq!(key.to_key(), "user:42:profile:a%3Ab");
+/// assert_eq!(ProfileKey::from_key("user:42:profile:a%3Ab"), Some(key));
+/// assert_eq!(ProfileKey::from_key("user:42:settings:a"), None);
+/// assert_eq!(ProfileKey::key_prefix(), "user:");
+/// ```
+#[macro_export]
+macro_rules! kv_key {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident { $($segments:tt)* }
+    ) => {
+        $crate::kv_key!(@parse [$(#[$meta])* $vis $name] [] [] $($segments)*);
+    };
+
+    (@parse $head:tt [$($fields:tt)*] [$($segs:tt)*] $literal:literal $(, $($rest:tt)*)?) => {
+        $crate::kv_key!(@parse $head [$($fields)*] [$($segs)* (literal $literal)] $($($rest)*)?);
+    };
+
+    (@parse $head:tt [$($fields:tt)*] [$($segs:tt)*] $field:ident : $ty:ty $(, $($rest:tt)*)?) => {
+        $crate::kv_key!(
+            @parse $head [$($fields)* ($field: $ty)] [$($segs)* (field $field)] $($($rest)*)?
+        );
+    };
+
+    (@parse
+        [$(#[$meta:meta])* $vis:vis $name:ident]
+        [$(($field:ident: $ty:ty))*]
+        [$($seg:tt)*]
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $(pub $field: $ty,)*
+        }
+
+        impl $crate::KvKey for $name {
+            fn to_key(&self) -> ::std::string::String {
+                let mut writer = $crate::__private::KeyWriter::new();
+                $($crate::kv_key!(@write self writer $seg);)*
+                writer.finish()
+            }
+
+            fn from_key(key: &str) -> ::std::option::Option<Self> {
+                let mut reader = $crate::__private::KeyReader::new(key);
+                $($crate::kv_key!(@read reader $seg);)*
+                reader.finish()?;
+                ::std::option::Option::Some(Self { $($field),* })
+            }
+
+            fn key_prefix() -> ::std::string::String {
+                let mut writer = $crate::__private::KeyWriter::new();
+                $($crate::kv_key!(@prefix writer $seg);)*
+                writer.finish()
+            }
+        }
+    };
+
+    (@write $self:ident $writer:ident (literal $literal:literal)) => {
+        $writer.literal($literal)
+    };
+    (@write $self:ident $writer:ident (field $field:ident)) => {
+        $writer.field(&$self.$field)
+    };
+    (@read $reader:ident (literal $literal:literal)) => {
+        $reader.literal($literal)?
+    };
+    (@read $reader:ident (field $field:ident)) => {
+        let $field = $reader.field()?;
+    };
+    (@prefix $writer:ident (literal $literal:literal)) => {
+        $writer.literal($literal)
+    };
+    (@prefix $writer:ident (field $field:ident)) => {
+        $writer.placeholder()
+    };
+}
+
+/// Separator between the segments of a [`kv_key!`](crate::kv_key) key.
+const SEPARATOR: char = ':';
+
+/// Builds a [`kv_key!`](crate::kv_key) key or key prefix.
+#[doc(hidden)]
+#[derive(Debug, Default)]
+pub struct KeyWriter {
+    key: String,
+    started: bool,
+    /// Set once a field placeholder ends a prefix.
+    closed: bool,
+}
+
+impl KeyWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn literal(&mut self, literal: &str) {
+        if self.separate() {
+            self.key.push_str(literal);
+        }
+    }
+
+    pub fn field(&mut self, value: &impl Display) {
+        if self.separate() {
+            for c in value.to_string().chars() {
+                match c {
+                    '%' => self.key.push_str("%25"),
+                    SEPARATOR => self.key.push_str("%3A"),
+                    c => self.key.push(c),
+                }
+            }
+        }
+    }
+
+    /// Ends a prefix at the first field.
+    pub fn placeholder(&mut self) {
+        self.separate();
+        self.closed = true;
+    }
+
+    pub fn finish(self) -> String {
+        self.key
+    }
+
+    /// Writes the separator before every segment but the first, returning
+    /// whether the segment should be written.
+    fn separate(&mut self) -> bool {
+        if self.closed {
+            return false;
+        }
+        if self.started {
+            self.key.push(SEPARATOR);
+        }
+        self.started = true;
+        true
+    }
+}
+
+/// Parses a [`kv_key!`](crate::kv_key) key.
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct KeyReader<'a> {
+    rest: &'a str,
+    started: bool,
+}
+
+impl<'a> KeyReader<'a> {
+    pub fn new(key: &'a str) -> Self {
+        Self {
+            rest: key,
+            started: false,
+        }
+    }
+
+    pub fn literal(&mut self, literal: &str) -> Option<()> {
+        self.separate()?;
+        self.rest = self.rest.strip_prefix(literal)?;
+        Some(())
+    }
+
+    pub fn field<T: FromStr>(&mut self) -> Option<T> {
+        self.separate()?;
+        let end = self.rest.find(SEPARATOR).unwrap_or(self.rest.len());
+        let (raw, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        unescape(raw)?.parse().ok()
+    }
+
+    pub fn finish(self) -> Option<()> {
+        self.rest.is_empty().then_some(())
+    }
+
+    fn separate(&mut self) -> Option<()> {
+        if self.started {
+            self.rest = self.rest.strip_prefix(SEPARATOR)?;
+        }
+        self.started = true;
+        Some(())
+    }
+}
+
+/// Decodes a field escaped by [`KeyWriter::field`].
+fn unescape(raw: &str) -> Option<String> {
+    let mut value = String::with_capacity(raw.len());
+    let mut parts = raw.split('%');
+    value.push_str(parts.next()?);
+    for part in parts {
+        if let Some(rest) = part.strip_prefix("25") {
+            value.push('%');
+            value.push_str(rest);
+        } else if let Some(rest) = part.strip_prefix("3A") {
+            value.push(SEPARATOR);
+            value.push_str(rest);
+        } else {
+            return None;
+        }
+    }
+    Some(value)
+}
+
+/// Returns the smallest byte string greater than every key starting with
+/// `prefix`, or `None` if there is no such string.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Formats a key for error messages: as text if it is valid UTF-8, otherwise
+/// in PostgreSQL's `\x` hex format.
+pub(crate) fn display_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) => key.to_string(),
+        Err(_) => {
+            let mut hex = String::with_capacity(2 + key.len() * 2);
+            hex.push_str("\\x");
+            for byte in key {
+                let _ = write!(hex, "{:02x}", byte);
+            }
+            hex
+        }
+    }
+}
+
+/// Parses a `BYTEA` key in PostgreSQL's `\x` hex text format.
+pub(crate) fn parse_hex_key(text: &str) -> Option<Vec<u8>> {
+    let hex = text.strip_prefix("\\x")?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    kv_key! {
+        #[derive(Debug, Clone, PartialEq)]
+        struct ProfileKey {
+            "user", user_id: u64, "profile", section: String,
+        }
+    }
+
+    kv_key! {
+        #[derive(Debug, PartialEq)]
+        struct ConfigKey {
+            "config:global",
+        }
+    }
+
+    #[test]
+    fn test_kv_key_roundtrip() {
+        let key = ProfileKey {
+            user_id: 42,
+            section: "50%:done".to_string(),
+        };
+        assert_eq!(key.to_key(), "user:42:profile:50%25%3Adone");
+        assert_eq!(ProfileKey::from_key(&key.to_key()), Some(key));
+        assert_eq!(ProfileKey::key_prefix(), "user:");
+
+        assert_eq!(ConfigKey {}.to_key(), "config:global");
+        assert_eq!(ConfigKey::from_key("config:global"), Some(ConfigKey {}));
+        assert_eq!(ConfigKey::key_prefix(), "config:global");
+    }
+
+    #[test]
+    fn test_kv_key_rejects_other_keys() {
+        for key in [
+            "user:42:profile",
+            "user:42:profile:a:b",
+            "user:x:profile:a",
+            "user:42:settings:a",
+            "user:42:profile:a%2",
+            "account:42:profile:a",
+            "user42:profile:a",
+        ] {
+            assert_eq!(ProfileKey::from_key(key), None, "{}", key);
+        }
+        assert_eq!(ConfigKey::from_key("config:global:x"), None);
+    }
+
+    #[test]
+    fn test_key_bytes() {
+        assert_eq!("user:1".key_bytes(), b"user:1");
+        assert_eq!(String::from("a").key_bytes(), b"a");
+        assert_eq!([1u8, 2].key_bytes(), &[1, 2]);
+        assert_eq!(vec![3u8].key_bytes(), &[3]);
+        assert_eq!((&&"k").key_bytes(), b"k");
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_end(&[0x01, 0xff]), Some(vec![0x02]));
+        assert_eq!(prefix_end(&[0xff, 0xff]), None);
+        assert_eq!(prefix_end(b""), None);
+    }
+
+    #[test]
+    fn test_display_and_parse() {
+        assert_eq!(display_key(b"user:1"), "user:1");
+        assert_eq!(display_key(&[0xff, 0x01]), "\\xff01");
+
+        assert_eq!(parse_hex_key("\\xdead"), Some(vec![0xde, 0xad]));
+        assert_eq!(parse_hex_key("\\x"), Some(vec![]));
+        for text in ["dead", "\\xdea", "\\xzz", "\\xé0"] {
+            assert_eq!(parse_hex_key(text), None);
+        }
+    }
+}
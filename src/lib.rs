@@ -11,6 +11,7 @@
 //! - **Type Safe**: Strong typing with optional serde support for automatic serialization
 //! - **Production Ready**: Comprehensive error handling, connection pooling support, and transaction safety
 //! - **Configurable TTL Cleanup**: Choose between automatic, manual, or disabled key expiration cleanup
+//! - **Two-Tier Caching**: Optional in-process LRU with cross-process invalidation via `LISTEN`/`NOTIFY`
 //!
 //! ## Quick Start
 //!
@@ -54,18 +55,28 @@
 //!
 //! ## Architecture
 //!
-//! The library creates a simple schema:
+//! The library creates a simple schema, built up by numbered migrations
+//! (schema version 5):
 //!
 //! ```sql
+//! CREATE SEQUENCE IF NOT EXISTS {table_name}_version_seq;
+//!
 //! CREATE UNLOGGED TABLE IF NOT EXISTS {table_name} (
 //!     key TEXT PRIMARY KEY,
 //!     value BYTEA NOT NULL,
 //!     expires_at TIMESTAMPTZ,
 //!     created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
-//!     updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+//!     updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+//!     stale_at TIMESTAMPTZ,
+//!     tags TEXT[],
+//!     version BIGINT NOT NULL DEFAULT nextval('{table_name}_version_seq'),
+//!     encoding SMALLINT NOT NULL DEFAULT 0
 //! );
 //! ```
 //!
+//! `encoding` flags values that were compressed or encrypted and the
+//! manifests of streamed values.
+//!
 //! ## Configuration
 //!
 //! ```rust,no_run
@@ -110,22 +121,74 @@
 #![warn(rustdoc::missing_crate_level_docs)]
 #![deny(unsafe_code)]
 
+mod batch;
+mod cache;
+mod compression;
 mod config;
+mod encryption;
 mod error;
+mod index;
+mod json;
+mod key;
+mod migrate;
 mod store;
+mod stream;
+mod tx;
 mod types;
 
+#[cfg(feature = "serde")]
+mod codec;
 #[cfg(feature = "serde")]
 mod serde_support;
+#[cfg(feature = "serde")]
+mod versioning;
 
-pub use config::{Config, TableType, TtlCleanupStrategy};
+pub use batch::{Batch, BatchResult, OpResult, Precondition};
+pub use cache::CachedStore;
+pub use compression::Compression;
+pub use config::{Config, KeyType, LocalCacheConfig, TableType, TtlCleanupStrategy, ValueType};
 pub use error::{Error, Result};
+pub use index::IndexExpr;
+pub use key::{AsKey, KvKey};
+pub use migrate::Migration;
 pub use store::Store;
-pub use types::{CasResult, Entry, KeyValue, ScanOptions, Stats};
+pub use stream::ValueReader;
+pub use tx::Tx;
+pub use types::{
+    CacheStats, CasResult, Entry, Freshness, HistoryEntry, HistoryOp, IndexInfo, IsolationLevel,
+    KeyValue, ScanOptions, SetOptions, SetOutcome, Stats, TxOptions, VersionResult,
+};
 
+#[cfg(feature = "bincode")]
+#[cfg_attr(docsrs, doc(cfg(feature = "bincode")))]
+pub use codec::Bincode;
+#[cfg(feature = "cbor")]
+#[cfg_attr(docsrs, doc(cfg(feature = "cbor")))]
+pub use codec::Cbor;
+#[cfg(feature = "msgpack")]
+#[cfg_attr(docsrs, doc(cfg(feature = "msgpack")))]
+pub use codec::MessagePack;
+#[cfg(feature = "postcard")]
+#[cfg_attr(docsrs, doc(cfg(feature = "postcard")))]
+pub use codec::Postcard;
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+pub use codec::{Codec, Json};
+#[cfg(feature = "encryption")]
+#[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+pub use encryption::{KeyProvider, Keyring};
 #[cfg(feature = "serde")]
 #[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
-pub use serde_support::{TypedStore, TypedStoreExt};
+pub use serde_support::{KeyedStore, TypedStore, TypedStoreExt};
+#[cfg(feature = "serde")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+pub use versioning::ValueMigrations;
+
+/// Support code for the crate's macros. Not public API.
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::key::{KeyReader, KeyWriter};
+}
 
 /// Prelude module for convenient imports.
 ///
@@ -133,11 +196,31 @@ pub use serde_support::{TypedStore, TypedStoreExt};
 /// use pgkv::prelude::*;
 /// ```
 pub mod prelude {
-    pub use crate::config::{Config, TableType, TtlCleanupStrategy};
+    pub use crate::batch::{Batch, BatchResult, OpResult, Precondition};
+    pub use crate::cache::CachedStore;
+    pub use crate::compression::Compression;
+    pub use crate::config::{
+        Config, KeyType, LocalCacheConfig, TableType, TtlCleanupStrategy, ValueType,
+    };
     pub use crate::error::{Error, Result};
+    pub use crate::index::IndexExpr;
+    pub use crate::key::{AsKey, KvKey};
+    pub use crate::migrate::Migration;
     pub use crate::store::Store;
-    pub use crate::types::{CasResult, Entry, KeyValue, ScanOptions, Stats};
+    pub use crate::stream::ValueReader;
+    pub use crate::tx::Tx;
+    pub use crate::types::{
+        CacheStats, CasResult, Entry, Freshness, HistoryEntry, HistoryOp, IndexInfo,
+        IsolationLevel, KeyValue, ScanOptions, SetOptions, SetOutcome, Stats, TxOptions,
+        VersionResult,
+    };
 
     #[cfg(feature = "serde")]
-    pub use crate::serde_support::{TypedStore, TypedStoreExt};
+    pub use crate::codec::{Codec, Json};
+    #[cfg(feature = "encryption")]
+    pub use crate::encryption::{KeyProvider, Keyring};
+    #[cfg(feature = "serde")]
+    pub use crate::serde_support::{KeyedStore, TypedStore, TypedStoreExt};
+    #[cfg(feature = "serde")]
+    pub use crate::versioning::ValueMigrations;
 }
@@ -0,0 +1,456 @@
+//! Schema migrations for the key-value table.
+//!
+//! Each change pgkv makes to the table's columns or indexes is a numbered
+//! [`Migration`]. The version a table has reached is recorded in
+//! `pgkv_schema_version`, one row per table, and pending migrations are
+//! applied in order in a single transaction under an advisory lock, so
+//! concurrent stores don't apply them twice.
+//!
+//! Tables created before versions were recorded are treated as version 1.
+//! Every migration is idempotent, so replaying one on such a table is harmless.
+//!
+//! Stores that don't create their table check instead that it matches the
+//! configuration, with [`validate`].
+
+use std::fmt;
+
+use postgres::GenericClient;
+
+use crate::config::{Config, KeyType, TableType, ValueType, quote_literal};
+use crate::error::{Error, Result};
+
+/// Version of the table schema this release of pgkv uses.
+pub(crate) const SCHEMA_VERSION: u32 = 5;
+
+/// A change to the key-value table's schema.
+///
+/// Returned by [`Store::pending_migrations`](crate::Store::pending_migrations)
+/// and [`Store::migrate`](crate::Store::migrate). Its `Display` output is the
+/// migration's SQL, preceded by a comment naming it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    /// The schema version the migration upgrades the table to.
+    pub version: u32,
+    /// What the migration changes.
+    pub description: &'static str,
+    /// The SQL the migration runs.
+    pub sql: String,
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "-- {}: {}", self.version, self.description)?;
+        write!(f, "{}", self.sql)
+    }
+}
+
+/// Returns the migrations that upgrade a table from version `from`, in order.
+pub(crate) fn pending(config: &Config, from: u32) -> Vec<Migration> {
+    let table = config.qualified_table_name();
+    let table_type = config.table_type.sql_keyword();
+    let key_type = config.key_type.sql_type();
+    let value_type = config.value_type.sql_type();
+    let version_seq = config.qualified_version_seq_name();
+    let expires_idx = format!("{}_expires_idx", config.table_name);
+    let tags_idx = format!("{}_tags_idx", config.table_name);
+
+    let columns = format!(
+        r#"(
+    key {key_type} PRIMARY KEY,
+    value {value_type} NOT NULL,
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+)"#
+    );
+    // Partitioned tables have no storage of their own, so only the
+    // partitions are unlogged
+    let create_table = match config.partitions {
+        None => format!("CREATE {table_type} TABLE IF NOT EXISTS {table} {columns};"),
+        Some(count) => {
+            let mut sql =
+                format!("CREATE TABLE IF NOT EXISTS {table} {columns} PARTITION BY HASH (key);");
+            for i in 0..count {
+                sql.push_str(&format!(
+                    "\nCREATE {table_type} TABLE IF NOT EXISTS {} PARTITION OF {table} \
+                     FOR VALUES WITH (MODULUS {count}, REMAINDER {i});",
+                    config.qualified_partition_name(i)
+                ));
+            }
+            sql
+        }
+    };
+
+    let all = [
+        (
+            "create table",
+            format!(
+                r#"{create_table}
+CREATE INDEX IF NOT EXISTS "{expires_idx}" ON {table} (expires_at) WHERE expires_at IS NOT NULL;"#
+            ),
+        ),
+        (
+            "add stale_at column for soft expiry",
+            format!("ALTER TABLE {table} ADD COLUMN IF NOT EXISTS stale_at TIMESTAMPTZ;"),
+        ),
+        (
+            "add tags column for cache tags",
+            format!(
+                r#"ALTER TABLE {table} ADD COLUMN IF NOT EXISTS tags TEXT[];
+CREATE INDEX IF NOT EXISTS "{tags_idx}" ON {table} USING GIN (tags) WHERE tags IS NOT NULL;"#
+            ),
+        ),
+        (
+            "add version column for optimistic concurrency",
+            format!(
+                "CREATE SEQUENCE IF NOT EXISTS {version_seq};\n\
+                 ALTER TABLE {table} ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL \
+                 DEFAULT nextval({});",
+                quote_literal(&version_seq)
+            ),
+        ),
+        (
+            "add encoding column marking compressed, encrypted and streamed values",
+            format!(
+                "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS encoding SMALLINT NOT NULL DEFAULT 0;"
+            ),
+        ),
+    ];
+    debug_assert_eq!(all.len(), SCHEMA_VERSION as usize);
+
+    all.into_iter()
+        .zip(1..)
+        .skip(from as usize)
+        .map(|((description, sql), version)| Migration {
+            version,
+            description,
+            sql,
+        })
+        .collect()
+}
+
+/// Returns the schema version of the configured table.
+///
+/// This is `0` if the table doesn't exist, and `1` if it exists but no
+/// version was recorded for it.
+pub(crate) fn schema_version(client: &mut impl GenericClient, config: &Config) -> Result<u32> {
+    let versions = config.qualified_schema_version_table_name();
+    let row = client.query_one(
+        "SELECT to_regclass($1) IS NOT NULL, to_regclass($2) IS NOT NULL",
+        &[&config.qualified_table_name(), &versions],
+    )?;
+    let (table_exists, versions_exist): (bool, bool) = (row.get(0), row.get(1));
+
+    if !table_exists {
+        return Ok(0);
+    }
+    if !versions_exist {
+        return Ok(1);
+    }
+
+    let sql = format!("SELECT version FROM {} WHERE table_name = $1", versions);
+    let row = client.query_opt(&sql, &[&config.table_name])?;
+    Ok(row.map_or(1, |row| row.get::<_, i32>(0) as u32))
+}
+
+/// Applies the pending migrations to the configured table, creating it if
+/// it doesn't exist, and returns them.
+pub(crate) fn apply(client: &mut impl GenericClient, config: &Config) -> Result<Vec<Migration>> {
+    let versions = config.qualified_schema_version_table_name();
+
+    let mut tx = client.transaction()?;
+    tx.batch_execute(&format!(
+        r#"
+        SELECT pg_advisory_xact_lock(hashtext('pgkv_migrate'));
+
+        CREATE TABLE IF NOT EXISTS {versions} (
+            table_name TEXT PRIMARY KEY,
+            version INTEGER NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+        "#,
+    ))?;
+
+    // Read under the lock, in case another store migrated the table first
+    let migrations = pending(config, schema_version(&mut tx, config)?);
+    for migration in &migrations {
+        tx.batch_execute(&migration.sql)?;
+    }
+
+    let sql = format!(
+        r#"
+        INSERT INTO {versions} (table_name, version) VALUES ($1, $2)
+        ON CONFLICT (table_name) DO UPDATE SET version = EXCLUDED.version, updated_at = NOW()
+        "#,
+    );
+    tx.execute(&sql, &[&config.table_name, &(SCHEMA_VERSION as i32)])?;
+    tx.commit()?;
+
+    Ok(migrations)
+}
+
+/// Returns the actual type of the configured table, or `None` if it doesn't exist.
+pub(crate) fn table_type(
+    client: &mut impl GenericClient,
+    config: &Config,
+) -> Result<Option<TableType>> {
+    let row = client.query_opt(PERSISTENCE_SQL, &[&config.qualified_table_name()])?;
+    Ok(row.map(|row| TableType::from_persistence(row.get(0))))
+}
+
+/// Triggers that record changes in the history table, when it's enabled.
+const HISTORY_TRIGGERS: [&str; 2] = ["pgkv_history", "pgkv_history_update"];
+
+/// Selects the `relpersistence` of the table named `$1`, or of its first
+/// partition if it is partitioned.
+const PERSISTENCE_SQL: &str = r#"
+    SELECT COALESCE(
+        (SELECT p.relpersistence FROM pg_inherits i JOIN pg_class p ON p.oid = i.inhrelid
+         WHERE i.inhparent = c.oid ORDER BY p.relname LIMIT 1),
+        c.relpersistence
+    )::text
+    FROM pg_class c WHERE c.oid = to_regclass($1)
+"#;
+
+/// Returns the leaf tables holding the configured table's rows: its
+/// partitions, or the table itself if it isn't partitioned.
+pub(crate) fn partitions(client: &mut impl GenericClient, config: &Config) -> Result<Vec<String>> {
+    let rows = client.query(
+        "SELECT oid::regclass::text FROM pg_class WHERE oid = to_regclass($1) AND relkind <> 'p' \
+         UNION ALL \
+         SELECT relid::regclass::text FROM pg_partition_tree(to_regclass($1)) WHERE isleaf \
+         ORDER BY 1",
+        &[&config.qualified_table_name()],
+    )?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Checks that the configured table exists and has the columns, indexes and
+/// table type the configuration expects.
+///
+/// Fails with [`Error::TableNotFound`] if the table doesn't exist, and with
+/// [`Error::SchemaMismatch`] listing every difference otherwise.
+pub(crate) fn validate(client: &mut impl GenericClient, config: &Config) -> Result<()> {
+    let table = config.qualified_table_name();
+    let row = client.query_opt(
+        "SELECT oid, relkind::text FROM pg_class WHERE oid = to_regclass($1)",
+        &[&table],
+    )?;
+    let Some(row) = row else {
+        return Err(Error::TableNotFound { table });
+    };
+    let (oid, kind): (postgres::types::Oid, String) = (row.get(0), row.get(1));
+
+    let mut expected = Vec::new();
+    let mut found = Vec::new();
+
+    let columns: Vec<(String, String)> = client
+        .query(
+            "SELECT attname::text, format_type(atttypid, atttypmod) FROM pg_attribute \
+             WHERE attrelid = $1 AND attnum > 0 AND NOT attisdropped",
+            &[&oid],
+        )?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+    for (name, ty) in expected_columns(config) {
+        let column = columns.iter().find(|(column, _)| column == name);
+        match column {
+            Some((_, actual)) if actual == ty => {}
+            Some((_, actual)) => {
+                expected.push(format!("column {} of type {}", name, ty));
+                found.push(format!("column {} of type {}", name, actual));
+            }
+            None => {
+                expected.push(format!("column {} of type {}", name, ty));
+                found.push(format!("no column {}", name));
+            }
+        }
+    }
+
+    let indexes: Vec<(String, bool, String)> = client
+        .query(
+            "SELECT c.relname::text, i.indisprimary, am.amname::text FROM pg_index i \
+             JOIN pg_class c ON c.oid = i.indexrelid \
+             JOIN pg_am am ON am.oid = c.relam WHERE i.indrelid = $1",
+            &[&oid],
+        )?
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2)))
+        .collect();
+    if !indexes.iter().any(|(_, primary, _)| *primary) {
+        expected.push("primary key on key".to_string());
+        found.push("no primary key".to_string());
+    }
+    for (index, method) in expected_indexes(config) {
+        match indexes.iter().find(|(name, _, _)| *name == index) {
+            Some((_, _, actual)) if actual == method => {}
+            Some((_, _, actual)) => {
+                expected.push(format!("{} index {}", method, index));
+                found.push(format!("{} index {}", actual, index));
+            }
+            None => {
+                expected.push(format!("index {}", index));
+                found.push(format!("no index {}", index));
+            }
+        }
+    }
+
+    if config.history {
+        let triggers: Vec<String> = client
+            .query(
+                "SELECT tgname::text FROM pg_trigger WHERE tgrelid = $1 AND NOT tgisinternal",
+                &[&oid],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        for trigger in HISTORY_TRIGGERS {
+            if !triggers.iter().any(|name| name == trigger) {
+                expected.push(format!("history trigger {}", trigger));
+                found.push(format!("no trigger {}", trigger));
+            }
+        }
+
+        let history = config.qualified_history_table_name();
+        let row = client.query_one("SELECT to_regclass($1) IS NOT NULL", &[&history])?;
+        if !row.get::<_, bool>(0) {
+            expected.push(format!("history table {}", history));
+            found.push(format!("no table {}", history));
+        }
+    }
+
+    let partitions = if kind == "p" {
+        let row = client.query_one(
+            "SELECT COUNT(*) FROM pg_inherits WHERE inhparent = $1",
+            &[&oid],
+        )?;
+        Some(row.get::<_, i64>(0) as u32)
+    } else {
+        None
+    };
+    if partitions != config.partitions {
+        let describe = |partitions: Option<u32>| match partitions {
+            Some(count) => format!("table with {} hash partitions", count),
+            None => "unpartitioned table".to_string(),
+        };
+        expected.push(describe(config.partitions));
+        found.push(describe(partitions));
+    }
+
+    let table_type = table_type(client, config)?.unwrap_or(config.table_type);
+    if table_type != config.table_type {
+        expected.push(format!("{} table", config.table_type.persistence_keyword()));
+        found.push(format!("{} table", table_type.persistence_keyword()));
+    }
+
+    if expected.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::SchemaMismatch {
+            table,
+            expected,
+            found,
+        })
+    }
+}
+
+/// Returns the columns of the table and their types, as PostgreSQL's
+/// `format_type` names them.
+fn expected_columns(config: &Config) -> [(&'static str, &'static str); 9] {
+    let key_type = match config.key_type {
+        KeyType::Text => "text",
+        KeyType::Bytes => "bytea",
+    };
+    let value_type = match config.value_type {
+        ValueType::Bytes => "bytea",
+        ValueType::Jsonb => "jsonb",
+    };
+    [
+        ("key", key_type),
+        ("value", value_type),
+        ("expires_at", "timestamp with time zone"),
+        ("created_at", "timestamp with time zone"),
+        ("updated_at", "timestamp with time zone"),
+        ("stale_at", "timestamp with time zone"),
+        ("tags", "text[]"),
+        ("version", "bigint"),
+        ("encoding", "smallint"),
+    ]
+}
+
+/// Returns the names and access methods of the indexes the table needs
+/// besides its primary key.
+fn expected_indexes(config: &Config) -> Vec<(String, &'static str)> {
+    let mut indexes = vec![
+        (format!("{}_expires_idx", config.table_name), "btree"),
+        (format!("{}_tags_idx", config.table_name), "gin"),
+    ];
+    if config.json_index {
+        indexes.push((format!("{}_value_idx", config.table_name), "gin"));
+    }
+    indexes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending() {
+        let config = Config::new("postgresql://localhost/test").table_name("cache");
+
+        let all = pending(&config, 0);
+        let versions: Vec<u32> = all.iter().map(|m| m.version).collect();
+        assert_eq!(versions, [1, 2, 3, 4, 5]);
+        assert!(
+            all[0]
+                .sql
+                .starts_with("CREATE UNLOGGED TABLE IF NOT EXISTS \"cache\"")
+        );
+        assert!(all[3].sql.contains("nextval('\"cache_version_seq\"')"));
+
+        assert!(all[4].sql.contains("encoding SMALLINT NOT NULL DEFAULT 0"));
+
+        assert_eq!(pending(&config, 3).len(), 2);
+        assert!(pending(&config, SCHEMA_VERSION).is_empty());
+    }
+
+    #[test]
+    fn test_pending_uses_config() {
+        let config = Config::new("postgresql://localhost/test")
+            .schema("app")
+            .key_type(KeyType::Bytes);
+
+        let create = &pending(&config, 0)[0];
+        assert!(create.sql.contains("\"app\".\"kv_store\""));
+        assert!(create.sql.contains("key BYTEA PRIMARY KEY"));
+    }
+
+    #[test]
+    fn test_pending_partitioned() {
+        let config = Config::new("postgresql://localhost/test")
+            .table_name("big")
+            .partitions(4);
+
+        let create = &pending(&config, 0)[0].sql;
+        assert!(create.starts_with("CREATE TABLE IF NOT EXISTS \"big\""));
+        assert!(create.contains("PARTITION BY HASH (key)"));
+        assert!(create.contains(
+            "CREATE UNLOGGED TABLE IF NOT EXISTS \"big_p3\" PARTITION OF \"big\" \
+             FOR VALUES WITH (MODULUS 4, REMAINDER 3)"
+        ));
+        assert!(!create.contains("\"big_p4\""));
+    }
+
+    #[test]
+    fn test_display() {
+        let config = Config::new("postgresql://localhost/test");
+        let migration = &pending(&config, 1)[0];
+        assert_eq!(
+            migration.to_string(),
+            "-- 2: add stale_at column for soft expiry\n\
+             ALTER TABLE \"kv_store\" ADD COLUMN IF NOT EXISTS stale_at TIMESTAMPTZ;"
+        );
+    }
+}
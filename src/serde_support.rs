@@ -6,9 +6,12 @@ use serde::{Serialize, de::DeserializeOwned};
 use std::marker::PhantomData;
 use std::time::Duration;
 
+use crate::codec::{Codec, Json};
 use crate::error::{Error, Result};
+use crate::key::KvKey;
 use crate::store::Store;
-use crate::types::ScanOptions;
+use crate::types::{KeyValue, ScanOptions};
+use crate::versioning::ValueMigrations;
 
 /// A typed wrapper around [`Store`] that automatically serializes/deserializes values.
 ///
@@ -39,31 +42,71 @@ use crate::types::ScanOptions;
 /// let user: Option<User> = typed.get("user:1")?;
 /// # Ok::<(), pgkv::Error>(())
 /// ```
-pub struct TypedStore<'a, T> {
+///
+/// Values are stored as JSON by default. Pick another [`Codec`] with the
+/// third type parameter, e.g. `TypedStore<User, MessagePack>` with the
+/// `msgpack` feature. Reading a value written with a different codec fails
+/// with [`Error::Serialization`] rather than returning garbage.
+///
+/// To change `T` without breaking stored values, register a chain of
+/// [`ValueMigrations`] with [`TypedStore::with_migrations`].
+pub struct TypedStore<'a, T, C = Json> {
     store: &'a Store,
-    _phantom: PhantomData<T>,
+    migrations: ValueMigrations<T, C>,
 }
 
-impl<'a, T> TypedStore<'a, T>
+impl<'a, T, C> TypedStore<'a, T, C>
 where
     T: Serialize + DeserializeOwned,
+    C: Codec,
 {
     /// Creates a new typed store wrapper.
     pub fn new(store: &'a Store) -> Self {
         Self {
             store,
-            _phantom: PhantomData,
+            migrations: ValueMigrations::new(),
         }
     }
 
+    /// Upcasts values written at older schema versions with `migrations`.
+    ///
+    /// Values are written at the newest version of the chain.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Store, TypedStore, ValueMigrations};
+    /// use serde::{Serialize, Deserialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct UserV1 { name: String }
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct User { name: String, email: Option<String> }
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let users: TypedStore<User> = TypedStore::new(&store).with_migrations(
+    ///     ValueMigrations::<UserV1>::new()
+    ///         .then(|v1| User { name: v1.name, email: None })
+    ///         .write_back(true),
+    /// );
+    ///
+    /// // Old values are read as `UserV1` and converted
+    /// let user = users.get("user:1")?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn with_migrations(mut self, migrations: ValueMigrations<T, C>) -> Self {
+        self.migrations = migrations;
+        self
+    }
+
     /// Gets a value by key and deserializes it.
     ///
     /// Returns `None` if the key doesn't exist or has expired.
     pub fn get(&self, key: &str) -> Result<Option<T>> {
         match self.store.get(key)? {
             Some(bytes) => {
-                let value: T = serde_json::from_slice(&bytes)
-                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                let value = self.migrations.read(self.store, key, &bytes)?;
                 Ok(Some(value))
             }
             None => Ok(None),
@@ -77,27 +120,27 @@ where
         })
     }
 
-    /// Sets a value by key, serializing it to JSON.
+    /// Sets a value by key, serializing it with the codec.
     pub fn set(&self, key: &str, value: &T) -> Result<()> {
-        let bytes = serde_json::to_vec(value).map_err(|e| Error::Serialization(e.to_string()))?;
+        let bytes = self.migrations.encode(value)?;
         self.store.set(key, &bytes)
     }
 
     /// Sets a value with an expiration time.
     pub fn set_ex(&self, key: &str, value: &T, ttl: Duration) -> Result<()> {
-        let bytes = serde_json::to_vec(value).map_err(|e| Error::Serialization(e.to_string()))?;
+        let bytes = self.migrations.encode(value)?;
         self.store.set_ex(key, &bytes, ttl)
     }
 
     /// Sets a value only if the key doesn't exist.
     pub fn set_nx(&self, key: &str, value: &T) -> Result<bool> {
-        let bytes = serde_json::to_vec(value).map_err(|e| Error::Serialization(e.to_string()))?;
+        let bytes = self.migrations.encode(value)?;
         self.store.set_nx(key, &bytes)
     }
 
     /// Sets a value with TTL only if the key doesn't exist.
     pub fn set_nx_ex(&self, key: &str, value: &T, ttl: Duration) -> Result<bool> {
-        let bytes = serde_json::to_vec(value).map_err(|e| Error::Serialization(e.to_string()))?;
+        let bytes = self.migrations.encode(value)?;
         self.store.set_nx_ex(key, &bytes, ttl)
     }
 
@@ -107,8 +150,7 @@ where
         let mut results = Vec::with_capacity(kvs.len());
 
         for kv in kvs {
-            let value: T = serde_json::from_slice(&kv.value)
-                .map_err(|e| Error::Serialization(e.to_string()))?;
+            let value = self.migrations.read(self.store, &kv.key, &kv.value)?;
             results.push((kv.key, value));
         }
 
@@ -120,8 +162,7 @@ where
         let serialized: Vec<(&str, Vec<u8>)> = items
             .iter()
             .map(|(k, v)| {
-                let bytes =
-                    serde_json::to_vec(v).map_err(|e| Error::Serialization(e.to_string()))?;
+                let bytes = self.migrations.encode(v)?;
                 Ok((*k, bytes))
             })
             .collect::<Result<Vec<_>>>()?;
@@ -136,8 +177,57 @@ where
         let mut results = Vec::with_capacity(kvs.len());
 
         for kv in kvs {
-            let value: T = serde_json::from_slice(&kv.value)
-                .map_err(|e| Error::Serialization(e.to_string()))?;
+            let value = self.migrations.read(self.store, &kv.key, &kv.value)?;
+            results.push((kv.key, value));
+        }
+
+        Ok(results)
+    }
+
+    /// Scans key-value pairs, reporting values that fail to decode per key.
+    ///
+    /// Unlike [`TypedStore::scan`], which fails on the first value that can't
+    /// be deserialized or upcast, this returns every matching key with its
+    /// own result. Database errors still fail the whole call.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{ScanOptions, Store, TypedStoreExt};
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let users = store.typed::<serde_json::Value>();
+    ///
+    /// for (key, user) in users.scan_lenient(ScanOptions::new().prefix("user:"))? {
+    ///     match user {
+    ///         Ok(user) => println!("{}: {}", key, user),
+    ///         Err(e) => eprintln!("skipping {}: {}", key, e),
+    ///     }
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn scan_lenient(&self, options: ScanOptions) -> Result<Vec<(String, Result<T>)>> {
+        let kvs = self.store.scan(options)?;
+        Ok(kvs
+            .into_iter()
+            .map(|kv| {
+                let value = self.migrations.read(self.store, &kv.key, &kv.value);
+                (kv.key, value)
+            })
+            .collect())
+    }
+
+    /// Finds the entries whose indexed expression equals `value`, ordered by key.
+    ///
+    /// See [`Store::find_by`]. JSON path indexes only see values written
+    /// with the [`Json`] codec at schema version 1, since later versions are
+    /// stored with a header in front of the JSON.
+    pub fn find_by(&self, index: &str, value: &str) -> Result<Vec<(String, T)>> {
+        let kvs = self.store.find_by(index, value)?;
+        let mut results = Vec::with_capacity(kvs.len());
+
+        for kv in kvs {
+            let value = self.migrations.read(self.store, &kv.key, &kv.value)?;
             results.push((kv.key, value));
         }
 
@@ -146,12 +236,11 @@ where
 
     /// Gets the current value and sets a new value atomically.
     pub fn get_and_set(&self, key: &str, value: &T) -> Result<Option<T>> {
-        let bytes = serde_json::to_vec(value).map_err(|e| Error::Serialization(e.to_string()))?;
+        let bytes = self.migrations.encode(value)?;
 
         match self.store.get_and_set(key, &bytes)? {
             Some(old_bytes) => {
-                let old_value: T = serde_json::from_slice(&old_bytes)
-                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                let (old_value, _) = self.migrations.decode(&old_bytes)?;
                 Ok(Some(old_value))
             }
             None => Ok(None),
@@ -162,30 +251,313 @@ where
     pub fn get_and_delete(&self, key: &str) -> Result<Option<T>> {
         match self.store.get_and_delete(key)? {
             Some(bytes) => {
-                let value: T = serde_json::from_slice(&bytes)
-                    .map_err(|e| Error::Serialization(e.to_string()))?;
+                let (value, _) = self.migrations.decode(&bytes)?;
                 Ok(Some(value))
             }
             None => Ok(None),
         }
     }
 
+    /// Gets a value, computing and storing it with the given TTL if missing.
+    ///
+    /// See [`Store::get_or_set_with`] for the stampede protection guarantees.
+    pub fn get_or_set_with<F>(&self, key: &str, ttl: Duration, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let bytes = self.store.get_or_set_with(key, ttl, || {
+            let value = f()?;
+            self.migrations.encode(&value)
+        })?;
+
+        self.migrations.read(self.store, key, &bytes)
+    }
+
+    /// Returns a reference to the underlying store.
+    pub fn store(&self) -> &Store {
+        self.store
+    }
+}
+
+/// JSON operations, for stores with [`ValueType::Jsonb`](crate::ValueType::Jsonb) values.
+///
+/// These wrap the `json_*` methods of [`Store`], serializing their arguments
+/// and deserializing their results with `serde_json`. Combine
+/// [`TypedStore::scan`] with [`ScanOptions::json_contains`] to filter on
+/// fields server-side.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use pgkv::{Config, ScanOptions, Store, TypedStoreExt, ValueType};
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+///     role: String,
+/// }
+///
+/// let config = Config::new("postgresql://localhost/mydb").value_type(ValueType::Jsonb);
+/// let store = Store::with_config(config)?;
+/// let users = store.typed::<User>();
+///
+/// users.json_set("user:1", &["role"], "admin")?;
+/// let name: Option<String> = users.json_get("user:1", &["name"])?;
+/// let admins = users.scan(ScanOptions::new().json_contains(r#"{"role": "admin"}"#))?;
+/// # Ok::<(), pgkv::Error>(())
+/// ```
+impl<T> TypedStore<'_, T, Json>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// Gets and deserializes the JSON at `path` inside a value.
+    ///
+    /// See [`Store::json_get`].
+    pub fn json_get<U: DeserializeOwned>(&self, key: &str, path: &[&str]) -> Result<Option<U>> {
+        match self.store.json_get(key, path)? {
+            Some(json) => Ok(Some(
+                serde_json::from_str(&json).map_err(|e| Error::Serialization(e.to_string()))?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the JSON at `path` inside a value to `value`.
+    ///
+    /// See [`Store::json_set`].
+    pub fn json_set<U: Serialize + ?Sized>(
+        &self,
+        key: &str,
+        path: &[&str],
+        value: &U,
+    ) -> Result<bool> {
+        self.store.json_set(key, path, &to_json(value)?)
+    }
+
+    /// Merges the fields of `patch` into a value.
+    ///
+    /// See [`Store::json_merge`].
+    pub fn json_merge<U: Serialize + ?Sized>(&self, key: &str, patch: &U) -> Result<()> {
+        self.store.json_merge(key, &to_json(patch)?)
+    }
+
+    /// Appends `value` to the array at `path` inside a value.
+    ///
+    /// See [`Store::json_array_append`].
+    pub fn json_array_append<U: Serialize + ?Sized>(
+        &self,
+        key: &str,
+        path: &[&str],
+        value: &U,
+    ) -> Result<bool> {
+        self.store.json_array_append(key, path, &to_json(value)?)
+    }
+}
+
+fn to_json<U: Serialize + ?Sized>(value: &U) -> Result<String> {
+    serde_json::to_string(value).map_err(|e| Error::Serialization(e.to_string()))
+}
+
+/// A typed wrapper around [`Store`] with structured keys as well as values.
+///
+/// Like [`TypedStore`], but keys are a [`KvKey`] type, usually declared with
+/// [`kv_key!`](crate::kv_key), instead of strings. [`KeyedStore::keys`] and
+/// [`KeyedStore::scan`] parse keys back into `K`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use pgkv::{KeyedStore, ScanOptions, Store, kv_key};
+/// use serde::{Serialize, Deserialize};
+///
+/// kv_key! {
+///     pub struct ProfileKey {
+///         "user", user_id: u64, "profile",
+///     }
+/// }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Profile {
+///     name: String,
+/// }
+///
+/// let store = Store::connect("postgresql://localhost/mydb")?;
+/// let profiles: KeyedStore<ProfileKey, Profile> = KeyedStore::new(&store);
+///
+/// profiles.set(&ProfileKey { user_id: 1 }, &Profile { name: "Alice".into() })?;
+///
+/// for (key, profile) in profiles.scan(ScanOptions::new())? {
+///     println!("{}: {}", key.user_id, profile.name);
+/// }
+/// # Ok::<(), pgkv::Error>(())
+/// ```
+pub struct KeyedStore<'a, K, V, C = Json> {
+    store: &'a Store,
+    migrations: ValueMigrations<V, C>,
+    _phantom: PhantomData<K>,
+}
+
+impl<'a, K, V, C> KeyedStore<'a, K, V, C>
+where
+    K: KvKey,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Creates a new keyed store wrapper.
+    pub fn new(store: &'a Store) -> Self {
+        Self {
+            store,
+            migrations: ValueMigrations::new(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Upcasts values written at older schema versions with `migrations`.
+    ///
+    /// See [`TypedStore::with_migrations`].
+    pub fn with_migrations(mut self, migrations: ValueMigrations<V, C>) -> Self {
+        self.migrations = migrations;
+        self
+    }
+
+    /// Gets a value by key and deserializes it.
+    ///
+    /// Returns `None` if the key doesn't exist or has expired.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let key = key.to_key();
+        match self.store.get(&key)? {
+            Some(bytes) => Ok(Some(self.migrations.read(self.store, &key, &bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets a value by key, returning an error if not found.
+    pub fn get_or_err(&self, key: &K) -> Result<V> {
+        let key = key.to_key();
+        match self.store.get(&key)? {
+            Some(bytes) => self.migrations.read(self.store, &key, &bytes),
+            None => Err(Error::NotFound { key }),
+        }
+    }
+
+    /// Sets a value by key, serializing it with the codec.
+    pub fn set(&self, key: &K, value: &V) -> Result<()> {
+        let bytes = self.migrations.encode(value)?;
+        self.store.set(&key.to_key(), &bytes)
+    }
+
+    /// Sets a value with an expiration time.
+    pub fn set_ex(&self, key: &K, value: &V, ttl: Duration) -> Result<()> {
+        let bytes = self.migrations.encode(value)?;
+        self.store.set_ex(&key.to_key(), &bytes, ttl)
+    }
+
+    /// Sets a value only if the key doesn't exist.
+    pub fn set_nx(&self, key: &K, value: &V) -> Result<bool> {
+        let bytes = self.migrations.encode(value)?;
+        self.store.set_nx(&key.to_key(), &bytes)
+    }
+
+    /// Deletes a key. Returns `true` if it existed.
+    pub fn delete(&self, key: &K) -> Result<bool> {
+        self.store.delete(&key.to_key())
+    }
+
+    /// Checks if a key exists.
+    pub fn exists(&self, key: &K) -> Result<bool> {
+        self.store.exists(&key.to_key())
+    }
+
+    /// Gets multiple values and deserializes them.
+    ///
+    /// Missing keys are omitted from the result.
+    pub fn get_many(&self, keys: &[K]) -> Result<Vec<(K, V)>> {
+        let rendered: Vec<String> = keys.iter().map(KvKey::to_key).collect();
+        let refs: Vec<&str> = rendered.iter().map(String::as_str).collect();
+        self.parse_entries(self.store.get_many(&refs)?)
+    }
+
+    /// Lists the keys of this type.
+    ///
+    /// Scans [`KvKey::key_prefix`] unless `options` sets a prefix, and skips
+    /// keys that don't parse as `K`, so `limit` and `offset` count them too.
+    pub fn keys(&self, options: ScanOptions) -> Result<Vec<K>> {
+        let keys = self.store.keys(Self::with_prefix(options))?;
+        Ok(keys.iter().filter_map(|key| K::from_key(key)).collect())
+    }
+
+    /// Scans the entries of this type.
+    ///
+    /// Selects keys like [`KeyedStore::keys`].
+    pub fn scan(&self, options: ScanOptions) -> Result<Vec<(K, V)>> {
+        self.parse_entries(self.store.scan(Self::with_prefix(options))?)
+    }
+
+    /// Scans the entries of this type, reporting values that fail to decode
+    /// per key.
+    ///
+    /// Selects keys like [`KeyedStore::keys`]. See [`TypedStore::scan_lenient`].
+    pub fn scan_lenient(&self, options: ScanOptions) -> Result<Vec<(K, Result<V>)>> {
+        let kvs = self.store.scan(Self::with_prefix(options))?;
+        Ok(kvs
+            .into_iter()
+            .filter_map(|kv| {
+                let key = K::from_key(&kv.key)?;
+                Some((key, self.migrations.read(self.store, &kv.key, &kv.value)))
+            })
+            .collect())
+    }
+
     /// Returns a reference to the underlying store.
     pub fn store(&self) -> &Store {
         self.store
     }
+
+    fn with_prefix(options: ScanOptions) -> ScanOptions {
+        if options.prefix.is_some() || options.prefix_bytes.is_some() {
+            options
+        } else {
+            options.prefix(K::key_prefix())
+        }
+    }
+
+    fn parse_entries(&self, kvs: Vec<KeyValue>) -> Result<Vec<(K, V)>> {
+        let mut results = Vec::with_capacity(kvs.len());
+        for kv in kvs {
+            if let Some(key) = K::from_key(&kv.key) {
+                let value = self.migrations.read(self.store, &kv.key, &kv.value)?;
+                results.push((key, value));
+            }
+        }
+        Ok(results)
+    }
 }
 
 /// Extension trait for convenient typed access.
 pub trait TypedStoreExt {
-    /// Creates a typed store wrapper for the given type.
+    /// Creates a typed store wrapper for the given type, using JSON.
     fn typed<T: Serialize + DeserializeOwned>(&self) -> TypedStore<'_, T>;
+
+    /// Creates a typed store wrapper for the given type and codec.
+    fn typed_with<T: Serialize + DeserializeOwned, C: Codec>(&self) -> TypedStore<'_, T, C>;
+
+    /// Creates a keyed store wrapper for the given key and value types, using JSON.
+    fn keyed<K: KvKey, V: Serialize + DeserializeOwned>(&self) -> KeyedStore<'_, K, V>;
 }
 
 impl TypedStoreExt for Store {
     fn typed<T: Serialize + DeserializeOwned>(&self) -> TypedStore<'_, T> {
         TypedStore::new(self)
     }
+
+    fn typed_with<T: Serialize + DeserializeOwned, C: Codec>(&self) -> TypedStore<'_, T, C> {
+        TypedStore::new(self)
+    }
+
+    fn keyed<K: KvKey, V: Serialize + DeserializeOwned>(&self) -> KeyedStore<'_, K, V> {
+        KeyedStore::new(self)
+    }
 }
 
 #[cfg(test)]
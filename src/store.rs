@@ -1,12 +1,44 @@
 //! The main Store implementation.
 
-use postgres::{Client, NoTls, Row};
-use std::cell::RefCell;
+use postgres::error::SqlState;
+use postgres::types::ToSql;
+use postgres::{Client, NoTls, Row, SimpleQueryMessage};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, SystemTime};
 
-use crate::config::Config;
+use crate::batch::{Batch, BatchOp, BatchResult, OpResult, Precondition};
+use crate::compression::{self, Compression};
+use crate::config::{Config, KeyType, TableType, ValueType, quote_literal};
+use crate::encryption;
 use crate::error::{Error, Result};
-use crate::types::{CasResult, Entry, KeyValue, ScanOptions, Stats};
+use crate::index::{self, IndexExpr};
+use crate::json::{StoredValue, ValueParam};
+use crate::key::{self, AsKey};
+use crate::migrate::{self, Migration};
+use crate::stream::{self, Manifest, ValueReader};
+use crate::tx::Tx;
+use crate::types::{
+    CasResult, Entry, Freshness, HistoryEntry, HistoryOp, IndexInfo, KeyValue, ScanOptions,
+    SetCondition, SetExpiry, SetOptions, SetOutcome, Stats, TxOptions, VersionResult,
+};
+
+/// Maximum number of attempts for a batch that hits a serialization conflict.
+const BATCH_MAX_ATTEMPTS: usize = 3;
+
+/// Error message raised by a batch whose precondition did not hold; the
+/// error detail holds the precondition's index.
+const BATCH_PRECONDITION_FAILED: &str = "pgkv batch precondition failed";
+
+/// Error message raised by a batch incrementing a value that isn't an
+/// integer; the error detail holds the write's index.
+const BATCH_NOT_NUMERIC: &str = "pgkv batch increment of a non-numeric value";
+
+/// Number of values re-encrypted per transaction by `reencrypt_all`.
+#[cfg(feature = "encryption")]
+const REENCRYPT_BATCH_SIZE: i64 = 1000;
 
 /// The main key-value store backed by PostgreSQL.
 ///
@@ -36,6 +68,12 @@ pub struct Store {
     client: RefCell<Client>,
     config: Config,
     qualified_table: String,
+    /// SQL expression producing the next row version.
+    next_version: String,
+    /// Number of open transactions and savepoints on the connection.
+    tx_depth: Cell<u32>,
+    /// Whether the chunks table for streamed values is known to exist.
+    chunks_ready: Cell<bool>,
 }
 
 impl Store {
@@ -71,50 +109,240 @@ impl Store {
     pub fn with_config(config: Config) -> Result<Self> {
         config.validate()?;
 
-        let mut client = Client::connect(&config.connection_string, NoTls)
-            .map_err(|e| Error::Connection(e.to_string()))?;
+        let mut client = Self::connect_client(&config)?;
 
         let qualified_table = config.qualified_table_name();
+        let next_version = format!(
+            "nextval({})",
+            quote_literal(&config.qualified_version_seq_name())
+        );
+
+        let version = migrate::schema_version(&mut client, &config)?;
+        let migrate = if version == 0 {
+            config.auto_create_table
+        } else {
+            config.migrate_on_connect() && version < migrate::SCHEMA_VERSION
+        };
+        if migrate {
+            migrate::apply(&mut client, &config)?;
+        }
 
         if config.auto_create_table {
-            Self::create_table_internal(&mut client, &config, &qualified_table)?;
+            Self::create_features_internal(&mut client, &config, &qualified_table)?;
+
+            let actual = migrate::table_type(&mut client, &config)?;
+            if let Some(actual) = actual.filter(|actual| *actual != config.table_type) {
+                log::warn!(
+                    "table {} is {} but the configuration expects {}; \
+                     change it with Store::set_table_type",
+                    qualified_table,
+                    actual.persistence_keyword(),
+                    config.table_type.persistence_keyword()
+                );
+            }
+        } else {
+            migrate::validate(&mut client, &config)?;
         }
 
         Ok(Self {
             client: RefCell::new(client),
             config,
             qualified_table,
+            next_version,
+            tx_depth: Cell::new(0),
+            chunks_ready: Cell::new(false),
         })
     }
 
-    /// Creates the table and indexes if they don't exist.
-    fn create_table_internal(client: &mut Client, config: &Config, table_name: &str) -> Result<()> {
+    /// Opens a connection as configured, without creating or checking the table.
+    fn connect_client(config: &Config) -> Result<Client> {
+        let mut client = Client::connect(&config.connection_string, NoTls)
+            .map_err(|e| Error::Connection(e.to_string()))?;
+
+        if let Some(ref name) = config.application_name {
+            client.execute("SELECT set_config('application_name', $1, false)", &[name])?;
+        }
+
+        Ok(client)
+    }
+
+    /// Creates the optional indexes and tables enabled in the configuration.
+    ///
+    /// The table itself is created by the schema migrations.
+    fn create_features_internal(
+        client: &mut Client,
+        config: &Config,
+        table_name: &str,
+    ) -> Result<()> {
+        if config.json_index {
+            let value_idx_name = format!("{}_value_idx", config.table_name);
+            let create_value_idx = format!(
+                r#"CREATE INDEX IF NOT EXISTS "{}" ON {} USING GIN (value jsonb_path_ops)"#,
+                value_idx_name, table_name
+            );
+            client.execute(&create_value_idx, &[])?;
+        }
+
+        if config.history {
+            Self::create_history_internal(client, config, table_name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the history table and the triggers that populate it.
+    fn create_history_internal(
+        client: &mut Client,
+        config: &Config,
+        table_name: &str,
+    ) -> Result<()> {
         let table_type = config.table_type.sql_keyword();
+        let key_type = config.key_type.sql_type();
+        let value_type = config.value_type.sql_type();
+        let history = config.qualified_history_table_name();
+        let function = config.qualified_name("pgkv_record_history");
+        let history_literal = quote_literal(&history);
+        let table_literal = quote_literal(table_name);
+        let key_idx = format!("{}_history_key_idx", config.table_name);
+        let changed_idx = format!("{}_history_changed_idx", config.table_name);
 
-        let create_table = format!(
+        let sql = format!(
             r#"
-            CREATE {table_type} TABLE IF NOT EXISTS {table_name} (
-                key TEXT PRIMARY KEY,
-                value BYTEA NOT NULL,
+            SELECT pg_advisory_xact_lock(hashtext('pgkv_record_history'));
+
+            CREATE {table_type} TABLE IF NOT EXISTS {history} (
+                id BIGSERIAL PRIMARY KEY,
+                key {key_type} NOT NULL,
+                op TEXT NOT NULL,
+                value {value_type} NOT NULL,
+                encoding SMALLINT NOT NULL DEFAULT 0,
+                version BIGINT NOT NULL,
                 expires_at TIMESTAMPTZ,
-                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
-                updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
-            )
+                changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                changed_by TEXT NOT NULL
+                    DEFAULT COALESCE(NULLIF(current_setting('application_name'), ''), session_user)
+            );
+            ALTER TABLE {history} ADD COLUMN IF NOT EXISTS encoding SMALLINT NOT NULL DEFAULT 0;
+
+            CREATE INDEX IF NOT EXISTS "{key_idx}" ON {history} (key, changed_at);
+            CREATE INDEX IF NOT EXISTS "{changed_idx}" ON {history} (changed_at);
+
+            CREATE OR REPLACE FUNCTION {function}() RETURNS trigger AS $$
+            DECLARE
+                insert_sql TEXT := format(
+                    'INSERT INTO %s (key, op, value, encoding, version, expires_at) '
+                    'VALUES ($1, $2, $3, $4, $5, $6)',
+                    TG_ARGV[0]
+                );
+            BEGIN
+                IF TG_OP = 'DELETE' THEN
+                    EXECUTE insert_sql
+                        USING OLD.key, 'DELETE', OLD.value, OLD.encoding, OLD.version, OLD.expires_at;
+                ELSE
+                    EXECUTE insert_sql
+                        USING NEW.key, 'SET', NEW.value, NEW.encoding, NEW.version, NEW.expires_at;
+                END IF;
+                RETURN NULL;
+            END;
+            $$ LANGUAGE plpgsql;
+
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM pg_trigger
+                    WHERE tgname = 'pgkv_history' AND tgrelid = {table_literal}::regclass
+                ) THEN
+                    CREATE TRIGGER pgkv_history
+                        AFTER INSERT OR DELETE ON {table_name}
+                        FOR EACH ROW EXECUTE FUNCTION {function}({history_literal});
+                    -- TTL-only updates (expire/persist) keep the version and are not recorded
+                    CREATE TRIGGER pgkv_history_update
+                        AFTER UPDATE ON {table_name}
+                        FOR EACH ROW WHEN (OLD.version IS DISTINCT FROM NEW.version)
+                        EXECUTE FUNCTION {function}({history_literal});
+                END IF;
+            END
+            $$;
             "#,
         );
 
-        client.execute(&create_table, &[])?;
-
-        let idx_name = format!("{}_expires_idx", config.table_name);
-        let create_idx = format!(
-            r#"CREATE INDEX IF NOT EXISTS "{}" ON {} (expires_at) WHERE expires_at IS NOT NULL"#,
-            idx_name, table_name
-        );
-        client.execute(&create_idx, &[])?;
-
+        let mut tx = client.transaction()?;
+        tx.batch_execute(&sql)?;
+        tx.commit()?;
         Ok(())
     }
 
+    /// Returns the SQL creating the table holding streamed value chunks and
+    /// the triggers that delete a value's chunks once no key refers to them.
+    fn chunks_table_sql(config: &Config, table_name: &str) -> String {
+        let table_type = config.table_type.sql_keyword();
+        let chunks = config.qualified_chunks_table_name();
+        let function = config.qualified_name("pgkv_delete_chunks");
+        let chunks_literal = quote_literal(&chunks);
+        let table_literal = quote_literal(table_name);
+        let manifest_idx = format!("{}_manifest_idx", config.table_name);
+        let is_manifest = stream::manifest_sql("encoding");
+        let old_is_manifest = stream::manifest_sql("OLD.encoding");
+        let object_id = stream::object_id_sql("OLD.value");
+
+        format!(
+            r#"
+            SELECT pg_advisory_xact_lock(hashtext('pgkv_delete_chunks'));
+
+            CREATE {table_type} TABLE IF NOT EXISTS {chunks} (
+                object_id BIGINT NOT NULL,
+                seq INTEGER NOT NULL,
+                data BYTEA NOT NULL,
+                checksum BIGINT NOT NULL,
+                PRIMARY KEY (object_id, seq)
+            );
+
+            CREATE INDEX IF NOT EXISTS "{manifest_idx}" ON {table_name} (value) WHERE {is_manifest};
+
+            CREATE OR REPLACE FUNCTION {function}() RETURNS trigger AS $$
+            DECLARE
+                referenced BOOLEAN;
+            BEGIN
+                IF NOT {old_is_manifest} THEN
+                    RETURN NULL;
+                END IF;
+                -- Copies of a key share its chunks
+                EXECUTE format(
+                    'SELECT EXISTS (SELECT 1 FROM %s WHERE value = $1 AND {is_manifest})',
+                    TG_ARGV[0]
+                ) INTO referenced USING OLD.value;
+                IF NOT referenced THEN
+                    EXECUTE format('DELETE FROM %s WHERE object_id = $1', TG_ARGV[1])
+                        USING {object_id};
+                END IF;
+                RETURN NULL;
+            END;
+            $$ LANGUAGE plpgsql;
+
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM pg_trigger
+                    WHERE tgname = 'pgkv_chunks' AND tgrelid = {table_literal}::regclass
+                ) THEN
+                    CREATE TRIGGER pgkv_chunks
+                        AFTER DELETE ON {table_name}
+                        FOR EACH ROW WHEN ({old_is_manifest})
+                        EXECUTE FUNCTION {function}({table_literal}, {chunks_literal});
+                    CREATE TRIGGER pgkv_chunks_update
+                        AFTER UPDATE OF value, encoding ON {table_name}
+                        FOR EACH ROW WHEN ({old_is_manifest} AND (
+                            OLD.value IS DISTINCT FROM NEW.value
+                            OR OLD.encoding IS DISTINCT FROM NEW.encoding
+                        ))
+                        EXECUTE FUNCTION {function}({table_literal}, {chunks_literal});
+                END IF;
+            END
+            $$;
+            "#,
+        )
+    }
+
     /// Recreates the table (drops all data).
     ///
     /// **Warning**: This will delete all data in the table!
@@ -130,17 +358,139 @@ impl Store {
     /// ```
     pub fn recreate_table(&self) -> Result<()> {
         let mut client = self.client.borrow_mut();
-        let drop_sql = format!("DROP TABLE IF EXISTS {}", self.qualified_table);
+        let drop_sql = format!(
+            "DROP TABLE IF EXISTS {}, {}, {}",
+            self.qualified_table,
+            self.config.qualified_chunks_table_name(),
+            self.config.qualified_indexes_table_name()
+        );
         client.execute(&drop_sql, &[])?;
-        Self::create_table_internal(&mut client, &self.config, &self.qualified_table)?;
+        self.chunks_ready.set(false);
+        migrate::apply(&mut *client, &self.config)?;
+        Self::create_features_internal(&mut client, &self.config, &self.qualified_table)?;
         Ok(())
     }
 
+    /// Applies pending schema migrations, creating the table if it doesn't
+    /// exist, and returns the migrations applied.
+    ///
+    /// Stores apply migrations on connect if [`Config::auto_migrate`] is
+    /// enabled, which it is by default unless [`Config::auto_create_table`]
+    /// is disabled. Migrations run in one
+    /// transaction under an advisory lock, so concurrent calls apply each
+    /// migration once.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, Store};
+    ///
+    /// let config = Config::new("postgresql://localhost/mydb").auto_migrate(false);
+    /// let store = Store::with_config(config)?;
+    ///
+    /// for migration in store.migrate()? {
+    ///     println!("applied {}: {}", migration.version, migration.description);
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn migrate(&self) -> Result<Vec<Migration>> {
+        let mut client = self.client.borrow_mut();
+        let migrations = migrate::apply(&mut *client, &self.config)?;
+        Self::create_features_internal(&mut client, &self.config, &self.qualified_table)?;
+        Ok(migrations)
+    }
+
+    /// Returns the migrations [`Store::migrate`] would apply, without applying them.
+    ///
+    /// Each migration's `Display` output is its SQL, so printing them gives a
+    /// script that can be reviewed or run by hand.
+    pub fn pending_migrations(&self) -> Result<Vec<Migration>> {
+        let version = migrate::schema_version(&mut *self.client.borrow_mut(), &self.config)?;
+        Ok(migrate::pending(&self.config, version))
+    }
+
+    /// Returns the schema version of the table, or `0` if it doesn't exist.
+    pub fn schema_version(&self) -> Result<u32> {
+        migrate::schema_version(&mut *self.client.borrow_mut(), &self.config)
+    }
+
+    /// Returns the actual type of the table, read from the database.
+    ///
+    /// This can differ from [`Config::table_type`], which only applies when
+    /// the table is created. A store logs a warning on connect when they
+    /// disagree.
+    pub fn table_type(&self) -> Result<TableType> {
+        migrate::table_type(&mut *self.client.borrow_mut(), &self.config)?.ok_or_else(|| {
+            Error::TableNotFound {
+                table: self.qualified_table.clone(),
+            }
+        })
+    }
+
+    /// Switches the table between `UNLOGGED` and `LOGGED`, keeping its data.
+    ///
+    /// The table's indexes follow it, and so do the history, chunks and
+    /// secondary index tables if they exist. PostgreSQL rewrites each table,
+    /// holding an exclusive lock until it is done, so this blocks every
+    /// other reader and writer for as long as copying the table takes.
+    ///
+    /// This doesn't change the store's [`Config`]; companion tables created
+    /// later still use [`Config::table_type`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Store, TableType};
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    ///
+    /// // Make the cache crash-safe without losing its contents
+    /// store.set_table_type(TableType::Regular)?;
+    /// assert_eq!(store.table_type()?, TableType::Regular);
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn set_table_type(&self, table_type: TableType) -> Result<()> {
+        let companions = [
+            self.config.qualified_history_table_name(),
+            self.config.qualified_chunks_table_name(),
+            self.config.qualified_indexes_table_name(),
+        ];
+
+        self.atomically("BEGIN", || {
+            let mut client = self.client.borrow_mut();
+
+            // A partitioned table has no storage of its own, so its
+            // partitions are switched instead
+            let mut tables = migrate::partitions(&mut *client, &self.config)?;
+            if tables.is_empty() {
+                return Err(Error::TableNotFound {
+                    table: self.qualified_table.clone(),
+                });
+            }
+            for table in &companions {
+                let row = client.query_one("SELECT to_regclass($1) IS NOT NULL", &[table])?;
+                if row.get(0) {
+                    tables.push(table.clone());
+                }
+            }
+
+            for table in &tables {
+                client.batch_execute(&format!(
+                    "ALTER TABLE {} SET {}",
+                    table,
+                    table_type.persistence_keyword()
+                ))?;
+            }
+            Ok(())
+        })
+    }
+
     // ==================== Basic Operations ====================
 
     /// Gets a value by key.
     ///
-    /// Returns `None` if the key doesn't exist or has expired.
+    /// Returns `None` if the key doesn't exist or has expired. Like the other
+    /// basic operations, this takes text or binary keys (see [`AsKey`]).
     ///
     /// # Example
     ///
@@ -153,18 +503,21 @@ impl Store {
     /// }
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    pub fn get(&self, key: &(impl AsKey + ?Sized)) -> Result<Option<Vec<u8>>> {
         self.validate_key(key)?;
 
         let sql = format!(
             r#"
-            SELECT value, expires_at FROM {}
+            SELECT value, encoding, expires_at FROM {}
             WHERE key = $1
             "#,
             self.qualified_table
         );
 
-        let row = self.client.borrow_mut().query_opt(&sql, &[&key])?;
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?])?;
 
         match row {
             Some(row) => {
@@ -181,7 +534,7 @@ impl Store {
                         }
                     }
                 }
-                Ok(Some(row.get("value")))
+                Ok(Some(self.decode_row(key, &row)?))
             }
             None => Ok(None),
         }
@@ -246,17 +599,20 @@ impl Store {
 
         let sql = format!(
             r#"
-            SELECT key, value, expires_at, created_at, updated_at
+            SELECT key, value, encoding, expires_at, stale_at, tags, version, created_at, updated_at
             FROM {} WHERE key = $1
             "#,
             self.qualified_table
         );
 
-        let row = self.client.borrow_mut().query_opt(&sql, &[&key])?;
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?])?;
 
         match row {
             Some(row) => {
-                let entry = Self::row_to_entry(&row)?;
+                let entry = self.row_to_entry(&row)?;
                 if self.config.ttl_enabled() && entry.is_expired() {
                     if self.config.cleanup_on_read() {
                         let _ = self.delete_internal(key);
@@ -269,6 +625,30 @@ impl Store {
         }
     }
 
+    /// Gets a value along with whether it is past its soft expiry.
+    ///
+    /// Returns `None` if the key doesn't exist or has passed its hard expiry.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Freshness, Store};
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// if let Some((value, freshness)) = store.get_with_staleness("api:/products")? {
+    ///     if freshness == Freshness::Stale {
+    ///         println!("Serving stale value, refresh is due");
+    ///     }
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn get_with_staleness(&self, key: &str) -> Result<Option<(Vec<u8>, Freshness)>> {
+        Ok(self.get_entry(key)?.map(|entry| {
+            let freshness = entry.freshness();
+            (entry.value, freshness)
+        }))
+    }
+
     /// Sets a value for a key.
     ///
     /// # Example
@@ -281,8 +661,9 @@ impl Store {
     /// store.set("key", "string value")?;  // Also accepts &str
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn set(&self, key: &str, value: impl AsRef<[u8]>) -> Result<()> {
-        self.set_internal(key, value.as_ref(), None)
+    pub fn set(&self, key: &(impl AsKey + ?Sized), value: impl AsRef<[u8]>) -> Result<()> {
+        self.set_internal(key, value.as_ref(), None, None, &[])?;
+        Ok(())
     }
 
     /// Sets a value with an expiration time (TTL).
@@ -297,9 +678,15 @@ impl Store {
     /// store.set_ex("session", b"data", Duration::from_secs(3600))?;  // Expires in 1 hour
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn set_ex(&self, key: &str, value: impl AsRef<[u8]>, ttl: Duration) -> Result<()> {
+    pub fn set_ex(
+        &self,
+        key: &(impl AsKey + ?Sized),
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<()> {
         let expires_at = SystemTime::now() + ttl;
-        self.set_internal(key, value.as_ref(), Some(expires_at))
+        self.set_internal(key, value.as_ref(), Some(expires_at), None, &[])?;
+        Ok(())
     }
 
     /// Sets a value with an absolute expiration time.
@@ -316,29 +703,276 @@ impl Store {
     /// # Ok::<(), pgkv::Error>(())
     /// ```
     pub fn set_at(&self, key: &str, value: impl AsRef<[u8]>, expires_at: SystemTime) -> Result<()> {
-        self.set_internal(key, value.as_ref(), Some(expires_at))
+        self.set_internal(key, value.as_ref(), Some(expires_at), None, &[])?;
+        Ok(())
+    }
+
+    /// Sets a value that becomes stale after `soft_ttl` and expires after `hard_ttl`.
+    ///
+    /// Stale entries are still returned by reads; use [`Store::get_with_staleness`]
+    /// or [`Store::get_or_revalidate`] to find out whether a refresh is due.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    /// use std::time::Duration;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// // Fresh for 1 minute, served stale for up to 1 hour
+    /// store.set_ex_soft(
+    ///     "api:/products",
+    ///     b"[...]",
+    ///     Duration::from_secs(60),
+    ///     Duration::from_secs(3600),
+    /// )?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn set_ex_soft(
+        &self,
+        key: &str,
+        value: impl AsRef<[u8]>,
+        soft_ttl: Duration,
+        hard_ttl: Duration,
+    ) -> Result<()> {
+        let now = SystemTime::now();
+        self.set_internal(
+            key,
+            value.as_ref(),
+            Some(now + hard_ttl),
+            Some(now + soft_ttl),
+            &[],
+        )?;
+        Ok(())
+    }
+
+    /// Sets a value tagged with one or more cache tags.
+    ///
+    /// Tags replace any tags previously set on the key. All keys sharing a tag
+    /// can be deleted at once with [`Store::invalidate_tag`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    /// use std::time::Duration;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// store.set_with_tags(
+    ///     "page:/products/42",
+    ///     b"<html>...</html>",
+    ///     Some(Duration::from_secs(3600)),
+    ///     &["product:42", "category:7"],
+    /// )?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn set_with_tags(
+        &self,
+        key: &str,
+        value: impl AsRef<[u8]>,
+        ttl: Option<Duration>,
+        tags: &[&str],
+    ) -> Result<()> {
+        for tag in tags {
+            validate_tag(tag)?;
+        }
+
+        let expires_at = ttl.map(|ttl| SystemTime::now() + ttl);
+        self.set_internal(key, value.as_ref(), expires_at, None, tags)?;
+        Ok(())
     }
 
-    fn set_internal(&self, key: &str, value: &[u8], expires_at: Option<SystemTime>) -> Result<()> {
+    /// Sets a value with conditions and expiration controlled by [`SetOptions`].
+    ///
+    /// Compiles to a single statement, so the condition check and the write
+    /// are atomic.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{SetOptions, Store};
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    ///
+    /// // Refresh a session only if it still exists, keeping its expiry
+    /// let outcome = store.set_with(
+    ///     "session:abc",
+    ///     b"updated",
+    ///     SetOptions::new().if_exists().keep_ttl().return_previous(),
+    /// )?;
+    /// if outcome.written {
+    ///     println!("previous: {:?}", outcome.previous);
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn set_with(
+        &self,
+        key: &str,
+        value: impl AsRef<[u8]>,
+        options: SetOptions,
+    ) -> Result<SetOutcome> {
         self.validate_key(key)?;
+        let value = value.as_ref();
         self.validate_value(value)?;
+        let (value, encoding) = self.encode_value(key, value)?;
+
+        let expired = self.expired_condition("t");
+        let keep_ttl = options.expiry == SetExpiry::Keep;
+        let expires_at = match options.expiry {
+            SetExpiry::Persist | SetExpiry::Keep => None,
+            SetExpiry::Ttl(ttl) => Some(SystemTime::now() + ttl),
+            SetExpiry::At(at) => Some(at),
+        };
+
+        // `$4` is only bound when the expiration is being set
+        let (new_expires_at, existing_expires_at, existing_stale_at) = if keep_ttl {
+            ("NULL", "t.expires_at", "t.stale_at")
+        } else {
+            ("$4::timestamptz", "$4::timestamptz", "NULL::timestamptz")
+        };
+
+        let write = match options.condition {
+            SetCondition::IfExists => format!(
+                r#"
+                UPDATE {} t SET
+                    value = $2,
+                    encoding = $3,
+                    expires_at = {existing_expires_at},
+                    stale_at = {existing_stale_at},
+                    tags = NULL,
+                    version = {},
+                    updated_at = NOW()
+                WHERE key = $1 AND NOT {expired}
+                "#,
+                self.qualified_table, self.next_version
+            ),
+            SetCondition::Always | SetCondition::IfNotExists => format!(
+                r#"
+                INSERT INTO {} AS t (key, value, encoding, expires_at, created_at, updated_at)
+                VALUES ($1, $2, $3, {new_expires_at}, NOW(), NOW())
+                ON CONFLICT (key) DO UPDATE SET
+                    value = EXCLUDED.value,
+                    encoding = EXCLUDED.encoding,
+                    expires_at = CASE WHEN {expired} THEN EXCLUDED.expires_at
+                        ELSE {existing_expires_at} END,
+                    stale_at = CASE WHEN {expired} THEN NULL ELSE {existing_stale_at} END,
+                    tags = NULL,
+                    version = {},
+                    updated_at = NOW()
+                {}
+                "#,
+                self.qualified_table,
+                self.next_version,
+                if options.condition == SetCondition::IfNotExists {
+                    format!("WHERE {}", expired)
+                } else {
+                    String::new()
+                },
+            ),
+        };
+
+        let previous = if options.return_previous {
+            format!(
+                "SELECT value, encoding FROM {} t WHERE key = $1 AND NOT {expired}",
+                self.qualified_table
+            )
+        } else {
+            format!(
+                "SELECT NULL::{} AS value, NULL::smallint AS encoding",
+                self.config.value_type.sql_type()
+            )
+        };
 
         let sql = format!(
             r#"
-            INSERT INTO {} (key, value, expires_at, created_at, updated_at)
-            VALUES ($1, $2, $3, NOW(), NOW())
+            WITH previous AS ({previous}),
+            written AS ({write} RETURNING version)
+            SELECT
+                (SELECT version FROM written) AS version,
+                (SELECT value FROM previous) AS previous,
+                (SELECT encoding FROM previous) AS previous_encoding
+            "#
+        );
+
+        let row = if keep_ttl {
+            self.client.borrow_mut().query_one(
+                &sql,
+                &[&*self.key_param(key)?, &ValueParam(&value), &encoding],
+            )?
+        } else {
+            self.client.borrow_mut().query_one(
+                &sql,
+                &[
+                    &*self.key_param(key)?,
+                    &ValueParam(&value),
+                    &encoding,
+                    &expires_at,
+                ],
+            )?
+        };
+
+        let version = row.get::<_, Option<i64>>("version").map(|v| v as u64);
+        let previous = row
+            .get::<_, Option<StoredValue>>("previous")
+            .map(|value| self.decode_value(key, value.0, row.get("previous_encoding")))
+            .transpose()?;
+        Ok(SetOutcome {
+            written: version.is_some(),
+            version,
+            previous,
+        })
+    }
+
+    fn set_internal(
+        &self,
+        key: &(impl AsKey + ?Sized),
+        value: &[u8],
+        expires_at: Option<SystemTime>,
+        stale_at: Option<SystemTime>,
+        tags: &[&str],
+    ) -> Result<u64> {
+        self.validate_key(key)?;
+        self.validate_value(value)?;
+        let (value, encoding) = self.encode_value(key, value)?;
+
+        let sql = self.set_sql(["$1", "$2", "$3", "$4", "$5", "$6"]);
+        let tags = (!tags.is_empty()).then_some(tags);
+        let row = self.client.borrow_mut().query_one(
+            &sql,
+            &[
+                &*self.key_param(key)?,
+                &ValueParam(&value),
+                &encoding,
+                &expires_at,
+                &stale_at,
+                &tags,
+            ],
+        )?;
+        Ok(row.get::<_, i64>("version") as u64)
+    }
+
+    /// SQL that writes a value and returns its new version.
+    ///
+    /// `args` are SQL expressions for the key, the encoded value, its
+    /// encoding, `expires_at`, `stale_at` and `tags`.
+    fn set_sql(&self, args: [&str; 6]) -> String {
+        let [key, value, encoding, expires_at, stale_at, tags] = args;
+        format!(
+            r#"
+            INSERT INTO {} (key, value, encoding, expires_at, stale_at, tags, created_at, updated_at)
+            VALUES ({key}, {value}, {encoding}, {expires_at}, {stale_at}, {tags}, NOW(), NOW())
             ON CONFLICT (key) DO UPDATE SET
                 value = EXCLUDED.value,
+                encoding = EXCLUDED.encoding,
                 expires_at = EXCLUDED.expires_at,
+                stale_at = EXCLUDED.stale_at,
+                tags = EXCLUDED.tags,
+                version = {},
                 updated_at = NOW()
+            RETURNING version
             "#,
-            self.qualified_table
-        );
-
-        self.client
-            .borrow_mut()
-            .execute(&sql, &[&key, &value, &expires_at])?;
-        Ok(())
+            self.qualified_table, self.next_version
+        )
     }
 
     /// Sets a value only if the key doesn't exist.
@@ -358,38 +992,49 @@ impl Store {
     /// }
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn set_nx(&self, key: &str, value: impl AsRef<[u8]>) -> Result<bool> {
+    pub fn set_nx(&self, key: &(impl AsKey + ?Sized), value: impl AsRef<[u8]>) -> Result<bool> {
         self.set_nx_internal(key, value.as_ref(), None)
     }
 
     /// Sets a value with TTL only if the key doesn't exist.
-    pub fn set_nx_ex(&self, key: &str, value: impl AsRef<[u8]>, ttl: Duration) -> Result<bool> {
+    pub fn set_nx_ex(
+        &self,
+        key: &(impl AsKey + ?Sized),
+        value: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<bool> {
         let expires_at = SystemTime::now() + ttl;
         self.set_nx_internal(key, value.as_ref(), Some(expires_at))
     }
 
     fn set_nx_internal(
         &self,
-        key: &str,
+        key: &(impl AsKey + ?Sized),
         value: &[u8],
         expires_at: Option<SystemTime>,
     ) -> Result<bool> {
         self.validate_key(key)?;
         self.validate_value(value)?;
+        let (value, encoding) = self.encode_value(key, value)?;
 
         let sql = format!(
             r#"
-            INSERT INTO {} (key, value, expires_at, created_at, updated_at)
-            VALUES ($1, $2, $3, NOW(), NOW())
+            INSERT INTO {} (key, value, encoding, expires_at, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, NOW(), NOW())
             ON CONFLICT (key) DO NOTHING
             "#,
             self.qualified_table
         );
 
-        let count = self
-            .client
-            .borrow_mut()
-            .execute(&sql, &[&key, &value, &expires_at])?;
+        let count = self.client.borrow_mut().execute(
+            &sql,
+            &[
+                &*self.key_param(key)?,
+                &ValueParam(&value),
+                &encoding,
+                &expires_at,
+            ],
+        )?;
         Ok(count > 0)
     }
 
@@ -406,14 +1051,17 @@ impl Store {
     /// store.delete("key")?;
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn delete(&self, key: &str) -> Result<bool> {
+    pub fn delete(&self, key: &(impl AsKey + ?Sized)) -> Result<bool> {
         self.validate_key(key)?;
         self.delete_internal(key)
     }
 
-    fn delete_internal(&self, key: &str) -> Result<bool> {
+    fn delete_internal(&self, key: &(impl AsKey + ?Sized)) -> Result<bool> {
         let sql = format!("DELETE FROM {} WHERE key = $1", self.qualified_table);
-        let count = self.client.borrow_mut().execute(&sql, &[&key])?;
+        let count = self
+            .client
+            .borrow_mut()
+            .execute(&sql, &[&*self.key_param(key)?])?;
         Ok(count > 0)
     }
 
@@ -430,7 +1078,7 @@ impl Store {
     /// }
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn exists(&self, key: &str) -> Result<bool> {
+    pub fn exists(&self, key: &(impl AsKey + ?Sized)) -> Result<bool> {
         self.validate_key(key)?;
 
         let sql = format!(
@@ -441,7 +1089,10 @@ impl Store {
             self.qualified_table
         );
 
-        let row = self.client.borrow_mut().query_opt(&sql, &[&key])?;
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?])?;
         Ok(row.is_some())
     }
 
@@ -473,26 +1124,27 @@ impl Store {
             self.validate_key(key)?;
         }
 
-        let keys: Vec<String> = keys.iter().map(|s| s.to_string()).collect();
-
         let sql = format!(
             r#"
-            SELECT key, value FROM {}
+            SELECT key, value, encoding FROM {}
             WHERE key = ANY($1)
             AND (expires_at IS NULL OR expires_at > NOW())
             "#,
             self.qualified_table
         );
 
-        let rows = self.client.borrow_mut().query(&sql, &[&keys])?;
+        let keys = self.keys_param(keys);
+        let rows = self.client.borrow_mut().query(&sql, &[&*keys])?;
 
-        Ok(rows
-            .into_iter()
-            .map(|row| KeyValue {
-                key: row.get("key"),
-                value: row.get("value"),
+        rows.into_iter()
+            .map(|row| {
+                let key = self.row_key(&row)?;
+                Ok(KeyValue {
+                    value: self.decode_row(&key, &row)?,
+                    key,
+                })
             })
-            .collect())
+            .collect()
     }
 
     /// Sets multiple key-value pairs atomically.
@@ -520,31 +1172,34 @@ impl Store {
             self.validate_value(value)?;
         }
 
+        let values = items
+            .iter()
+            .map(|(key, value)| self.encode_value(key, value))
+            .collect::<Result<Vec<_>>>()?;
+
         let sql = format!(
             r#"
-            INSERT INTO {} (key, value, created_at, updated_at)
-            VALUES ($1, $2, NOW(), NOW())
+            INSERT INTO {} (key, value, encoding, created_at, updated_at)
+            VALUES ($1, $2, $3, NOW(), NOW())
             ON CONFLICT (key) DO UPDATE SET
                 value = EXCLUDED.value,
+                encoding = EXCLUDED.encoding,
+                version = {},
                 updated_at = NOW()
             "#,
-            self.qualified_table
+            self.qualified_table, self.next_version
         );
 
-        let mut client = self.client.borrow_mut();
-
-        // Execute in a transaction
-        client.execute("BEGIN", &[])?;
-
-        for (key, value) in items {
-            if let Err(e) = client.execute(&sql, &[key, value]) {
-                let _ = client.execute("ROLLBACK", &[]);
-                return Err(e.into());
+        self.atomically("BEGIN", || {
+            let mut client = self.client.borrow_mut();
+            for ((key, _), (value, encoding)) in items.iter().zip(&values) {
+                client.execute(
+                    &sql,
+                    &[&*self.key_param(key)?, &ValueParam(value), encoding],
+                )?;
             }
-        }
-
-        client.execute("COMMIT", &[])?;
-        Ok(())
+            Ok(())
+        })
     }
 
     /// Deletes multiple keys.
@@ -570,74 +1225,517 @@ impl Store {
             self.validate_key(key)?;
         }
 
-        let keys: Vec<String> = keys.iter().map(|s| s.to_string()).collect();
-
         let sql = format!("DELETE FROM {} WHERE key = ANY($1)", self.qualified_table);
-        let count = self.client.borrow_mut().execute(&sql, &[&keys])?;
+        let count = self
+            .client
+            .borrow_mut()
+            .execute(&sql, &[&*self.keys_param(keys)])?;
         Ok(count)
     }
 
-    // ==================== Atomic Operations ====================
-
-    /// Atomically increments a numeric value.
+    /// Applies a [`Batch`] atomically if all of its preconditions hold.
     ///
-    /// The value is stored as a string representation of an integer.
-    /// If the key doesn't exist, it's created with the delta value.
-    /// Returns the new value.
+    /// The batch is sent in one round-trip and runs as a single
+    /// `REPEATABLE READ` transaction: the keys named in preconditions are
+    /// locked with `SELECT ... FOR UPDATE` and checked in SQL, then the writes
+    /// are applied in order. If another connection modifies a checked key
+    /// concurrently, the batch is retried from the start (up to three
+    /// attempts) and then fails with [`Error::Conflict`].
+    ///
+    /// Inside a transaction, the batch runs in a savepoint at the enclosing
+    /// transaction's isolation level and conflicts are not retried; a failed
+    /// precondition then costs a second round-trip to roll the savepoint back.
+    ///
+    /// Returns [`Error::NotNumeric`] if an increment hits a value that isn't
+    /// an integer, and [`Error::Config`] for increments or
+    /// [`Batch::if_equals`] on a store that encrypts values.
     ///
     /// # Example
     ///
     /// ```rust,no_run
-    /// use pgkv::Store;
+    /// use pgkv::{Batch, Store};
     ///
     /// let store = Store::connect("postgresql://localhost/mydb")?;
-    /// let new_value = store.increment("counter", 1)?;
-    /// println!("Counter is now: {}", new_value);
-    ///
-    /// let new_value = store.increment("counter", -1)?;  // Decrement
+    /// let result = store.execute_batch(
+    ///     &Batch::new()
+    ///         .if_equals("lock:job", b"worker-1")
+    ///         .delete("lock:job")
+    ///         .put("job:status", b"done"),
+    /// )?;
+    /// assert!(result.is_committed());
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn increment(&self, key: &str, delta: i64) -> Result<i64> {
-        self.validate_key(key)?;
-
-        // Use a CTE for atomic increment
-        let sql = format!(
-            r#"
-            INSERT INTO {} (key, value, created_at, updated_at)
-            VALUES ($1, $2::text::bytea, NOW(), NOW())
-            ON CONFLICT (key) DO UPDATE SET
-                value = (COALESCE(
-                    (encode({}.value, 'escape')::bigint),
-                    0
-                ) + $3)::text::bytea,
-                updated_at = NOW()
-            RETURNING encode(value, 'escape')::bigint as new_value
-            "#,
-            self.qualified_table, self.qualified_table
-        );
+    pub fn execute_batch(&self, batch: &Batch) -> Result<BatchResult> {
+        for precondition in &batch.preconditions {
+            self.validate_key(precondition.key())?;
+        }
 
-        let row = self
-            .client
-            .borrow_mut()
-            .query_one(&sql, &[&key, &delta.to_string(), &delta])?;
-        Ok(row.get("new_value"))
+        let mut attempt = 1;
+        loop {
+            match self.execute_batch_once(batch) {
+                Err(e)
+                    if e.is_conflict()
+                        && attempt < BATCH_MAX_ATTEMPTS
+                        && self.tx_depth.get() == 0 =>
+                {
+                    attempt += 1
+                }
+                result => return result,
+            }
+        }
     }
 
-    /// Atomically decrements a numeric value.
-    ///
-    /// Equivalent to `increment(key, -delta)`.
-    pub fn decrement(&self, key: &str, delta: i64) -> Result<i64> {
-        self.increment(key, -delta)
+    fn execute_batch_once(&self, batch: &Batch) -> Result<BatchResult> {
+        let depth = self.tx_depth.get();
+        let script = self.batch_script(batch)?;
+
+        // A multi-statement query runs as one implicit transaction, which a
+        // failed precondition rolls back
+        let sql = if depth == 0 {
+            format!(
+                "SET TRANSACTION ISOLATION LEVEL REPEATABLE READ;\n{}",
+                script
+            )
+        } else {
+            format!(
+                "SAVEPOINT {};\n{}{};",
+                savepoint_name(depth),
+                script,
+                end_transaction_sql(depth, true)
+            )
+        };
+
+        let result = self.client.borrow_mut().simple_query(&sql);
+        let messages = match result {
+            Ok(messages) => messages,
+            Err(e) => {
+                if depth > 0 {
+                    let _ = self
+                        .client
+                        .borrow_mut()
+                        .batch_execute(&end_transaction_sql(depth, false));
+                }
+                return batch_failure(batch, e);
+            }
+        };
+
+        let mut rows = messages.iter().filter_map(|message| match message {
+            SimpleQueryMessage::Row(row) => row.get(0),
+            _ => None,
+        });
+        let mut results = Vec::with_capacity(batch.ops.len());
+        for op in &batch.ops {
+            let field = rows
+                .next()
+                .ok_or_else(|| Error::Query("batch returned too few results".into()))?;
+            let result = match op {
+                BatchOp::Put { .. } => OpResult::Put {
+                    version: parse_field(field)?,
+                },
+                BatchOp::Delete { .. } => OpResult::Delete {
+                    existed: parse_field::<i64>(field)? > 0,
+                },
+                BatchOp::Increment { .. } => OpResult::Increment {
+                    value: parse_field(field)?,
+                },
+            };
+            results.push(result);
+        }
+
+        Ok(BatchResult::Committed(results))
     }
 
-    /// Compare-and-swap operation.
-    ///
-    /// Atomically sets the value only if the current value matches the expected value.
+    /// Builds the script that checks a batch's preconditions and applies its
+    /// writes in order, returning one row per write.
     ///
-    /// # Example
-    ///
-    /// ```rust,no_run
-    /// use pgkv::{Store, CasResult};
+    /// Arguments are inlined as literals, since a multi-statement query can't
+    /// take parameters.
+    fn batch_script(&self, batch: &Batch) -> Result<String> {
+        let mut script = String::new();
+
+        if !batch.preconditions.is_empty() {
+            let keys = batch
+                .preconditions
+                .iter()
+                .map(|p| self.key_literal(p.key()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut checks = String::new();
+            for (index, precondition) in batch.preconditions.iter().enumerate() {
+                checks.push_str(&format!(
+                    "IF NOT {} THEN RAISE EXCEPTION '{}' USING DETAIL = '{}'; END IF;\n",
+                    self.precondition_sql(precondition)?,
+                    BATCH_PRECONDITION_FAILED,
+                    index
+                ));
+            }
+
+            script.push_str(&format!(
+                r#"
+                DO $$
+                BEGIN
+                    PERFORM 1 FROM {} WHERE key IN ({}) ORDER BY key FOR UPDATE;
+                    {}
+                END
+                $$;
+                "#,
+                self.qualified_table, keys, checks
+            ));
+        }
+
+        for (index, op) in batch.ops.iter().enumerate() {
+            match op {
+                BatchOp::Put { key, value, ttl } => {
+                    self.validate_key(key)?;
+                    self.validate_value(value)?;
+                    let (value, encoding) = self.encode_value(key, value)?;
+                    let expires_at = match ttl {
+                        Some(ttl) => format!("NOW() + INTERVAL '{} microseconds'", ttl.as_micros()),
+                        None => "NULL".to_string(),
+                    };
+                    script.push_str(&self.set_sql([
+                        &self.key_literal(key),
+                        &self.value_literal(&value),
+                        &encoding.to_string(),
+                        &expires_at,
+                        "NULL",
+                        "NULL",
+                    ]));
+                }
+                BatchOp::Delete { key } => {
+                    self.validate_key(key)?;
+                    script.push_str(&format!(
+                        r#"
+                        WITH deleted AS (DELETE FROM {} WHERE key = {} RETURNING 1)
+                        SELECT count(*) FROM deleted
+                        "#,
+                        self.qualified_table,
+                        self.key_literal(key)
+                    ));
+                }
+                BatchOp::Increment { key, delta } => {
+                    self.require_unencrypted_values("increment")?;
+                    self.validate_key(key)?;
+                    let key = self.key_literal(key);
+
+                    // Casts the current value first, so that a failure can be
+                    // traced back to this write
+                    script.push_str(&format!(
+                        r#"
+                        DO $$
+                        BEGIN
+                            PERFORM {}::bigint FROM {} AS t
+                            WHERE t.key = {} AND NOT {};
+                        EXCEPTION WHEN invalid_text_representation THEN
+                            RAISE EXCEPTION '{}' USING DETAIL = '{}';
+                        END
+                        $$;
+                        "#,
+                        self.value_to_text_sql("t.value"),
+                        self.qualified_table,
+                        key,
+                        self.expired_condition("t"),
+                        BATCH_NOT_NUMERIC,
+                        index
+                    ));
+                    script.push_str(&self.increment_sql([
+                        &key,
+                        &quote_literal(&delta.to_string()),
+                        "NULL",
+                        &bigint_literal(*delta),
+                        &bigint_literal(i64::MIN),
+                        &bigint_literal(i64::MAX),
+                    ]));
+                }
+            }
+            script.push_str(";\n");
+        }
+
+        Ok(script)
+    }
+
+    /// SQL condition that holds when `precondition` does, evaluated against
+    /// the rows locked by the batch.
+    fn precondition_sql(&self, precondition: &Precondition) -> Result<String> {
+        let key = self.key_literal(precondition.key());
+        let live = format!(
+            "SELECT 1 FROM {} AS t WHERE t.key = {} AND NOT {}",
+            self.qualified_table,
+            key,
+            self.expired_condition("t")
+        );
+
+        let condition = match precondition {
+            Precondition::Absent { .. } => format!("NOT EXISTS ({})", live),
+            Precondition::Version { version, .. } => {
+                format!("EXISTS ({} AND t.version = {})", live, *version as i64)
+            }
+            Precondition::Equals { key, value } => {
+                self.require_unencrypted_values("if_equals")?;
+
+                // Compressed values can't be decompressed in SQL, so the
+                // expected value is compressed the same way and compared as is
+                let (encoded, encoding) = self.encode_value(key, value)?;
+                let mut matches = format!(
+                    "(t.encoding = 0 AND {} = {})",
+                    self.value_bytes_sql("t.value"),
+                    bytes_literal(value)
+                );
+                if encoding != 0 {
+                    matches = format!(
+                        "({} OR (t.encoding = {} AND t.value = {}))",
+                        matches,
+                        encoding,
+                        bytes_literal(&encoded)
+                    );
+                }
+                format!("EXISTS ({} AND {})", live, matches)
+            }
+        };
+
+        Ok(format!("({})", condition))
+    }
+
+    // ==================== Atomic Operations ====================
+
+    /// Atomically increments a numeric value.
+    ///
+    /// The value is stored as a string representation of an integer.
+    /// If the key doesn't exist, it's created with the delta value.
+    /// Returns the new value, or [`Error::NotNumeric`] if the current value
+    /// is not an integer. Returns [`Error::Config`] if the store encrypts
+    /// values, since the new value is computed in SQL.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let new_value = store.increment("counter", 1)?;
+    /// println!("Counter is now: {}", new_value);
+    ///
+    /// let new_value = store.increment("counter", -1)?;  // Decrement
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn increment(&self, key: &str, delta: i64) -> Result<i64> {
+        self.increment_internal(key, delta, None, i64::MIN, i64::MAX)
+    }
+
+    /// Atomically increments a numeric value, setting a TTL if the key is created.
+    ///
+    /// An existing key keeps its expiration; an expired key is restarted from
+    /// `delta` with the new TTL. Useful for rate limiting windows.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    /// use std::time::Duration;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let requests = store.increment_with_ttl("ratelimit:1.2.3.4", 1, Duration::from_secs(60))?;
+    /// if requests > 100 {
+    ///     println!("Too many requests");
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn increment_with_ttl(&self, key: &str, delta: i64, ttl: Duration) -> Result<i64> {
+        let expires_at = SystemTime::now() + ttl;
+        self.increment_internal(key, delta, Some(expires_at), i64::MIN, i64::MAX)
+    }
+
+    /// Atomically increments a numeric value, keeping the result within `min..=max`.
+    ///
+    /// A new key starts at `delta` clamped to the bounds.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// // Take a token from a bucket, never going below zero
+    /// let tokens = store.increment_clamped("bucket:api", -1, 0, 100)?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn increment_clamped(&self, key: &str, delta: i64, min: i64, max: i64) -> Result<i64> {
+        if min > max {
+            return Err(Error::InvalidValue {
+                reason: format!("min ({}) is greater than max ({})", min, max),
+            });
+        }
+
+        self.increment_internal(key, delta, None, min, max)
+    }
+
+    /// Atomically increments several numeric values in one transaction.
+    ///
+    /// Returns the new values in the same order as `deltas`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let values = store.increment_many(&[("views:home", 1), ("views:total", 1)])?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn increment_many(&self, deltas: &[(&str, i64)]) -> Result<Vec<i64>> {
+        if deltas.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        for (key, _) in deltas {
+            self.validate_key(key)?;
+        }
+
+        self.atomically("BEGIN", || {
+            deltas
+                .iter()
+                .map(|(key, delta)| self.increment(key, *delta))
+                .collect()
+        })
+    }
+
+    fn increment_internal(
+        &self,
+        key: &str,
+        delta: i64,
+        expires_at: Option<SystemTime>,
+        min: i64,
+        max: i64,
+    ) -> Result<i64> {
+        self.require_unencrypted_values("increment")?;
+        self.validate_key(key)?;
+
+        let sql = self.increment_sql(["$1", "$2", "$3", "$4", "$5", "$6"]);
+
+        let initial = delta.clamp(min, max).to_string();
+        let row = self
+            .client
+            .borrow_mut()
+            .query_one(
+                &sql,
+                &[
+                    &*self.key_param(key)?,
+                    &initial,
+                    &expires_at,
+                    &delta,
+                    &min,
+                    &max,
+                ],
+            )
+            .map_err(|e| not_numeric(e, key))?;
+        Ok(row.get("new_value"))
+    }
+
+    /// SQL that increments a numeric value and returns the result.
+    ///
+    /// `args` are SQL expressions for the key, the initial value as text,
+    /// `expires_at` for a new key, the delta, and the bounds.
+    fn increment_sql(&self, args: [&str; 6]) -> String {
+        let [key, initial, expires_at, delta, min, max] = args;
+        format!(
+            r#"
+            INSERT INTO {} AS t (key, value, expires_at, created_at, updated_at)
+            VALUES ({key}, {initial}, {expires_at}, NOW(), NOW())
+            ON CONFLICT (key) DO UPDATE SET
+                value = CASE WHEN {expired} THEN EXCLUDED.value
+                    ELSE {incremented}
+                END,
+                encoding = 0,
+                expires_at = CASE WHEN {expired} THEN EXCLUDED.expires_at ELSE t.expires_at END,
+                version = {},
+                updated_at = NOW()
+            RETURNING {new_value}::bigint as new_value
+            "#,
+            self.qualified_table,
+            self.next_version,
+            expired = self.expired_condition("t"),
+            initial = self.value_from_text_sql(initial),
+            incremented = self.value_from_text_sql(&format!(
+                "LEAST(GREATEST(COALESCE({}::bigint, 0) + {delta}, {min}), {max})",
+                self.value_to_text_sql("t.value")
+            )),
+            new_value = self.value_to_text_sql("value"),
+        )
+    }
+
+    /// Atomically increments a floating-point value.
+    ///
+    /// The value is stored as a string representation of the number, so it
+    /// stays readable with [`Store::get_string`]. If the key doesn't exist,
+    /// it's created with the delta value. Returns the new value.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let total = store.increment_float("revenue", 19.99)?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn increment_float(&self, key: &str, delta: f64) -> Result<f64> {
+        self.require_unencrypted_values("increment_float")?;
+        self.validate_key(key)?;
+
+        if !delta.is_finite() {
+            return Err(Error::InvalidValue {
+                reason: format!("increment must be finite, got {}", delta),
+            });
+        }
+
+        let sql = format!(
+            r#"
+            INSERT INTO {} AS t (key, value, created_at, updated_at)
+            VALUES ($1, {initial}, NOW(), NOW())
+            ON CONFLICT (key) DO UPDATE SET
+                value = CASE WHEN {expired} THEN EXCLUDED.value
+                    ELSE {incremented}
+                END,
+                encoding = 0,
+                expires_at = CASE WHEN {expired} THEN NULL ELSE t.expires_at END,
+                version = {},
+                updated_at = NOW()
+            RETURNING {new_value}::double precision as new_value
+            "#,
+            self.qualified_table,
+            self.next_version,
+            expired = self.expired_condition("t"),
+            initial = self.value_from_text_sql("$2"),
+            incremented = self.value_from_text_sql(&format!(
+                "COALESCE({}::double precision, 0) + $3",
+                self.value_to_text_sql("t.value")
+            )),
+            new_value = self.value_to_text_sql("value"),
+        );
+
+        let row = self
+            .client
+            .borrow_mut()
+            .query_one(&sql, &[&*self.key_param(key)?, &delta.to_string(), &delta])
+            .map_err(|e| not_numeric(e, key))?;
+        Ok(row.get("new_value"))
+    }
+
+    /// Atomically decrements a numeric value.
+    ///
+    /// Equivalent to `increment(key, -delta)`.
+    pub fn decrement(&self, key: &str, delta: i64) -> Result<i64> {
+        self.increment(key, -delta)
+    }
+
+    /// Compare-and-swap operation.
+    ///
+    /// Atomically sets the value only if the current value matches the expected value.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Store, CasResult};
     ///
     /// let store = Store::connect("postgresql://localhost/mydb")?;
     ///
@@ -675,21 +1773,57 @@ impl Store {
                     Ok(CasResult::Mismatch { current })
                 }
             }
+            Some(expected_value) if self.encrypts() => {
+                // Ciphertexts differ on every write, so compare the decrypted
+                // value under a row lock instead
+                let sql = format!(
+                    "UPDATE {} SET value = $2, encoding = $3, version = {}, updated_at = NOW() \
+                     WHERE key = $1",
+                    self.qualified_table, self.next_version
+                );
+
+                self.atomically("BEGIN", || match self.get_for_update(key)? {
+                    Some(current) if current == expected_value => {
+                        let (new_value, encoding) = self.encode_value(key, new_value)?;
+                        self.client.borrow_mut().execute(
+                            &sql,
+                            &[&*self.key_param(key)?, &ValueParam(&new_value), &encoding],
+                        )?;
+                        Ok(CasResult::Success)
+                    }
+                    Some(current) => Ok(CasResult::Mismatch {
+                        current: Some(current),
+                    }),
+                    None => Ok(CasResult::NotFound),
+                })
+            }
             Some(expected_value) => {
                 // Expect specific value
                 let sql = format!(
                     r#"
-                    UPDATE {} SET value = $2, updated_at = NOW()
-                    WHERE key = $1 AND value = $3
+                    UPDATE {} SET value = $2, encoding = $3, version = {}, updated_at = NOW()
+                    WHERE key = $1
+                    AND ((value = $4 AND encoding = 0) OR (value = $5 AND encoding = $6))
                     AND (expires_at IS NULL OR expires_at > NOW())
                     "#,
-                    self.qualified_table
+                    self.qualified_table, self.next_version
                 );
 
-                let count = self
-                    .client
-                    .borrow_mut()
-                    .execute(&sql, &[&key, &new_value, &expected_value])?;
+                // The stored value may be the compressed or the raw form
+                let (new_value, encoding) = self.encode_value(key, new_value)?;
+                let (encoded_expected, expected_encoding) =
+                    self.encode_value(key, expected_value)?;
+                let count = self.client.borrow_mut().execute(
+                    &sql,
+                    &[
+                        &*self.key_param(key)?,
+                        &ValueParam(&new_value),
+                        &encoding,
+                        &ValueParam(expected_value),
+                        &ValueParam(&encoded_expected),
+                        &expected_encoding,
+                    ],
+                )?;
 
                 if count > 0 {
                     Ok(CasResult::Success)
@@ -726,20 +1860,28 @@ impl Store {
 
         let sql = format!(
             r#"
-            INSERT INTO {} (key, value, created_at, updated_at)
-            VALUES ($1, $2, NOW(), NOW())
+            WITH old AS (SELECT value, encoding FROM {table} WHERE key = $1)
+            INSERT INTO {table} (key, value, encoding, created_at, updated_at)
+            VALUES ($1, $2, $3, NOW(), NOW())
             ON CONFLICT (key) DO UPDATE SET
                 value = EXCLUDED.value,
+                encoding = EXCLUDED.encoding,
+                version = {},
                 updated_at = NOW()
-            RETURNING (
-                SELECT value FROM {} WHERE key = $1
-            ) as old_value
+            RETURNING (SELECT value FROM old) AS old_value, (SELECT encoding FROM old) AS old_encoding
             "#,
-            self.qualified_table, self.qualified_table
+            self.next_version,
+            table = self.qualified_table,
         );
 
-        let row = self.client.borrow_mut().query_one(&sql, &[&key, &value])?;
-        Ok(row.get("old_value"))
+        let (value, encoding) = self.encode_value(key, value)?;
+        let row = self.client.borrow_mut().query_one(
+            &sql,
+            &[&*self.key_param(key)?, &ValueParam(&value), &encoding],
+        )?;
+        row.get::<_, Option<StoredValue>>("old_value")
+            .map(|value| self.decode_value(key, value.0, row.get("old_encoding")))
+            .transpose()
     }
 
     /// Gets the current value and deletes the key atomically.
@@ -759,19 +1901,23 @@ impl Store {
         self.validate_key(key)?;
 
         let sql = format!(
-            "DELETE FROM {} WHERE key = $1 RETURNING value",
+            "DELETE FROM {} WHERE key = $1 RETURNING value, encoding",
             self.qualified_table
         );
 
-        let row = self.client.borrow_mut().query_opt(&sql, &[&key])?;
-        Ok(row.map(|r| r.get("value")))
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?])?;
+        row.map(|row| self.decode_row(key, &row)).transpose()
     }
 
-    // ==================== TTL Operations ====================
-
-    /// Updates the TTL of an existing key.
+    /// Gets a value, computing and storing it with the given TTL if missing.
     ///
-    /// Returns `false` if the key doesn't exist.
+    /// Only one caller computes the value at a time: concurrent callers for the
+    /// same key (across all connections) wait on a PostgreSQL advisory lock and
+    /// then receive the value stored by the first caller instead of calling the
+    /// loader themselves.
     ///
     /// # Example
     ///
@@ -780,55 +1926,137 @@ impl Store {
     /// use std::time::Duration;
     ///
     /// let store = Store::connect("postgresql://localhost/mydb")?;
-    /// store.set("key", b"value")?;
-    /// store.expire("key", Duration::from_secs(60))?;  // Expires in 60 seconds
+    /// let report = store.get_or_set_with("report:daily", Duration::from_secs(300), || {
+    ///     // Expensive computation runs at most once per expiry
+    ///     Ok(b"rendered report".to_vec())
+    /// })?;
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
-        self.validate_key(key)?;
+    pub fn get_or_set_with<F>(&self, key: &str, ttl: Duration, f: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
+        }
 
-        let expires_at = SystemTime::now() + ttl;
+        let lock = self.lock_key(key)?;
+        let result = self.load_locked(key, ttl, f);
+        let unlocked = lock.unlock();
 
-        let sql = format!(
-            "UPDATE {} SET expires_at = $2, updated_at = NOW() WHERE key = $1",
-            self.qualified_table
-        );
+        let value = result?;
+        unlocked?;
+        Ok(value)
+    }
 
-        let count = self
-            .client
-            .borrow_mut()
-            .execute(&sql, &[&key, &expires_at])?;
-        Ok(count > 0)
+    fn load_locked<F>(&self, key: &str, ttl: Duration, f: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        // Another caller may have populated the key while we were waiting
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
+        }
+
+        let value = f()?;
+        self.set_ex(key, &value, ttl)?;
+        Ok(value)
     }
 
-    /// Removes the TTL from a key (makes it persistent).
+    /// Gets a value, serving stale data while a single caller refreshes it.
     ///
-    /// Returns `false` if the key doesn't exist.
+    /// - A fresh value is returned as-is.
+    /// - A stale value is returned immediately to every caller except one,
+    ///   which runs the loader and stores the result with new soft and hard
+    ///   expiries. Refreshes are coordinated across connections with a
+    ///   PostgreSQL advisory lock, so exactly one refresh runs at a time.
+    ///   The refreshing caller blocks while the loader runs; if the loader
+    ///   fails, the error is logged and the stale value is returned.
+    /// - A missing or expired value is loaded as in [`Store::get_or_set_with`].
     ///
     /// # Example
     ///
     /// ```rust,no_run
     /// use pgkv::Store;
+    /// use std::time::Duration;
     ///
     /// let store = Store::connect("postgresql://localhost/mydb")?;
-    /// store.persist("key")?;  // Remove expiration
+    /// let (body, freshness) = store.get_or_revalidate(
+    ///     "api:/products",
+    ///     Duration::from_secs(60),
+    ///     Duration::from_secs(3600),
+    ///     || Ok(b"[...]".to_vec()),
+    /// )?;
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn persist(&self, key: &str) -> Result<bool> {
-        self.validate_key(key)?;
-
-        let sql = format!(
-            "UPDATE {} SET expires_at = NULL, updated_at = NOW() WHERE key = $1",
-            self.qualified_table
-        );
+    pub fn get_or_revalidate<F>(
+        &self,
+        key: &str,
+        soft_ttl: Duration,
+        hard_ttl: Duration,
+        f: F,
+    ) -> Result<(Vec<u8>, Freshness)>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        let lock = match self.get_with_staleness(key)? {
+            Some((value, Freshness::Fresh)) => return Ok((value, Freshness::Fresh)),
+            Some((value, Freshness::Stale)) => match self.try_lock_key(key)? {
+                Some(lock) => lock,
+                // Someone else is already refreshing; serve the stale value
+                None => return Ok((value, Freshness::Stale)),
+            },
+            None => self.lock_key(key)?,
+        };
+
+        let result = self.revalidate_locked(key, soft_ttl, hard_ttl, f);
+        let unlocked = lock.unlock();
+
+        let value = result?;
+        unlocked?;
+        Ok(value)
+    }
 
-        let count = self.client.borrow_mut().execute(&sql, &[&key])?;
-        Ok(count > 0)
+    fn revalidate_locked<F>(
+        &self,
+        key: &str,
+        soft_ttl: Duration,
+        hard_ttl: Duration,
+        f: F,
+    ) -> Result<(Vec<u8>, Freshness)>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        // Another caller may have refreshed the key while we were acquiring the lock
+        let stale = match self.get_with_staleness(key)? {
+            Some((value, Freshness::Fresh)) => return Ok((value, Freshness::Fresh)),
+            Some((value, Freshness::Stale)) => Some(value),
+            None => None,
+        };
+
+        let value = match (f(), stale) {
+            (Ok(value), _) => value,
+            (Err(e), Some(stale)) => {
+                log::warn!(
+                    "failed to refresh stale key {:?}, serving the stale value: {}",
+                    key,
+                    e
+                );
+                return Ok((stale, Freshness::Stale));
+            }
+            (Err(e), None) => return Err(e),
+        };
+        self.set_ex_soft(key, &value, soft_ttl, hard_ttl)?;
+        Ok((value, Freshness::Fresh))
     }
 
-    /// Gets the remaining TTL of a key.
+    // ==================== Byte Operations ====================
+
+    /// Appends bytes to the end of a value.
     ///
-    /// Returns `None` if the key doesn't exist or has no expiration.
+    /// Creates the key if it doesn't exist (or has expired). The key's TTL is
+    /// left unchanged. Returns the new length of the value, or
+    /// [`Error::InvalidValue`] if it would exceed the configured maximum size.
     ///
     /// # Example
     ///
@@ -836,66 +2064,1279 @@ impl Store {
     /// use pgkv::Store;
     ///
     /// let store = Store::connect("postgresql://localhost/mydb")?;
-    /// if let Some(ttl) = store.ttl("key")? {
-    ///     println!("Key expires in {:?}", ttl);
-    /// }
+    /// store.append("log:today", b"user logged in\n")?;
+    /// let len = store.append("log:today", b"user logged out\n")?;
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+    pub fn append(&self, key: &str, bytes: impl AsRef<[u8]>) -> Result<u64> {
+        self.concat_internal(key, bytes.as_ref(), "t.value || EXCLUDED.value")
+    }
+
+    /// Prepends bytes to the start of a value.
+    ///
+    /// Behaves like [`Store::append`], but adds the bytes at the beginning.
+    pub fn prepend(&self, key: &str, bytes: impl AsRef<[u8]>) -> Result<u64> {
+        self.concat_internal(key, bytes.as_ref(), "EXCLUDED.value || t.value")
+    }
+
+    fn concat_internal(&self, key: &str, bytes: &[u8], concat: &str) -> Result<u64> {
+        self.require_bytes_values("append")?;
+        self.require_unencrypted_values("append")?;
         self.validate_key(key)?;
+        self.validate_value(bytes)?;
 
         let sql = format!(
-            "SELECT expires_at FROM {} WHERE key = $1",
-            self.qualified_table
+            r#"
+            INSERT INTO {} AS t (key, value, created_at, updated_at)
+            VALUES ($1, $2, NOW(), NOW())
+            ON CONFLICT (key) DO UPDATE SET
+                value = CASE WHEN {expired} THEN EXCLUDED.value ELSE {concat} END,
+                encoding = 0,
+                expires_at = CASE WHEN {expired} THEN NULL ELSE t.expires_at END,
+                version = {},
+                updated_at = NOW()
+            WHERE {expired} OR (
+                NOT {encoded}
+                AND octet_length(t.value)::bigint + octet_length(EXCLUDED.value) <= $3
+            )
+            RETURNING octet_length(value)::bigint AS len
+            "#,
+            self.qualified_table,
+            self.next_version,
+            expired = self.expired_condition("t"),
+            encoded = Self::encoded_condition("t.encoding"),
         );
 
-        let row = self.client.borrow_mut().query_opt(&sql, &[&key])?;
+        let max = self.config.max_value_size as i64;
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?, &bytes, &max])?;
 
         match row {
-            Some(row) => {
-                let expires_at: Option<SystemTime> = row.get("expires_at");
-                Ok(expires_at.and_then(|exp| exp.duration_since(SystemTime::now()).ok()))
-            }
-            None => Ok(None),
+            Some(row) => Ok(row.get::<_, i64>("len") as u64),
+            None if self.is_encoded(key)? => Err(encoded_value_error()),
+            None => Err(Error::InvalidValue {
+                reason: format!(
+                    "value size would exceed maximum {}",
+                    self.config.max_value_size
+                ),
+            }),
         }
     }
 
-    // ==================== Scanning Operations ====================
-
-    /// Lists all keys with optional filtering.
+    /// Gets a byte range of a value.
+    ///
+    /// `start` and `end` are inclusive offsets; negative offsets count from the
+    /// end of the value (`-1` is the last byte). Out-of-range offsets are
+    /// clamped to the value, so `get_range(key, 0, -1)` returns the whole
+    /// value. Offsets refer to the original value, before compression or
+    /// encryption. Returns `None` if the key doesn't exist.
     ///
     /// # Example
     ///
     /// ```rust,no_run
-    /// use pgkv::{Store, ScanOptions};
+    /// use pgkv::Store;
     ///
     /// let store = Store::connect("postgresql://localhost/mydb")?;
-    ///
+    /// store.set("greeting", b"Hello, world")?;
+    /// assert_eq!(store.get_range("greeting", 0, 4)?, Some(b"Hello".to_vec()));
+    /// assert_eq!(store.get_range("greeting", -5, -1)?, Some(b"world".to_vec()));
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn get_range(&self, key: &str, start: i64, end: i64) -> Result<Option<Vec<u8>>> {
+        self.require_bytes_values("get_range")?;
+        self.validate_key(key)?;
+
+        // Encoded values are returned whole and sliced after decoding. The
+        // offsets are clamped to the value as bigints, so they fit in an int
+        let sql = format!(
+            r#"
+            SELECT v.encoded, v.encoding, CASE WHEN v.encoded THEN v.value ELSE
+                substring(v.value FROM (r.lo + 1)::int FOR GREATEST(r.hi - r.lo + 1, 0)::int)
+            END AS value
+            FROM (
+                SELECT value, encoding, octet_length(value)::bigint AS len, {encoded} AS encoded
+                FROM {} t
+                WHERE key = $1 AND NOT {expired}
+            ) v,
+            LATERAL (
+                SELECT
+                    LEAST(GREATEST(CASE WHEN $2::bigint < 0 THEN v.len + $2 ELSE $2 END, 0), v.len) AS lo,
+                    GREATEST(LEAST(CASE WHEN $3::bigint < 0 THEN v.len + $3 ELSE $3 END, v.len - 1), -1) AS hi
+            ) r
+            "#,
+            self.qualified_table,
+            expired = self.expired_condition("t"),
+            encoded = Self::encoded_condition("encoding"),
+        );
+
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?, &start, &end])?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let value = Self::row_value(&row);
+        if !row.get::<_, bool>("encoded") {
+            self.check_encrypted(key.as_bytes(), 0)?;
+            return Ok(Some(value));
+        }
+
+        let value = self.decode_value(key, value, row.get("encoding"))?;
+        let len = value.len() as i64;
+        let lo = if start < 0 { len + start } else { start }.max(0);
+        let hi = if end < 0 { len + end } else { end }.min(len - 1);
+        Ok(Some(if lo > hi {
+            Vec::new()
+        } else {
+            value[lo as usize..=hi as usize].to_vec()
+        }))
+    }
+
+    /// Overwrites part of a value starting at `offset`.
+    ///
+    /// The value is padded with zero bytes if `offset` is past its end, and is
+    /// created if the key doesn't exist (or has expired). The key's TTL is left
+    /// unchanged. Returns the new length of the value, or
+    /// [`Error::InvalidValue`] if the value is compressed, encrypted or streamed.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// store.set("greeting", b"Hello, world")?;
+    /// store.set_range("greeting", 7, b"there")?;
+    /// assert_eq!(store.get("greeting")?, Some(b"Hello, there".to_vec()));
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn set_range(&self, key: &str, offset: u64, bytes: impl AsRef<[u8]>) -> Result<u64> {
+        self.require_bytes_values("set_range")?;
+        self.require_unencrypted_values("set_range")?;
+        self.validate_key(key)?;
+        let bytes = bytes.as_ref();
+
+        if bytes.is_empty() {
+            return self.strlen(key);
+        }
+
+        let end = offset.saturating_add(bytes.len() as u64);
+        let offset = match i32::try_from(offset) {
+            Ok(offset) if end <= self.config.max_value_size as u64 => offset,
+            _ => {
+                return Err(Error::InvalidValue {
+                    reason: format!(
+                        "value size {} exceeds maximum {}",
+                        end, self.config.max_value_size
+                    ),
+                });
+            }
+        };
+
+        let sql = format!(
+            r#"
+            INSERT INTO {} AS t (key, value, created_at, updated_at)
+            VALUES ($1, $2, NOW(), NOW())
+            ON CONFLICT (key) DO UPDATE SET
+                value = CASE WHEN {expired} THEN EXCLUDED.value ELSE overlay(
+                    t.value || decode(repeat('00', GREATEST($3 - octet_length(t.value), 0)), 'hex')
+                    PLACING $4::bytea FROM $3 + 1 FOR octet_length($4::bytea)
+                ) END,
+                encoding = 0,
+                expires_at = CASE WHEN {expired} THEN NULL ELSE t.expires_at END,
+                version = {},
+                updated_at = NOW()
+            WHERE {expired} OR NOT {encoded}
+            RETURNING octet_length(value)::bigint AS len
+            "#,
+            self.qualified_table,
+            self.next_version,
+            expired = self.expired_condition("t"),
+            encoded = Self::encoded_condition("t.encoding"),
+        );
+
+        let mut initial = vec![0u8; offset as usize];
+        initial.extend_from_slice(bytes);
+
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?, &initial, &offset, &bytes])?;
+        match row {
+            Some(row) => Ok(row.get::<_, i64>("len") as u64),
+            None => Err(encoded_value_error()),
+        }
+    }
+
+    /// Returns the length of a value in bytes, or `0` if the key doesn't exist.
+    ///
+    /// For a compressed, encrypted or streamed value this is the length of the original value.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let len = store.strlen("log:today")?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn strlen(&self, key: &str) -> Result<u64> {
+        self.require_bytes_values("strlen")?;
+        self.validate_key(key)?;
+
+        let sql = format!(
+            r#"
+            SELECT CASE WHEN {streamed} THEN {size} ELSE {len} END AS len,
+                CASE WHEN {encrypted} THEN value END AS encrypted, encoding
+            FROM {} t
+            WHERE key = $1 AND NOT {expired}
+            "#,
+            self.qualified_table,
+            streamed = stream::manifest_sql("encoding"),
+            size = stream::size_sql("value"),
+            len = compression::uncompressed_len_sql("value", "encoding"),
+            encrypted = encryption::encrypted_sql("encoding"),
+            expired = self.expired_condition("t"),
+        );
+
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?])?;
+        let Some(row) = row else {
+            return Ok(0);
+        };
+
+        // The length of an encrypted value is only known after decrypting it
+        match row
+            .get::<_, Option<StoredValue>>("encrypted")
+            .map(|value| value.0)
+        {
+            Some(value) => Ok(self.decode_value(key, value, row.get("encoding"))?.len() as u64),
+            None => {
+                self.check_encrypted(key.as_bytes(), row.get("encoding"))?;
+                Ok(row.get::<_, i64>("len") as u64)
+            }
+        }
+    }
+
+    // ==================== JSON Operations ====================
+
+    /// Gets the JSON at `path` inside a value, as JSON text.
+    ///
+    /// `path` lists object keys and array indexes from the top of the
+    /// document; an empty path returns the whole value. Returns `None` if the
+    /// key doesn't exist or nothing is at `path`. Requires
+    /// [`ValueType::Jsonb`](crate::ValueType::Jsonb).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, Store, ValueType};
+    ///
+    /// let config = Config::new("postgresql://localhost/mydb").value_type(ValueType::Jsonb);
+    /// let store = Store::with_config(config)?;
+    ///
+    /// store.set("user:1", r#"{"name": "Alice", "tags": ["admin"]}"#)?;
+    /// assert_eq!(store.json_get("user:1", &["tags", "0"])?, Some(r#""admin""#.into()));
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn json_get(&self, key: &str, path: &[&str]) -> Result<Option<String>> {
+        self.require_jsonb_values("json_get")?;
+        self.validate_key(key)?;
+
+        let sql = format!(
+            "SELECT value #> $2::text[] AS value FROM {} t WHERE key = $1 AND NOT {}",
+            self.qualified_table,
+            self.expired_condition("t")
+        );
+
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?, &path])?;
+        Ok(row
+            .and_then(|row| row.get::<_, Option<StoredValue>>("value"))
+            .map(|value| String::from_utf8_lossy(&value.0).into_owned()))
+    }
+
+    /// Sets the JSON at `path` inside a value, like PostgreSQL's `jsonb_set`.
+    ///
+    /// A missing last key of `path` is created, but missing parents are not,
+    /// and the value is left unchanged. An empty path replaces the whole
+    /// value. The key's TTL is left unchanged. Returns `false` if the key
+    /// doesn't exist. Requires [`ValueType::Jsonb`](crate::ValueType::Jsonb).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// store.json_set("user:1", &["address", "city"], r#""Berlin""#)?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn json_set(&self, key: &str, path: &[&str], json: &str) -> Result<bool> {
+        self.require_jsonb_values("json_set")?;
+        self.require_unencrypted_values("json_set")?;
+        self.validate_key(key)?;
+        self.validate_value(json.as_bytes())?;
+
+        let sql = format!(
+            r#"
+            UPDATE {} t SET
+                value = CASE WHEN cardinality($2::text[]) = 0 THEN $3::text::jsonb
+                    ELSE jsonb_set(value, $2::text[], $3::text::jsonb) END,
+                version = {},
+                updated_at = NOW()
+            WHERE key = $1 AND NOT {}
+            "#,
+            self.qualified_table,
+            self.next_version,
+            self.expired_condition("t")
+        );
+
+        let count = self
+            .client
+            .borrow_mut()
+            .execute(&sql, &[&*self.key_param(key)?, &path, &json])
+            .map_err(|e| invalid_json(e, key))?;
+        Ok(count > 0)
+    }
+
+    /// Merges a JSON object into a value.
+    ///
+    /// The merge is shallow, like PostgreSQL's `||` operator: top-level keys
+    /// of `patch` replace those of the value. Creates the key if it doesn't
+    /// exist (or has expired). The key's TTL is left unchanged. Requires
+    /// [`ValueType::Jsonb`](crate::ValueType::Jsonb).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// store.json_merge("user:1", r#"{"last_login": "2024-05-01", "visits": 12}"#)?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn json_merge(&self, key: &str, patch: &str) -> Result<()> {
+        self.require_jsonb_values("json_merge")?;
+        self.require_unencrypted_values("json_merge")?;
+        self.validate_key(key)?;
+        self.validate_value(patch.as_bytes())?;
+
+        let sql = format!(
+            r#"
+            INSERT INTO {} AS t (key, value, created_at, updated_at)
+            VALUES ($1, $2::text::jsonb, NOW(), NOW())
+            ON CONFLICT (key) DO UPDATE SET
+                value = CASE WHEN {expired} THEN EXCLUDED.value ELSE t.value || EXCLUDED.value END,
+                expires_at = CASE WHEN {expired} THEN NULL ELSE t.expires_at END,
+                version = {},
+                updated_at = NOW()
+            "#,
+            self.qualified_table,
+            self.next_version,
+            expired = self.expired_condition("t"),
+        );
+
+        self.client
+            .borrow_mut()
+            .execute(&sql, &[&*self.key_param(key)?, &patch])
+            .map_err(|e| invalid_json(e, key))?;
+        Ok(())
+    }
+
+    /// Appends a JSON value to the array at `path` inside a value.
+    ///
+    /// An empty path appends to the value itself. The key's TTL is left
+    /// unchanged. Returns `false` if the key doesn't exist, or
+    /// [`Error::InvalidValue`] if there is no array at `path`. Requires
+    /// [`ValueType::Jsonb`](crate::ValueType::Jsonb).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// store.json_array_append("user:1", &["tags"], r#""beta""#)?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn json_array_append(&self, key: &str, path: &[&str], json: &str) -> Result<bool> {
+        self.require_jsonb_values("json_array_append")?;
+        self.require_unencrypted_values("json_array_append")?;
+        self.validate_key(key)?;
+        self.validate_value(json.as_bytes())?;
+
+        let sql = format!(
+            r#"
+            WITH target AS (
+                SELECT key, jsonb_typeof(value #> $2::text[]) = 'array' AS is_array
+                FROM {table} t
+                WHERE key = $1 AND NOT {expired}
+                FOR UPDATE
+            ), appended AS (
+                UPDATE {table} t SET
+                    value = CASE WHEN cardinality($2::text[]) = 0
+                        THEN t.value || jsonb_build_array($3::text::jsonb)
+                        ELSE jsonb_set(
+                            t.value, $2::text[],
+                            (t.value #> $2::text[]) || jsonb_build_array($3::text::jsonb)
+                        ) END,
+                    version = {version},
+                    updated_at = NOW()
+                FROM target
+                WHERE t.key = target.key AND target.is_array
+            )
+            SELECT is_array FROM target
+            "#,
+            table = self.qualified_table,
+            version = self.next_version,
+            expired = self.expired_condition("t"),
+        );
+
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?, &path, &json])
+            .map_err(|e| invalid_json(e, key))?;
+
+        match row.map(|row| row.get::<_, Option<bool>>("is_array")) {
+            None => Ok(false),
+            Some(Some(true)) => Ok(true),
+            Some(_) => Err(Error::InvalidValue {
+                reason: format!("no array at path {:?} in key {:?}", path, key),
+            }),
+        }
+    }
+
+    // ==================== Streaming Operations ====================
+
+    /// Stores a value read from `reader` in chunks, replacing any existing value.
+    ///
+    /// The value is split into chunks of [`Config::chunk_size`] bytes, so it
+    /// is never held in memory as a whole. Chunks are written to the companion
+    /// table `{table_name}_chunks` (created on first use) and published in
+    /// the same transaction as the key, so readers see either the old value
+    /// or the complete new one. Any TTL and tags are cleared.
+    ///
+    /// Streamed values are read with [`Store::get_reader`]; the in-memory
+    /// reads ([`Store::get`], [`Store::scan`], ...) fail with
+    /// [`Error::InvalidValue`] on them. Chunks are not compressed or
+    /// encrypted, so a store that encrypts values returns [`Error::Config`].
+    /// The value may not exceed [`Config::max_value_size`].
+    ///
+    /// Returns the number of bytes written.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    /// use std::fs::File;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let size = store.put_stream("backup.tar", File::open("backup.tar")?)?;
+    /// println!("Stored {} bytes", size);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn put_stream(&self, key: &str, mut reader: impl Read) -> Result<u64> {
+        self.require_bytes_values("put_stream")?;
+        self.require_unencrypted_values("put_stream")?;
+        self.validate_key(key)?;
+
+        let chunk_size = self.config.chunk_size;
+        let insert_chunk_sql = format!(
+            "INSERT INTO {} (object_id, seq, data, checksum) VALUES ($1, $2, $3, $4)",
+            self.config.qualified_chunks_table_name()
+        );
+        let upsert_sql = format!(
+            r#"
+            INSERT INTO {} (key, value, encoding, created_at, updated_at)
+            VALUES ($1, $2, {}, NOW(), NOW())
+            ON CONFLICT (key) DO UPDATE SET
+                value = EXCLUDED.value,
+                encoding = EXCLUDED.encoding,
+                expires_at = NULL,
+                stale_at = NULL,
+                tags = NULL,
+                version = {},
+                updated_at = NOW()
+            "#,
+            self.qualified_table,
+            stream::ENCODING_FLAG,
+            self.next_version
+        );
+
+        let size = self.atomically("BEGIN", || {
+            if !self.chunks_ready.get() {
+                self.client
+                    .borrow_mut()
+                    .batch_execute(&Self::chunks_table_sql(&self.config, &self.qualified_table))?;
+            }
+
+            // Object IDs share the version sequence, so they are never reused
+            let object_id: i64 = self
+                .client
+                .borrow_mut()
+                .query_one(&format!("SELECT {}", self.next_version), &[])?
+                .get(0);
+
+            // The client is only borrowed for each statement: the reader may
+            // be a `ValueReader` fetching chunks through this same store
+
+            let mut buf = vec![0; chunk_size];
+            let mut size = 0u64;
+            let mut seq = 0i32;
+            loop {
+                let n = stream::read_full(&mut reader, &mut buf)?;
+                if n == 0 {
+                    break;
+                }
+
+                size += n as u64;
+                if size > self.config.max_value_size as u64 {
+                    return Err(Error::InvalidValue {
+                        reason: format!(
+                            "value size exceeds maximum {}",
+                            self.config.max_value_size
+                        ),
+                    });
+                }
+
+                let data = &buf[..n];
+                self.client.borrow_mut().execute(
+                    &insert_chunk_sql,
+                    &[&object_id, &seq, &data, &stream::checksum(data)],
+                )?;
+                seq += 1;
+
+                if n < chunk_size {
+                    break;
+                }
+            }
+
+            let manifest = Manifest {
+                object_id,
+                size,
+                chunk_size: chunk_size as u32,
+            };
+            self.client
+                .borrow_mut()
+                .execute(&upsert_sql, &[&*self.key_param(key)?, &manifest.to_bytes()])?;
+            Ok(size)
+        })?;
+
+        // A table created inside an outer transaction is gone if it rolls back
+        if self.tx_depth.get() == 0 {
+            self.chunks_ready.set(true);
+        }
+
+        Ok(size)
+    }
+
+    /// Returns a reader over the value at `key`.
+    ///
+    /// Values written with [`Store::put_stream`] are fetched one chunk at a
+    /// time, verifying each chunk's checksum. Other values are read into
+    /// memory. Returns `None` if the key doesn't exist or has expired.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    /// use std::fs::File;
+    /// use std::io;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// if let Some(mut reader) = store.get_reader("backup.tar")? {
+    ///     io::copy(&mut reader, &mut File::create("restored.tar")?)?;
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn get_reader(&self, key: &str) -> Result<Option<ValueReader<'_>>> {
+        self.validate_key(key)?;
+
+        let sql = format!(
+            "SELECT value, encoding FROM {} t WHERE key = $1 AND NOT {}",
+            self.qualified_table,
+            self.expired_condition("t")
+        );
+        let Some(row) = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?])?
+        else {
+            return Ok(None);
+        };
+
+        let encoding: i16 = row.get("encoding");
+        if encoding & stream::ENCODING_FLAG == 0 {
+            return Ok(Some(ValueReader::memory(self.decode_row(key, &row)?)));
+        }
+        // Streamed values are never encrypted
+        self.check_encrypted(key.as_bytes(), encoding)?;
+        match Manifest::from_bytes(&Self::row_value(&row)) {
+            Some(manifest) => Ok(Some(ValueReader::chunked(self, manifest))),
+            None => Err(Error::Query(format!(
+                "invalid manifest for streamed value at key {:?}",
+                key
+            ))),
+        }
+    }
+
+    /// Fetches a chunk of a streamed value and its stored checksum.
+    pub(crate) fn fetch_chunk(&self, object_id: i64, seq: i32) -> Result<Option<(Vec<u8>, i64)>> {
+        let sql = format!(
+            "SELECT data, checksum FROM {} WHERE object_id = $1 AND seq = $2",
+            self.config.qualified_chunks_table_name()
+        );
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&object_id, &seq])?;
+        Ok(row.map(|row| (row.get("data"), row.get("checksum"))))
+    }
+
+    // ==================== Key Operations ====================
+
+    /// Renames a key, replacing `dst` if it exists.
+    ///
+    /// The value, TTL, tags and creation time move with the key. Returns
+    /// [`Error::NotFound`] if `src` doesn't exist.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// store.rename("upload:tmp:1", "upload:1")?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn rename(&self, src: &str, dst: &str) -> Result<()> {
+        match self.transfer(
+            src,
+            dst,
+            &self.qualified_table,
+            &self.next_version,
+            true,
+            true,
+        )? {
+            Some(_) => Ok(()),
+            None => Err(Error::NotFound {
+                key: src.to_string(),
+            }),
+        }
+    }
+
+    /// Renames a key only if `dst` doesn't exist.
+    ///
+    /// Returns `true` if the key was renamed, `false` if `dst` already exists.
+    /// Returns [`Error::NotFound`] if `src` doesn't exist.
+    pub fn rename_nx(&self, src: &str, dst: &str) -> Result<bool> {
+        self.transfer(
+            src,
+            dst,
+            &self.qualified_table,
+            &self.next_version,
+            false,
+            true,
+        )?
+        .ok_or_else(|| Error::NotFound {
+            key: src.to_string(),
+        })
+    }
+
+    /// Copies a key's value, TTL, tags and creation time to `dst`.
+    ///
+    /// If `dst` exists it is overwritten only when `replace` is `true`.
+    /// Returns `true` if the key was copied, `false` if `src` doesn't exist or
+    /// `dst` exists and `replace` is `false`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// store.copy("template:welcome", "user:1:welcome", false)?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn copy(&self, src: &str, dst: &str, replace: bool) -> Result<bool> {
+        Ok(self
+            .transfer(
+                src,
+                dst,
+                &self.qualified_table,
+                &self.next_version,
+                replace,
+                false,
+            )?
+            .unwrap_or(false))
+    }
+
+    /// Moves a key to another pgkv table in the same database and schema.
+    ///
+    /// The value, TTL, tags and creation time move with the key. Returns
+    /// `true` if the key was moved, `false` if it doesn't exist here or
+    /// already exists in `table`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// store.move_to("job:42", "kv_archive")?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn move_to(&self, key: &str, table: &str) -> Result<bool> {
+        if table.is_empty() || table.len() > 63 {
+            return Err(Error::Config(format!("invalid table name: {:?}", table)));
+        }
+
+        let dst_table = self.config.qualified_name(table);
+        let dst_next_version = format!(
+            "nextval({})",
+            quote_literal(
+                &self
+                    .config
+                    .qualified_name(&format!("{}_version_seq", table))
+            )
+        );
+
+        Ok(self
+            .transfer(key, key, &dst_table, &dst_next_version, false, true)?
+            .unwrap_or(false))
+    }
+
+    /// Copies `src` to `dst` in `dst_table` in one transaction, deleting `src`
+    /// afterwards if `remove_src` is set.
+    ///
+    /// Returns `None` if `src` doesn't exist, `Some(false)` if `dst` exists and
+    /// `replace` is `false`, and `Some(true)` on success.
+    fn transfer(
+        &self,
+        src: &str,
+        dst: &str,
+        dst_table: &str,
+        dst_next_version: &str,
+        replace: bool,
+        remove_src: bool,
+    ) -> Result<Option<bool>> {
+        self.validate_key(src)?;
+        self.validate_key(dst)?;
+
+        if src == dst && dst_table == self.qualified_table {
+            return Err(Error::InvalidKey {
+                reason: "source and destination keys are the same".into(),
+            });
+        }
+
+        let select_sql = format!(
+            r#"
+            SELECT value, encoding, expires_at, stale_at, tags, created_at FROM {} t
+            WHERE key = $1 AND NOT {expired}
+            FOR UPDATE
+            "#,
+            self.qualified_table,
+            expired = self.expired_condition("t"),
+        );
+
+        // Without `replace`, only an expired destination may be overwritten
+        let conflict_filter = if replace {
+            String::new()
+        } else {
+            format!("WHERE {}", self.expired_condition("t"))
+        };
+
+        let insert_sql = format!(
+            r#"
+            INSERT INTO {} AS t
+                (key, value, encoding, expires_at, stale_at, tags, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, NOW())
+            ON CONFLICT (key) DO UPDATE SET
+                value = EXCLUDED.value,
+                encoding = EXCLUDED.encoding,
+                expires_at = EXCLUDED.expires_at,
+                stale_at = EXCLUDED.stale_at,
+                tags = EXCLUDED.tags,
+                created_at = EXCLUDED.created_at,
+                version = {},
+                updated_at = NOW()
+            {}
+            "#,
+            dst_table, dst_next_version, conflict_filter
+        );
+
+        let delete_sql = format!("DELETE FROM {} WHERE key = $1", self.qualified_table);
+
+        self.atomically("BEGIN", || {
+            let mut client = self.client.borrow_mut();
+
+            let Some(row) = client.query_opt(&select_sql, &[&*self.key_param(src)?])? else {
+                return Ok(None);
+            };
+
+            let value = Self::row_value(&row);
+            let encoding: i16 = row.get("encoding");
+            let expires_at: Option<SystemTime> = row.get("expires_at");
+
+            // Chunks live in this table's companion table
+            let streamed = encoding & stream::ENCODING_FLAG != 0;
+            if dst_table != self.qualified_table && streamed {
+                return Err(Error::InvalidValue {
+                    reason: "cannot move a streamed value to another table".into(),
+                });
+            }
+
+            // Encrypted values are bound to their key and must be re-encrypted
+            let encrypted = encoding & encryption::ENCODING_FLAG != 0;
+            let (value, encoding) = if src != dst && encrypted {
+                let value = self.decode_value(src, value, encoding)?;
+                let (value, encoding) = self.encode_value(dst, &value)?;
+                (value.into_owned(), encoding)
+            } else {
+                (value, encoding)
+            };
+            let stale_at: Option<SystemTime> = row.get("stale_at");
+            let tags: Option<Vec<String>> = row.get("tags");
+            let created_at: SystemTime = row.get("created_at");
+
+            let inserted = client.execute(
+                &insert_sql,
+                &[
+                    &*self.key_param(dst)?,
+                    &ValueParam(&value),
+                    &encoding,
+                    &expires_at,
+                    &stale_at,
+                    &tags,
+                    &created_at,
+                ],
+            )?;
+            if inserted == 0 {
+                return Ok(Some(false));
+            }
+
+            if remove_src {
+                client.execute(&delete_sql, &[&*self.key_param(src)?])?;
+            }
+
+            Ok(Some(true))
+        })
+    }
+
+    // ==================== Versioned Operations ====================
+
+    /// Gets a value along with its current version.
+    ///
+    /// Versions are unique within the table and strictly increase every time
+    /// a key's value is written, including across delete and re-create, so
+    /// they can be used for optimistic concurrency without comparing values.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// if let Some((value, version)) = store.get_versioned("config")? {
+    ///     store.set_if_version("config", b"updated", version)?;
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>> {
+        Ok(self
+            .get_entry(key)?
+            .map(|entry| (entry.value, entry.version)))
+    }
+
+    /// Sets a value only if the key is currently at `expected_version`.
+    ///
+    /// Pass `0` to require that the key does not exist (or has expired). The
+    /// key's TTL is left unchanged.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Store, VersionResult};
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// match store.set_if_version("config", b"v2", 7)? {
+    ///     VersionResult::Success { version } => println!("Now at version {}", version),
+    ///     VersionResult::Mismatch { current } => println!("Changed, now at {}", current),
+    ///     VersionResult::NotFound => println!("Key was deleted"),
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn set_if_version(
+        &self,
+        key: &str,
+        value: impl AsRef<[u8]>,
+        expected_version: u64,
+    ) -> Result<VersionResult> {
+        self.validate_key(key)?;
+        let value = value.as_ref();
+        self.validate_value(value)?;
+        let (value, encoding) = self.encode_value(key, value)?;
+
+        let row = if expected_version == 0 {
+            let sql = format!(
+                r#"
+                INSERT INTO {} AS t (key, value, encoding, created_at, updated_at)
+                VALUES ($1, $2, $3, NOW(), NOW())
+                ON CONFLICT (key) DO UPDATE SET
+                    value = EXCLUDED.value,
+                    encoding = EXCLUDED.encoding,
+                    expires_at = NULL,
+                    stale_at = NULL,
+                    tags = NULL,
+                    version = {},
+                    updated_at = NOW()
+                WHERE {}
+                RETURNING version
+                "#,
+                self.qualified_table,
+                self.next_version,
+                self.expired_condition("t")
+            );
+
+            self.client.borrow_mut().query_opt(
+                &sql,
+                &[&*self.key_param(key)?, &ValueParam(&value), &encoding],
+            )?
+        } else {
+            let sql = format!(
+                r#"
+                UPDATE {} SET value = $2, encoding = $3, version = {}, updated_at = NOW()
+                WHERE key = $1 AND version = $4
+                AND (expires_at IS NULL OR expires_at > NOW())
+                RETURNING version
+                "#,
+                self.qualified_table, self.next_version
+            );
+
+            let expected = expected_version as i64;
+            self.client.borrow_mut().query_opt(
+                &sql,
+                &[
+                    &*self.key_param(key)?,
+                    &ValueParam(&value),
+                    &encoding,
+                    &expected,
+                ],
+            )?
+        };
+
+        match row {
+            Some(row) => Ok(VersionResult::Success {
+                version: row.get::<_, i64>("version") as u64,
+            }),
+            None => self.version_mismatch(key),
+        }
+    }
+
+    /// Deletes a key only if it is currently at `expected_version`.
+    ///
+    /// On success, the returned version is the one that was deleted.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// if let Some((_, version)) = store.get_versioned("lock")? {
+    ///     store.delete_if_version("lock", version)?;
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn delete_if_version(&self, key: &str, expected_version: u64) -> Result<VersionResult> {
+        self.validate_key(key)?;
+
+        let sql = format!(
+            r#"
+            DELETE FROM {} WHERE key = $1 AND version = $2
+            AND (expires_at IS NULL OR expires_at > NOW())
+            RETURNING version
+            "#,
+            self.qualified_table
+        );
+
+        let expected = expected_version as i64;
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?, &expected])?;
+
+        match row {
+            Some(row) => Ok(VersionResult::Success {
+                version: row.get::<_, i64>("version") as u64,
+            }),
+            None => self.version_mismatch(key),
+        }
+    }
+
+    /// Builds the result of a failed version check from the key's current state.
+    fn version_mismatch(&self, key: &str) -> Result<VersionResult> {
+        match self.get_versioned(key)? {
+            Some((_, current)) => Ok(VersionResult::Mismatch { current }),
+            None => Ok(VersionResult::NotFound),
+        }
+    }
+
+    // ==================== TTL Operations ====================
+
+    /// Updates the TTL of an existing key.
+    ///
+    /// Returns `false` if the key doesn't exist.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    /// use std::time::Duration;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// store.set("key", b"value")?;
+    /// store.expire("key", Duration::from_secs(60))?;  // Expires in 60 seconds
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+        self.validate_key(key)?;
+
+        let expires_at = SystemTime::now() + ttl;
+
+        let sql = format!(
+            "UPDATE {} SET expires_at = $2, updated_at = NOW() WHERE key = $1",
+            self.qualified_table
+        );
+
+        let count = self
+            .client
+            .borrow_mut()
+            .execute(&sql, &[&*self.key_param(key)?, &expires_at])?;
+        Ok(count > 0)
+    }
+
+    /// Removes the TTL from a key (makes it persistent).
+    ///
+    /// Returns `false` if the key doesn't exist.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// store.persist("key")?;  // Remove expiration
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn persist(&self, key: &str) -> Result<bool> {
+        self.validate_key(key)?;
+
+        let sql = format!(
+            "UPDATE {} SET expires_at = NULL, updated_at = NOW() WHERE key = $1",
+            self.qualified_table
+        );
+
+        let count = self
+            .client
+            .borrow_mut()
+            .execute(&sql, &[&*self.key_param(key)?])?;
+        Ok(count > 0)
+    }
+
+    /// Gets the remaining TTL of a key.
+    ///
+    /// Returns `None` if the key doesn't exist or has no expiration.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// if let Some(ttl) = store.ttl("key")? {
+    ///     println!("Key expires in {:?}", ttl);
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        self.validate_key(key)?;
+
+        let sql = format!(
+            "SELECT expires_at FROM {} WHERE key = $1",
+            self.qualified_table
+        );
+
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?])?;
+
+        match row {
+            Some(row) => {
+                let expires_at: Option<SystemTime> = row.get("expires_at");
+                Ok(expires_at.and_then(|exp| exp.duration_since(SystemTime::now()).ok()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // ==================== Scanning Operations ====================
+
+    /// Lists all keys with optional filtering.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Store, ScanOptions};
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    ///
     /// // Get all keys
     /// let keys = store.keys(ScanOptions::new())?;
     ///
     /// // Get keys with prefix
     /// let user_keys = store.keys(ScanOptions::new().prefix("user:"))?;
     ///
-    /// // Paginate
-    /// let page = store.keys(ScanOptions::new().limit(100).offset(200))?;
+    /// // Paginate
+    /// let page = store.keys(ScanOptions::new().limit(100).offset(200))?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn keys(&self, options: ScanOptions) -> Result<Vec<String>> {
+        let rows = self.scan_rows("key", &options)?;
+        rows.iter().map(|row| self.row_key(row)).collect()
+    }
+
+    /// Lists keys as bytes, for tables with [`KeyType::Bytes`] keys.
+    ///
+    /// Behaves like [`Store::keys`], but doesn't require keys to be UTF-8.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, KeyType, ScanOptions, Store};
+    ///
+    /// let config = Config::new("postgresql://localhost/mydb").key_type(KeyType::Bytes);
+    /// let store = Store::with_config(config)?;
+    ///
+    /// // Keys starting with the bytes 0x01 0x02, in byte order
+    /// let keys = store.keys_bytes(ScanOptions::new().prefix_bytes([1, 2]))?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn keys_bytes(&self, options: ScanOptions) -> Result<Vec<Vec<u8>>> {
+        let rows = self.scan_rows("key", &options)?;
+        Ok(rows.iter().map(|row| self.row_key_bytes(row)).collect())
+    }
+
+    /// Scans key-value pairs with optional filtering.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Store, ScanOptions};
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let items = store.scan(ScanOptions::new().prefix("user:").limit(100))?;
+    /// for kv in items {
+    ///     println!("{}: {:?}", kv.key, kv.value);
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn scan(&self, options: ScanOptions) -> Result<Vec<KeyValue>> {
+        let rows = self.scan_rows("key, value, encoding", &options)?;
+        rows.iter()
+            .map(|row| {
+                let key = self.row_key(row)?;
+                Ok(KeyValue {
+                    value: self.decode_row(&key, row)?,
+                    key,
+                })
+            })
+            .collect()
+    }
+
+    /// Scans key-value pairs with keys as bytes, for tables with
+    /// [`KeyType::Bytes`] keys.
+    ///
+    /// Behaves like [`Store::scan`], but doesn't require keys to be UTF-8.
+    pub fn scan_bytes(&self, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let rows = self.scan_rows("key, value, encoding", &options)?;
+        rows.iter()
+            .map(|row| {
+                let key = self.row_key_bytes(row);
+                let value = self.decode_row(&key, row)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    /// Counts keys matching the given options.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Store, ScanOptions};
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let count = store.count(ScanOptions::new().prefix("user:"))?;
+    /// println!("User keys: {}", count);
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn count(&self, options: ScanOptions) -> Result<u64> {
+        let mut sql = format!(
+            "SELECT COUNT(*) as count FROM {} WHERE 1=1",
+            self.qualified_table
+        );
+        let mut params: Vec<Box<dyn ToSql + Sync>> = vec![];
+        self.push_scan_filters(&options, &mut sql, &mut params)?;
+
+        let param_refs: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref()).collect();
+
+        let row = self.client.borrow_mut().query_one(&sql, &param_refs)?;
+        let count: i64 = row.get("count");
+        Ok(count as u64)
+    }
+
+    /// Deletes all keys matching the prefix.
+    ///
+    /// Returns the number of keys deleted.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let deleted = store.delete_prefix("temp:")?;
+    /// println!("Deleted {} temp keys", deleted);
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn keys(&self, options: ScanOptions) -> Result<Vec<String>> {
-        let mut sql = format!("SELECT key FROM {} WHERE 1=1", self.qualified_table);
+    pub fn delete_prefix(&self, prefix: &(impl AsKey + ?Sized)) -> Result<u64> {
+        let mut params: Vec<Box<dyn ToSql + Sync>> = vec![];
+        let condition = self.prefix_condition(prefix.key_bytes(), &mut params)?;
+        let sql = format!("DELETE FROM {} WHERE {}", self.qualified_table, condition);
 
-        let mut params: Vec<Box<dyn postgres::types::ToSql + Sync>> = vec![];
-        let mut param_idx = 1;
-
-        if !options.include_expired {
-            sql.push_str(" AND (expires_at IS NULL OR expires_at > NOW())");
-        }
+        let param_refs: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref()).collect();
+        let count = self.client.borrow_mut().execute(&sql, &param_refs)?;
+        Ok(count)
+    }
 
-        if let Some(ref prefix) = options.prefix {
-            sql.push_str(&format!(" AND key LIKE ${}", param_idx));
-            params.push(Box::new(format!("{}%", escape_like(prefix))));
-            param_idx += 1;
-        }
+    /// Selects `columns` from the rows matching `options`, ordered by key.
+    fn scan_rows(&self, columns: &str, options: &ScanOptions) -> Result<Vec<Row>> {
+        let mut sql = format!("SELECT {} FROM {} WHERE 1=1", columns, self.qualified_table);
+        let mut params: Vec<Box<dyn ToSql + Sync>> = vec![];
+        self.push_scan_filters(options, &mut sql, &mut params)?;
 
         sql.push_str(" ORDER BY key");
 
@@ -907,114 +3348,308 @@ impl Store {
             sql.push_str(&format!(" OFFSET {}", offset));
         }
 
-        let _ = param_idx; // Suppress unused warning
+        let param_refs: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref()).collect();
+
+        Ok(self.client.borrow_mut().query(&sql, &param_refs)?)
+    }
+
+    /// Appends the expiration and prefix filters of `options` to `sql`.
+    fn push_scan_filters(
+        &self,
+        options: &ScanOptions,
+        sql: &mut String,
+        params: &mut Vec<Box<dyn ToSql + Sync>>,
+    ) -> Result<()> {
+        if !options.include_expired {
+            sql.push_str(" AND (expires_at IS NULL OR expires_at > NOW())");
+        }
+
+        if let Some(prefix) = options.key_prefix() {
+            sql.push_str(" AND ");
+            sql.push_str(&self.prefix_condition(prefix, params)?);
+        }
 
-        let param_refs: Vec<&(dyn postgres::types::ToSql + Sync)> =
-            params.iter().map(|p| p.as_ref()).collect();
+        if let Some(ref json) = options.json_contains {
+            self.require_jsonb_values("json_contains")?;
+            params.push(Box::new(json.clone()));
+            sql.push_str(&format!(" AND value @> ${}::text::jsonb", params.len()));
+        }
 
-        let rows = self.client.borrow_mut().query(&sql, &param_refs)?;
-        Ok(rows.into_iter().map(|r| r.get("key")).collect())
+        Ok(())
     }
 
-    /// Scans key-value pairs with optional filtering.
+    /// Returns the condition selecting keys that start with `prefix`, adding
+    /// its parameters to `params`.
+    fn prefix_condition(
+        &self,
+        prefix: &[u8],
+        params: &mut Vec<Box<dyn ToSql + Sync>>,
+    ) -> Result<String> {
+        let idx = params.len() + 1;
+
+        match self.config.key_type {
+            KeyType::Text => {
+                let prefix = std::str::from_utf8(prefix).map_err(|_| Error::InvalidKey {
+                    reason: "prefix is not valid UTF-8".into(),
+                })?;
+                params.push(Box::new(format!("{}%", escape_like(prefix))));
+                Ok(format!("key LIKE ${}", idx))
+            }
+            KeyType::Bytes => {
+                // A range, so the primary key index is used
+                params.push(Box::new(prefix.to_vec()));
+                match key::prefix_end(prefix) {
+                    Some(end) => {
+                        params.push(Box::new(end));
+                        Ok(format!("key >= ${} AND key < ${}", idx, idx + 1))
+                    }
+                    None => Ok(format!("key >= ${}", idx)),
+                }
+            }
+        }
+    }
+
+    // ==================== Index Operations ====================
+
+    /// Creates a secondary index named `name` over `expr`.
+    ///
+    /// The index is a PostgreSQL expression index, so it is updated on every
+    /// write, and it is recorded in the table `{table_name}_indexes`, so
+    /// [`Store::find_by`] works from every store using this table. Creating
+    /// an index that already exists with the same expression does nothing.
+    ///
+    /// Names are lowercase letters, digits and underscores. Fails with
+    /// [`Error::Config`] if an index of the same name has a different
+    /// expression, or if values are compressed or encrypted, since the
+    /// database can't read them.
     ///
     /// # Example
     ///
     /// ```rust,no_run
-    /// use pgkv::{Store, ScanOptions};
+    /// use pgkv::{IndexExpr, Store};
     ///
     /// let store = Store::connect("postgresql://localhost/mydb")?;
-    /// let items = store.scan(ScanOptions::new().prefix("user:").limit(100))?;
-    /// for kv in items {
-    ///     println!("{}: {:?}", kv.key, kv.value);
-    /// }
+    /// store.create_index("by_user", IndexExpr::json_path(&["user_id"]))?;
+    ///
+    /// store.set("session:a", r#"{"user_id": 123}"#)?;
+    /// let sessions = store.find_by("by_user", "123")?;
+    /// assert_eq!(sessions[0].key, "session:a");
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn scan(&self, options: ScanOptions) -> Result<Vec<KeyValue>> {
-        let mut sql = format!("SELECT key, value FROM {} WHERE 1=1", self.qualified_table);
-
-        let mut params: Vec<Box<dyn postgres::types::ToSql + Sync>> = vec![];
-        let mut param_idx = 1;
-
-        if !options.include_expired {
-            sql.push_str(" AND (expires_at IS NULL OR expires_at > NOW())");
+    pub fn create_index(&self, name: &str, expr: IndexExpr) -> Result<()> {
+        let index = index::index_name(&self.config.table_name, name)?;
+        if self.encrypts() || self.config.compression != Compression::None {
+            return Err(Error::Config(
+                "secondary indexes are not supported with compression or encryption".into(),
+            ));
         }
 
-        if let Some(ref prefix) = options.prefix {
-            sql.push_str(&format!(" AND key LIKE ${}", param_idx));
-            params.push(Box::new(format!("{}%", escape_like(prefix))));
-            param_idx += 1;
-        }
+        let function = self.config.qualified_name("pgkv_try_jsonb");
+        let expression = expr.to_sql(self.config.value_type, &function)?;
+        let indexes = self.config.qualified_indexes_table_name();
 
-        sql.push_str(" ORDER BY key");
+        self.atomically("BEGIN", || {
+            let mut client = self.client.borrow_mut();
+            client.batch_execute(&format!(
+                r#"
+                SELECT pg_advisory_xact_lock(hashtext('pgkv_create_index'));
 
-        if let Some(limit) = options.limit {
-            sql.push_str(&format!(" LIMIT {}", limit));
-        }
+                CREATE {} TABLE IF NOT EXISTS {} (
+                    name TEXT PRIMARY KEY,
+                    expression TEXT NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                );
+                "#,
+                self.config.table_type.sql_keyword(),
+                indexes
+            ))?;
+
+            let existing = client.query_opt(
+                &format!("SELECT expression FROM {} WHERE name = $1", indexes),
+                &[&name],
+            )?;
+            match existing {
+                Some(row) if row.get::<_, String>(0) != expression => {
+                    return Err(Error::Config(format!(
+                        "index {:?} already exists with expression {}",
+                        name,
+                        row.get::<_, String>(0)
+                    )));
+                }
+                Some(_) => {}
+                None => {
+                    client.execute(
+                        &format!("INSERT INTO {} (name, expression) VALUES ($1, $2)", indexes),
+                        &[&name, &expression],
+                    )?;
+                }
+            }
 
-        if let Some(offset) = options.offset {
-            sql.push_str(&format!(" OFFSET {}", offset));
+            if matches!(expr, IndexExpr::JsonPath(_)) && self.config.value_type == ValueType::Bytes
+            {
+                client.batch_execute(&index::try_jsonb_function_sql(&function))?;
+            }
+            client.batch_execute(&format!(
+                "CREATE INDEX IF NOT EXISTS \"{}\" ON {} ({})",
+                index, self.qualified_table, expression
+            ))?;
+            Ok(())
+        })
+    }
+
+    /// Drops the secondary index named `name`.
+    ///
+    /// Returns `true` if the index existed.
+    pub fn drop_index(&self, name: &str) -> Result<bool> {
+        let index = index::index_name(&self.config.table_name, name)?;
+        if !self.has_indexes_table()? {
+            return Ok(false);
         }
 
-        let _ = param_idx; // Suppress unused warning
+        self.atomically("BEGIN", || {
+            let mut client = self.client.borrow_mut();
+            let deleted = client.execute(
+                &format!(
+                    "DELETE FROM {} WHERE name = $1",
+                    self.config.qualified_indexes_table_name()
+                ),
+                &[&name],
+            )?;
+            if deleted > 0 {
+                client.batch_execute(&format!(
+                    "DROP INDEX IF EXISTS {}",
+                    self.config.qualified_name(&index)
+                ))?;
+            }
+            Ok(deleted > 0)
+        })
+    }
 
-        let param_refs: Vec<&(dyn postgres::types::ToSql + Sync)> =
-            params.iter().map(|p| p.as_ref()).collect();
+    /// Lists the secondary indexes on this table, ordered by name.
+    pub fn indexes(&self) -> Result<Vec<IndexInfo>> {
+        if !self.has_indexes_table()? {
+            return Ok(Vec::new());
+        }
 
-        let rows = self.client.borrow_mut().query(&sql, &param_refs)?;
+        let sql = format!(
+            "SELECT name, expression FROM {} ORDER BY name",
+            self.config.qualified_indexes_table_name()
+        );
+        let rows = self.client.borrow_mut().query(&sql, &[])?;
         Ok(rows
-            .into_iter()
-            .map(|r| KeyValue {
-                key: r.get("key"),
-                value: r.get("value"),
+            .iter()
+            .map(|row| IndexInfo {
+                name: row.get("name"),
+                expression: row.get("expression"),
             })
             .collect())
     }
 
-    /// Counts keys matching the given options.
+    /// Finds the entries whose indexed expression equals `value`, ordered by key.
+    ///
+    /// Expressions are compared as text, so JSON strings are matched without
+    /// their quotes and numbers as written (`"123"`). Fails with
+    /// [`Error::Config`] if no index named `index` exists.
     ///
     /// # Example
     ///
     /// ```rust,no_run
-    /// use pgkv::{Store, ScanOptions};
+    /// use pgkv::Store;
     ///
     /// let store = Store::connect("postgresql://localhost/mydb")?;
-    /// let count = store.count(ScanOptions::new().prefix("user:"))?;
-    /// println!("User keys: {}", count);
+    /// for kv in store.find_by("by_user", "123")? {
+    ///     println!("{}: {:?}", kv.key, kv.value_str());
+    /// }
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn count(&self, options: ScanOptions) -> Result<u64> {
-        let mut sql = format!(
-            "SELECT COUNT(*) as count FROM {} WHERE 1=1",
-            self.qualified_table
-        );
+    pub fn find_by(&self, index: &str, value: &str) -> Result<Vec<KeyValue>> {
+        let expression = self
+            .index_expression(index)?
+            .ok_or_else(|| Error::Config(format!("index {:?} does not exist", index)))?;
 
-        let mut params: Vec<Box<dyn postgres::types::ToSql + Sync>> = vec![];
-        let mut param_idx = 1;
+        let sql = format!(
+            "SELECT key, value, encoding FROM {} t WHERE {} = $1 AND NOT {} ORDER BY key",
+            self.qualified_table,
+            expression,
+            self.expired_condition("t")
+        );
+        let rows = self.client.borrow_mut().query(&sql, &[&value])?;
+        rows.iter()
+            .map(|row| {
+                let key = self.row_key(row)?;
+                Ok(KeyValue {
+                    value: self.decode_row(&key, row)?,
+                    key,
+                })
+            })
+            .collect()
+    }
 
-        if !options.include_expired {
-            sql.push_str(" AND (expires_at IS NULL OR expires_at > NOW())");
+    /// Returns the expression of the secondary index `name`, if it exists.
+    fn index_expression(&self, name: &str) -> Result<Option<String>> {
+        if !self.has_indexes_table()? {
+            return Ok(None);
         }
 
-        if let Some(ref prefix) = options.prefix {
-            sql.push_str(&format!(" AND key LIKE ${}", param_idx));
-            params.push(Box::new(format!("{}%", escape_like(prefix))));
-            param_idx += 1;
-        }
+        let sql = format!(
+            "SELECT expression FROM {} WHERE name = $1",
+            self.config.qualified_indexes_table_name()
+        );
+        let row = self.client.borrow_mut().query_opt(&sql, &[&name])?;
+        Ok(row.map(|row| row.get(0)))
+    }
 
-        let _ = param_idx; // Suppress unused warning
+    /// Returns whether any store has created a secondary index on this table.
+    fn has_indexes_table(&self) -> Result<bool> {
+        let indexes = self.config.qualified_indexes_table_name();
+        let row = self
+            .client
+            .borrow_mut()
+            .query_one("SELECT to_regclass($1) IS NOT NULL", &[&indexes])?;
+        Ok(row.get(0))
+    }
 
-        let param_refs: Vec<&(dyn postgres::types::ToSql + Sync)> =
-            params.iter().map(|p| p.as_ref()).collect();
+    // ==================== Tag Operations ====================
 
-        let row = self.client.borrow_mut().query_one(&sql, &param_refs)?;
-        let count: i64 = row.get("count");
-        Ok(count as u64)
+    /// Deletes all keys tagged with `tag`.
+    ///
+    /// Returns the number of keys deleted.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let deleted = store.invalidate_tag("product:42")?;
+    /// println!("Invalidated {} pages", deleted);
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn invalidate_tag(&self, tag: &str) -> Result<u64> {
+        self.invalidate_tags(&[tag])
     }
 
-    /// Deletes all keys matching the prefix.
+    /// Deletes all keys tagged with any of `tags`.
     ///
     /// Returns the number of keys deleted.
+    pub fn invalidate_tags(&self, tags: &[&str]) -> Result<u64> {
+        if tags.is_empty() {
+            return Ok(0);
+        }
+
+        for tag in tags {
+            validate_tag(tag)?;
+        }
+
+        let sql = format!(
+            "DELETE FROM {} WHERE tags && $1::text[]",
+            self.qualified_table
+        );
+        let count = self.client.borrow_mut().execute(&sql, &[&tags])?;
+        Ok(count)
+    }
+
+    /// Lists all keys tagged with `tag` that haven't expired.
     ///
     /// # Example
     ///
@@ -1022,18 +3657,160 @@ impl Store {
     /// use pgkv::Store;
     ///
     /// let store = Store::connect("postgresql://localhost/mydb")?;
-    /// let deleted = store.delete_prefix("temp:")?;
-    /// println!("Deleted {} temp keys", deleted);
+    /// let pages = store.keys_by_tag("category:7")?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn keys_by_tag(&self, tag: &str) -> Result<Vec<String>> {
+        validate_tag(tag)?;
+
+        let sql = format!(
+            r#"
+            SELECT key FROM {}
+            WHERE tags @> ARRAY[$1::text]
+            AND (expires_at IS NULL OR expires_at > NOW())
+            ORDER BY key
+            "#,
+            self.qualified_table
+        );
+
+        let rows = self.client.borrow_mut().query(&sql, &[&tag])?;
+        rows.iter().map(|row| self.row_key(row)).collect()
+    }
+
+    // ==================== History Operations ====================
+
+    /// Returns the most recent changes to a key, newest first.
+    ///
+    /// Requires [`Config::history`] to be enabled.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, Store};
+    ///
+    /// let store = Store::with_config(Config::new("postgresql://localhost/mydb").history(true))?;
+    /// for change in store.history("flags:checkout", 10)? {
+    ///     println!("{:?} by {} at {:?}", change.op, change.changed_by, change.changed_at);
+    /// }
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn history(&self, key: &str, limit: usize) -> Result<Vec<HistoryEntry>> {
+        self.validate_key(key)?;
+        let history = self.history_table()?;
+
+        let sql = format!(
+            r#"
+            SELECT key, op, value, encoding, version, expires_at, changed_at, changed_by
+            FROM {} WHERE key = $1
+            ORDER BY changed_at DESC, id DESC
+            LIMIT $2
+            "#,
+            history
+        );
+
+        let limit = limit as i64;
+        let rows = self
+            .client
+            .borrow_mut()
+            .query(&sql, &[&*self.key_param(key)?, &limit])?;
+        rows.iter()
+            .map(|row| self.row_to_history_entry(row))
+            .collect()
+    }
+
+    /// Gets the value a key had at a point in time.
+    ///
+    /// Returns `None` if the key didn't exist, was deleted, or had expired at
+    /// that time. Requires [`Config::history`] to be enabled, and only changes
+    /// recorded since then (and not yet pruned) are visible.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, Store};
+    /// use std::time::{Duration, SystemTime};
+    ///
+    /// let store = Store::with_config(Config::new("postgresql://localhost/mydb").history(true))?;
+    /// let last_week = SystemTime::now() - Duration::from_secs(7 * 24 * 3600);
+    /// let value = store.get_as_of("flags:checkout", last_week)?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn get_as_of(&self, key: &str, at: SystemTime) -> Result<Option<Vec<u8>>> {
+        self.validate_key(key)?;
+        let history = self.history_table()?;
+
+        let sql = format!(
+            r#"
+            SELECT key, op, value, encoding, version, expires_at, changed_at, changed_by
+            FROM {} WHERE key = $1 AND changed_at <= $2
+            ORDER BY changed_at DESC, id DESC
+            LIMIT 1
+            "#,
+            history
+        );
+
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?, &at])?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let entry = self.row_to_history_entry(&row)?;
+        if entry.op == HistoryOp::Delete {
+            return Ok(None);
+        }
+
+        if self.config.ttl_enabled() && entry.expires_at.is_some_and(|exp| exp <= at) {
+            return Ok(None);
+        }
+
+        Ok(Some(entry.value))
+    }
+
+    /// Deletes history records older than [`Config::history_retention`].
+    ///
+    /// Returns the number of records deleted. Does nothing if no retention is
+    /// configured. Like [`Store::cleanup_expired`], call this on your own schedule.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{Config, Store};
+    /// use std::time::Duration;
+    ///
+    /// let config = Config::new("postgresql://localhost/mydb")
+    ///     .history(true)
+    ///     .history_retention(Duration::from_secs(90 * 24 * 3600));
+    /// let store = Store::with_config(config)?;
+    /// let pruned = store.prune_history()?;
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn delete_prefix(&self, prefix: &str) -> Result<u64> {
-        let sql = format!("DELETE FROM {} WHERE key LIKE $1", self.qualified_table);
+    pub fn prune_history(&self) -> Result<u64> {
+        let history = self.history_table()?;
+
+        let Some(retention) = self.config.history_retention else {
+            return Ok(0);
+        };
 
-        let pattern = format!("{}%", escape_like(prefix));
-        let count = self.client.borrow_mut().execute(&sql, &[&pattern])?;
+        let cutoff = SystemTime::now() - retention;
+        let sql = format!("DELETE FROM {} WHERE changed_at < $1", history);
+        let count = self.client.borrow_mut().execute(&sql, &[&cutoff])?;
         Ok(count)
     }
 
+    /// Returns the qualified history table name, or an error if history is disabled.
+    fn history_table(&self) -> Result<String> {
+        if !self.config.history {
+            return Err(Error::Config(
+                "history is not enabled for this store".into(),
+            ));
+        }
+
+        Ok(self.config.qualified_history_table_name())
+    }
+
     // ==================== Maintenance Operations ====================
 
     /// Deletes all expired keys.
@@ -1051,13 +3828,14 @@ impl Store {
     /// # Ok::<(), pgkv::Error>(())
     /// ```
     pub fn cleanup_expired(&self) -> Result<u64> {
-        let sql = format!(
-            "DELETE FROM {} WHERE expires_at IS NOT NULL AND expires_at < NOW()",
-            self.qualified_table
-        );
-
-        let count = self.client.borrow_mut().execute(&sql, &[])?;
-        Ok(count)
+        let counts = self.for_each_partition(|client, partition| {
+            let sql = format!(
+                "DELETE FROM {} WHERE expires_at IS NOT NULL AND expires_at < NOW()",
+                partition
+            );
+            Ok(client.execute(&sql, &[])?)
+        })?;
+        Ok(counts.iter().sum())
     }
 
     /// Deletes all keys.
@@ -1081,84 +3859,284 @@ impl Store {
         Ok(count)
     }
 
-    /// Truncates the table (faster than clear for large datasets).
-    ///
-    /// **Warning**: This will delete all data!
-    pub fn truncate(&self) -> Result<()> {
-        let sql = format!("TRUNCATE {}", self.qualified_table);
-        self.client.borrow_mut().execute(&sql, &[])?;
-        Ok(())
+    /// Truncates the table (faster than clear for large datasets).
+    ///
+    /// The partitions of a partitioned table are truncated one at a time, in
+    /// parallel if [`Config::maintenance_workers`] allows, so that the whole
+    /// table is never locked while data is being removed. The empty table is
+    /// then truncated once more as a whole, which notifies any
+    /// [`CachedStore`](crate::CachedStore) and removes streamed value chunks.
+    ///
+    /// **Warning**: This will delete all data!
+    pub fn truncate(&self) -> Result<()> {
+        if self.config.partitions.is_some() {
+            self.for_each_partition(|client, partition| {
+                client.batch_execute(&format!("TRUNCATE {}", partition))?;
+                Ok(())
+            })?;
+        }
+
+        let mut client = self.client.borrow_mut();
+        let chunks = self.config.qualified_chunks_table_name();
+
+        // TRUNCATE doesn't fire the triggers that delete streamed value chunks
+        let row = client.query_one("SELECT to_regclass($1) IS NOT NULL", &[&chunks])?;
+        let sql = if row.get(0) {
+            format!("TRUNCATE {}, {}", self.qualified_table, chunks)
+        } else {
+            format!("TRUNCATE {}", self.qualified_table)
+        };
+        client.execute(&sql, &[])?;
+        Ok(())
+    }
+
+    /// Gets statistics about the store.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::Store;
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let stats = store.stats()?;
+    /// println!("Total keys: {}", stats.total_keys);
+    /// println!("Table size: {} bytes", stats.table_size_bytes);
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn stats(&self) -> Result<Stats> {
+        let value = self.value_bytes_sql("value");
+        let compressed = compression::compressed_sql("encoding");
+        let len = compression::uncompressed_len_sql("value", "encoding");
+
+        let partitions = self.for_each_partition(|client, partition| {
+            let sql = format!(
+                r#"
+                SELECT
+                    COUNT(*) as total_keys,
+                    COUNT(*) FILTER (WHERE expires_at IS NOT NULL AND expires_at < NOW()) as expired_keys,
+                    COALESCE(SUM(LENGTH(value)), 0)::bigint as total_value_bytes,
+                    COALESCE(MAX(LENGTH(value)), 0)::integer as max_value_bytes,
+                    COUNT(*) FILTER (WHERE {compressed}) as compressed_keys,
+                    COALESCE(SUM({len}), 0)::bigint as uncompressed_value_bytes,
+                    pg_total_relation_size({table}) as table_size,
+                    pg_indexes_size({table}) as index_size
+                FROM (SELECT expires_at, encoding, {value} AS value FROM {partition}) t
+                "#,
+                table = quote_literal(partition),
+            );
+            let row = client.query_one(&sql, &[])?;
+
+            Ok(Stats {
+                total_keys: row.get::<_, i64>("total_keys") as u64,
+                expired_keys: row.get::<_, i64>("expired_keys") as u64,
+                total_value_bytes: row.get::<_, i64>("total_value_bytes") as u64,
+                avg_value_bytes: 0.0,
+                max_value_bytes: row.get::<_, i32>("max_value_bytes") as u64,
+                compressed_keys: row.get::<_, i64>("compressed_keys") as u64,
+                uncompressed_value_bytes: row.get::<_, i64>("uncompressed_value_bytes") as u64,
+                table_size_bytes: row.get::<_, i64>("table_size") as u64,
+                index_size_bytes: row.get::<_, i64>("index_size") as u64,
+            })
+        })?;
+
+        let mut stats = Stats::default();
+        for partition in partitions {
+            stats.total_keys += partition.total_keys;
+            stats.expired_keys += partition.expired_keys;
+            stats.total_value_bytes += partition.total_value_bytes;
+            stats.max_value_bytes = stats.max_value_bytes.max(partition.max_value_bytes);
+            stats.compressed_keys += partition.compressed_keys;
+            stats.uncompressed_value_bytes += partition.uncompressed_value_bytes;
+            stats.table_size_bytes += partition.table_size_bytes;
+            stats.index_size_bytes += partition.index_size_bytes;
+        }
+        if stats.total_keys > 0 {
+            stats.avg_value_bytes = stats.total_value_bytes as f64 / stats.total_keys as f64;
+        }
+        Ok(stats)
+    }
+
+    /// Runs VACUUM on the table.
+    ///
+    /// This reclaims storage space after deletes. A partitioned table is
+    /// vacuumed a partition at a time, on up to
+    /// [`Config::maintenance_workers`] connections at once.
+    pub fn vacuum(&self) -> Result<()> {
+        self.for_each_partition(|client, partition| {
+            client.batch_execute(&format!("VACUUM {}", partition))?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Runs ANALYZE on the table.
+    ///
+    /// This updates statistics for the query planner. Like
+    /// [`Store::vacuum`], it runs a partition at a time.
+    pub fn analyze(&self) -> Result<()> {
+        self.for_each_partition(|client, partition| {
+            client.batch_execute(&format!("ANALYZE {}", partition))?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Runs `f` on each partition of the table, or on the table itself if it
+    /// isn't partitioned, and returns the results in partition order.
+    ///
+    /// Partitions are processed on up to [`Config::maintenance_workers`]
+    /// extra connections at once, except inside a transaction, where the
+    /// store's own connection is used.
+    fn for_each_partition<T: Send>(
+        &self,
+        f: impl Fn(&mut Client, &str) -> Result<T> + Sync,
+    ) -> Result<Vec<T>> {
+        let partitions = migrate::partitions(&mut *self.client.borrow_mut(), &self.config)?;
+        if partitions.is_empty() {
+            return Err(Error::TableNotFound {
+                table: self.qualified_table.clone(),
+            });
+        }
+        let workers = self.config.maintenance_workers.min(partitions.len());
+        if workers <= 1 || self.tx_depth.get() > 0 {
+            let mut client = self.client.borrow_mut();
+            return partitions.iter().map(|p| f(&mut client, p)).collect();
+        }
+
+        let config = &self.config;
+        let next = AtomicUsize::new(0);
+        let (partitions, next, f) = (&partitions, &next, &f);
+        let results = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(move || -> Result<Vec<(usize, T)>> {
+                        let mut client = Self::connect_client(config)?;
+                        let mut results = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(partition) = partitions.get(i) else {
+                                break;
+                            };
+                            results.push((i, f(&mut client, partition)?));
+                        }
+                        Ok(results)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|e| std::panic::resume_unwind(e))
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut results: Vec<(usize, T)> = results.into_iter().flatten().collect();
+        results.sort_by_key(|(i, _)| *i);
+        Ok(results.into_iter().map(|(_, result)| result).collect())
     }
 
-    /// Gets statistics about the store.
+    /// Re-encrypts every value that was encrypted with a key other than the
+    /// key provider's current key.
+    ///
+    /// Run this after rotating keys. Values are processed in batches, each in
+    /// its own transaction, so other connections are only blocked briefly.
+    /// Unencrypted values are left as they are unless
+    /// [`Config::reject_unencrypted`] is enabled, in which case values other
+    /// than streamed ones are encrypted too. The history table is left as it
+    /// is, so retired keys are still needed to read history written before
+    /// the rotation. Returns the number of values re-encrypted.
     ///
     /// # Example
     ///
     /// ```rust,no_run
-    /// use pgkv::Store;
+    /// use pgkv::{Config, Keyring, Store};
     ///
-    /// let store = Store::connect("postgresql://localhost/mydb")?;
-    /// let stats = store.stats()?;
-    /// println!("Total keys: {}", stats.total_keys);
-    /// println!("Table size: {} bytes", stats.table_size_bytes);
+    /// # let (old_key, new_key) = ([1u8; 32], [2u8; 32]);
+    /// let config = Config::new("postgresql://localhost/mydb")
+    ///     .encryption(Keyring::new(2, new_key).retired_key(1, old_key));
+    /// let store = Store::with_config(config)?;
+    ///
+    /// let count = store.reencrypt_all()?;
+    /// println!("Re-encrypted {} values with key 2", count);
     /// # Ok::<(), pgkv::Error>(())
     /// ```
-    pub fn stats(&self) -> Result<Stats> {
-        let sql = format!(
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encryption")))]
+    pub fn reencrypt_all(&self) -> Result<u64> {
+        let Some(ref encryptor) = self.config.encryption else {
+            return Err(Error::Config(
+                "reencrypt_all requires Config::encryption".into(),
+            ));
+        };
+
+        let current = encryptor.current_key_id().to_le_bytes().to_vec();
+        let select_sql = format!(
             r#"
-            SELECT
-                COUNT(*) as total_keys,
-                COUNT(*) FILTER (WHERE expires_at IS NOT NULL AND expires_at < NOW()) as expired_keys,
-                COALESCE(SUM(LENGTH(value)), 0)::bigint as total_value_bytes,
-                COALESCE(AVG(LENGTH(value)), 0)::float8 as avg_value_bytes,
-                COALESCE(MAX(LENGTH(value)), 0)::integer as max_value_bytes
-            FROM {}
+            SELECT key, value, encoding FROM {}
+            WHERE key > $1 AND (
+                ({} AND substring(value FROM 3 FOR 4) <> $2)
+                OR ($3 AND encoding & {} = 0)
+            )
+            ORDER BY key
+            LIMIT {}
+            FOR UPDATE
             "#,
-            self.qualified_table
+            self.qualified_table,
+            encryption::encrypted_sql("encoding"),
+            encryption::ENCODING_FLAG | stream::ENCODING_FLAG,
+            REENCRYPT_BATCH_SIZE
         );
-
-        let mut client = self.client.borrow_mut();
-        let row = client.query_one(&sql, &[])?;
-
-        // Get table size
-        let size_sql = format!(
-            r#"
-            SELECT
-                pg_total_relation_size('{}') as table_size,
-                pg_indexes_size('{}') as index_size
-            "#,
-            self.qualified_table, self.qualified_table
+        // Keeps the version: the stored value changes but the logical one doesn't
+        let update_sql = format!(
+            "UPDATE {} SET value = $2, encoding = $3 WHERE key = $1",
+            self.qualified_table
         );
 
-        let size_row = client.query_one(&size_sql, &[])?;
-
-        Ok(Stats {
-            total_keys: row.get::<_, i64>("total_keys") as u64,
-            expired_keys: row.get::<_, i64>("expired_keys") as u64,
-            total_value_bytes: row.get::<_, i64>("total_value_bytes") as u64,
-            avg_value_bytes: row.get::<_, f64>("avg_value_bytes"),
-            max_value_bytes: row.get::<_, i32>("max_value_bytes") as u64,
-            table_size_bytes: size_row.get::<_, i64>("table_size") as u64,
-            index_size_bytes: size_row.get::<_, i64>("index_size") as u64,
-        })
-    }
+        let mut total = 0;
+        let mut after = Vec::new();
+        loop {
+            let last = self.atomically("BEGIN", || {
+                let rows = self.client.borrow_mut().query(
+                    &select_sql,
+                    &[
+                        &*self.key_param(&after)?,
+                        &current,
+                        &self.config.reject_unencrypted,
+                    ],
+                )?;
+
+                for row in &rows {
+                    let key = self.row_key_bytes(row);
+                    let encoding: i16 = row.get("encoding");
+                    // Compressed values stay compressed under the encryption
+                    let value = if encoding & encryption::ENCODING_FLAG != 0 {
+                        encryptor.decrypt(&key, &Self::row_value(row))?
+                    } else {
+                        Self::row_value(row)
+                    };
+                    let value = encryptor.encrypt(&key, &value)?;
+                    self.client.borrow_mut().execute(
+                        &update_sql,
+                        &[
+                            &*self.key_param(&key)?,
+                            &ValueParam(&value),
+                            &(encoding | encryption::ENCODING_FLAG),
+                        ],
+                    )?;
+                }
 
-    /// Runs VACUUM on the table.
-    ///
-    /// This reclaims storage space after deletes.
-    pub fn vacuum(&self) -> Result<()> {
-        let sql = format!("VACUUM {}", self.qualified_table);
-        self.client.borrow_mut().execute(&sql, &[])?;
-        Ok(())
-    }
+                total += rows.len() as u64;
+                Ok(rows.last().map(|row| self.row_key_bytes(row)))
+            })?;
 
-    /// Runs ANALYZE on the table.
-    ///
-    /// This updates statistics for the query planner.
-    pub fn analyze(&self) -> Result<()> {
-        let sql = format!("ANALYZE {}", self.qualified_table);
-        self.client.borrow_mut().execute(&sql, &[])?;
-        Ok(())
+            match last {
+                Some(key) => after = key,
+                None => return Ok(total),
+            }
+        }
     }
 
     // ==================== Transaction Support ====================
@@ -1184,23 +4162,172 @@ impl Store {
     where
         F: FnOnce(&Self) -> Result<T>,
     {
-        self.client.borrow_mut().execute("BEGIN", &[])?;
+        self.atomically("BEGIN", || f(self))
+    }
+
+    /// Executes a function within a transaction with the given options.
+    ///
+    /// The function receives a [`Tx`] handle, which exposes the full store API
+    /// plus [`Tx::savepoint`] and [`Tx::get_for_update`]. The transaction is
+    /// committed if the function returns `Ok`, rolled back otherwise. On a
+    /// serialization failure or deadlock ([`Error::Conflict`]) the whole
+    /// function is run again, up to `options.max_retries` times, so it should
+    /// not have side effects outside the database.
+    ///
+    /// When called inside another transaction, this runs in a savepoint: the
+    /// options are ignored and conflicts are returned to the outer transaction.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use pgkv::{IsolationLevel, Store, TxOptions};
+    ///
+    /// let store = Store::connect("postgresql://localhost/mydb")?;
+    /// let options = TxOptions::new().isolation(IsolationLevel::Serializable);
+    ///
+    /// store.transaction_with(options, |tx| {
+    ///     let balance = tx.get_for_update("balance")?.unwrap_or_default();
+    ///     let balance: i64 = String::from_utf8_lossy(&balance).parse().unwrap_or(0);
+    ///     tx.set("balance", (balance - 10).to_string())?;
+    ///     Ok(())
+    /// })?;
+    /// # Ok::<(), pgkv::Error>(())
+    /// ```
+    pub fn transaction_with<F, T>(&self, options: TxOptions, mut f: F) -> Result<T>
+    where
+        F: FnMut(&Tx<'_>) -> Result<T>,
+    {
+        let begin = options.begin_sql();
+        let mut retries = 0;
+        loop {
+            let nested = self.tx_depth.get() > 0;
+            match self.atomically(&begin, || f(&Tx::new(self))) {
+                Err(e) if e.is_conflict() && !nested && retries < options.max_retries => {
+                    retries += 1
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Gets a value and locks the key's row until the current transaction ends.
+    ///
+    /// Outside a transaction the lock is released immediately.
+    pub(crate) fn get_for_update(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.validate_key(key)?;
+
+        let sql = format!(
+            r#"
+            SELECT value, encoding, {expired} AS expired FROM {} t
+            WHERE key = $1
+            FOR UPDATE
+            "#,
+            self.qualified_table,
+            expired = self.expired_condition("t"),
+        );
+
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?])?;
+
+        // An expired row is still locked, so the key can't be written concurrently
+        row.filter(|row| !row.get::<_, bool>("expired"))
+            .map(|row| self.decode_row(key, &row))
+            .transpose()
+    }
+
+    /// Runs `f` in a new transaction started with `begin`, or in a savepoint
+    /// if a transaction is already open.
+    ///
+    /// Commits (or releases the savepoint) if `f` returns `Ok`, rolls back otherwise.
+    pub(crate) fn atomically<T>(&self, begin: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let depth = self.tx_depth.get();
+
+        if depth == 0 {
+            self.client.borrow_mut().batch_execute(begin)?;
+        } else {
+            self.client
+                .borrow_mut()
+                .batch_execute(&format!("SAVEPOINT {}", savepoint_name(depth)))?;
+        }
 
-        match f(self) {
-            Ok(result) => {
-                self.client.borrow_mut().execute("COMMIT", &[])?;
-                Ok(result)
+        let result = {
+            let _guard = TxDepthGuard { store: self, depth };
+            self.tx_depth.set(depth + 1);
+            f()
+        };
+
+        let end = end_transaction_sql(depth, result.is_ok());
+        match result {
+            Ok(value) => {
+                self.client.borrow_mut().batch_execute(&end)?;
+                Ok(value)
             }
             Err(e) => {
-                let _ = self.client.borrow_mut().execute("ROLLBACK", &[]);
+                let _ = self.client.borrow_mut().batch_execute(&end);
                 Err(e)
             }
         }
     }
 
+    // ==================== Change Notifications ====================
+
+    /// Installs triggers that publish every change to the table on `channel`.
+    ///
+    /// Row changes send the affected key as the payload (binary keys in
+    /// PostgreSQL's `\x` hex format); `TRUNCATE` sends an empty payload.
+    /// Installation is idempotent and safe to run concurrently.
+    pub(crate) fn create_notify_trigger(&self, channel: &str) -> Result<()> {
+        let function = self.config.qualified_name("pgkv_notify_change");
+        let table = &self.qualified_table;
+        let channel = quote_literal(channel);
+        let table_literal = quote_literal(table);
+
+        let sql = format!(
+            r#"
+            SELECT pg_advisory_xact_lock(hashtext('pgkv_notify_change'));
+
+            CREATE OR REPLACE FUNCTION {function}() RETURNS trigger AS $$
+            BEGIN
+                IF TG_OP = 'TRUNCATE' THEN
+                    PERFORM pg_notify(TG_ARGV[0], '');
+                ELSIF TG_OP = 'DELETE' THEN
+                    PERFORM pg_notify(TG_ARGV[0], OLD.key::text);
+                ELSE
+                    PERFORM pg_notify(TG_ARGV[0], NEW.key::text);
+                END IF;
+                RETURN NULL;
+            END;
+            $$ LANGUAGE plpgsql;
+
+            DO $$
+            BEGIN
+                IF NOT EXISTS (
+                    SELECT 1 FROM pg_trigger
+                    WHERE tgname = 'pgkv_notify' AND tgrelid = {table_literal}::regclass
+                ) THEN
+                    CREATE TRIGGER pgkv_notify
+                        AFTER INSERT OR UPDATE OR DELETE ON {table}
+                        FOR EACH ROW EXECUTE FUNCTION {function}({channel});
+                    CREATE TRIGGER pgkv_notify_truncate
+                        AFTER TRUNCATE ON {table}
+                        FOR EACH STATEMENT EXECUTE FUNCTION {function}({channel});
+                END IF;
+            END
+            $$;
+            "#,
+        );
+
+        self.atomically("BEGIN", || {
+            Ok(self.client.borrow_mut().batch_execute(&sql)?)
+        })
+    }
+
     // ==================== Helper Methods ====================
 
-    fn validate_key(&self, key: &str) -> Result<()> {
+    fn validate_key(&self, key: &(impl AsKey + ?Sized)) -> Result<()> {
+        let key = key.key_bytes();
         if key.is_empty() {
             return Err(Error::InvalidKey {
                 reason: "key cannot be empty".into(),
@@ -1217,9 +4344,88 @@ impl Store {
             });
         }
 
+        if self.config.key_type == KeyType::Text && std::str::from_utf8(key).is_err() {
+            return Err(Error::InvalidKey {
+                reason: "key is not valid UTF-8; binary keys require KeyType::Bytes".into(),
+            });
+        }
+
         Ok(())
     }
 
+    /// Returns `key` as a query parameter of the key column's type.
+    ///
+    /// Fails if a binary key isn't valid UTF-8 and keys are stored as text.
+    fn key_param<'k>(&self, key: &'k (impl AsKey + ?Sized)) -> Result<Box<dyn ToSql + Sync + 'k>> {
+        let key = key.key_bytes();
+        match self.config.key_type {
+            KeyType::Text => match std::str::from_utf8(key) {
+                Ok(key) => Ok(Box::new(key)),
+                Err(_) => Err(Error::InvalidKey {
+                    reason: "key is not valid UTF-8; binary keys require KeyType::Bytes".into(),
+                }),
+            },
+            KeyType::Bytes => Ok(Box::new(key)),
+        }
+    }
+
+    /// Returns `keys` as an array query parameter of the key column's type.
+    fn keys_param<'k>(&self, keys: &[&'k str]) -> Box<dyn ToSql + Sync + 'k> {
+        match self.config.key_type {
+            KeyType::Text => Box::new(keys.to_vec()),
+            KeyType::Bytes => Box::new(keys.iter().map(|k| k.as_bytes()).collect::<Vec<_>>()),
+        }
+    }
+
+    /// Returns `key` as an SQL literal of the key column's type.
+    fn key_literal(&self, key: &str) -> String {
+        match self.config.key_type {
+            KeyType::Text => text_literal(key),
+            KeyType::Bytes => bytes_literal(key.as_bytes()),
+        }
+    }
+
+    /// Returns an encoded value as an SQL literal of the value column's type.
+    fn value_literal(&self, value: &[u8]) -> String {
+        match self.config.value_type {
+            ValueType::Bytes => bytes_literal(value),
+            ValueType::Jsonb => format!("convert_from({}, 'UTF8')::jsonb", bytes_literal(value)),
+        }
+    }
+
+    /// Reads the key column of `row` as bytes.
+    fn row_key_bytes(&self, row: &Row) -> Vec<u8> {
+        match self.config.key_type {
+            KeyType::Text => row.get::<_, String>("key").into_bytes(),
+            KeyType::Bytes => row.get("key"),
+        }
+    }
+
+    /// Reads the stored (still encoded) value of a row.
+    fn row_value(row: &Row) -> Vec<u8> {
+        row.get::<_, StoredValue>("value").0
+    }
+
+    /// Reads and decodes the value of a row read from `key`.
+    ///
+    /// The row must have the `value` and `encoding` columns.
+    fn decode_row(&self, key: &(impl AsKey + ?Sized), row: &Row) -> Result<Vec<u8>> {
+        self.decode_value(key, Self::row_value(row), row.get("encoding"))
+    }
+
+    /// Reads the key column of `row` as text.
+    ///
+    /// Fails on binary keys that aren't valid UTF-8.
+    fn row_key(&self, row: &Row) -> Result<String> {
+        match self.config.key_type {
+            KeyType::Text => Ok(row.get("key")),
+            KeyType::Bytes => String::from_utf8(row.get("key")).map_err(|_| Error::InvalidKey {
+                reason: "key is not valid UTF-8; read binary keys with keys_bytes or scan_bytes"
+                    .into(),
+            }),
+        }
+    }
+
     fn validate_value(&self, value: &[u8]) -> Result<()> {
         if value.len() > self.config.max_value_size {
             return Err(Error::InvalidValue {
@@ -1234,16 +4440,262 @@ impl Store {
         Ok(())
     }
 
-    fn row_to_entry(row: &Row) -> Result<Entry> {
+    /// Encodes a value for storage at `key`, compressing and encrypting it
+    /// if configured.
+    ///
+    /// Returns the encoded value and the flags to store in its `encoding` column.
+    fn encode_value<'v>(
+        &self,
+        key: &(impl AsKey + ?Sized),
+        value: &'v [u8],
+    ) -> Result<(Cow<'v, [u8]>, i16)> {
+        let key = key.key_bytes();
+        let value = compression::compress(self.config.compression, value)?;
+        let encoding = match value {
+            Cow::Owned(_) => compression::ENCODING_FLAG,
+            Cow::Borrowed(_) => 0,
+        };
+
+        #[cfg(feature = "encryption")]
+        if let Some(ref encryptor) = self.config.encryption {
+            return Ok((
+                Cow::Owned(encryptor.encrypt(key, &value)?),
+                encoding | encryption::ENCODING_FLAG,
+            ));
+        }
+
+        // Only used for encryption
+        let _ = key;
+        Ok((value, encoding))
+    }
+
+    /// Decodes a value read from `key`, whose `encoding` column is `encoding`.
+    fn decode_value(
+        &self,
+        key: &(impl AsKey + ?Sized),
+        value: Vec<u8>,
+        encoding: i16,
+    ) -> Result<Vec<u8>> {
+        let key = key.key_bytes();
+        self.check_encrypted(key, encoding)?;
+        if encoding & stream::ENCODING_FLAG != 0 {
+            return Err(stream::streamed_value_error(key));
+        }
+
+        let value = if encoding & encryption::ENCODING_FLAG != 0 {
+            self.decrypt(key, &value)?
+        } else {
+            value
+        };
+        if encoding & compression::ENCODING_FLAG != 0 {
+            compression::decompress(&value)
+        } else {
+            Ok(value)
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn decrypt(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        match self.config.encryption {
+            Some(ref encryptor) => encryptor.decrypt(key, value),
+            None => Err(encryption::encryption_error(
+                key,
+                "value is encrypted but no key provider is configured",
+            )),
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn decrypt(&self, key: &[u8], _value: &[u8]) -> Result<Vec<u8>> {
+        Err(encryption::encryption_error(
+            key,
+            "value is encrypted but the `encryption` feature is disabled",
+        ))
+    }
+
+    /// Returns an error if the store rejects unencrypted values and the value
+    /// at `key`, whose `encoding` column is `encoding`, isn't encrypted.
+    fn check_encrypted(&self, key: &[u8], encoding: i16) -> Result<()> {
+        if self.rejects_unencrypted() && encoding & encryption::ENCODING_FLAG == 0 {
+            return Err(encryption::encryption_error(key, "value is not encrypted"));
+        }
+        Ok(())
+    }
+
+    /// Returns whether reads reject values that aren't encrypted.
+    fn rejects_unencrypted(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.config.reject_unencrypted;
+        #[cfg(not(feature = "encryption"))]
+        false
+    }
+
+    /// Returns whether new values are encrypted.
+    fn encrypts(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.config.encryption.is_some();
+        #[cfg(not(feature = "encryption"))]
+        false
+    }
+
+    /// SQL condition that is true when the `encoding` column marks a
+    /// compressed, encrypted or streamed value, which byte operations can't
+    /// work on directly.
+    fn encoded_condition(encoding: &str) -> String {
+        format!("({} <> 0)", encoding)
+    }
+
+    /// Returns whether the live value at `key` is compressed, encrypted or streamed.
+    fn is_encoded(&self, key: &str) -> Result<bool> {
+        let sql = format!(
+            "SELECT {} FROM {} t WHERE key = $1 AND NOT {}",
+            Self::encoded_condition("encoding"),
+            self.qualified_table,
+            self.expired_condition("t")
+        );
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt(&sql, &[&*self.key_param(key)?])?;
+        Ok(row.is_some_and(|row| row.get(0)))
+    }
+
+    /// SQL condition that is true when the row aliased `alias` has expired.
+    fn expired_condition(&self, alias: &str) -> String {
+        if self.config.ttl_enabled() {
+            format!(
+                "({0}.expires_at IS NOT NULL AND {0}.expires_at <= NOW())",
+                alias
+            )
+        } else {
+            "FALSE".to_string()
+        }
+    }
+
+    /// SQL expression converting the text expression `expr` to a value.
+    fn value_from_text_sql(&self, expr: &str) -> String {
+        format!("({})::text::{}", expr, self.config.value_type.sql_type())
+    }
+
+    /// SQL expression converting the value expression `expr` to text.
+    fn value_to_text_sql(&self, expr: &str) -> String {
+        match self.config.value_type {
+            ValueType::Bytes => format!("encode({}, 'escape')", expr),
+            ValueType::Jsonb => format!("({} #>> '{{}}')", expr),
+        }
+    }
+
+    /// SQL expression for the value expression `expr` as `BYTEA`.
+    fn value_bytes_sql(&self, expr: &str) -> String {
+        match self.config.value_type {
+            ValueType::Bytes => expr.to_string(),
+            ValueType::Jsonb => format!("convert_to({}::text, 'UTF8')", expr),
+        }
+    }
+
+    /// Returns an error unless values are stored as `BYTEA`.
+    fn require_bytes_values(&self, operation: &str) -> Result<()> {
+        match self.config.value_type {
+            ValueType::Bytes => Ok(()),
+            ValueType::Jsonb => Err(Error::Config(format!(
+                "{} is not supported with ValueType::Jsonb",
+                operation
+            ))),
+        }
+    }
+
+    /// Returns an error if new values are encrypted.
+    ///
+    /// Operations that compute the new value in SQL can't encrypt it, and
+    /// would store it in plaintext.
+    fn require_unencrypted_values(&self, operation: &str) -> Result<()> {
+        if self.encrypts() {
+            return Err(Error::Config(format!(
+                "{} is not supported with encryption",
+                operation
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns an error unless values are stored as `JSONB`.
+    fn require_jsonb_values(&self, operation: &str) -> Result<()> {
+        match self.config.value_type {
+            ValueType::Jsonb => Ok(()),
+            ValueType::Bytes => Err(Error::Config(format!(
+                "{} requires ValueType::Jsonb",
+                operation
+            ))),
+        }
+    }
+
+    /// Acquires a session-level advisory lock scoped to this table and key.
+    fn lock_key<'k>(&self, key: &'k str) -> Result<KeyLock<'_, 'k>> {
+        let lock_name = format!("{}:{}", self.qualified_table, key);
+        self.client.borrow_mut().execute(
+            "SELECT pg_advisory_lock(hashtextextended($1, 0))",
+            &[&lock_name],
+        )?;
+        Ok(KeyLock { store: self, key })
+    }
+
+    /// Tries to acquire the advisory lock for a key without waiting.
+    ///
+    /// Returns `None` if another session holds the lock.
+    fn try_lock_key<'k>(&self, key: &'k str) -> Result<Option<KeyLock<'_, 'k>>> {
+        let lock_name = format!("{}:{}", self.qualified_table, key);
+        let row = self.client.borrow_mut().query_one(
+            "SELECT pg_try_advisory_lock(hashtextextended($1, 0))",
+            &[&lock_name],
+        )?;
+        Ok(row
+            .get::<_, bool>(0)
+            .then_some(KeyLock { store: self, key }))
+    }
+
+    /// Releases an advisory lock acquired with [`Store::lock_key`].
+    fn unlock_key(&self, key: &str) -> Result<()> {
+        let lock_name = format!("{}:{}", self.qualified_table, key);
+        self.client.borrow_mut().execute(
+            "SELECT pg_advisory_unlock(hashtextextended($1, 0))",
+            &[&lock_name],
+        )?;
+        Ok(())
+    }
+
+    fn row_to_entry(&self, row: &Row) -> Result<Entry> {
+        let key = self.row_key(row)?;
         Ok(Entry {
-            key: row.get("key"),
-            value: row.get("value"),
+            value: self.decode_row(&key, row)?,
+            key,
             expires_at: row.get("expires_at"),
+            stale_at: row.get("stale_at"),
+            tags: row
+                .get::<_, Option<Vec<String>>>("tags")
+                .unwrap_or_default(),
+            version: row.get::<_, i64>("version") as u64,
             created_at: row.get("created_at"),
             updated_at: row.get("updated_at"),
         })
     }
 
+    fn row_to_history_entry(&self, row: &Row) -> Result<HistoryEntry> {
+        let op: String = row.get("op");
+        let op = HistoryOp::parse(&op)
+            .ok_or_else(|| Error::Query(format!("unknown history operation: {}", op)))?;
+
+        let key = self.row_key(row)?;
+        Ok(HistoryEntry {
+            value: self.decode_row(&key, row)?,
+            key,
+            op,
+            version: row.get::<_, i64>("version") as u64,
+            expires_at: row.get("expires_at"),
+            changed_at: row.get("changed_at"),
+            changed_by: row.get("changed_by"),
+        })
+    }
+
     /// Returns a reference to the configuration.
     pub fn config(&self) -> &Config {
         &self.config
@@ -1255,6 +4707,170 @@ impl Store {
     }
 }
 
+/// Error for a byte operation that would modify a compressed, encrypted or streamed value.
+fn encoded_value_error() -> Error {
+    Error::InvalidValue {
+        reason: "cannot modify a compressed, encrypted or streamed value in place".into(),
+    }
+}
+
+/// Validates a cache tag.
+fn validate_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() {
+        return Err(Error::InvalidKey {
+            reason: "tag cannot be empty".into(),
+        });
+    }
+
+    Ok(())
+}
+
+/// An advisory lock taken with [`Store::lock_key`].
+///
+/// Release it with [`KeyLock::unlock`] to see errors. If it is dropped
+/// instead, for example because the loader panicked, it is still released,
+/// so other callers don't wait on it for the life of the connection.
+struct KeyLock<'s, 'k> {
+    store: &'s Store,
+    key: &'k str,
+}
+
+impl KeyLock<'_, '_> {
+    fn unlock(self) -> Result<()> {
+        let result = self.store.unlock_key(self.key);
+        std::mem::forget(self);
+        result
+    }
+}
+
+impl Drop for KeyLock<'_, '_> {
+    fn drop(&mut self) {
+        let _ = self.store.unlock_key(self.key);
+    }
+}
+
+/// Restores the transaction depth when [`Store::atomically`] returns, and
+/// rolls back its transaction or savepoint if the closure panics, so the
+/// connection is left as it was found.
+struct TxDepthGuard<'a> {
+    store: &'a Store,
+    depth: u32,
+}
+
+impl Drop for TxDepthGuard<'_> {
+    fn drop(&mut self) {
+        self.store.tx_depth.set(self.depth);
+        if std::thread::panicking() {
+            if let Ok(mut client) = self.store.client.try_borrow_mut() {
+                let _ = client.batch_execute(&end_transaction_sql(self.depth, false));
+            }
+        }
+    }
+}
+
+/// Returns `bytes` as a `BYTEA` literal.
+///
+/// Hex-encoded, so user data inlined in SQL never depends on how the server
+/// parses backslashes in strings.
+fn bytes_literal(bytes: &[u8]) -> String {
+    let mut literal = String::with_capacity(bytes.len() * 2 + 20);
+    literal.push_str("decode('");
+    for byte in bytes {
+        literal.push_str(&format!("{:02x}", byte));
+    }
+    literal.push_str("', 'hex')");
+    literal
+}
+
+/// Returns `text` as a `TEXT` literal; see [`bytes_literal`].
+fn text_literal(text: &str) -> String {
+    format!("convert_from({}, 'UTF8')", bytes_literal(text.as_bytes()))
+}
+
+/// Returns `value` as a `BIGINT` literal.
+fn bigint_literal(value: i64) -> String {
+    format!("'{}'::bigint", value)
+}
+
+/// Parses a column returned by a simple query.
+fn parse_field<T: std::str::FromStr>(field: &str) -> Result<T> {
+    field
+        .parse()
+        .map_err(|_| Error::Query(format!("unexpected batch result {:?}", field)))
+}
+
+/// Maps the error that stopped a batch script to the batch's outcome.
+fn batch_failure(batch: &Batch, err: postgres::Error) -> Result<BatchResult> {
+    if let Some(db) = err.as_db_error() {
+        let index = db.detail().and_then(|detail| detail.parse::<usize>().ok());
+        match (db.message(), index) {
+            (BATCH_PRECONDITION_FAILED, Some(index)) => {
+                if let Some(precondition) = batch.preconditions.get(index) {
+                    return Ok(BatchResult::Failed {
+                        precondition: precondition.clone(),
+                    });
+                }
+            }
+            (BATCH_NOT_NUMERIC, Some(index)) => {
+                if let Some(BatchOp::Increment { key, .. }) = batch.ops.get(index) {
+                    return Err(Error::NotNumeric { key: key.clone() });
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(err.into())
+}
+
+/// Returns the name of the savepoint opened at transaction depth `depth`.
+fn savepoint_name(depth: u32) -> String {
+    format!("pgkv_savepoint_{}", depth)
+}
+
+/// Returns the SQL that commits or rolls back the transaction (at depth `0`)
+/// or savepoint opened at `depth`.
+fn end_transaction_sql(depth: u32, commit: bool) -> String {
+    let savepoint = savepoint_name(depth);
+    match (commit, depth) {
+        (true, 0) => "COMMIT".to_string(),
+        (true, _) => format!("RELEASE SAVEPOINT {}", savepoint),
+        (false, 0) => "ROLLBACK".to_string(),
+        (false, _) => format!(
+            "ROLLBACK TO SAVEPOINT {0}; RELEASE SAVEPOINT {0}",
+            savepoint
+        ),
+    }
+}
+
+/// Maps a failed cast of the stored value to [`Error::NotNumeric`].
+fn not_numeric(err: postgres::Error, key: &str) -> Error {
+    if err.code() == Some(&SqlState::INVALID_TEXT_REPRESENTATION) {
+        Error::NotNumeric {
+            key: key.to_string(),
+        }
+    } else {
+        err.into()
+    }
+}
+
+/// Maps errors from JSON that PostgreSQL rejected to [`Error::InvalidValue`].
+fn invalid_json(err: postgres::Error, key: &str) -> Error {
+    match err.code() {
+        Some(code)
+            if *code == SqlState::INVALID_TEXT_REPRESENTATION
+                || *code == SqlState::INVALID_PARAMETER_VALUE =>
+        {
+            let reason = err
+                .as_db_error()
+                .map_or_else(|| err.to_string(), |e| e.message().to_string());
+            Error::InvalidValue {
+                reason: format!("invalid JSON operation on key {:?}: {}", key, reason),
+            }
+        }
+        _ => err.into(),
+    }
+}
+
 /// Escapes special characters for LIKE pattern.
 fn escape_like(s: &str) -> String {
     s.replace('\\', "\\\\")
@@ -1277,6 +4893,23 @@ mod tests {
         assert_eq!(escape_like("combo%_\\"), "combo\\%\\_\\\\");
     }
 
+    #[test]
+    fn test_literals() {
+        assert_eq!(bytes_literal(b"a'\\"), "decode('61275c', 'hex')");
+        assert_eq!(bytes_literal(b""), "decode('', 'hex')");
+        assert_eq!(
+            text_literal("a"),
+            "convert_from(decode('61', 'hex'), 'UTF8')"
+        );
+        assert_eq!(bigint_literal(i64::MIN), "'-9223372036854775808'::bigint");
+    }
+
+    #[test]
+    fn test_validate_tag() {
+        assert!(validate_tag("product:42").is_ok());
+        assert!(validate_tag("").is_err());
+    }
+
     #[test]
     fn test_validate_key() {
         let config = Config::new("postgresql://localhost/test").max_key_length(10);
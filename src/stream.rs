@@ -0,0 +1,371 @@
+//! Chunked storage for values too large to hold in memory.
+//!
+//! [`Store::put_stream`](crate::Store::put_stream) splits a value into chunks
+//! of [`Config::chunk_size`](crate::Config::chunk_size) bytes, stored in the
+//! companion table `{table_name}_chunks` with a CRC-32 checksum each, and
+//! stores a small manifest as the key's value. [`ValueReader`] reads the
+//! chunks back one at a time.
+//!
+//! # Value format
+//!
+//! A manifest is 22 bytes: `0xF6`, the format version (`1`), the object ID as
+//! a big-endian `i64`, the total size as a big-endian `u64` and the chunk size
+//! as a big-endian `u32`. The row's `encoding` column marks manifests, so
+//! values that merely look like one are stored and read as they are. Chunks
+//! are stored as written, without compression or encryption, so stores that
+//! encrypt values don't accept streamed values.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::error::Error;
+use crate::key::display_key;
+use crate::store::Store;
+
+/// First byte of every manifest.
+const HEADER_MAGIC: u8 = 0xF6;
+
+/// Version of the manifest format.
+const FORMAT_VERSION: u8 = 1;
+
+/// Length of a manifest in bytes.
+const MANIFEST_LEN: usize = 22;
+
+/// Bit set in a row's `encoding` column when its value is a manifest.
+pub(crate) const ENCODING_FLAG: i16 = 4;
+
+/// Where the chunks of a streamed value are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub(crate) object_id: i64,
+    pub(crate) size: u64,
+    pub(crate) chunk_size: u32,
+}
+
+impl Manifest {
+    /// Encodes the manifest as a value.
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MANIFEST_LEN);
+        bytes.extend_from_slice(&[HEADER_MAGIC, FORMAT_VERSION]);
+        bytes.extend_from_slice(&self.object_id.to_be_bytes());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&self.chunk_size.to_be_bytes());
+        bytes
+    }
+
+    /// Decodes a manifest, returning `None` for any other value.
+    pub(crate) fn from_bytes(value: &[u8]) -> Option<Self> {
+        if !is_manifest(value) {
+            return None;
+        }
+        Some(Self {
+            object_id: i64::from_be_bytes(value[2..10].try_into().ok()?),
+            size: u64::from_be_bytes(value[10..18].try_into().ok()?),
+            chunk_size: u32::from_be_bytes(value[18..22].try_into().ok()?),
+        })
+    }
+
+    /// Returns the expected length of chunk `seq`.
+    fn chunk_len(&self, seq: u64) -> usize {
+        let start = seq * u64::from(self.chunk_size);
+        (self.size - start).min(u64::from(self.chunk_size)) as usize
+    }
+}
+
+/// Returns whether `value` has the format of a manifest.
+fn is_manifest(value: &[u8]) -> bool {
+    value.len() == MANIFEST_LEN && value[0] == HEADER_MAGIC && value[1] == FORMAT_VERSION
+}
+
+/// SQL condition that is true when the `encoding` column marks a manifest.
+pub(crate) fn manifest_sql(encoding: &str) -> String {
+    format!("(({} & {}) <> 0)", encoding, ENCODING_FLAG)
+}
+
+/// SQL expression for the object ID in the manifest held by `column`.
+pub(crate) fn object_id_sql(column: &str) -> String {
+    format!(
+        "('x' || encode(substring({} FROM 3 FOR 8), 'hex'))::bit(64)::bigint",
+        column
+    )
+}
+
+/// SQL expression for the total size in the manifest held by `column`.
+pub(crate) fn size_sql(column: &str) -> String {
+    format!(
+        "('x' || encode(substring({} FROM 11 FOR 8), 'hex'))::bit(64)::bigint",
+        column
+    )
+}
+
+/// Returns the checksum stored with a chunk.
+pub(crate) fn checksum(data: &[u8]) -> i64 {
+    i64::from(crc32fast::hash(data))
+}
+
+/// Error for a streamed value read through the in-memory API.
+pub(crate) fn streamed_value_error(key: &[u8]) -> Error {
+    Error::InvalidValue {
+        reason: format!(
+            "value at key {:?} is stored in chunks; read it with Store::get_reader",
+            display_key(key)
+        ),
+    }
+}
+
+/// Reads from `reader` until `buf` is full or the reader is exhausted.
+///
+/// Returns the number of bytes read.
+pub(crate) fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// A reader over a value, returned by [`Store::get_reader`].
+///
+/// Values written with [`Store::put_stream`] are fetched one chunk at a time
+/// as they are read, and each chunk's checksum is verified before it is
+/// returned. Other values are read from memory.
+///
+/// Chunks are fetched outside a transaction, so if the value is replaced or
+/// deleted while it is being read, the next chunk fetch fails with an
+/// [`io::ErrorKind::NotFound`] error.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use pgkv::Store;
+/// use std::io::{Read, Seek, SeekFrom};
+///
+/// let store = Store::connect("postgresql://localhost/mydb")?;
+///
+/// if let Some(mut reader) = store.get_reader("backup.tar")? {
+///     // Read the last kilobyte
+///     reader.seek(SeekFrom::End(-1024))?;
+///     let mut tail = Vec::new();
+///     reader.read_to_end(&mut tail)?;
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct ValueReader<'a> {
+    inner: Inner<'a>,
+}
+
+enum Inner<'a> {
+    Memory(io::Cursor<Vec<u8>>),
+    Chunked {
+        store: &'a Store,
+        manifest: Manifest,
+        pos: u64,
+        /// Sequence number of the most recently fetched chunk.
+        chunk_seq: Option<u64>,
+        chunk: Vec<u8>,
+    },
+}
+
+impl<'a> ValueReader<'a> {
+    pub(crate) fn memory(value: Vec<u8>) -> Self {
+        Self {
+            inner: Inner::Memory(io::Cursor::new(value)),
+        }
+    }
+
+    pub(crate) fn chunked(store: &'a Store, manifest: Manifest) -> Self {
+        Self {
+            inner: Inner::Chunked {
+                store,
+                manifest,
+                pos: 0,
+                chunk_seq: None,
+                chunk: Vec::new(),
+            },
+        }
+    }
+
+    /// Returns the size of the value in bytes.
+    pub fn len(&self) -> u64 {
+        match self.inner {
+            Inner::Memory(ref cursor) => cursor.get_ref().len() as u64,
+            Inner::Chunked { ref manifest, .. } => manifest.size,
+        }
+    }
+
+    /// Returns whether the value is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the value is stored in chunks.
+    pub fn is_chunked(&self) -> bool {
+        matches!(self.inner, Inner::Chunked { .. })
+    }
+}
+
+impl fmt::Debug for ValueReader<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueReader")
+            .field("len", &self.len())
+            .field("chunked", &self.is_chunked())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (store, manifest, pos, chunk_seq, chunk) = match &mut self.inner {
+            Inner::Memory(cursor) => return cursor.read(buf),
+            Inner::Chunked {
+                store,
+                manifest,
+                pos,
+                chunk_seq,
+                chunk,
+            } => (*store, *manifest, pos, chunk_seq, chunk),
+        };
+
+        if *pos >= manifest.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let chunk_size = u64::from(manifest.chunk_size);
+        let seq = *pos / chunk_size;
+        if *chunk_seq != Some(seq) {
+            *chunk = fetch_chunk(store, &manifest, seq)?;
+            *chunk_seq = Some(seq);
+        }
+
+        let offset = (*pos - seq * chunk_size) as usize;
+        let n = buf.len().min(chunk.len() - offset);
+        buf[..n].copy_from_slice(&chunk[offset..offset + n]);
+        *pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ValueReader<'_> {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        let (pos, size) = match &mut self.inner {
+            Inner::Memory(cursor) => return cursor.seek(from),
+            Inner::Chunked { pos, manifest, .. } => (pos, manifest.size),
+        };
+
+        let target = match from {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => pos.checked_add_signed(offset),
+        };
+        let Some(target) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ));
+        };
+
+        *pos = target;
+        Ok(target)
+    }
+}
+
+/// Fetches chunk `seq` of a streamed value and verifies its checksum.
+fn fetch_chunk(store: &Store, manifest: &Manifest, seq: u64) -> io::Result<Vec<u8>> {
+    let (data, stored_checksum) = store
+        .fetch_chunk(manifest.object_id, seq as i32)
+        .map_err(io::Error::other)?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "chunk {} of object {} is missing; the value was replaced or deleted",
+                    seq, manifest.object_id
+                ),
+            )
+        })?;
+
+    if data.len() != manifest.chunk_len(seq) || checksum(&data) != stored_checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "checksum mismatch in chunk {} of object {}",
+                seq, manifest.object_id
+            ),
+        ));
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            object_id: 42,
+            size: 2_500_000,
+            chunk_size: 1_048_576,
+        }
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let bytes = manifest().to_bytes();
+        assert_eq!(bytes.len(), MANIFEST_LEN);
+        assert!(is_manifest(&bytes));
+        assert_eq!(Manifest::from_bytes(&bytes), Some(manifest()));
+
+        for value in [&b""[..], b"plain", &bytes[..MANIFEST_LEN - 1]] {
+            assert_eq!(Manifest::from_bytes(value), None);
+        }
+    }
+
+    #[test]
+    fn test_chunk_len() {
+        let manifest = manifest();
+        assert_eq!(manifest.chunk_len(0), 1_048_576);
+        assert_eq!(manifest.chunk_len(1), 1_048_576);
+        assert_eq!(manifest.chunk_len(2), 2_500_000 - 2 * 1_048_576);
+    }
+
+    #[test]
+    fn test_read_full() {
+        // A reader that returns one byte at a time
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let Some((first, rest)) = self.0.split_first() else {
+                    return Ok(0);
+                };
+                buf[0] = *first;
+                self.0 = rest;
+                Ok(1)
+            }
+        }
+
+        let mut buf = [0; 4];
+        let mut reader = Trickle(b"abcdef");
+        assert_eq!(read_full(&mut reader, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(read_full(&mut reader, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"ef");
+    }
+
+    #[test]
+    fn test_memory_reader_seeks() {
+        let mut reader = ValueReader::memory(b"hello world".to_vec());
+        assert!(!reader.is_chunked());
+        assert_eq!(reader.len(), 11);
+
+        reader.seek(SeekFrom::End(-5)).unwrap();
+        let mut tail = String::new();
+        reader.read_to_string(&mut tail).unwrap();
+        assert_eq!(tail, "world");
+    }
+}
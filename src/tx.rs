@@ -0,0 +1,67 @@
+//! Transaction handle.
+
+use std::ops::Deref;
+
+use crate::error::Result;
+use crate::store::Store;
+
+/// A handle to an open transaction, passed to the closure given to
+/// [`Store::transaction_with`].
+///
+/// Dereferences to [`Store`], so every store operation is available and runs
+/// inside the transaction. Store operations that need their own transaction,
+/// such as [`Store::set_many`] or [`Store::execute_batch`], use a savepoint instead.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use pgkv::{Store, TxOptions};
+///
+/// let store = Store::connect("postgresql://localhost/mydb")?;
+/// store.transaction_with(TxOptions::new(), |tx| {
+///     tx.set("order:1", b"pending")?;
+///
+///     // Roll back only the savepoint if reserving stock fails
+///     let reserved = tx.savepoint(|tx| tx.increment("stock:42", -1)).is_ok();
+///     tx.set("order:1", if reserved { "reserved" } else { "backordered" })?;
+///     Ok(())
+/// })?;
+/// # Ok::<(), pgkv::Error>(())
+/// ```
+pub struct Tx<'a> {
+    store: &'a Store,
+}
+
+impl<'a> Tx<'a> {
+    pub(crate) fn new(store: &'a Store) -> Self {
+        Self { store }
+    }
+
+    /// Runs `f` in a savepoint.
+    ///
+    /// If `f` returns an error, only the changes made inside the savepoint are
+    /// rolled back and the transaction can continue. Savepoints can be nested.
+    pub fn savepoint<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Tx<'a>) -> Result<T>,
+    {
+        self.store.atomically("SAVEPOINT", || f(self))
+    }
+
+    /// Gets a value and locks the key until the transaction ends.
+    ///
+    /// Other transactions that try to write or lock the key wait until this one
+    /// commits or rolls back. Returns `None` if the key doesn't exist; no lock
+    /// is taken in that case.
+    pub fn get_for_update(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.store.get_for_update(key)
+    }
+}
+
+impl Deref for Tx<'_> {
+    type Target = Store;
+
+    fn deref(&self) -> &Store {
+        self.store
+    }
+}
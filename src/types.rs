@@ -1,6 +1,6 @@
 //! Type definitions for pgkv.
 
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 /// A key-value pair.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -35,6 +35,14 @@ pub struct Entry {
     pub value: Vec<u8>,
     /// When the entry expires, if set.
     pub expires_at: Option<SystemTime>,
+    /// When the entry becomes stale (soft expiry), if set.
+    ///
+    /// Stale entries are still served until `expires_at`.
+    pub stale_at: Option<SystemTime>,
+    /// Cache tags attached to the entry.
+    pub tags: Vec<String>,
+    /// Version of the value; strictly increases on every write.
+    pub version: u64,
     /// When the entry was created.
     pub created_at: SystemTime,
     /// When the entry was last updated.
@@ -49,6 +57,22 @@ impl Entry {
             .unwrap_or(false)
     }
 
+    /// Returns `true` if this entry is past its soft expiry.
+    pub fn is_stale(&self) -> bool {
+        self.stale_at
+            .map(|stale| stale < SystemTime::now())
+            .unwrap_or(false)
+    }
+
+    /// Returns whether this entry is fresh or stale.
+    pub fn freshness(&self) -> Freshness {
+        if self.is_stale() {
+            Freshness::Stale
+        } else {
+            Freshness::Fresh
+        }
+    }
+
     /// Returns the time remaining until expiration, if set.
     pub fn ttl(&self) -> Option<std::time::Duration> {
         self.expires_at
@@ -61,6 +85,78 @@ impl Entry {
     }
 }
 
+/// The kind of change recorded in a key's history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryOp {
+    /// The key was created or its value was written.
+    Set,
+    /// The key was deleted (explicitly or by expiry cleanup).
+    Delete,
+}
+
+impl HistoryOp {
+    /// Parses the value stored in the history table's `op` column.
+    pub(crate) fn parse(op: &str) -> Option<Self> {
+        match op {
+            "SET" => Some(HistoryOp::Set),
+            "DELETE" => Some(HistoryOp::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// A recorded change to a key, from [`Store::history`](crate::Store::history).
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    /// The key.
+    pub key: String,
+    /// The kind of change.
+    pub op: HistoryOp,
+    /// The value written, or the last value for a delete.
+    pub value: Vec<u8>,
+    /// The version written, or the last version for a delete.
+    pub version: u64,
+    /// When the value was set to expire, if it had a TTL.
+    pub expires_at: Option<SystemTime>,
+    /// When the change was made.
+    pub changed_at: SystemTime,
+    /// Who made the change: the connection's `application_name`, or the database user.
+    pub changed_by: String,
+}
+
+impl HistoryEntry {
+    /// Returns the value as a UTF-8 string, if valid.
+    pub fn value_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.value).ok()
+    }
+}
+
+/// A secondary index, from [`Store::indexes`](crate::Store::indexes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexInfo {
+    /// The index name passed to [`Store::create_index`](crate::Store::create_index).
+    pub name: String,
+    /// The indexed SQL expression.
+    pub expression: String,
+}
+
+/// Whether a value is within its soft expiry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// The value has not reached its soft expiry.
+    Fresh,
+    /// The value is past its soft expiry but not yet expired; it should be refreshed.
+    Stale,
+}
+
+impl Freshness {
+    /// Returns `true` if the value is stale.
+    #[inline]
+    pub fn is_stale(&self) -> bool {
+        matches!(self, Freshness::Stale)
+    }
+}
+
 /// Statistics about the store.
 #[derive(Debug, Clone, Default)]
 pub struct Stats {
@@ -74,23 +170,72 @@ pub struct Stats {
     pub avg_value_bytes: f64,
     /// Size of the largest value in bytes.
     pub max_value_bytes: u64,
+    /// Number of values stored compressed.
+    pub compressed_keys: u64,
+    /// Total size of all values in bytes before compression.
+    pub uncompressed_value_bytes: u64,
     /// Table size on disk in bytes.
     pub table_size_bytes: u64,
     /// Index size on disk in bytes.
     pub index_size_bytes: u64,
 }
 
+impl Stats {
+    /// Returns the uncompressed size of all values divided by their stored
+    /// size, or `1.0` if the store is empty.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.total_value_bytes == 0 {
+            1.0
+        } else {
+            self.uncompressed_value_bytes as f64 / self.total_value_bytes as f64
+        }
+    }
+}
+
+/// Statistics about the in-process cache of a [`CachedStore`](crate::CachedStore).
+#[derive(Debug, Clone, Default)]
+pub struct CacheStats {
+    /// Number of reads served from memory.
+    pub hits: u64,
+    /// Number of reads that went to PostgreSQL.
+    pub misses: u64,
+    /// Number of entries evicted to stay within the size limits.
+    pub evictions: u64,
+    /// Number of entries dropped due to local or remote writes.
+    pub invalidations: u64,
+    /// Number of entries currently cached.
+    pub entries: u64,
+    /// Total size of cached keys and values in bytes.
+    pub size_bytes: u64,
+}
+
+impl CacheStats {
+    /// Returns the fraction of reads served from memory, or `0.0` if there were none.
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
 /// Options for scanning keys.
 #[derive(Debug, Clone, Default)]
 pub struct ScanOptions {
     /// Only return keys matching this prefix.
     pub prefix: Option<String>,
+    /// Only return keys starting with these bytes; replaces `prefix`.
+    pub prefix_bytes: Option<Vec<u8>>,
     /// Maximum number of keys to return.
     pub limit: Option<usize>,
     /// Offset for pagination.
     pub offset: Option<usize>,
     /// Whether to include expired keys.
     pub include_expired: bool,
+    /// Only return values containing this JSON document.
+    pub json_contains: Option<String>,
 }
 
 impl ScanOptions {
@@ -102,9 +247,26 @@ impl ScanOptions {
     /// Sets the prefix filter.
     pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
         self.prefix = Some(prefix.into());
+        self.prefix_bytes = None;
         self
     }
 
+    /// Sets a binary prefix filter, for [`KeyType::Bytes`](crate::KeyType::Bytes) keys.
+    ///
+    /// Binary prefixes are matched with a range scan over the key index.
+    pub fn prefix_bytes(mut self, prefix: impl Into<Vec<u8>>) -> Self {
+        self.prefix_bytes = Some(prefix.into());
+        self.prefix = None;
+        self
+    }
+
+    /// Returns the prefix filter as bytes.
+    pub(crate) fn key_prefix(&self) -> Option<&[u8]> {
+        self.prefix_bytes
+            .as_deref()
+            .or_else(|| self.prefix.as_deref().map(str::as_bytes))
+    }
+
     /// Sets the maximum number of results.
     pub fn limit(mut self, limit: usize) -> Self {
         self.limit = Some(limit);
@@ -122,6 +284,218 @@ impl ScanOptions {
         self.include_expired = include;
         self
     }
+
+    /// Only returns values that contain `json`, using PostgreSQL's `@>`
+    /// operator. Requires [`ValueType::Jsonb`](crate::ValueType::Jsonb).
+    ///
+    /// Objects match if they contain the given keys and values, arrays if they
+    /// contain the given elements. With [`Config::json_index`](crate::Config::json_index)
+    /// the filter is served from a GIN index.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use pgkv::ScanOptions;
+    ///
+    /// let admins = ScanOptions::new()
+    ///     .prefix("user:")
+    ///     .json_contains(r#"{"role": "admin"}"#);
+    /// ```
+    pub fn json_contains(mut self, json: impl Into<String>) -> Self {
+        self.json_contains = Some(json.into());
+        self
+    }
+}
+
+/// Options for [`Store::set_with`](crate::Store::set_with).
+///
+/// By default the value is always written and any TTL is removed, like
+/// [`Store::set`](crate::Store::set).
+///
+/// # Example
+///
+/// ```rust
+/// use pgkv::SetOptions;
+/// use std::time::Duration;
+///
+/// // Only overwrite an existing key, keep its TTL, and return the old value
+/// let opts = SetOptions::new().if_exists().keep_ttl().return_previous();
+///
+/// // Only create the key, expiring in a minute
+/// let opts = SetOptions::new().if_not_exists().ttl(Duration::from_secs(60));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SetOptions {
+    pub(crate) condition: SetCondition,
+    pub(crate) expiry: SetExpiry,
+    pub(crate) return_previous: bool,
+}
+
+/// When a [`SetOptions`] write applies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum SetCondition {
+    #[default]
+    Always,
+    IfExists,
+    IfNotExists,
+}
+
+/// How a [`SetOptions`] write sets the key's expiration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum SetExpiry {
+    #[default]
+    Persist,
+    Keep,
+    Ttl(Duration),
+    At(SystemTime),
+}
+
+impl SetOptions {
+    /// Creates new set options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only write if the key already exists (Redis `XX`).
+    pub fn if_exists(mut self) -> Self {
+        self.condition = SetCondition::IfExists;
+        self
+    }
+
+    /// Only write if the key doesn't exist (Redis `NX`).
+    pub fn if_not_exists(mut self) -> Self {
+        self.condition = SetCondition::IfNotExists;
+        self
+    }
+
+    /// Keep the key's current expiration instead of removing it (Redis `KEEPTTL`).
+    pub fn keep_ttl(mut self) -> Self {
+        self.expiry = SetExpiry::Keep;
+        self
+    }
+
+    /// Expire the key after `ttl`.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.expiry = SetExpiry::Ttl(ttl);
+        self
+    }
+
+    /// Expire the key at `expires_at`.
+    pub fn expires_at(mut self, expires_at: SystemTime) -> Self {
+        self.expiry = SetExpiry::At(expires_at);
+        self
+    }
+
+    /// Return the value the key held before the write (Redis `GET`).
+    pub fn return_previous(mut self) -> Self {
+        self.return_previous = true;
+        self
+    }
+}
+
+/// Result of [`Store::set_with`](crate::Store::set_with).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetOutcome {
+    /// Whether the value was written.
+    pub written: bool,
+    /// The key's new version, if the value was written.
+    pub version: Option<u64>,
+    /// The value before the write, if requested with
+    /// [`SetOptions::return_previous`] and the key existed.
+    pub previous: Option<Vec<u8>>,
+}
+
+/// Transaction isolation level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IsolationLevel {
+    /// Each statement sees data committed before it began (PostgreSQL default).
+    #[default]
+    ReadCommitted,
+    /// All statements see a snapshot taken at the first statement.
+    RepeatableRead,
+    /// Transactions behave as if run one at a time; conflicts abort with a retryable error.
+    Serializable,
+}
+
+impl IsolationLevel {
+    /// Returns the SQL keyword for this isolation level.
+    pub(crate) fn sql_keyword(&self) -> &'static str {
+        match self {
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+/// Options for [`Store::transaction_with`](crate::Store::transaction_with).
+#[derive(Debug, Clone)]
+pub struct TxOptions {
+    /// The isolation level.
+    pub isolation: IsolationLevel,
+    /// Whether the transaction may only read.
+    pub read_only: bool,
+    /// Whether a serializable read-only transaction waits for a safe snapshot
+    /// instead of risking a serialization failure.
+    pub deferrable: bool,
+    /// How many times to retry after a serialization failure or deadlock.
+    pub max_retries: u32,
+}
+
+impl Default for TxOptions {
+    fn default() -> Self {
+        Self {
+            isolation: IsolationLevel::default(),
+            read_only: false,
+            deferrable: false,
+            max_retries: 3,
+        }
+    }
+}
+
+impl TxOptions {
+    /// Creates new transaction options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the isolation level.
+    pub fn isolation(mut self, isolation: IsolationLevel) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    /// Sets whether the transaction is read-only.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets whether the transaction is deferrable.
+    pub fn deferrable(mut self, deferrable: bool) -> Self {
+        self.deferrable = deferrable;
+        self
+    }
+
+    /// Sets how many times to retry after a serialization failure or deadlock.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Returns the `BEGIN` statement for these options.
+    pub(crate) fn begin_sql(&self) -> String {
+        let mut sql = format!("BEGIN ISOLATION LEVEL {}", self.isolation.sql_keyword());
+        sql.push_str(if self.read_only {
+            " READ ONLY"
+        } else {
+            " READ WRITE"
+        });
+        if self.deferrable {
+            sql.push_str(" DEFERRABLE");
+        }
+        sql
+    }
 }
 
 /// Result of a compare-and-swap operation.
@@ -152,10 +526,40 @@ impl CasResult {
     }
 }
 
+/// Result of a version-checked write or delete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionResult {
+    /// The operation was applied.
+    Success {
+        /// The new version after a write, or the deleted version after a delete.
+        version: u64,
+    },
+    /// The key is at a different version.
+    Mismatch {
+        /// The current version of the key.
+        current: u64,
+    },
+    /// The key was not found.
+    NotFound,
+}
+
+impl VersionResult {
+    /// Returns `true` if the operation was applied.
+    #[inline]
+    pub fn is_success(&self) -> bool {
+        matches!(self, VersionResult::Success { .. })
+    }
+
+    /// Returns `true` if there was a version mismatch.
+    #[inline]
+    pub fn is_mismatch(&self) -> bool {
+        matches!(self, VersionResult::Mismatch { .. })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Duration;
 
     #[test]
     fn test_key_value() {
@@ -177,6 +581,9 @@ mod tests {
             key: "test".into(),
             value: vec![],
             expires_at: None,
+            stale_at: None,
+            tags: vec![],
+            version: 1,
             created_at: now,
             updated_at: now,
         };
@@ -188,12 +595,65 @@ mod tests {
             key: "test".into(),
             value: vec![],
             expires_at: Some(now - Duration::from_secs(1)),
+            stale_at: None,
+            tags: vec![],
+            version: 1,
             created_at: now,
             updated_at: now,
         };
         assert!(entry.is_expired());
     }
 
+    #[test]
+    fn test_entry_freshness() {
+        let now = SystemTime::now();
+
+        let mut entry = Entry {
+            key: "test".into(),
+            value: vec![],
+            expires_at: Some(now + Duration::from_secs(60)),
+            stale_at: None,
+            tags: vec![],
+            version: 1,
+            created_at: now,
+            updated_at: now,
+        };
+        assert_eq!(entry.freshness(), Freshness::Fresh);
+
+        entry.stale_at = Some(now + Duration::from_secs(30));
+        assert!(!entry.is_stale());
+        assert_eq!(entry.freshness(), Freshness::Fresh);
+
+        entry.stale_at = Some(now - Duration::from_secs(1));
+        assert!(entry.is_stale());
+        assert!(entry.freshness().is_stale());
+        assert!(!entry.is_expired());
+    }
+
+    #[test]
+    fn test_cache_stats_hit_ratio() {
+        assert_eq!(CacheStats::default().hit_ratio(), 0.0);
+
+        let stats = CacheStats {
+            hits: 3,
+            misses: 1,
+            ..Default::default()
+        };
+        assert_eq!(stats.hit_ratio(), 0.75);
+    }
+
+    #[test]
+    fn test_stats_compression_ratio() {
+        assert_eq!(Stats::default().compression_ratio(), 1.0);
+
+        let stats = Stats {
+            total_value_bytes: 250,
+            uncompressed_value_bytes: 1000,
+            ..Default::default()
+        };
+        assert_eq!(stats.compression_ratio(), 4.0);
+    }
+
     #[test]
     fn test_scan_options_builder() {
         let opts = ScanOptions::new()
@@ -208,6 +668,43 @@ mod tests {
         assert!(opts.include_expired);
     }
 
+    #[test]
+    fn test_set_options_builder() {
+        let opts = SetOptions::new();
+        assert_eq!(opts.condition, SetCondition::Always);
+        assert_eq!(opts.expiry, SetExpiry::Persist);
+        assert!(!opts.return_previous);
+
+        let opts = SetOptions::new()
+            .if_not_exists()
+            .if_exists()
+            .ttl(Duration::from_secs(5))
+            .keep_ttl()
+            .return_previous();
+        assert_eq!(opts.condition, SetCondition::IfExists);
+        assert_eq!(opts.expiry, SetExpiry::Keep);
+        assert!(opts.return_previous);
+    }
+
+    #[test]
+    fn test_tx_options_begin_sql() {
+        assert_eq!(
+            TxOptions::default().begin_sql(),
+            "BEGIN ISOLATION LEVEL READ COMMITTED READ WRITE"
+        );
+
+        let opts = TxOptions::new()
+            .isolation(IsolationLevel::Serializable)
+            .read_only(true)
+            .deferrable(true)
+            .max_retries(5);
+        assert_eq!(
+            opts.begin_sql(),
+            "BEGIN ISOLATION LEVEL SERIALIZABLE READ ONLY DEFERRABLE"
+        );
+        assert_eq!(opts.max_retries, 5);
+    }
+
     #[test]
     fn test_cas_result() {
         assert!(CasResult::Success.is_success());
@@ -219,4 +716,24 @@ mod tests {
         assert!(!mismatch.is_success());
         assert!(mismatch.is_mismatch());
     }
+
+    #[test]
+    fn test_history_op_parse() {
+        assert_eq!(HistoryOp::parse("SET"), Some(HistoryOp::Set));
+        assert_eq!(HistoryOp::parse("DELETE"), Some(HistoryOp::Delete));
+        assert_eq!(HistoryOp::parse("TRUNCATE"), None);
+    }
+
+    #[test]
+    fn test_version_result() {
+        let success = VersionResult::Success { version: 3 };
+        assert!(success.is_success());
+        assert!(!success.is_mismatch());
+
+        let mismatch = VersionResult::Mismatch { current: 4 };
+        assert!(!mismatch.is_success());
+        assert!(mismatch.is_mismatch());
+
+        assert!(!VersionResult::NotFound.is_success());
+    }
 }
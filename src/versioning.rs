@@ -0,0 +1,277 @@
+//! Schema versions and upcasting for [`TypedStore`](crate::TypedStore) values.
+//!
+//! This module is only available when the `serde` feature is enabled.
+//!
+//! # Value format
+//!
+//! Values at schema version 2 or later start with a five-byte header: `0xC2`
+//! and the version as a little-endian `u32`, followed by the value as encoded
+//! by the codec. `0xC2` can never start a UTF-8 value. Values without a header
+//! are at version 1, so data written before migrations were registered is
+//! upcast from the first type in the chain, and stores with no migrations
+//! write exactly what they did before.
+
+use serde::{Serialize, de::DeserializeOwned};
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::codec::{Codec, Json, decode_value, encode_value};
+use crate::error::{Error, Result};
+use crate::store::Store;
+
+/// First byte of every versioned value.
+const HEADER_MAGIC: u8 = 0xC2;
+
+/// Length of the header in front of versioned values.
+const HEADER_LEN: usize = 5;
+
+/// Upgrades an encoded value by one version.
+type Step = Box<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+/// A chain of migrations that upcast stored values to `T` on read.
+///
+/// The chain starts at version 1 with the type passed to
+/// [`ValueMigrations::new`], and each [`ValueMigrations::then`] adds a version
+/// with a function converting the previous type. Attach it to a store with
+/// [`TypedStore::with_migrations`](crate::TypedStore::with_migrations); values
+/// are then written at the newest version, and older values are decoded as
+/// the type they were written as and converted step by step.
+///
+/// # Example
+///
+/// ```rust
+/// use pgkv::ValueMigrations;
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct UserV1 { name: String }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct UserV2 { name: String, email: Option<String> }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct User { first_name: String, last_name: String, email: Option<String> }
+///
+/// let migrations = ValueMigrations::<UserV1>::new()
+///     .then(|v1| UserV2 { name: v1.name, email: None })
+///     .then(|v2| {
+///         let (first, last) = v2.name.split_once(' ').unwrap_or((&v2.name, ""));
+///         User { first_name: first.into(), last_name: last.into(), email: v2.email }
+///     })
+///     .write_back(true);
+///
+/// assert_eq!(migrations.version(), 3);
+/// ```
+pub struct ValueMigrations<T, C = Json> {
+    steps: Vec<Step>,
+    write_back: bool,
+    _phantom: PhantomData<fn() -> (T, C)>,
+}
+
+impl<T, C> ValueMigrations<T, C>
+where
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Starts a chain whose version 1 values are `T`.
+    pub fn new() -> Self {
+        Self {
+            steps: Vec::new(),
+            write_back: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Adds a version whose values are `U`, converted from the previous version by `f`.
+    pub fn then<U, F>(mut self, f: F) -> ValueMigrations<U, C>
+    where
+        U: Serialize + DeserializeOwned,
+        F: Fn(T) -> U + Send + Sync + 'static,
+    {
+        self.steps.push(Box::new(move |bytes| {
+            let old = decode_value::<C, T>(bytes)?;
+            encode_value::<C, _>(&f(old))
+        }));
+
+        ValueMigrations {
+            steps: self.steps,
+            write_back: self.write_back,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Writes upcast values back at the newest version when they are read.
+    ///
+    /// Write-back only replaces the value if it hasn't changed since it was
+    /// read, and leaves its TTL alone. Disabled by default.
+    pub fn write_back(mut self, enabled: bool) -> Self {
+        self.write_back = enabled;
+        self
+    }
+
+    /// Returns the newest version, at which values are written.
+    pub fn version(&self) -> u32 {
+        self.steps.len() as u32 + 1
+    }
+
+    /// Serializes a value at the newest version.
+    pub(crate) fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        let encoded = encode_value::<C, _>(value)?;
+        let version = self.version();
+        if version == 1 {
+            return Ok(encoded);
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + encoded.len());
+        bytes.push(HEADER_MAGIC);
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend_from_slice(&encoded);
+        Ok(bytes)
+    }
+
+    /// Deserializes a value at any known version, returning whether it was upcast.
+    pub(crate) fn decode(&self, bytes: &[u8]) -> Result<(T, bool)> {
+        let (version, payload) = match bytes {
+            [HEADER_MAGIC, a, b, c, d, payload @ ..] => {
+                (u32::from_le_bytes([*a, *b, *c, *d]), payload)
+            }
+            [HEADER_MAGIC, ..] => {
+                return Err(Error::Serialization(
+                    "truncated schema version header".to_string(),
+                ));
+            }
+            _ => (1, bytes),
+        };
+
+        if version == 0 || version > self.version() {
+            return Err(Error::Serialization(format!(
+                "value has schema version {} but the newest known version is {}",
+                version,
+                self.version()
+            )));
+        }
+
+        let mut payload = payload.to_vec();
+        for step in &self.steps[version as usize - 1..] {
+            payload = step(&payload)?;
+        }
+
+        let value = decode_value::<C, T>(&payload)?;
+        Ok((value, version < self.version()))
+    }
+
+    /// Deserializes the value read from `key`, writing it back if it was
+    /// upcast and write-back is enabled.
+    pub(crate) fn read(&self, store: &Store, key: &str, bytes: &[u8]) -> Result<T> {
+        let (value, upcast) = self.decode(bytes)?;
+        if upcast && self.write_back {
+            // A concurrent write wins; its value is already current
+            store.compare_and_swap(key, Some(bytes), &self.encode(&value)?)?;
+        }
+        Ok(value)
+    }
+}
+
+impl<T, C> Default for ValueMigrations<T, C>
+where
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, C> fmt::Debug for ValueMigrations<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ValueMigrations")
+            .field("version", &(self.steps.len() + 1))
+            .field("write_back", &self.write_back)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct V1 {
+        name: String,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct V2 {
+        name: String,
+        visits: u32,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct V3 {
+        name: String,
+        visits: u64,
+        admin: bool,
+    }
+
+    fn migrations() -> ValueMigrations<V3> {
+        ValueMigrations::<V1>::new()
+            .then(|v1| V2 {
+                name: v1.name,
+                visits: 0,
+            })
+            .then(|v2| V3 {
+                name: v2.name,
+                visits: u64::from(v2.visits),
+                admin: false,
+            })
+    }
+
+    #[test]
+    fn test_unversioned_chain_writes_plain_values() {
+        let migrations = ValueMigrations::<V1>::new();
+        let value = V1 { name: "a".into() };
+        let bytes = migrations.encode(&value).unwrap();
+        assert_eq!(bytes, br#"{"name":"a"}"#);
+        assert_eq!(migrations.decode(&bytes).unwrap(), (value, false));
+    }
+
+    #[test]
+    fn test_upcast_from_each_version() {
+        let migrations = migrations();
+        let expected = V3 {
+            name: "a".into(),
+            visits: 0,
+            admin: false,
+        };
+
+        // Version 1 has no header
+        let v1 = br#"{"name":"a"}"#;
+        assert_eq!(migrations.decode(v1).unwrap(), (expected, true));
+
+        let mut v2 = vec![HEADER_MAGIC, 2, 0, 0, 0];
+        v2.extend_from_slice(br#"{"name":"a","visits":4}"#);
+        let (value, upcast) = migrations.decode(&v2).unwrap();
+        assert!(upcast);
+        assert_eq!(value.visits, 4);
+
+        let current = migrations.encode(&value).unwrap();
+        assert_eq!(&current[..HEADER_LEN], &[HEADER_MAGIC, 3, 0, 0, 0]);
+        assert_eq!(migrations.decode(&current).unwrap(), (value, false));
+    }
+
+    #[test]
+    fn test_unknown_versions_are_rejected() {
+        let migrations = migrations();
+        for bytes in [
+            &[HEADER_MAGIC, 4, 0, 0, 0, b'{', b'}'][..],
+            &[HEADER_MAGIC, 0, 0, 0, 0],
+            &[HEADER_MAGIC, 3],
+        ] {
+            assert!(matches!(
+                migrations.decode(bytes),
+                Err(Error::Serialization(_))
+            ));
+        }
+    }
+}
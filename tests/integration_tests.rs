@@ -5,8 +5,12 @@
 //!
 //! DATABASE_URL=postgresql://localhost/pgkv_test cargo test
 
-use pgkv::{CasResult, Config, ScanOptions, Store, TableType, TtlCleanupStrategy};
-use std::time::Duration;
+use pgkv::{
+    Batch, BatchResult, CachedStore, CasResult, Config, Freshness, HistoryOp, IndexExpr, IndexInfo,
+    IsolationLevel, KeyType, KeyValue, LocalCacheConfig, OpResult, Precondition, ScanOptions,
+    SetOptions, Store, TableType, TtlCleanupStrategy, TxOptions, ValueType, VersionResult,
+};
+use std::time::{Duration, SystemTime};
 
 /// Get database URL from environment.
 fn get_database_url() -> Option<String> {
@@ -179,6 +183,106 @@ fn test_expiration() {
     assert!(value.is_none());
 }
 
+#[test]
+fn test_set_with_conditions() {
+    let Some(store) = create_test_store("set_with_conditions") else {
+        return;
+    };
+
+    // if_exists doesn't create the key
+    let outcome = store
+        .set_with("key", b"v1", SetOptions::new().if_exists())
+        .unwrap();
+    assert!(!outcome.written);
+    assert_eq!(outcome.version, None);
+    assert!(!store.exists("key").unwrap());
+
+    // if_not_exists creates it once
+    let outcome = store
+        .set_with("key", b"v1", SetOptions::new().if_not_exists())
+        .unwrap();
+    assert!(outcome.written);
+    assert!(outcome.version.is_some());
+
+    let outcome = store
+        .set_with(
+            "key",
+            b"v2",
+            SetOptions::new().if_not_exists().return_previous(),
+        )
+        .unwrap();
+    assert!(!outcome.written);
+    assert_eq!(outcome.previous, Some(b"v1".to_vec()));
+    assert_eq!(store.get("key").unwrap(), Some(b"v1".to_vec()));
+
+    let outcome = store
+        .set_with(
+            "key",
+            b"v3",
+            SetOptions::new().if_exists().return_previous(),
+        )
+        .unwrap();
+    assert!(outcome.written);
+    assert_eq!(outcome.previous, Some(b"v1".to_vec()));
+    assert_eq!(store.get("key").unwrap(), Some(b"v3".to_vec()));
+
+    // return_previous on a new key
+    let outcome = store
+        .set_with("new", b"value", SetOptions::new().return_previous())
+        .unwrap();
+    assert!(outcome.written);
+    assert_eq!(outcome.previous, None);
+}
+
+#[test]
+fn test_set_with_ttl_options() {
+    let Some(store) = create_test_store("set_with_ttl") else {
+        return;
+    };
+
+    store
+        .set_with("key", b"v1", SetOptions::new().ttl(Duration::from_secs(60)))
+        .unwrap();
+    let ttl = store.ttl("key").unwrap().unwrap();
+
+    // keep_ttl preserves the expiration
+    store
+        .set_with("key", b"v2", SetOptions::new().keep_ttl())
+        .unwrap();
+    assert!(store.ttl("key").unwrap().unwrap() <= ttl);
+    assert_eq!(store.get("key").unwrap(), Some(b"v2".to_vec()));
+
+    // The default removes it, like set()
+    store.set_with("key", b"v3", SetOptions::new()).unwrap();
+    assert!(store.ttl("key").unwrap().is_none());
+
+    let at = SystemTime::now() + Duration::from_secs(3600);
+    store
+        .set_with("key", b"v4", SetOptions::new().if_exists().expires_at(at))
+        .unwrap();
+    assert!(store.ttl("key").unwrap().unwrap() > Duration::from_secs(3500));
+
+    // An expired key counts as missing
+    store
+        .set_ex("expired", b"old", Duration::from_millis(50))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    let outcome = store
+        .set_with("expired", b"v", SetOptions::new().if_exists())
+        .unwrap();
+    assert!(!outcome.written);
+    let outcome = store
+        .set_with(
+            "expired",
+            b"new",
+            SetOptions::new().if_not_exists().keep_ttl(),
+        )
+        .unwrap();
+    assert!(outcome.written);
+    assert!(store.ttl("expired").unwrap().is_none());
+    assert_eq!(store.get("expired").unwrap(), Some(b"new".to_vec()));
+}
+
 // ==================== Batch Operations ====================
 
 #[test]
@@ -224,6 +328,171 @@ fn test_delete_many() {
     assert!(store.exists("key3").unwrap());
 }
 
+#[test]
+fn test_execute_batch() {
+    let Some(store) = create_test_store("execute_batch") else {
+        return;
+    };
+
+    store.set("account", b"100").unwrap();
+    store.set("stale", b"x").unwrap();
+    let (_, version) = store.get_versioned("account").unwrap().unwrap();
+
+    let batch = Batch::new()
+        .if_version("account", version)
+        .if_equals("stale", b"x")
+        .if_absent("transfer:1")
+        .put("account", b"90")
+        .put_ex("transfer:1", b"done", Duration::from_secs(60))
+        .delete("stale")
+        .increment("transfers", 1);
+
+    let BatchResult::Committed(results) = store.execute_batch(&batch).unwrap() else {
+        panic!("batch should commit");
+    };
+    assert_eq!(results.len(), 4);
+    assert!(matches!(results[0], OpResult::Put { version: v } if v > version));
+    assert_eq!(results[2], OpResult::Delete { existed: true });
+    assert_eq!(results[3], OpResult::Increment { value: 1 });
+
+    assert_eq!(store.get("account").unwrap(), Some(b"90".to_vec()));
+    assert!(store.ttl("transfer:1").unwrap().is_some());
+    assert!(!store.exists("stale").unwrap());
+
+    // Replaying the same batch fails on the first precondition
+    let result = store.execute_batch(&batch).unwrap();
+    assert_eq!(
+        result,
+        BatchResult::Failed {
+            precondition: Precondition::Version {
+                key: "account".into(),
+                version,
+            },
+        }
+    );
+}
+
+#[test]
+fn test_execute_batch_failed_precondition_writes_nothing() {
+    let Some(store) = create_test_store("execute_batch_failed") else {
+        return;
+    };
+
+    store.set("existing", b"value").unwrap();
+
+    let result = store
+        .execute_batch(
+            &Batch::new()
+                .put("a", b"1")
+                .delete("existing")
+                .if_absent("existing"),
+        )
+        .unwrap();
+    assert!(!result.is_committed());
+    assert!(!store.exists("a").unwrap());
+    assert!(store.exists("existing").unwrap());
+}
+
+#[test]
+fn test_execute_batch_sequences_writes() {
+    let Some(store) = create_test_store("execute_batch_sequence") else {
+        return;
+    };
+
+    store
+        .set_ex("expired", b"x", Duration::from_millis(1))
+        .unwrap();
+    store.set("text", b"abc").unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+
+    // Writes to the same key apply in order; expired keys count as absent
+    let result = store
+        .execute_batch(
+            &Batch::new()
+                .if_absent("expired")
+                .put("n", b"5")
+                .increment("n", 2)
+                .delete("n")
+                .increment("n", 1)
+                .put("o'quote\\", b"\x00'"),
+        )
+        .unwrap();
+    let BatchResult::Committed(results) = result else {
+        panic!("batch should commit");
+    };
+    assert_eq!(results[1], OpResult::Increment { value: 7 });
+    assert_eq!(results[2], OpResult::Delete { existed: true });
+    assert_eq!(results[3], OpResult::Increment { value: 1 });
+    assert_eq!(store.get("n").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.get("o'quote\\").unwrap(), Some(b"\x00'".to_vec()));
+
+    // The failing increment is reported, and nothing is written
+    let err = store
+        .execute_batch(&Batch::new().put("n", b"9").increment("text", 1))
+        .unwrap_err();
+    assert!(matches!(err, pgkv::Error::NotNumeric { ref key } if key == "text"));
+    assert_eq!(store.get("n").unwrap(), Some(b"1".to_vec()));
+
+    // Inside a transaction, a failed precondition only rolls back the batch
+    store
+        .transaction(|s| {
+            s.set("before", b"1")?;
+            let result = s.execute_batch(&Batch::new().if_absent("n").put("n", b"2"))?;
+            assert!(!result.is_committed());
+            s.set("after", b"1")?;
+            Ok(())
+        })
+        .unwrap();
+    assert!(store.exists("before").unwrap());
+    assert!(store.exists("after").unwrap());
+    assert_eq!(store.get("n").unwrap(), Some(b"1".to_vec()));
+}
+
+#[test]
+fn test_execute_batch_concurrent() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let Some(store) = create_test_store("execute_batch_concurrent") else {
+        return;
+    };
+    store.set("counter", b"0").unwrap();
+    drop(store);
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let url = url.clone();
+            std::thread::spawn(move || {
+                let config = Config::new(url).table_name("test_execute_batch_concurrent");
+                let store = Store::with_config(config).unwrap();
+                for _ in 0..10 {
+                    loop {
+                        let (value, version) = store.get_versioned("counter").unwrap().unwrap();
+                        let next = std::str::from_utf8(&value).unwrap().parse::<i64>().unwrap() + 1;
+                        let batch = Batch::new()
+                            .if_version("counter", version)
+                            .put("counter", next.to_string());
+                        match store.execute_batch(&batch) {
+                            Ok(result) if result.is_committed() => break,
+                            Ok(_) => continue,
+                            Err(e) if e.is_conflict() => continue,
+                            Err(e) => panic!("unexpected error: {}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let config = Config::new(url).table_name("test_execute_batch_concurrent");
+    let store = Store::with_config(config).unwrap();
+    assert_eq!(store.get_string("counter").unwrap(), Some("40".into()));
+}
+
 // ==================== Atomic Operations ====================
 
 #[test]
@@ -249,6 +518,90 @@ fn test_increment() {
     assert_eq!(value, -4);
 }
 
+#[test]
+fn test_increment_not_numeric() {
+    let Some(store) = create_test_store("increment_not_numeric") else {
+        return;
+    };
+
+    store.set("name", b"alice").unwrap();
+    let err = store.increment("name", 1).unwrap_err();
+    assert!(matches!(err, pgkv::Error::NotNumeric { ref key } if key == "name"));
+    assert!(matches!(
+        store.increment_float("name", 1.0),
+        Err(pgkv::Error::NotNumeric { .. })
+    ));
+    assert_eq!(store.get_string("name").unwrap(), Some("alice".into()));
+}
+
+#[test]
+fn test_increment_float() {
+    let Some(store) = create_test_store("increment_float") else {
+        return;
+    };
+
+    assert_eq!(store.increment_float("total", 1.5).unwrap(), 1.5);
+    assert_eq!(store.increment_float("total", 2.25).unwrap(), 3.75);
+    assert_eq!(store.get_string("total").unwrap(), Some("3.75".into()));
+
+    // Integer counters can be incremented as floats
+    store.increment("count", 2).unwrap();
+    assert_eq!(store.increment_float("count", 0.5).unwrap(), 2.5);
+
+    assert!(store.increment_float("total", f64::NAN).is_err());
+}
+
+#[test]
+fn test_increment_with_ttl() {
+    let Some(store) = create_test_store("increment_with_ttl") else {
+        return;
+    };
+
+    let ttl = Duration::from_millis(200);
+    assert_eq!(store.increment_with_ttl("window", 1, ttl).unwrap(), 1);
+    let first_ttl = store.ttl("window").unwrap().unwrap();
+
+    // TTL is not extended by later increments
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(store.increment_with_ttl("window", 1, ttl).unwrap(), 2);
+    assert!(store.ttl("window").unwrap().unwrap() < first_ttl);
+
+    // Once expired, the counter restarts with a fresh TTL
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.increment_with_ttl("window", 1, ttl).unwrap(), 1);
+    assert!(store.ttl("window").unwrap().is_some());
+}
+
+#[test]
+fn test_increment_clamped() {
+    let Some(store) = create_test_store("increment_clamped") else {
+        return;
+    };
+
+    assert_eq!(store.increment_clamped("tokens", 15, 0, 10).unwrap(), 10);
+    assert_eq!(store.increment_clamped("tokens", -4, 0, 10).unwrap(), 6);
+    assert_eq!(store.increment_clamped("tokens", -20, 0, 10).unwrap(), 0);
+    assert!(store.increment_clamped("tokens", 1, 10, 0).is_err());
+}
+
+#[test]
+fn test_increment_many() {
+    let Some(store) = create_test_store("increment_many") else {
+        return;
+    };
+
+    store.increment("b", 10).unwrap();
+    let values = store
+        .increment_many(&[("a", 1), ("b", 2), ("a", 3)])
+        .unwrap();
+    assert_eq!(values, vec![1, 12, 4]);
+
+    // A non-numeric value rolls back the whole batch
+    store.set("c", b"text").unwrap();
+    assert!(store.increment_many(&[("a", 1), ("c", 1)]).is_err());
+    assert_eq!(store.get_string("a").unwrap(), Some("4".into()));
+}
+
 #[test]
 fn test_compare_and_swap() {
     let Some(store) = create_test_store("cas") else {
@@ -319,198 +672,3183 @@ fn test_get_and_delete() {
     assert!(value.is_none());
 }
 
-// ==================== Scanning Operations ====================
-
 #[test]
-fn test_keys() {
-    let Some(store) = create_test_store("keys") else {
+fn test_get_or_set_with() {
+    let Some(store) = create_test_store("get_or_set_with") else {
         return;
     };
 
-    store.set("user:1", b"alice").unwrap();
-    store.set("user:2", b"bob").unwrap();
-    store.set("session:1", b"data").unwrap();
+    // Loader runs on a miss and the value is stored with a TTL
+    let value = store
+        .get_or_set_with("key", Duration::from_secs(60), || Ok(b"computed".to_vec()))
+        .unwrap();
+    assert_eq!(value, b"computed".to_vec());
+    assert!(store.ttl("key").unwrap().is_some());
 
-    // All keys
-    let keys = store.keys(ScanOptions::new()).unwrap();
-    assert_eq!(keys.len(), 3);
+    // Loader is not called on a hit
+    let value = store
+        .get_or_set_with("key", Duration::from_secs(60), || {
+            panic!("loader should not run for an existing key")
+        })
+        .unwrap();
+    assert_eq!(value, b"computed".to_vec());
 
-    // Keys with prefix
-    let keys = store.keys(ScanOptions::new().prefix("user:")).unwrap();
-    assert_eq!(keys.len(), 2);
+    // Loader errors are propagated and nothing is stored
+    let result = store.get_or_set_with("failing", Duration::from_secs(60), || {
+        Err(pgkv::Error::Config("load failed".into()))
+    });
+    assert!(result.is_err());
+    assert!(!store.exists("failing").unwrap());
 
-    // Keys with limit
-    let keys = store.keys(ScanOptions::new().limit(2)).unwrap();
-    assert_eq!(keys.len(), 2);
+    // The advisory lock is released after a failed load
+    let value = store
+        .get_or_set_with("failing", Duration::from_secs(60), || Ok(b"ok".to_vec()))
+        .unwrap();
+    assert_eq!(value, b"ok".to_vec());
 }
 
 #[test]
-fn test_scan() {
-    let Some(store) = create_test_store("scan") else {
+fn test_get_or_set_with_loader_panic_releases_lock() {
+    let Some(url) = get_database_url() else {
         return;
     };
-
-    store.set("user:1", b"alice").unwrap();
-    store.set("user:2", b"bob").unwrap();
-    store.set("session:1", b"data").unwrap();
-
-    let items = store.scan(ScanOptions::new().prefix("user:")).unwrap();
-    assert_eq!(items.len(), 2);
-
-    // Verify values
-    for kv in items {
-        assert!(kv.key.starts_with("user:"));
-    }
-}
-
-#[test]
-fn test_count() {
-    let Some(store) = create_test_store("count") else {
+    let Some(store) = create_test_store("loader_panic") else {
         return;
     };
+    let soft = Duration::from_secs(1);
+    let hard = Duration::from_secs(60);
+
+    // Loads `key` on another connection, failing if it waits on the lock
+    let load_elsewhere = |key: &'static str| {
+        let url = url.clone();
+        let (done, finished) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let config = Config::new(url).table_name("test_loader_panic");
+            let other = Store::with_config(config).unwrap();
+            let value = other
+                .get_or_set_with(key, hard, || Ok(b"loaded".to_vec()))
+                .unwrap();
+            done.send(value).unwrap();
+        });
+        finished
+            .recv_timeout(Duration::from_secs(10))
+            .expect("advisory lock was not released")
+    };
+    let catch = |f: &dyn Fn()| std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).is_err();
 
-    store.set("user:1", b"alice").unwrap();
-    store.set("user:2", b"bob").unwrap();
-    store.set("session:1", b"data").unwrap();
-
-    let count = store.count(ScanOptions::new()).unwrap();
-    assert_eq!(count, 3);
+    assert!(catch(&|| {
+        let _ = store.get_or_set_with("a", hard, || panic!("loader failed"));
+    }));
+    assert_eq!(load_elsewhere("a"), b"loaded".to_vec());
 
-    let count = store.count(ScanOptions::new().prefix("user:")).unwrap();
-    assert_eq!(count, 2);
+    assert!(catch(&|| {
+        let _ = store.get_or_revalidate("b", soft, hard, || panic!("loader failed"));
+    }));
+    assert_eq!(load_elsewhere("b"), b"loaded".to_vec());
 }
 
 #[test]
-fn test_delete_prefix() {
-    let Some(store) = create_test_store("delete_prefix") else {
+fn test_get_or_set_with_concurrent() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let Some(url) = get_database_url() else {
         return;
     };
+    let Some(store) = create_test_store("get_or_set_with_concurrent") else {
+        return;
+    };
+    drop(store);
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let url = url.clone();
+            let calls = Arc::clone(&calls);
+            std::thread::spawn(move || {
+                let config = Config::new(url).table_name("test_get_or_set_with_concurrent");
+                let store = Store::with_config(config).unwrap();
+                store
+                    .get_or_set_with("shared", Duration::from_secs(60), || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(100));
+                        Ok(b"computed".to_vec())
+                    })
+                    .unwrap()
+            })
+        })
+        .collect();
 
-    store.set("temp:1", b"data1").unwrap();
-    store.set("temp:2", b"data2").unwrap();
-    store.set("perm:1", b"data3").unwrap();
-
-    let deleted = store.delete_prefix("temp:").unwrap();
-    assert_eq!(deleted, 2);
-
-    assert!(!store.exists("temp:1").unwrap());
-    assert!(!store.exists("temp:2").unwrap());
-    assert!(store.exists("perm:1").unwrap());
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), b"computed".to_vec());
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
 }
 
-// ==================== Entry Operations ====================
-
 #[test]
-fn test_get_entry() {
-    let Some(store) = create_test_store("get_entry") else {
+fn test_get_with_staleness() {
+    let Some(store) = create_test_store("get_with_staleness") else {
         return;
     };
 
     store
-        .set_ex("key", b"value", Duration::from_secs(60))
+        .set_ex_soft(
+            "key",
+            b"value",
+            Duration::from_millis(50),
+            Duration::from_secs(60),
+        )
         .unwrap();
 
-    let entry = store.get_entry("key").unwrap().unwrap();
-    assert_eq!(entry.key, "key");
-    assert_eq!(entry.value, b"value".to_vec());
-    assert!(entry.expires_at.is_some());
-    assert!(!entry.is_expired());
+    let (value, freshness) = store.get_with_staleness("key").unwrap().unwrap();
+    assert_eq!(value, b"value".to_vec());
+    assert_eq!(freshness, Freshness::Fresh);
 
-    let ttl = entry.ttl().unwrap();
-    assert!(ttl <= Duration::from_secs(60));
-}
+    std::thread::sleep(Duration::from_millis(100));
 
-// ==================== Transaction Operations ====================
+    // Past the soft expiry the value is still served, but marked stale
+    let (value, freshness) = store.get_with_staleness("key").unwrap().unwrap();
+    assert_eq!(value, b"value".to_vec());
+    assert_eq!(freshness, Freshness::Stale);
+    assert_eq!(store.get("key").unwrap(), Some(b"value".to_vec()));
+
+    // Plain sets have no soft expiry
+    store.set("plain", b"value").unwrap();
+    let (_, freshness) = store.get_with_staleness("plain").unwrap().unwrap();
+    assert_eq!(freshness, Freshness::Fresh);
+    assert!(store.get_with_staleness("missing").unwrap().is_none());
+}
 
 #[test]
-fn test_transaction_commit() {
-    let Some(store) = create_test_store("tx_commit") else {
+fn test_get_or_revalidate() {
+    let Some(store) = create_test_store("get_or_revalidate") else {
         return;
     };
 
-    store
-        .transaction(|s| {
-            s.set("key1", b"value1")?;
-            s.set("key2", b"value2")?;
-            Ok(())
+    let soft = Duration::from_millis(50);
+    let hard = Duration::from_secs(60);
+
+    // Missing key is loaded
+    let (value, freshness) = store
+        .get_or_revalidate("key", soft, hard, || Ok(b"v1".to_vec()))
+        .unwrap();
+    assert_eq!(value, b"v1".to_vec());
+    assert_eq!(freshness, Freshness::Fresh);
+
+    // Fresh key is served without calling the loader
+    let (value, _) = store
+        .get_or_revalidate("key", soft, hard, || panic!("loader should not run"))
+        .unwrap();
+    assert_eq!(value, b"v1".to_vec());
+
+    std::thread::sleep(Duration::from_millis(100));
+
+    // A failed refresh serves the stale value and keeps it
+    let (value, freshness) = store
+        .get_or_revalidate("key", soft, hard, || {
+            Err(pgkv::Error::Query("backend down".into()))
         })
         .unwrap();
+    assert_eq!(value, b"v1".to_vec());
+    assert_eq!(freshness, Freshness::Stale);
 
-    assert!(store.exists("key1").unwrap());
-    assert!(store.exists("key2").unwrap());
+    // Stale key is refreshed by the only caller
+    let (value, freshness) = store
+        .get_or_revalidate("key", soft, hard, || Ok(b"v2".to_vec()))
+        .unwrap();
+    assert_eq!(value, b"v2".to_vec());
+    assert_eq!(freshness, Freshness::Fresh);
+
+    // Without a stale value to fall back on, the loader's error is returned
+    assert!(
+        store
+            .get_or_revalidate("missing", soft, hard, || {
+                Err(pgkv::Error::Query("backend down".into()))
+            })
+            .is_err()
+    );
 }
 
 #[test]
-fn test_transaction_rollback() {
-    let Some(store) = create_test_store("tx_rollback") else {
+fn test_get_or_revalidate_serves_stale_during_refresh() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let Some(store) = create_test_store("get_or_revalidate_stale") else {
         return;
     };
 
-    let result: Result<(), pgkv::Error> = store.transaction(|s| {
-        s.set("key1", b"value1")?;
-        // Simulate error
-        Err(pgkv::Error::Config("test error".into()))
+    let soft = Duration::from_millis(50);
+    let hard = Duration::from_secs(60);
+
+    store.set_ex_soft("key", b"old", soft, hard).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+
+    let refresher = std::thread::spawn(move || {
+        let config = Config::new(url).table_name("test_get_or_revalidate_stale");
+        let store = Store::with_config(config).unwrap();
+        store
+            .get_or_revalidate("key", soft, hard, || {
+                std::thread::sleep(Duration::from_millis(300));
+                Ok(b"new".to_vec())
+            })
+            .unwrap()
     });
 
-    assert!(result.is_err());
-    // Key should not exist due to rollback
-    assert!(!store.exists("key1").unwrap());
+    // While the other connection refreshes, the stale value is returned immediately
+    std::thread::sleep(Duration::from_millis(100));
+    let (value, freshness) = store
+        .get_or_revalidate("key", soft, hard, || panic!("only one refresh should run"))
+        .unwrap();
+    assert_eq!(value, b"old".to_vec());
+    assert_eq!(freshness, Freshness::Stale);
+
+    assert_eq!(refresher.join().unwrap().0, b"new".to_vec());
+    assert_eq!(store.get("key").unwrap(), Some(b"new".to_vec()));
 }
 
-// ==================== Maintenance Operations ====================
+// ==================== Byte Operations ====================
 
 #[test]
-fn test_cleanup_expired() {
-    let Some(store) = create_test_store("cleanup") else {
+fn test_append_prepend() {
+    let Some(store) = create_test_store("append_prepend") else {
         return;
     };
 
-    // Create some expired keys
-    store
-        .set_ex("expired1", b"value", Duration::from_millis(1))
-        .unwrap();
+    assert_eq!(store.append("log", b"b").unwrap(), 1);
+    assert_eq!(store.append("log", b"cd").unwrap(), 3);
+    assert_eq!(store.prepend("log", b"a").unwrap(), 4);
+    assert_eq!(store.get("log").unwrap(), Some(b"abcd".to_vec()));
+    assert_eq!(store.strlen("log").unwrap(), 4);
+    assert_eq!(store.strlen("missing").unwrap(), 0);
+
+    // TTL is preserved, and an expired value starts over
     store
-        .set_ex("expired2", b"value", Duration::from_millis(1))
+        .set_ex("temp", b"old", Duration::from_millis(100))
         .unwrap();
-    store.set("permanent", b"value").unwrap();
+    store.append("temp", b"+").unwrap();
+    assert!(store.ttl("temp").unwrap().is_some());
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.append("temp", b"new").unwrap(), 3);
+    assert_eq!(store.get("temp").unwrap(), Some(b"new".to_vec()));
+    assert!(store.ttl("temp").unwrap().is_none());
+}
 
-    std::thread::sleep(Duration::from_millis(50));
+#[test]
+fn test_append_respects_max_value_size() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let Some(store) = create_test_store("append_max_size") else {
+        return;
+    };
+    drop(store);
 
-    let deleted = store.cleanup_expired().unwrap();
-    assert_eq!(deleted, 2);
+    let config = Config::new(url)
+        .table_name("test_append_max_size")
+        .max_value_size(4);
+    let store = Store::with_config(config).unwrap();
 
-    assert!(store.exists("permanent").unwrap());
+    store.append("key", b"abc").unwrap();
+    assert!(store.append("key", b"de").is_err());
+    assert!(store.set_range("key", 3, b"de").is_err());
+    assert_eq!(store.get("key").unwrap(), Some(b"abc".to_vec()));
 }
 
 #[test]
-fn test_clear() {
-    let Some(store) = create_test_store("clear") else {
+fn test_get_range() {
+    let Some(store) = create_test_store("get_range") else {
         return;
     };
 
-    store.set("key1", b"value1").unwrap();
-    store.set("key2", b"value2").unwrap();
+    store.set("greeting", b"Hello, world").unwrap();
+    assert_eq!(
+        store.get_range("greeting", 0, 4).unwrap(),
+        Some(b"Hello".to_vec())
+    );
+    assert_eq!(
+        store.get_range("greeting", -5, -1).unwrap(),
+        Some(b"world".to_vec())
+    );
+    assert_eq!(
+        store.get_range("greeting", 0, -1).unwrap(),
+        Some(b"Hello, world".to_vec())
+    );
+    assert_eq!(
+        store.get_range("greeting", 7, 100).unwrap(),
+        Some(b"world".to_vec())
+    );
+    assert_eq!(store.get_range("greeting", 5, 2).unwrap(), Some(vec![]));
+
+    // Offsets beyond the range of an int are clamped, not cast
+    assert_eq!(
+        store.get_range("greeting", i64::MAX, i64::MAX).unwrap(),
+        Some(vec![])
+    );
+    assert_eq!(
+        store.get_range("greeting", i64::MIN, i64::MAX).unwrap(),
+        Some(b"Hello, world".to_vec())
+    );
+    assert_eq!(
+        store.get_range("greeting", 0, i64::MIN).unwrap(),
+        Some(vec![])
+    );
+    assert_eq!(store.get_range("missing", 0, -1).unwrap(), None);
+}
 
-    let deleted = store.clear().unwrap();
+#[test]
+fn test_set_range() {
+    let Some(store) = create_test_store("set_range") else {
+        return;
+    };
+
+    store.set("greeting", b"Hello, world").unwrap();
+    assert_eq!(store.set_range("greeting", 7, b"there").unwrap(), 12);
+    assert_eq!(
+        store.get("greeting").unwrap(),
+        Some(b"Hello, there".to_vec())
+    );
+
+    // Extends the value
+    assert_eq!(store.set_range("greeting", 12, b"!").unwrap(), 13);
+
+    // Pads with zero bytes past the end, including for new keys
+    assert_eq!(store.set_range("greeting", 15, b"?").unwrap(), 16);
+    assert_eq!(
+        store.get_range("greeting", 12, -1).unwrap(),
+        Some(b"!\0\0?".to_vec())
+    );
+    assert_eq!(store.set_range("new", 2, b"x").unwrap(), 3);
+    assert_eq!(store.get("new").unwrap(), Some(b"\0\0x".to_vec()));
+}
+
+// ==================== Key Operations ====================
+
+#[test]
+fn test_rename() {
+    let Some(store) = create_test_store("rename") else {
+        return;
+    };
+
+    store
+        .set_ex("src", b"value", Duration::from_secs(60))
+        .unwrap();
+    let created_at = store.get_entry("src").unwrap().unwrap().created_at;
+    store.set("dst", b"old").unwrap();
+
+    store.rename("src", "dst").unwrap();
+    assert!(!store.exists("src").unwrap());
+
+    let entry = store.get_entry("dst").unwrap().unwrap();
+    assert_eq!(entry.value, b"value");
+    assert!(entry.expires_at.is_some());
+    assert_eq!(entry.created_at, created_at);
+
+    assert!(store.rename("missing", "dst").unwrap_err().is_not_found());
+    assert!(store.rename("dst", "dst").is_err());
+}
+
+#[test]
+fn test_rename_nx() {
+    let Some(store) = create_test_store("rename_nx") else {
+        return;
+    };
+
+    store.set("a", b"1").unwrap();
+    store.set("b", b"2").unwrap();
+
+    assert!(!store.rename_nx("a", "b").unwrap());
+    assert_eq!(store.get("a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(store.get("b").unwrap(), Some(b"2".to_vec()));
+
+    assert!(store.rename_nx("a", "c").unwrap());
+    assert!(!store.exists("a").unwrap());
+    assert_eq!(store.get("c").unwrap(), Some(b"1".to_vec()));
+}
+
+#[test]
+fn test_copy() {
+    let Some(store) = create_test_store("copy") else {
+        return;
+    };
+
+    store
+        .set_with_tags("src", b"value", None, &["tag"])
+        .unwrap();
+    store.set("dst", b"old").unwrap();
+
+    assert!(!store.copy("src", "dst", false).unwrap());
+    assert_eq!(store.get("dst").unwrap(), Some(b"old".to_vec()));
+
+    assert!(store.copy("src", "dst", true).unwrap());
+    assert!(store.copy("src", "new", false).unwrap());
+    assert_eq!(store.get("src").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(store.get("dst").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(store.get_entry("new").unwrap().unwrap().tags, vec!["tag"]);
+
+    assert!(!store.copy("missing", "other", true).unwrap());
+}
+
+#[test]
+fn test_move_to() {
+    let Some(store) = create_test_store("move_src") else {
+        return;
+    };
+    let Some(archive) = create_test_store("move_dst") else {
+        return;
+    };
+
+    store
+        .set_ex("job", b"done", Duration::from_secs(60))
+        .unwrap();
+    assert!(store.move_to("job", "test_move_dst").unwrap());
+    assert!(!store.exists("job").unwrap());
+    assert_eq!(archive.get("job").unwrap(), Some(b"done".to_vec()));
+    assert!(archive.ttl("job").unwrap().is_some());
+
+    // Existing destination keys are not overwritten
+    store.set("job", b"again").unwrap();
+    assert!(!store.move_to("job", "test_move_dst").unwrap());
+    assert!(store.exists("job").unwrap());
+    assert!(!store.move_to("missing", "test_move_dst").unwrap());
+}
+
+// ==================== Versioned Operations ====================
+
+#[test]
+fn test_versions_increase_on_write() {
+    let Some(store) = create_test_store("versions") else {
+        return;
+    };
+
+    store.set("key", b"v1").unwrap();
+    let (_, v1) = store.get_versioned("key").unwrap().unwrap();
+
+    store.set("key", b"v2").unwrap();
+    let (value, v2) = store.get_versioned("key").unwrap().unwrap();
+    assert_eq!(value, b"v2".to_vec());
+    assert!(v2 > v1);
+
+    store.increment("counter", 1).unwrap();
+    let c1 = store.get_entry("counter").unwrap().unwrap().version;
+    store.increment("counter", 1).unwrap();
+    let c2 = store.get_entry("counter").unwrap().unwrap().version;
+    assert!(c2 > c1);
+
+    // Re-creating a deleted key never reuses an old version
+    store.delete("key").unwrap();
+    store.set("key", b"v3").unwrap();
+    let (_, v3) = store.get_versioned("key").unwrap().unwrap();
+    assert!(v3 > v2);
+
+    assert!(store.get_versioned("missing").unwrap().is_none());
+}
+
+#[test]
+fn test_set_if_version() {
+    let Some(store) = create_test_store("set_if_version") else {
+        return;
+    };
+
+    // Version 0 means the key must not exist
+    let VersionResult::Success { version } = store.set_if_version("key", b"v1", 0).unwrap() else {
+        panic!("expected success");
+    };
+    assert_eq!(
+        store.set_if_version("key", b"v1", 0).unwrap(),
+        VersionResult::Mismatch { current: version }
+    );
+
+    // Stale version is rejected with the current version
+    let result = store.set_if_version("key", b"v2", version + 1000).unwrap();
+    assert_eq!(result, VersionResult::Mismatch { current: version });
+
+    // Matching version succeeds and bumps the version
+    let result = store.set_if_version("key", b"v2", version).unwrap();
+    let VersionResult::Success {
+        version: new_version,
+    } = result
+    else {
+        panic!("expected success");
+    };
+    assert!(new_version > version);
+    assert_eq!(store.get("key").unwrap(), Some(b"v2".to_vec()));
+
+    // The old version can no longer be used
+    assert!(
+        store
+            .set_if_version("key", b"v3", version)
+            .unwrap()
+            .is_mismatch()
+    );
+
+    assert_eq!(
+        store.set_if_version("missing", b"v", 1).unwrap(),
+        VersionResult::NotFound
+    );
+
+    // An expired key that hasn't been cleaned up yet counts as absent
+    store
+        .set_ex("expired", b"old", Duration::from_millis(1))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(
+        store
+            .set_if_version("expired", b"new", 0)
+            .unwrap()
+            .is_success()
+    );
+    assert_eq!(store.get("expired").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(store.ttl("expired").unwrap(), None);
+}
+
+#[test]
+fn test_delete_if_version() {
+    let Some(store) = create_test_store("delete_if_version") else {
+        return;
+    };
+
+    store.set("key", b"value").unwrap();
+    let (_, version) = store.get_versioned("key").unwrap().unwrap();
+
+    assert!(
+        store
+            .delete_if_version("key", version + 1000)
+            .unwrap()
+            .is_mismatch()
+    );
+    assert!(store.exists("key").unwrap());
+
+    assert_eq!(
+        store.delete_if_version("key", version).unwrap(),
+        VersionResult::Success { version }
+    );
+    assert!(!store.exists("key").unwrap());
+
+    assert_eq!(
+        store.delete_if_version("key", version).unwrap(),
+        VersionResult::NotFound
+    );
+}
+
+// ==================== Scanning Operations ====================
+
+#[test]
+fn test_keys() {
+    let Some(store) = create_test_store("keys") else {
+        return;
+    };
+
+    store.set("user:1", b"alice").unwrap();
+    store.set("user:2", b"bob").unwrap();
+    store.set("session:1", b"data").unwrap();
+
+    // All keys
+    let keys = store.keys(ScanOptions::new()).unwrap();
+    assert_eq!(keys.len(), 3);
+
+    // Keys with prefix
+    let keys = store.keys(ScanOptions::new().prefix("user:")).unwrap();
+    assert_eq!(keys.len(), 2);
+
+    // Keys with limit
+    let keys = store.keys(ScanOptions::new().limit(2)).unwrap();
+    assert_eq!(keys.len(), 2);
+}
+
+#[test]
+fn test_scan() {
+    let Some(store) = create_test_store("scan") else {
+        return;
+    };
+
+    store.set("user:1", b"alice").unwrap();
+    store.set("user:2", b"bob").unwrap();
+    store.set("session:1", b"data").unwrap();
+
+    let items = store.scan(ScanOptions::new().prefix("user:")).unwrap();
+    assert_eq!(items.len(), 2);
+
+    // Verify values
+    for kv in items {
+        assert!(kv.key.starts_with("user:"));
+    }
+}
+
+#[test]
+fn test_count() {
+    let Some(store) = create_test_store("count") else {
+        return;
+    };
+
+    store.set("user:1", b"alice").unwrap();
+    store.set("user:2", b"bob").unwrap();
+    store.set("session:1", b"data").unwrap();
+
+    let count = store.count(ScanOptions::new()).unwrap();
+    assert_eq!(count, 3);
+
+    let count = store.count(ScanOptions::new().prefix("user:")).unwrap();
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn test_delete_prefix() {
+    let Some(store) = create_test_store("delete_prefix") else {
+        return;
+    };
+
+    store.set("temp:1", b"data1").unwrap();
+    store.set("temp:2", b"data2").unwrap();
+    store.set("perm:1", b"data3").unwrap();
+
+    let deleted = store.delete_prefix("temp:").unwrap();
     assert_eq!(deleted, 2);
 
-    assert_eq!(store.count(ScanOptions::new()).unwrap(), 0);
+    assert!(!store.exists("temp:1").unwrap());
+    assert!(!store.exists("temp:2").unwrap());
+    assert!(store.exists("perm:1").unwrap());
 }
 
+// ==================== Tag Operations ====================
+
 #[test]
-fn test_stats() {
-    let Some(store) = create_test_store("stats") else {
+fn test_set_with_tags_and_invalidate() {
+    let Some(store) = create_test_store("tags") else {
         return;
     };
 
-    store.set("key1", b"value1").unwrap();
-    store.set("key2", b"longer_value").unwrap();
+    store
+        .set_with_tags("page:1", b"a", None, &["product:42", "category:7"])
+        .unwrap();
+    store
+        .set_with_tags(
+            "page:2",
+            b"b",
+            Some(Duration::from_secs(60)),
+            &["category:7"],
+        )
+        .unwrap();
+    store.set("page:3", b"c").unwrap();
+
+    let entry = store.get_entry("page:1").unwrap().unwrap();
+    assert_eq!(entry.tags, vec!["product:42", "category:7"]);
+    assert!(store.ttl("page:2").unwrap().is_some());
+
+    assert_eq!(
+        store.keys_by_tag("category:7").unwrap(),
+        vec!["page:1", "page:2"]
+    );
+
+    // Only keys carrying the tag are deleted
+    assert_eq!(store.invalidate_tag("product:42").unwrap(), 1);
+    assert!(!store.exists("page:1").unwrap());
+    assert!(store.exists("page:2").unwrap());
+    assert!(store.exists("page:3").unwrap());
+
+    // Deleted keys no longer show up under their other tags
+    assert_eq!(store.keys_by_tag("category:7").unwrap(), vec!["page:2"]);
+    assert_eq!(store.invalidate_tag("product:42").unwrap(), 0);
+}
 
-    let stats = store.stats().unwrap();
-    assert_eq!(stats.total_keys, 2);
-    assert!(stats.total_value_bytes > 0);
-    assert!(stats.avg_value_bytes > 0.0);
-    assert!(stats.table_size_bytes > 0);
+#[test]
+fn test_set_replaces_tags() {
+    let Some(store) = create_test_store("tags_replace") else {
+        return;
+    };
+
+    store.set_with_tags("key", b"v1", None, &["old"]).unwrap();
+    store.set_with_tags("key", b"v2", None, &["new"]).unwrap();
+    assert_eq!(store.invalidate_tag("old").unwrap(), 0);
+    assert!(
+        store
+            .keys_by_tag("new")
+            .unwrap()
+            .contains(&"key".to_string())
+    );
+
+    // A plain set clears the tags
+    store.set("key", b"v3").unwrap();
+    assert!(store.get_entry("key").unwrap().unwrap().tags.is_empty());
+    assert_eq!(store.invalidate_tags(&["old", "new"]).unwrap(), 0);
+
+    assert!(store.set_with_tags("key", b"v", None, &[""]).is_err());
+}
+
+// ==================== Entry Operations ====================
+
+#[test]
+fn test_get_entry() {
+    let Some(store) = create_test_store("get_entry") else {
+        return;
+    };
+
+    store
+        .set_ex("key", b"value", Duration::from_secs(60))
+        .unwrap();
+
+    let entry = store.get_entry("key").unwrap().unwrap();
+    assert_eq!(entry.key, "key");
+    assert_eq!(entry.value, b"value".to_vec());
+    assert!(entry.expires_at.is_some());
+    assert!(!entry.is_expired());
+
+    let ttl = entry.ttl().unwrap();
+    assert!(ttl <= Duration::from_secs(60));
+}
+
+// ==================== Transaction Operations ====================
+
+#[test]
+fn test_transaction_commit() {
+    let Some(store) = create_test_store("tx_commit") else {
+        return;
+    };
+
+    store
+        .transaction(|s| {
+            s.set("key1", b"value1")?;
+            s.set("key2", b"value2")?;
+            Ok(())
+        })
+        .unwrap();
+
+    assert!(store.exists("key1").unwrap());
+    assert!(store.exists("key2").unwrap());
+}
+
+#[test]
+fn test_transaction_rollback() {
+    let Some(store) = create_test_store("tx_rollback") else {
+        return;
+    };
+
+    let result: Result<(), pgkv::Error> = store.transaction(|s| {
+        s.set("key1", b"value1")?;
+        // Simulate error
+        Err(pgkv::Error::Config("test error".into()))
+    });
+
+    assert!(result.is_err());
+    // Key should not exist due to rollback
+    assert!(!store.exists("key1").unwrap());
+}
+
+#[test]
+fn test_nested_transaction_uses_savepoint() {
+    let Some(store) = create_test_store("tx_nested") else {
+        return;
+    };
+
+    store
+        .transaction(|s| {
+            s.set("outer", b"1")?;
+            let inner: Result<(), pgkv::Error> = s.transaction(|s| {
+                s.set("inner", b"1")?;
+                Err(pgkv::Error::Config("test error".into()))
+            });
+            assert!(inner.is_err());
+            s.set_many(&[("many", b"1".as_slice())])?;
+            Ok(())
+        })
+        .unwrap();
+
+    assert!(store.exists("outer").unwrap());
+    assert!(!store.exists("inner").unwrap());
+    assert!(store.exists("many").unwrap());
+}
+
+#[test]
+fn test_transaction_with_savepoints() {
+    let Some(store) = create_test_store("tx_savepoints") else {
+        return;
+    };
+
+    let options = TxOptions::new().isolation(IsolationLevel::RepeatableRead);
+    store
+        .transaction_with(options, |tx| {
+            tx.set("a", b"1")?;
+            let failed: Result<(), pgkv::Error> = tx.savepoint(|tx| {
+                tx.set("b", b"1")?;
+                tx.savepoint(|tx| tx.set("c", b"1"))?;
+                Err(pgkv::Error::Config("test error".into()))
+            });
+            assert!(failed.is_err());
+            tx.savepoint(|tx| tx.set("d", b"1"))?;
+            Ok(())
+        })
+        .unwrap();
+
+    assert!(store.exists("a").unwrap());
+    assert!(!store.exists("b").unwrap());
+    assert!(!store.exists("c").unwrap());
+    assert!(store.exists("d").unwrap());
+}
+
+#[test]
+fn test_transaction_panic_rolls_back() {
+    let Some(store) = create_test_store("tx_panic") else {
+        return;
+    };
+    let run = |f: &dyn Fn()| std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).is_err();
+
+    // A panic in a savepoint only undoes the savepoint
+    store
+        .transaction(|s| {
+            s.set("outer", b"1")?;
+            assert!(run(&|| {
+                let _ = s.transaction(|s| -> pgkv::Result<()> {
+                    s.set("inner", b"1")?;
+                    panic!("loader failed");
+                });
+            }));
+            Ok(())
+        })
+        .unwrap();
+    assert!(store.exists("outer").unwrap());
+    assert!(!store.exists("inner").unwrap());
+
+    // A panic in a transaction rolls it back and leaves the store in autocommit
+    assert!(run(&|| {
+        let _ = store.transaction(|s| -> pgkv::Result<()> {
+            s.set("rolled_back", b"1")?;
+            panic!("loader failed");
+        });
+    }));
+    store.set("autocommit", b"1").unwrap();
+
+    let url = get_database_url().unwrap();
+    let other = Store::with_config(Config::new(url).table_name("test_tx_panic")).unwrap();
+    assert!(!other.exists("rolled_back").unwrap());
+    assert!(other.exists("autocommit").unwrap());
+}
+
+#[test]
+fn test_transaction_read_only() {
+    let Some(store) = create_test_store("tx_read_only") else {
+        return;
+    };
+
+    store.set("key", b"value").unwrap();
+    let result = store.transaction_with(TxOptions::new().read_only(true), |tx| {
+        assert_eq!(tx.get("key")?, Some(b"value".to_vec()));
+        tx.set("key", b"changed")
+    });
+
+    assert!(result.is_err());
+    assert_eq!(store.get("key").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_transaction_with_retries_conflicts() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let Some(store) = create_test_store("tx_retry") else {
+        return;
+    };
+    store.set("counter", b"0").unwrap();
+    drop(store);
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let url = url.clone();
+            std::thread::spawn(move || {
+                let config = Config::new(url).table_name("test_tx_retry");
+                let store = Store::with_config(config).unwrap();
+                let options = TxOptions::new()
+                    .isolation(IsolationLevel::Serializable)
+                    .max_retries(1000);
+                for _ in 0..10 {
+                    store
+                        .transaction_with(options.clone(), |tx| {
+                            let value = tx.get_string("counter")?.unwrap();
+                            let next = value.parse::<i64>().unwrap() + 1;
+                            tx.set("counter", next.to_string())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let config = Config::new(url).table_name("test_tx_retry");
+    let store = Store::with_config(config).unwrap();
+    assert_eq!(store.get_string("counter").unwrap(), Some("40".into()));
+}
+
+#[test]
+fn test_get_for_update() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let Some(store) = create_test_store("get_for_update") else {
+        return;
+    };
+    store.set("counter", b"0").unwrap();
+    drop(store);
+
+    // Read-modify-write under READ COMMITTED is safe with a row lock
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let url = url.clone();
+            std::thread::spawn(move || {
+                let config = Config::new(url).table_name("test_get_for_update");
+                let store = Store::with_config(config).unwrap();
+                for _ in 0..10 {
+                    store
+                        .transaction_with(TxOptions::new(), |tx| {
+                            let value = tx.get_for_update("counter")?.unwrap();
+                            let next = std::str::from_utf8(&value).unwrap().parse::<i64>().unwrap();
+                            tx.set("counter", (next + 1).to_string())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let config = Config::new(url).table_name("test_get_for_update");
+    let store = Store::with_config(config).unwrap();
+    assert_eq!(store.get_string("counter").unwrap(), Some("40".into()));
+    assert_eq!(
+        store
+            .transaction_with(TxOptions::new(), |tx| tx.get_for_update("missing"))
+            .unwrap(),
+        None
+    );
+
+    // Expiry is judged by the database clock
+    store
+        .set_ex("expired", b"value", Duration::from_millis(1))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(
+        store
+            .transaction_with(TxOptions::new(), |tx| tx.get_for_update("expired"))
+            .unwrap(),
+        None
+    );
+}
+
+// ==================== Maintenance Operations ====================
+
+#[test]
+fn test_cleanup_expired() {
+    let Some(store) = create_test_store("cleanup") else {
+        return;
+    };
+
+    // Create some expired keys
+    store
+        .set_ex("expired1", b"value", Duration::from_millis(1))
+        .unwrap();
+    store
+        .set_ex("expired2", b"value", Duration::from_millis(1))
+        .unwrap();
+    store.set("permanent", b"value").unwrap();
+
+    std::thread::sleep(Duration::from_millis(50));
+
+    let deleted = store.cleanup_expired().unwrap();
+    assert_eq!(deleted, 2);
+
+    assert!(store.exists("permanent").unwrap());
+}
+
+#[test]
+fn test_clear() {
+    let Some(store) = create_test_store("clear") else {
+        return;
+    };
+
+    store.set("key1", b"value1").unwrap();
+    store.set("key2", b"value2").unwrap();
+
+    let deleted = store.clear().unwrap();
+    assert_eq!(deleted, 2);
+
+    assert_eq!(store.count(ScanOptions::new()).unwrap(), 0);
+}
+
+#[test]
+fn test_stats() {
+    let Some(store) = create_test_store("stats") else {
+        return;
+    };
+
+    store.set("key1", b"value1").unwrap();
+    store.set("key2", b"longer_value").unwrap();
+
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.total_keys, 2);
+    assert!(stats.total_value_bytes > 0);
+    assert!(stats.avg_value_bytes > 0.0);
+    assert!(stats.table_size_bytes > 0);
+}
+
+// ==================== Schema Migrations ====================
+
+/// Creates `table` with the schema of the first pgkv release, holding one key.
+fn create_legacy_table(table: &str) -> Option<String> {
+    let url = get_database_url()?;
+    let mut client = postgres::Client::connect(&url, postgres::NoTls).ok()?;
+    // Forget the version recorded by earlier runs; fails if nothing was recorded yet
+    let _ = client.execute(
+        "DELETE FROM pgkv_schema_version WHERE table_name = $1",
+        &[&table],
+    );
+    client
+        .batch_execute(&format!(
+            r#"
+            DROP TABLE IF EXISTS {table};
+            CREATE UNLOGGED TABLE {table} (
+                key TEXT PRIMARY KEY,
+                value BYTEA NOT NULL,
+                expires_at TIMESTAMPTZ,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            INSERT INTO {table} (key, value) VALUES ('old', 'legacy');
+            "#
+        ))
+        .ok()?;
+    Some(url)
+}
+
+#[test]
+fn test_migrate_legacy_table() {
+    let Some(url) = create_legacy_table("test_migrate_legacy") else {
+        return;
+    };
+
+    let config = Config::new(url)
+        .table_name("test_migrate_legacy")
+        .auto_migrate(false);
+    let store = Store::with_config(config).unwrap();
+    assert_eq!(store.schema_version().unwrap(), 1);
+
+    // A dry run lists the SQL without applying it
+    let pending = store.pending_migrations().unwrap();
+    let versions: Vec<u32> = pending.iter().map(|m| m.version).collect();
+    assert_eq!(versions, [2, 3, 4, 5]);
+    assert!(
+        pending[0]
+            .to_string()
+            .contains("ADD COLUMN IF NOT EXISTS stale_at")
+    );
+    assert_eq!(store.schema_version().unwrap(), 1);
+
+    assert_eq!(store.migrate().unwrap().len(), 4);
+    assert_eq!(store.schema_version().unwrap(), 5);
+    assert!(store.pending_migrations().unwrap().is_empty());
+    assert!(store.migrate().unwrap().is_empty());
+
+    // Existing rows get the new columns
+    let (value, version) = store.get_versioned("old").unwrap().unwrap();
+    assert_eq!(value, b"legacy");
+    assert!(version > 0);
+    store
+        .set_with_tags("new", b"value", None, &["fresh"])
+        .unwrap();
+    assert_eq!(store.keys_by_tag("fresh").unwrap(), ["new"]);
+}
+
+#[test]
+fn test_auto_migrate_on_connect() {
+    let Some(url) = create_legacy_table("test_auto_migrate") else {
+        return;
+    };
+
+    let store = Store::with_config(Config::new(url).table_name("test_auto_migrate")).unwrap();
+    assert_eq!(store.schema_version().unwrap(), 5);
+    assert_eq!(store.get("old").unwrap(), Some(b"legacy".to_vec()));
+
+    // Recreating the table starts again from the first migration
+    store.recreate_table().unwrap();
+    assert_eq!(store.schema_version().unwrap(), 5);
+    assert!(!store.exists("old").unwrap());
+    store.set("key", b"value").unwrap();
+}
+
+#[test]
+fn test_no_migration_without_auto_create() {
+    let Some(url) = create_legacy_table("test_migrate_no_create") else {
+        return;
+    };
+    let config = || {
+        Config::new(&url)
+            .table_name("test_migrate_no_create")
+            .auto_create_table(false)
+    };
+
+    // The old schema is reported rather than altered
+    let err = Store::with_config(config()).err().unwrap();
+    let pgkv::Error::SchemaMismatch { found, .. } = err else {
+        panic!("expected a schema mismatch, got {:?}", err);
+    };
+    assert!(found.contains(&"no column version".to_string()));
+
+    let mut client = postgres::Client::connect(&url, postgres::NoTls).unwrap();
+    let columns: i64 = client
+        .query_one(
+            "SELECT count(*) FROM information_schema.columns \
+             WHERE table_name = 'test_migrate_no_create'",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(columns, 5);
+
+    // Migrating has to be asked for; the index is left to whoever owns the table
+    client
+        .batch_execute(
+            "CREATE INDEX test_migrate_no_create_expires_idx \
+             ON test_migrate_no_create (expires_at)",
+        )
+        .unwrap();
+    let store = Store::with_config(config().auto_migrate(true)).unwrap();
+    assert_eq!(store.schema_version().unwrap(), 5);
+    assert_eq!(store.get("old").unwrap(), Some(b"legacy".to_vec()));
+}
+
+#[test]
+fn test_missing_table_without_auto_create() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let mut client = postgres::Client::connect(&url, postgres::NoTls).unwrap();
+    client
+        .batch_execute("DROP TABLE IF EXISTS test_validate_missing")
+        .unwrap();
+
+    let config = Config::new(url)
+        .table_name("test_validate_missing")
+        .auto_create_table(false);
+    assert!(matches!(
+        Store::with_config(config),
+        Err(pgkv::Error::TableNotFound { ref table }) if table == "\"test_validate_missing\""
+    ));
+}
+
+#[test]
+fn test_schema_validation() {
+    let Some(store) = create_test_store("validate_schema") else {
+        return;
+    };
+    let url = get_database_url().unwrap();
+    let config = || {
+        Config::new(&url)
+            .table_name("test_validate_schema")
+            .auto_create_table(false)
+    };
+
+    // A table created by pgkv matches its configuration
+    Store::with_config(config()).unwrap();
+
+    let err = Store::with_config(config().table_type(TableType::Regular))
+        .err()
+        .unwrap();
+    let pgkv::Error::SchemaMismatch {
+        expected, found, ..
+    } = err
+    else {
+        panic!("expected a schema mismatch, got {:?}", err);
+    };
+    assert_eq!(expected, ["LOGGED table"]);
+    assert_eq!(found, ["UNLOGGED table"]);
+
+    let err = Store::with_config(config().key_type(KeyType::Bytes))
+        .err()
+        .unwrap();
+    assert!(matches!(
+        err,
+        pgkv::Error::SchemaMismatch { ref found, .. } if found == &["column key of type text"]
+    ));
+
+    // Missing columns and indexes are all listed
+    let mut client = postgres::Client::connect(&url, postgres::NoTls).unwrap();
+    client
+        .batch_execute(
+            "ALTER TABLE test_validate_schema DROP COLUMN tags; \
+             DROP INDEX test_validate_schema_expires_idx",
+        )
+        .unwrap();
+    let err = Store::with_config(config().auto_migrate(false))
+        .err()
+        .unwrap();
+    let pgkv::Error::SchemaMismatch { found, .. } = err else {
+        panic!("expected a schema mismatch, got {:?}", err);
+    };
+    assert_eq!(
+        found,
+        [
+            "no column tags",
+            "no index test_validate_schema_expires_idx",
+            "no index test_validate_schema_tags_idx",
+        ]
+    );
+
+    store.recreate_table().unwrap();
+    Store::with_config(config()).unwrap();
+}
+
+#[test]
+fn test_schema_validation_features() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let config = || {
+        Config::new(&url)
+            .table_name("test_validate_features")
+            .value_type(ValueType::Jsonb)
+            .json_index(true)
+            .history(true)
+    };
+    let store = Store::with_config(config()).unwrap();
+    store.recreate_table().unwrap();
+    Store::with_config(config().auto_create_table(false)).unwrap();
+
+    let mut client = postgres::Client::connect(&url, postgres::NoTls).unwrap();
+    client
+        .batch_execute(
+            "DROP TRIGGER pgkv_history_update ON test_validate_features; \
+             DROP INDEX test_validate_features_value_idx; \
+             CREATE INDEX test_validate_features_value_idx ON test_validate_features (value)",
+        )
+        .unwrap();
+    let err = Store::with_config(config().auto_create_table(false))
+        .err()
+        .unwrap();
+    let pgkv::Error::SchemaMismatch {
+        expected, found, ..
+    } = err
+    else {
+        panic!("expected a schema mismatch, got {:?}", err);
+    };
+    assert_eq!(
+        expected,
+        [
+            "gin index test_validate_features_value_idx",
+            "history trigger pgkv_history_update",
+        ]
+    );
+    assert_eq!(
+        found,
+        [
+            "btree index test_validate_features_value_idx",
+            "no trigger pgkv_history_update",
+        ]
+    );
+
+    store.recreate_table().unwrap();
+    Store::with_config(config().auto_create_table(false)).unwrap();
+}
+
+#[test]
+fn test_set_table_type() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let config = Config::new(url)
+        .table_name("test_set_table_type")
+        .table_type(TableType::Unlogged)
+        .history(true);
+    let store = Store::with_config(config).unwrap();
+    store.recreate_table().unwrap();
+    store.set("key", b"value").unwrap();
+    assert_eq!(store.table_type().unwrap(), TableType::Unlogged);
+
+    store.set_table_type(TableType::Regular).unwrap();
+    assert_eq!(store.table_type().unwrap(), TableType::Regular);
+    assert_eq!(store.get("key").unwrap(), Some(b"value".to_vec()));
+
+    // Companion tables and indexes follow the table
+    let mut client =
+        postgres::Client::connect(&get_database_url().unwrap(), postgres::NoTls).unwrap();
+    let persistence = |client: &mut postgres::Client, name: &str| -> String {
+        client
+            .query_one(
+                "SELECT relpersistence::text FROM pg_class WHERE relname = $1",
+                &[&name],
+            )
+            .unwrap()
+            .get(0)
+    };
+    for name in [
+        "test_set_table_type_pkey",
+        "test_set_table_type_history",
+        "test_set_table_type_history_key_idx",
+    ] {
+        assert_eq!(persistence(&mut client, name), "p", "{}", name);
+    }
+
+    // Writes keep working and are recorded in history
+    store.set("key", b"durable").unwrap();
+    assert_eq!(store.history("key", 1).unwrap()[0].value, b"durable");
+
+    store.set_table_type(TableType::Unlogged).unwrap();
+    assert_eq!(store.table_type().unwrap(), TableType::Unlogged);
+    assert_eq!(persistence(&mut client, "test_set_table_type_history"), "u");
+    assert_eq!(store.get("key").unwrap(), Some(b"durable".to_vec()));
+}
+
+// ==================== Partitioned Tables ====================
+
+#[test]
+fn test_partitioned_table() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let config = Config::new(&url)
+        .table_name("test_partitioned")
+        .table_type(TableType::Unlogged)
+        .partitions(4)
+        .maintenance_workers(2);
+    let store = Store::with_config(config).unwrap();
+    store.recreate_table().unwrap();
+
+    for i in 0..40 {
+        store.set(&format!("key{:02}", i), b"value").unwrap();
+    }
+    for i in 0..8 {
+        store
+            .set_ex(&format!("temp{}", i), b"value", Duration::from_millis(1))
+            .unwrap();
+    }
+    assert_eq!(store.get("key07").unwrap(), Some(b"value".to_vec()));
+
+    // Rows are spread over the partitions, each with its own expiry index
+    let mut client = postgres::Client::connect(&url, postgres::NoTls).unwrap();
+    let rows = client
+        .query(
+            "SELECT relid::regclass::text, \
+                    (SELECT count(*) FROM pg_indexes i \
+                     WHERE i.tablename = relid::regclass::text AND i.indexdef LIKE '%expires_at%') \
+             FROM pg_partition_tree('test_partitioned') WHERE isleaf",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(rows.len(), 4);
+    for row in &rows {
+        assert_eq!(row.get::<_, i64>(1), 1, "{}", row.get::<_, String>(0));
+    }
+
+    std::thread::sleep(Duration::from_millis(50));
+    store.analyze().unwrap();
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.total_keys, 48);
+    assert_eq!(stats.expired_keys, 8);
+    assert_eq!(stats.total_value_bytes, 48 * 5);
+    assert_eq!(stats.avg_value_bytes, 5.0);
+    assert!(stats.table_size_bytes > 0);
+
+    assert_eq!(store.cleanup_expired().unwrap(), 8);
+    store.vacuum().unwrap();
+    assert_eq!(
+        store.scan(ScanOptions::new().prefix("key")).unwrap().len(),
+        40
+    );
+
+    // Maintenance inside a transaction runs on the store's own connection
+    let deleted = store.transaction(|tx| tx.cleanup_expired()).unwrap();
+    assert_eq!(deleted, 0);
+
+    // Partitions switch table type together
+    assert_eq!(store.table_type().unwrap(), TableType::Unlogged);
+    store.set_table_type(TableType::Regular).unwrap();
+    assert_eq!(store.table_type().unwrap(), TableType::Regular);
+    let unlogged: i64 = client
+        .query_one(
+            "SELECT count(*) FROM pg_partition_tree('test_partitioned') t \
+             JOIN pg_class c ON c.oid = t.relid WHERE t.isleaf AND c.relpersistence = 'u'",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(unlogged, 0);
+
+    // The partitioning is part of the validated schema
+    let err = Store::with_config(
+        Config::new(&url)
+            .table_name("test_partitioned")
+            .table_type(TableType::Regular)
+            .partitions(8)
+            .auto_create_table(false),
+    )
+    .err()
+    .unwrap();
+    assert!(
+        matches!(
+            err,
+            pgkv::Error::SchemaMismatch { ref found, .. }
+                if found == &["table with 4 hash partitions"]
+        ),
+        "{:?}",
+        err
+    );
+
+    // Truncating partition by partition still invalidates cached keys
+    let cached = CachedStore::new(&store, LocalCacheConfig::new()).unwrap();
+    assert_eq!(cached.get("key00").unwrap(), Some(b"value".to_vec()));
+    store.truncate().unwrap();
+    assert_eq!(store.stats().unwrap().total_keys, 0);
+    cached.flush_invalidations().unwrap();
+    assert_eq!(cached.get("key00").unwrap(), None);
+
+    store.set("key00", b"value").unwrap();
+    store.transaction(|tx| tx.truncate()).unwrap();
+    assert!(!store.exists("key00").unwrap());
+}
+
+// ==================== History Operations ====================
+
+fn create_history_store(test_name: &str) -> Option<Store> {
+    let url = get_database_url()?;
+
+    let config = Config::new(url)
+        .table_name(format!("test_{}", test_name))
+        .table_type(TableType::Unlogged)
+        .history(true)
+        .history_retention(Duration::ZERO)
+        .application_name("pgkv_tests");
+
+    let store = Store::with_config(config).ok()?;
+    store.truncate().ok()?;
+    store.prune_history().ok()?;
+    Some(store)
+}
+
+#[test]
+fn test_history_records_writes() {
+    let Some(store) = create_history_store("history_writes") else {
+        return;
+    };
+
+    store.set("flag", b"on").unwrap();
+    store.set("flag", b"off").unwrap();
+    store.expire("flag", Duration::from_secs(60)).unwrap(); // TTL-only change, not recorded
+    store.delete("flag").unwrap();
+
+    let history = store.history("flag", 10).unwrap();
+    assert_eq!(history.len(), 3);
+
+    assert_eq!(history[0].op, HistoryOp::Delete);
+    assert_eq!(history[0].value_str(), Some("off"));
+    assert_eq!(history[1].op, HistoryOp::Set);
+    assert_eq!(history[1].value_str(), Some("off"));
+    assert_eq!(history[2].op, HistoryOp::Set);
+    assert_eq!(history[2].value_str(), Some("on"));
+    assert!(history[1].version > history[2].version);
+    assert_eq!(history[0].changed_by, "pgkv_tests");
+
+    assert_eq!(store.history("flag", 1).unwrap().len(), 1);
+    assert!(store.history("other", 10).unwrap().is_empty());
+}
+
+#[test]
+fn test_get_as_of() {
+    let Some(store) = create_history_store("history_as_of") else {
+        return;
+    };
+
+    let before = SystemTime::now();
+    std::thread::sleep(Duration::from_millis(20));
+    store.set("flag", b"v1").unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    let after_v1 = SystemTime::now();
+    std::thread::sleep(Duration::from_millis(20));
+    store.set("flag", b"v2").unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    let after_v2 = SystemTime::now();
+    std::thread::sleep(Duration::from_millis(20));
+    store.delete("flag").unwrap();
+
+    assert_eq!(store.get_as_of("flag", before).unwrap(), None);
+    assert_eq!(
+        store.get_as_of("flag", after_v1).unwrap(),
+        Some(b"v1".to_vec())
+    );
+    assert_eq!(
+        store.get_as_of("flag", after_v2).unwrap(),
+        Some(b"v2".to_vec())
+    );
+    assert_eq!(store.get_as_of("flag", SystemTime::now()).unwrap(), None);
+
+    // Retention of zero prunes everything
+    assert_eq!(store.prune_history().unwrap(), 3);
+    assert!(store.history("flag", 10).unwrap().is_empty());
+}
+
+#[test]
+fn test_history_disabled() {
+    let Some(store) = create_test_store("history_disabled") else {
+        return;
+    };
+
+    store.set("flag", b"on").unwrap();
+    assert!(store.history("flag", 10).is_err());
+    assert!(store.get_as_of("flag", SystemTime::now()).is_err());
+    assert!(store.prune_history().is_err());
+}
+
+// ==================== Local Cache ====================
+
+#[test]
+fn test_cached_store_hits_and_misses() {
+    let Some(store) = create_test_store("cached_hits") else {
+        return;
+    };
+
+    let cached = CachedStore::new(&store, LocalCacheConfig::new()).unwrap();
+
+    cached.set("key", b"value").unwrap();
+    // Let the write's own change notification arrive before caching the value
+    cached.flush_invalidations().unwrap();
+    assert_eq!(cached.get("key").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(cached.get("key").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(cached.get("missing").unwrap(), None);
+
+    let stats = cached.cache_stats();
+    assert_eq!(stats.hits, 1);
+    assert_eq!(stats.misses, 2);
+    assert_eq!(stats.entries, 1);
+
+    // Local writes drop the cached copy
+    cached.set("key", b"updated").unwrap();
+    assert_eq!(cached.get("key").unwrap(), Some(b"updated".to_vec()));
+
+    cached.delete("key").unwrap();
+    assert_eq!(cached.get("key").unwrap(), None);
+
+    // Writes made directly through the wrapped store evict the cached copy
+    cached.set("direct", b"v1").unwrap();
+    cached.flush_invalidations().unwrap();
+    assert_eq!(cached.get("direct").unwrap(), Some(b"v1".to_vec()));
+    store.set("direct", b"v2").unwrap();
+    cached.flush_invalidations().unwrap();
+    assert_eq!(cached.get("direct").unwrap(), Some(b"v2".to_vec()));
+
+    store.increment("counter", 1).unwrap();
+    assert_eq!(cached.get("counter").unwrap(), Some(b"1".to_vec()));
+    store.increment("counter", 1).unwrap();
+    cached.flush_invalidations().unwrap();
+    assert_eq!(cached.get("counter").unwrap(), Some(b"2".to_vec()));
+}
+
+#[test]
+fn test_cached_store_remote_invalidation() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let Some(store) = create_test_store("cached_remote") else {
+        return;
+    };
+
+    let cached = CachedStore::new(&store, LocalCacheConfig::new()).unwrap();
+    let other = Store::with_config(Config::new(url).table_name("test_cached_remote")).unwrap();
+
+    other.set("key", b"v1").unwrap();
+    assert_eq!(cached.get("key").unwrap(), Some(b"v1".to_vec()));
+
+    // A write from another connection evicts the cached copy
+    other.set("key", b"v2").unwrap();
+    cached.flush_invalidations().unwrap();
+    assert_eq!(cached.get("key").unwrap(), Some(b"v2".to_vec()));
+
+    // TRUNCATE evicts everything
+    other.truncate().unwrap();
+    cached.flush_invalidations().unwrap();
+    assert_eq!(cached.get("key").unwrap(), None);
+    assert!(cached.cache_stats().invalidations >= 2);
+}
+
+#[test]
+fn test_cached_store_respects_key_ttl() {
+    let Some(store) = create_test_store("cached_ttl") else {
+        return;
+    };
+
+    let cached =
+        CachedStore::new(&store, LocalCacheConfig::new().ttl(Duration::from_secs(60))).unwrap();
+
+    cached
+        .set_ex("key", b"value", Duration::from_millis(100))
+        .unwrap();
+    assert!(cached.get("key").unwrap().is_some());
+
+    std::thread::sleep(Duration::from_millis(150));
+    assert!(cached.get("key").unwrap().is_none());
+}
+
+// ==================== Typed Store ====================
+
+#[cfg(feature = "serde")]
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Profile {
+    name: String,
+    visits: u32,
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_typed_store_json_is_plain() {
+    use pgkv::TypedStoreExt;
+
+    let Some(store) = create_test_store("typed_json") else {
+        return;
+    };
+
+    let profiles = store.typed::<Profile>();
+    let alice = Profile {
+        name: "alice".into(),
+        visits: 3,
+    };
+    profiles.set("profile:1", &alice).unwrap();
+
+    assert_eq!(profiles.get("profile:1").unwrap(), Some(alice));
+    assert_eq!(
+        store.get_string("profile:1").unwrap(),
+        Some(r#"{"name":"alice","visits":3}"#.into())
+    );
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn test_typed_store_codec_mismatch() {
+    use pgkv::{MessagePack, TypedStore, TypedStoreExt};
+
+    let Some(store) = create_test_store("typed_codec") else {
+        return;
+    };
+
+    let packed: TypedStore<Profile, MessagePack> = TypedStore::new(&store);
+    let bob = Profile {
+        name: "bob".into(),
+        visits: 1,
+    };
+    packed.set("profile:2", &bob).unwrap();
+    assert_eq!(packed.get("profile:2").unwrap(), Some(bob));
+
+    // Reading with the wrong codec is an error, in both directions
+    let json = store.typed::<Profile>();
+    assert!(matches!(
+        json.get("profile:2"),
+        Err(pgkv::Error::Serialization(_))
+    ));
+
+    json.set(
+        "profile:3",
+        &Profile {
+            name: "carol".into(),
+            visits: 2,
+        },
+    )
+    .unwrap();
+    assert!(packed.get("profile:3").is_err());
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct ProfileV1 {
+    name: String,
+}
+
+#[cfg(feature = "serde")]
+fn profile_migrations() -> pgkv::ValueMigrations<Profile> {
+    pgkv::ValueMigrations::<ProfileV1>::new().then(|v1| Profile {
+        name: v1.name,
+        visits: 0,
+    })
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_typed_store_upcasts_old_values() {
+    use pgkv::{TypedStore, TypedStoreExt};
+
+    let Some(store) = create_test_store("typed_upcast") else {
+        return;
+    };
+
+    // Written before the struct gained a field
+    store.set("profile:1", br#"{"name":"alice"}"#).unwrap();
+
+    let plain = store.typed::<Profile>();
+    assert!(matches!(
+        plain.get("profile:1"),
+        Err(pgkv::Error::Serialization(_))
+    ));
+
+    let profiles: TypedStore<Profile> =
+        TypedStore::new(&store).with_migrations(profile_migrations());
+    let alice = Profile {
+        name: "alice".into(),
+        visits: 0,
+    };
+    assert_eq!(profiles.get("profile:1").unwrap(), Some(alice));
+
+    // Without write-back the stored value is untouched
+    assert_eq!(
+        store.get("profile:1").unwrap().unwrap(),
+        br#"{"name":"alice"}"#
+    );
+
+    // New values carry the version header
+    let bob = Profile {
+        name: "bob".into(),
+        visits: 2,
+    };
+    profiles.set("profile:2", &bob).unwrap();
+    assert_eq!(
+        store.get("profile:2").unwrap().unwrap()[..5],
+        [0xC2, 2, 0, 0, 0]
+    );
+    assert_eq!(profiles.get("profile:2").unwrap(), Some(bob));
+
+    // A store that only knows version 1 refuses newer values
+    let old: TypedStore<ProfileV1> = TypedStore::new(&store);
+    assert!(matches!(
+        old.get("profile:2"),
+        Err(pgkv::Error::Serialization(_))
+    ));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_typed_store_write_back() {
+    use pgkv::TypedStore;
+
+    let Some(store) = create_test_store("typed_write_back") else {
+        return;
+    };
+
+    store
+        .set_ex(
+            "profile:1",
+            br#"{"name":"alice"}"#,
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+    store.set("profile:2", br#"{"name":"bob"}"#).unwrap();
+
+    let profiles: TypedStore<Profile> =
+        TypedStore::new(&store).with_migrations(profile_migrations().write_back(true));
+    profiles.get("profile:1").unwrap();
+
+    let stored = store.get("profile:1").unwrap().unwrap();
+    assert_eq!(stored[..5], [0xC2, 2, 0, 0, 0]);
+    assert_eq!(&stored[5..], br#"{"name":"alice","visits":0}"#);
+    assert!(store.ttl("profile:1").unwrap().is_some());
+
+    // Scans write back too
+    assert_eq!(profiles.scan(ScanOptions::new()).unwrap().len(), 2);
+    assert_eq!(store.get("profile:2").unwrap().unwrap()[0], 0xC2);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_typed_store_scan_lenient() {
+    use pgkv::TypedStoreExt;
+
+    let Some(store) = create_test_store("typed_lenient") else {
+        return;
+    };
+
+    let profiles = store.typed::<Profile>();
+    profiles
+        .set(
+            "profile:1",
+            &Profile {
+                name: "alice".into(),
+                visits: 1,
+            },
+        )
+        .unwrap();
+    store.set("profile:2", b"not json").unwrap();
+    profiles
+        .set(
+            "profile:3",
+            &Profile {
+                name: "carol".into(),
+                visits: 3,
+            },
+        )
+        .unwrap();
+
+    assert!(profiles.scan(ScanOptions::new()).is_err());
+
+    let results = profiles.scan_lenient(ScanOptions::new()).unwrap();
+    let keys: Vec<&str> = results.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, ["profile:1", "profile:2", "profile:3"]);
+    assert!(results[0].1.is_ok());
+    assert!(matches!(results[1].1, Err(pgkv::Error::Serialization(_))));
+    assert_eq!(results[2].1.as_ref().unwrap().visits, 3);
+}
+
+#[cfg(feature = "serde")]
+pgkv::kv_key! {
+    #[derive(Debug, Clone, PartialEq)]
+    struct ProfileKey {
+        "user", user_id: u64, "profile",
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_keyed_store() {
+    use pgkv::TypedStoreExt;
+
+    let Some(store) = create_test_store("keyed") else {
+        return;
+    };
+
+    let profiles = store.keyed::<ProfileKey, Profile>();
+    for user_id in [1, 2] {
+        let profile = Profile {
+            name: format!("user{}", user_id),
+            visits: user_id as u32,
+        };
+        profiles.set(&ProfileKey { user_id }, &profile).unwrap();
+    }
+    assert!(store.exists("user:1:profile").unwrap());
+
+    // Keys of other types under the same prefix are skipped
+    store.set("user:1:settings", b"{}").unwrap();
+    store.set("other", b"{}").unwrap();
+
+    let keys = profiles.keys(ScanOptions::new()).unwrap();
+    assert_eq!(
+        keys,
+        vec![ProfileKey { user_id: 1 }, ProfileKey { user_id: 2 }]
+    );
+
+    let scanned = profiles.scan(ScanOptions::new()).unwrap();
+    assert_eq!(scanned.len(), 2);
+    assert_eq!(scanned[1].0, ProfileKey { user_id: 2 });
+    assert_eq!(scanned[1].1.name, "user2");
+
+    let many = profiles
+        .get_many(&[ProfileKey { user_id: 2 }, ProfileKey { user_id: 3 }])
+        .unwrap();
+    assert_eq!(many.len(), 1);
+    assert_eq!(many[0].0, ProfileKey { user_id: 2 });
+
+    assert!(profiles.delete(&ProfileKey { user_id: 1 }).unwrap());
+    assert!(matches!(
+        profiles.get_or_err(&ProfileKey { user_id: 1 }),
+        Err(pgkv::Error::NotFound { ref key }) if key == "user:1:profile"
+    ));
+}
+
+// ==================== Compression ====================
+
+/// Values that start with the headers pgkv uses for its own encodings.
+fn header_like_values() -> Vec<Vec<u8>> {
+    let prefixes: [&[u8]; 4] = [b"\xC0\x01", b"\xC0\x02", b"\xF5\x01", b"\xF6\x01"];
+    prefixes
+        .iter()
+        .map(|prefix| {
+            let mut value = prefix.to_vec();
+            value.extend((0..62).map(|i: u8| i.wrapping_mul(37)));
+            value
+        })
+        .collect()
+}
+
+#[test]
+fn test_values_starting_with_headers() {
+    let Some(store) = create_test_store("header_values") else {
+        return;
+    };
+
+    for (i, value) in header_like_values().into_iter().enumerate() {
+        let key = format!("raw:{}", i);
+        store.set(&key, &value).unwrap();
+        assert_eq!(store.get(&key).unwrap(), Some(value.clone()));
+        assert_eq!(store.strlen(&key).unwrap(), value.len() as u64);
+        assert_eq!(
+            store.get_range(&key, 0, 3).unwrap(),
+            Some(value[..4].to_vec())
+        );
+        assert_eq!(store.append(&key, b"!").unwrap(), value.len() as u64 + 1);
+
+        let mut appended = value.clone();
+        appended.push(b'!');
+        assert_eq!(store.get(&key).unwrap(), Some(appended));
+        assert_eq!(
+            store.get_and_set(&key, &value).unwrap().map(|v| v.len()),
+            Some(value.len() + 1)
+        );
+        assert_eq!(
+            store
+                .compare_and_swap(&key, Some(&value), b"swapped")
+                .unwrap(),
+            CasResult::Success
+        );
+    }
+    assert_eq!(store.stats().unwrap().compressed_keys, 0);
+
+    // A compressing store stores and reads them as they are too
+    #[cfg(feature = "lz4")]
+    {
+        let compressed =
+            create_compressed_store("header_values", pgkv::Compression::Lz4 { min_size: 0 })
+                .unwrap();
+        for (i, value) in header_like_values().into_iter().enumerate() {
+            let key = format!("compressing:{}", i);
+            compressed.set(&key, &value).unwrap();
+            assert_eq!(compressed.get(&key).unwrap(), Some(value.clone()));
+            assert_eq!(store.get(&key).unwrap(), Some(value));
+        }
+    }
+
+    // And so does an encrypting store
+    #[cfg(feature = "encryption")]
+    {
+        let encrypted =
+            create_encrypted_store("header_values", pgkv::Keyring::new(1, [7; 32])).unwrap();
+        for (i, value) in header_like_values().into_iter().enumerate() {
+            let key = format!("encrypting:{}", i);
+            store.set(&key, &value).unwrap();
+            assert_eq!(encrypted.get(&key).unwrap(), Some(value.clone()));
+            assert_eq!(encrypted.strlen(&key).unwrap(), value.len() as u64);
+            assert_eq!(encrypted.reencrypt_all().unwrap(), 0);
+        }
+    }
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn create_compressed_store(test_name: &str, compression: pgkv::Compression) -> Option<Store> {
+    let url = get_database_url()?;
+
+    let config = Config::new(url)
+        .table_name(format!("test_{}", test_name))
+        .table_type(TableType::Unlogged)
+        .compression(compression);
+
+    let store = Store::with_config(config).ok()?;
+    store.truncate().ok()?;
+    Some(store)
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn compressible(len: usize) -> Vec<u8> {
+    b"pgkv compresses repetitive values well. "
+        .iter()
+        .copied()
+        .cycle()
+        .take(len)
+        .collect()
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_compression_read_paths() {
+    let compression = pgkv::Compression::Zstd {
+        level: 3,
+        min_size: 100,
+    };
+    let Some(store) = create_compressed_store("compression_reads", compression) else {
+        return;
+    };
+
+    let big = compressible(10_000);
+    store.set("big", &big).unwrap();
+    store.set("small", b"tiny").unwrap();
+    store
+        .set_many(&[("many:1", big.as_slice()), ("many:2", b"short")])
+        .unwrap();
+
+    assert_eq!(store.get("big").unwrap(), Some(big.clone()));
+    assert_eq!(store.get("small").unwrap(), Some(b"tiny".to_vec()));
+    assert_eq!(store.get_entry("big").unwrap().unwrap().value, big);
+
+    let many = store.get_many(&["big", "many:1"]).unwrap();
+    assert!(many.iter().all(|kv| kv.value == big));
+
+    let scanned = store.scan(ScanOptions::new().prefix("many:")).unwrap();
+    assert_eq!(scanned[0].value, big);
+    assert_eq!(scanned[1].value, b"short");
+
+    assert_eq!(
+        store.get_and_set("big", b"replaced").unwrap(),
+        Some(big.clone())
+    );
+    assert_eq!(store.get_and_delete("many:1").unwrap(), Some(big.clone()));
+
+    // Only the large values are compressed
+    store.set("big", &big).unwrap();
+    let stats = store.stats().unwrap();
+    assert_eq!(stats.compressed_keys, 1);
+    assert_eq!(
+        stats.uncompressed_value_bytes,
+        (big.len() + b"tiny".len() + b"short".len()) as u64
+    );
+    assert!(stats.total_value_bytes < stats.uncompressed_value_bytes);
+    assert!(stats.compression_ratio() > 10.0);
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn test_compression_mixed_data() {
+    let Some(plain) = create_test_store("compression_mixed") else {
+        return;
+    };
+    let compressed =
+        create_compressed_store("compression_mixed", pgkv::Compression::Lz4 { min_size: 0 })
+            .unwrap();
+
+    let value = compressible(4096);
+    plain.set("old", &value).unwrap();
+    compressed.set("new", &value).unwrap();
+
+    // Both stores read both formats
+    for store in [&plain, &compressed] {
+        assert_eq!(store.get("old").unwrap(), Some(value.clone()));
+        assert_eq!(store.get("new").unwrap(), Some(value.clone()));
+    }
+    assert_eq!(plain.stats().unwrap().compressed_keys, 1);
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn test_compression_conditional_writes() {
+    let compression = pgkv::Compression::Lz4 { min_size: 0 };
+    let Some(store) = create_compressed_store("compression_cas", compression) else {
+        return;
+    };
+
+    let v1 = compressible(2048);
+    let v2 = compressible(3000);
+    store.set("doc", &v1).unwrap();
+
+    assert_eq!(
+        store.compare_and_swap("doc", Some(&v2), b"nope").unwrap(),
+        CasResult::Mismatch {
+            current: Some(v1.clone())
+        }
+    );
+    assert_eq!(
+        store.compare_and_swap("doc", Some(&v1), &v2).unwrap(),
+        CasResult::Success
+    );
+
+    let result = store
+        .execute_batch(&Batch::new().if_equals("doc", &v2).put("doc", &v1))
+        .unwrap();
+    assert!(result.is_committed());
+
+    let outcome = store
+        .set_with("doc", &v2, SetOptions::new().return_previous())
+        .unwrap();
+    assert_eq!(outcome.previous, Some(v1));
+}
+
+#[cfg(feature = "lz4")]
+#[test]
+fn test_compression_byte_operations() {
+    let compression = pgkv::Compression::Lz4 { min_size: 100 };
+    let Some(store) = create_compressed_store("compression_bytes", compression) else {
+        return;
+    };
+
+    let value = compressible(1000);
+    store.set("doc", &value).unwrap();
+
+    assert_eq!(store.strlen("doc").unwrap(), 1000);
+    assert_eq!(
+        store.get_range("doc", 5, 13).unwrap(),
+        Some(b"compresse".to_vec())
+    );
+    assert_eq!(
+        store.get_range("doc", -3, -1).unwrap(),
+        Some(value[997..].to_vec())
+    );
+    assert_eq!(store.get_range("doc", 2000, 3000).unwrap(), Some(vec![]));
+
+    assert!(matches!(
+        store.append("doc", b"more"),
+        Err(pgkv::Error::InvalidValue { .. })
+    ));
+    assert!(matches!(
+        store.set_range("doc", 0, b"x"),
+        Err(pgkv::Error::InvalidValue { .. })
+    ));
+    assert_eq!(store.get("doc").unwrap(), Some(value));
+
+    // Values written by byte operations are left uncompressed
+    store.append("log", b"line\n").unwrap();
+    store.append("log", b"line\n").unwrap();
+    assert_eq!(store.get("log").unwrap(), Some(b"line\nline\n".to_vec()));
+}
+
+// ==================== Encryption ====================
+
+#[cfg(feature = "encryption")]
+fn create_encrypted_store(test_name: &str, keyring: pgkv::Keyring) -> Option<Store> {
+    let url = get_database_url()?;
+
+    let config = Config::new(url)
+        .table_name(format!("test_{}", test_name))
+        .table_type(TableType::Unlogged)
+        .encryption(keyring);
+
+    Store::with_config(config).ok()
+}
+
+/// Reads a value's stored bytes, bypassing decryption.
+#[cfg(feature = "encryption")]
+fn raw_value(store: &Store, key: &str) -> Vec<u8> {
+    let mut client =
+        postgres::Client::connect(&get_database_url().unwrap(), postgres::NoTls).unwrap();
+    let sql = format!("SELECT value FROM {} WHERE key = $1", store.table_name());
+    client.query_one(&sql, &[&key]).unwrap().get(0)
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encryption_at_rest() {
+    let Some(store) = create_encrypted_store("encryption", pgkv::Keyring::new(1, [7; 32])) else {
+        return;
+    };
+    // Same table, without a key provider
+    let plain = create_test_store("encryption").unwrap();
+
+    store.set("session:1", b"alice@example.com").unwrap();
+    store
+        .set_many(&[("session:2", b"bob@example.com".as_slice())])
+        .unwrap();
+
+    let raw = raw_value(&store, "session:1");
+    assert!(!raw.windows(5).any(|w| w == b"alice"));
+
+    assert_eq!(
+        store.get("session:1").unwrap(),
+        Some(b"alice@example.com".to_vec())
+    );
+    assert_eq!(store.strlen("session:1").unwrap(), 17);
+    assert_eq!(
+        store.get_range("session:1", 0, 4).unwrap(),
+        Some(b"alice".to_vec())
+    );
+    let scanned = store.scan(ScanOptions::new().prefix("session:")).unwrap();
+    assert_eq!(scanned[1].value, b"bob@example.com");
+
+    // Without the key provider, values can't be read
+    plain.set("plain", b"visible").unwrap();
+    assert!(matches!(
+        plain.get("session:1"),
+        Err(pgkv::Error::Encryption { .. })
+    ));
+    assert_eq!(store.get("plain").unwrap(), Some(b"visible".to_vec()));
+
+    // Operations that compute the new value in SQL would store plaintext
+    assert!(matches!(
+        store.increment("visits", 2),
+        Err(pgkv::Error::Config(_))
+    ));
+    assert!(matches!(
+        store.increment_float("revenue", 1.5),
+        Err(pgkv::Error::Config(_))
+    ));
+    assert!(matches!(
+        store.append("log", b"secret"),
+        Err(pgkv::Error::Config(_))
+    ));
+    assert!(matches!(
+        store.prepend("log", b"secret"),
+        Err(pgkv::Error::Config(_))
+    ));
+    assert!(matches!(
+        store.set_range("log", 0, b"secret"),
+        Err(pgkv::Error::Config(_))
+    ));
+    assert!(matches!(
+        store.put_stream("log", &b"secret"[..]),
+        Err(pgkv::Error::Config(_))
+    ));
+    assert!(matches!(
+        store.execute_batch(&Batch::new().increment("visits", 1)),
+        Err(pgkv::Error::Config(_))
+    ));
+    assert!(!plain.exists("visits").unwrap());
+    assert!(!plain.exists("log").unwrap());
+
+    // Batches encrypt their puts, but can't compare encrypted values in SQL
+    let result = store
+        .execute_batch(&Batch::new().if_absent("batched").put("batched", b"secret"))
+        .unwrap();
+    assert!(result.is_committed());
+    assert_eq!(store.get("batched").unwrap(), Some(b"secret".to_vec()));
+    assert!(matches!(
+        plain.get("batched"),
+        Err(pgkv::Error::Encryption { .. })
+    ));
+    assert!(matches!(
+        store.execute_batch(&Batch::new().if_equals("batched", b"secret")),
+        Err(pgkv::Error::Config(_))
+    ));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encryption_binds_key_name() {
+    let Some(store) = create_encrypted_store("encryption_aad", pgkv::Keyring::new(1, [7; 32]))
+    else {
+        return;
+    };
+    store.truncate().unwrap();
+
+    store.set("user:1:email", b"alice@example.com").unwrap();
+    store.set("user:2:email", b"mallory@example.com").unwrap();
+
+    // Swapping ciphertexts between keys is detected
+    let mut client =
+        postgres::Client::connect(&get_database_url().unwrap(), postgres::NoTls).unwrap();
+    let sql = format!(
+        "UPDATE {0} SET value = (SELECT value FROM {0} WHERE key = 'user:2:email') WHERE key = 'user:1:email'",
+        store.table_name()
+    );
+    client.execute(&sql, &[]).unwrap();
+    let err = store.get("user:1:email").unwrap_err();
+    assert!(matches!(err, pgkv::Error::Encryption { ref key, .. } if key == "user:1:email"));
+
+    // Renames re-encrypt the value for its new key
+    store.rename("user:2:email", "user:3:email").unwrap();
+    assert_eq!(
+        store.get("user:3:email").unwrap(),
+        Some(b"mallory@example.com".to_vec())
+    );
+
+    // Compare-and-swap compares decrypted values
+    assert_eq!(
+        store
+            .compare_and_swap(
+                "user:3:email",
+                Some(b"mallory@example.com"),
+                b"carol@example.com"
+            )
+            .unwrap(),
+        CasResult::Success
+    );
+    assert_eq!(
+        store
+            .compare_and_swap("user:3:email", Some(b"mallory@example.com"), b"x")
+            .unwrap(),
+        CasResult::Mismatch {
+            current: Some(b"carol@example.com".to_vec())
+        }
+    );
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encryption_key_rotation() {
+    let (old_key, new_key) = ([1; 32], [2; 32]);
+    let Some(old) = create_encrypted_store("encryption_rotation", pgkv::Keyring::new(1, old_key))
+    else {
+        return;
+    };
+    old.truncate().unwrap();
+
+    for i in 0..5 {
+        old.set(&format!("secret:{}", i), format!("value {}", i))
+            .unwrap();
+    }
+
+    let rotated = create_encrypted_store(
+        "encryption_rotation",
+        pgkv::Keyring::new(2, new_key).retired_key(1, old_key),
+    )
+    .unwrap();
+    rotated.set("secret:new", b"already current").unwrap();
+    assert_eq!(rotated.get("secret:0").unwrap(), Some(b"value 0".to_vec()));
+
+    assert_eq!(rotated.reencrypt_all().unwrap(), 5);
+    assert_eq!(rotated.reencrypt_all().unwrap(), 0);
+
+    // The old key is no longer needed
+    let new =
+        create_encrypted_store("encryption_rotation", pgkv::Keyring::new(2, new_key)).unwrap();
+    for i in 0..5 {
+        assert_eq!(
+            new.get(&format!("secret:{}", i)).unwrap(),
+            Some(format!("value {}", i).into_bytes())
+        );
+    }
+    assert!(old.get("secret:0").is_err());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn test_reject_unencrypted() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+    let keyring = || pgkv::Keyring::new(1, [3; 32]);
+    let plain = create_test_store("reject_unencrypted").unwrap();
+    plain.truncate().unwrap();
+    let lenient = create_encrypted_store("reject_unencrypted", keyring()).unwrap();
+    let strict = Store::with_config(
+        Config::new(url.clone())
+            .table_name("test_reject_unencrypted")
+            .table_type(TableType::Unlogged)
+            .encryption(keyring())
+            .reject_unencrypted(true),
+    )
+    .unwrap();
+
+    plain.set("legacy", b"written before encryption").unwrap();
+    plain
+        .put_stream("streamed", b"streamed plaintext".as_slice())
+        .unwrap();
+    strict.set("session", b"alice@example.com").unwrap();
+
+    // Replace the ciphertext with plaintext and clear its flag
+    raw_execute(
+        "UPDATE test_reject_unencrypted SET value = 'forged'::bytea, encoding = 0 \
+         WHERE key = 'session'",
+    );
+    assert_eq!(lenient.get("session").unwrap(), Some(b"forged".to_vec()));
+
+    fn rejected<T>(result: pgkv::Result<T>) -> bool {
+        matches!(result, Err(pgkv::Error::Encryption { .. }))
+    }
+    assert!(rejected(strict.get("session")));
+    assert!(rejected(strict.get("legacy")));
+    assert!(rejected(strict.strlen("legacy")));
+    assert!(rejected(strict.get_range("legacy", 0, 4)));
+    assert!(rejected(strict.get_reader("legacy")));
+    assert!(rejected(strict.get_reader("streamed")));
+    assert!(rejected(strict.get_many(&["legacy"])));
+
+    // Encrypts the plaintext values, except the streamed one
+    strict.delete("session").unwrap();
+    assert_eq!(lenient.reencrypt_all().unwrap(), 0);
+    assert_eq!(strict.reencrypt_all().unwrap(), 1);
+    assert_eq!(strict.reencrypt_all().unwrap(), 0);
+    assert!(
+        !raw_value(&strict, "legacy")
+            .windows(7)
+            .any(|w| w == b"written")
+    );
+    assert_eq!(
+        strict.get("legacy").unwrap(),
+        Some(b"written before encryption".to_vec())
+    );
+    assert!(rejected(strict.get_reader("streamed")));
+
+    // Requires a key provider
+    assert!(matches!(
+        Store::with_config(
+            Config::new(url)
+                .table_name("test_reject_unencrypted")
+                .reject_unencrypted(true)
+        ),
+        Err(pgkv::Error::Config(_))
+    ));
+}
+
+// ==================== Streaming ====================
+
+fn create_streaming_store(test_name: &str) -> Option<Store> {
+    let url = get_database_url()?;
+
+    let config = Config::new(url)
+        .table_name(format!("test_{}", test_name))
+        .table_type(TableType::Unlogged)
+        .chunk_size(1000);
+
+    let store = Store::with_config(config).ok()?;
+    store.truncate().ok()?;
+    Some(store)
+}
+
+/// Runs a statement on a separate connection, bypassing the store.
+fn raw_execute(sql: &str) -> u64 {
+    let mut client =
+        postgres::Client::connect(&get_database_url().unwrap(), postgres::NoTls).unwrap();
+    client.execute(sql, &[]).unwrap()
+}
+
+fn count_chunks(test_name: &str) -> i64 {
+    let mut client =
+        postgres::Client::connect(&get_database_url().unwrap(), postgres::NoTls).unwrap();
+    let sql = format!("SELECT COUNT(*) FROM test_{}_chunks", test_name);
+    client.query_one(&sql, &[]).unwrap().get(0)
+}
+
+fn patterned(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+#[test]
+fn test_put_stream_and_get_reader() {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let Some(store) = create_streaming_store("stream") else {
+        return;
+    };
+
+    let value = patterned(3500);
+    assert_eq!(store.put_stream("blob", value.as_slice()).unwrap(), 3500);
+    assert_eq!(count_chunks("stream"), 4);
+
+    let mut reader = store.get_reader("blob").unwrap().unwrap();
+    assert!(reader.is_chunked());
+    assert_eq!(reader.len(), 3500);
+    let mut read = Vec::new();
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(read, value);
+
+    // Reads across a chunk boundary
+    reader.seek(SeekFrom::Start(995)).unwrap();
+    let mut buf = [0; 10];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, value[995..1005]);
+
+    reader.seek(SeekFrom::End(-10)).unwrap();
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail).unwrap();
+    assert_eq!(tail, value[3490..]);
+    assert!(reader.seek(SeekFrom::Current(-5000)).is_err());
+
+    // In-memory reads point to the reader; byte operations refuse the manifest
+    assert!(matches!(
+        store.get("blob"),
+        Err(pgkv::Error::InvalidValue { .. })
+    ));
+    assert!(matches!(
+        store.append("blob", b"more"),
+        Err(pgkv::Error::InvalidValue { .. })
+    ));
+    assert_eq!(store.strlen("blob").unwrap(), 3500);
+
+    // Other values are read from memory
+    store.set("small", b"hello").unwrap();
+    let mut reader = store.get_reader("small").unwrap().unwrap();
+    assert!(!reader.is_chunked());
+    let mut read = String::new();
+    reader.read_to_string(&mut read).unwrap();
+    assert_eq!(read, "hello");
+    assert!(store.get_reader("missing").unwrap().is_none());
+
+    // Replacing or deleting the value deletes its chunks
+    store.put_stream("blob", &b"short"[..]).unwrap();
+    assert_eq!(count_chunks("stream"), 1);
+    store.set("blob", b"inline").unwrap();
+    assert_eq!(count_chunks("stream"), 0);
+    assert_eq!(store.get("blob").unwrap(), Some(b"inline".to_vec()));
+
+    store.put_stream("empty", std::io::empty()).unwrap();
+    let reader = store.get_reader("empty").unwrap().unwrap();
+    assert!(reader.is_empty());
+    store.delete("empty").unwrap();
+    assert_eq!(count_chunks("stream"), 0);
+}
+
+#[test]
+fn test_put_stream_is_atomic() {
+    use std::io::{self, Read};
+
+    // Fails after producing `ok` bytes
+    struct Failing {
+        ok: usize,
+    }
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.ok == 0 {
+                return Err(io::Error::other("disk on fire"));
+            }
+            let n = buf.len().min(self.ok);
+            buf[..n].fill(1);
+            self.ok -= n;
+            Ok(n)
+        }
+    }
+
+    let Some(store) = create_streaming_store("stream_atomic") else {
+        return;
+    };
+
+    store.put_stream("blob", &b"original"[..]).unwrap();
+
+    let err = store.put_stream("blob", Failing { ok: 2500 }).unwrap_err();
+    assert!(matches!(err, pgkv::Error::Io(_)));
+    assert_eq!(count_chunks("stream_atomic"), 1);
+
+    let mut read = Vec::new();
+    let mut reader = store.get_reader("blob").unwrap().unwrap();
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(read, b"original");
+
+    // Inside a transaction, the failed write is rolled back to a savepoint
+    store
+        .transaction(|s| {
+            assert!(s.put_stream("other", Failing { ok: 10 }).is_err());
+            s.set("marker", b"1")
+        })
+        .unwrap();
+    assert!(!store.exists("other").unwrap());
+    assert!(store.exists("marker").unwrap());
+    assert_eq!(count_chunks("stream_atomic"), 1);
+}
+
+#[test]
+fn test_put_stream_respects_max_value_size() {
+    let Some(url) = get_database_url() else {
+        return;
+    };
+
+    let config = Config::new(url)
+        .table_name("test_stream_max_size")
+        .chunk_size(1000)
+        .max_value_size(2500);
+    let store = Store::with_config(config).unwrap();
+    store.truncate().unwrap();
+
+    let err = store.put_stream("blob", patterned(3000).as_slice());
+    assert!(matches!(err, Err(pgkv::Error::InvalidValue { .. })));
+    assert!(!store.exists("blob").unwrap());
+
+    assert_eq!(
+        store
+            .put_stream("blob", patterned(2500).as_slice())
+            .unwrap(),
+        2500
+    );
+    assert_eq!(count_chunks("stream_max_size"), 3);
+}
+
+#[test]
+fn test_put_stream_checksums() {
+    use std::io::{ErrorKind, Read};
+
+    let Some(store) = create_streaming_store("stream_checksum") else {
+        return;
+    };
+
+    store
+        .put_stream("blob", patterned(2500).as_slice())
+        .unwrap();
+    raw_execute(
+        "UPDATE test_stream_checksum_chunks \
+         SET data = overlay(data PLACING '\\x00'::bytea FROM 10) WHERE seq = 1",
+    );
+
+    let mut reader = store.get_reader("blob").unwrap().unwrap();
+    let mut buf = [0; 1000];
+    reader.read_exact(&mut buf).unwrap();
+    let err = reader.read_exact(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    // Chunks deleted while a reader is open are reported as missing
+    store
+        .put_stream("blob", patterned(2500).as_slice())
+        .unwrap();
+    let mut reader = store.get_reader("blob").unwrap().unwrap();
+    store.delete("blob").unwrap();
+    let err = reader.read_exact(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn test_put_stream_key_operations() {
+    use std::io::Read;
+
+    let Some(store) = create_streaming_store("stream_keys") else {
+        return;
+    };
+    let value = patterned(2500);
+
+    // Copies share chunks, which outlive the source key
+    store.put_stream("a", value.as_slice()).unwrap();
+    assert!(store.copy("a", "b", false).unwrap());
+    store.delete("a").unwrap();
+    assert_eq!(count_chunks("stream_keys"), 3);
+
+    store.rename("b", "c").unwrap();
+    let mut read = Vec::new();
+    let mut reader = store.get_reader("c").unwrap().unwrap();
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(read, value);
+
+    // A streamed value can be copied by streaming it through the same store
+    let reader = store.get_reader("c").unwrap().unwrap();
+    assert_eq!(store.put_stream("d", reader).unwrap(), 2500);
+    let mut read = Vec::new();
+    let mut reader = store.get_reader("d").unwrap().unwrap();
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(read, value);
+    store.delete("d").unwrap();
+
+    // Chunks stay with their table
+    assert!(matches!(
+        store.move_to("c", "test_stream_keys_other"),
+        Err(pgkv::Error::InvalidValue { .. })
+    ));
+
+    store.truncate().unwrap();
+    assert_eq!(count_chunks("stream_keys"), 0);
+}
+
+#[test]
+fn test_put_stream_forged_manifest() {
+    use std::io::Read;
+
+    let Some(store) = create_streaming_store("stream_forged") else {
+        return;
+    };
+    let value = patterned(2500);
+    store.put_stream("blob", value.as_slice()).unwrap();
+
+    // A plain value with the same bytes as the manifest is just bytes
+    let mut client =
+        postgres::Client::connect(&get_database_url().unwrap(), postgres::NoTls).unwrap();
+    let manifest: Vec<u8> = client
+        .query_one(
+            "SELECT value FROM test_stream_forged WHERE key = 'blob'",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(manifest.len(), 22);
+    store.set("forged", &manifest).unwrap();
+
+    assert_eq!(store.get("forged").unwrap(), Some(manifest.clone()));
+    assert_eq!(store.strlen("forged").unwrap(), 22);
+    let reader = store.get_reader("forged").unwrap().unwrap();
+    assert!(!reader.is_chunked());
+    assert_eq!(reader.len(), 22);
+
+    // Overwriting or deleting it leaves the streamed value's chunks alone
+    store.set("forged", b"other").unwrap();
+    store.set("forged", &manifest).unwrap();
+    store.delete("forged").unwrap();
+    assert_eq!(count_chunks("stream_forged"), 3);
+
+    // And it doesn't keep them alive once the streamed value is gone
+    store.set("forged", &manifest).unwrap();
+    let mut read = Vec::new();
+    let mut reader = store.get_reader("blob").unwrap().unwrap();
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(read, value);
+    store.delete("blob").unwrap();
+    assert_eq!(count_chunks("stream_forged"), 0);
+    assert_eq!(store.get("forged").unwrap(), Some(manifest));
+}
+
+// ==================== Binary Keys ====================
+
+fn create_bytes_store(test_name: &str) -> Option<Store> {
+    let url = get_database_url()?;
+
+    let config = Config::new(url)
+        .table_name(format!("test_{}", test_name))
+        .table_type(TableType::Unlogged)
+        .key_type(KeyType::Bytes);
+
+    let store = Store::with_config(config).ok()?;
+    store.truncate().ok()?;
+    Some(store)
+}
+
+#[test]
+fn test_bytes_keys_basic_operations() {
+    let Some(store) = create_bytes_store("bytes_basic") else {
+        return;
+    };
+
+    let digest: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+    store.set(&digest, b"object").unwrap();
+    assert!(store.exists(&digest).unwrap());
+    assert_eq!(store.get(&digest[..]).unwrap(), Some(b"object".to_vec()));
+    assert_eq!(
+        store.get(&digest.to_vec()).unwrap(),
+        Some(b"object".to_vec())
+    );
+
+    // Text keys are stored as their UTF-8 bytes
+    store.set("user:1", b"alice").unwrap();
+    assert_eq!(store.get(b"user:1").unwrap(), Some(b"alice".to_vec()));
+    assert_eq!(store.increment("counter", 2).unwrap(), 2);
+    store.rename("user:1", "user:2").unwrap();
+    assert_eq!(store.get("user:2").unwrap(), Some(b"alice".to_vec()));
+
+    store.set_many(&[("a", b"1"), ("b", b"2")]).unwrap();
+    assert_eq!(store.get_many(&["a", "b"]).unwrap().len(), 2);
+
+    assert!(!store.set_nx(&digest, b"other").unwrap());
+    assert!(store.delete(&digest).unwrap());
+    assert!(!store.exists(&digest).unwrap());
+}
+
+#[test]
+fn test_bytes_keys_scan() {
+    let Some(store) = create_bytes_store("bytes_scan") else {
+        return;
+    };
+
+    store.set(&[0x01, 0x00], b"a").unwrap();
+    store.set(&[0x01, 0xff], b"b").unwrap();
+    store.set(&[0x02], b"c").unwrap();
+    store.set(&[0xff, 0xff], b"d").unwrap();
+    store.set(&[0xff, 0xff, 0x00], b"e").unwrap();
+
+    assert_eq!(
+        store
+            .keys_bytes(ScanOptions::new().prefix_bytes([0x01]))
+            .unwrap(),
+        vec![vec![0x01, 0x00], vec![0x01, 0xff]]
+    );
+
+    // A prefix of 0xFF bytes has no upper bound
+    assert_eq!(
+        store
+            .scan_bytes(ScanOptions::new().prefix_bytes([0xff, 0xff]))
+            .unwrap(),
+        vec![
+            (vec![0xff, 0xff], b"d".to_vec()),
+            (vec![0xff, 0xff, 0x00], b"e".to_vec()),
+        ]
+    );
+
+    assert_eq!(
+        store
+            .count(ScanOptions::new().prefix_bytes([0x01, 0xff]))
+            .unwrap(),
+        1
+    );
+    assert_eq!(store.keys_bytes(ScanOptions::new()).unwrap().len(), 5);
+
+    // Keys that aren't UTF-8 can't be listed as strings
+    assert!(matches!(
+        store.keys(ScanOptions::new()),
+        Err(pgkv::Error::InvalidKey { .. })
+    ));
+
+    assert_eq!(store.delete_prefix(&[0xff]).unwrap(), 2);
+    assert_eq!(store.keys_bytes(ScanOptions::new()).unwrap().len(), 3);
+}
+
+#[test]
+fn test_text_keys_reject_invalid_utf8() {
+    let Some(store) = create_test_store("text_utf8") else {
+        return;
+    };
+
+    store.set(b"plain", b"value").unwrap();
+    assert_eq!(store.get("plain").unwrap(), Some(b"value".to_vec()));
+
+    assert!(matches!(
+        store.set(&[0xff, 0xfe], b"value"),
+        Err(pgkv::Error::InvalidKey { .. })
+    ));
+}
+
+// ==================== JSONB Values ====================
+
+fn create_json_store(test_name: &str) -> Option<Store> {
+    let url = get_database_url()?;
+
+    let config = Config::new(url)
+        .table_name(format!("test_{}", test_name))
+        .table_type(TableType::Unlogged)
+        .value_type(ValueType::Jsonb)
+        .json_index(true);
+
+    let store = Store::with_config(config).ok()?;
+    store.truncate().ok()?;
+    Some(store)
+}
+
+#[test]
+fn test_jsonb_values() {
+    let Some(store) = create_json_store("jsonb") else {
+        return;
+    };
+
+    // Values come back as PostgreSQL normalizes them
+    store.set("doc", r#"{"b": 1,  "a": [true]}"#).unwrap();
+    assert_eq!(
+        store.get_string("doc").unwrap(),
+        Some(r#"{"a": [true], "b": 1}"#.into())
+    );
+    assert!(store.set("bad", "not json").is_err());
+
+    // Counters are stored as JSON numbers
+    assert_eq!(store.increment("counter", 5).unwrap(), 5);
+    assert_eq!(store.increment("counter", -2).unwrap(), 3);
+    assert_eq!(store.get_string("counter").unwrap(), Some("3".into()));
+
+    let prev = store.get_and_set("doc", "[]").unwrap().unwrap();
+    assert_eq!(prev, br#"{"a": [true], "b": 1}"#);
+    assert_eq!(
+        store.compare_and_swap("doc", Some(b"[]"), b"[1]").unwrap(),
+        CasResult::Success
+    );
+
+    // Batches compare and write the same text
+    let result = store
+        .execute_batch(
+            &Batch::new()
+                .if_equals("doc", b"[1]")
+                .put("doc", r#"{"x":  2}"#)
+                .increment("counter", 1),
+        )
+        .unwrap();
+    assert!(result.is_committed());
+    assert_eq!(store.get_string("doc").unwrap(), Some(r#"{"x": 2}"#.into()));
+    assert_eq!(store.get_string("counter").unwrap(), Some("4".into()));
+
+    assert!(matches!(
+        store.append("doc", b"x"),
+        Err(pgkv::Error::Config(_))
+    ));
+    assert!(matches!(
+        store.put_stream("blob", &b"data"[..]),
+        Err(pgkv::Error::Config(_))
+    ));
+
+    assert_eq!(store.stats().unwrap().total_keys, 2);
+}
+
+#[test]
+fn test_json_path_operations() {
+    let Some(store) = create_json_store("json_path") else {
+        return;
+    };
+
+    store
+        .set(
+            "user:1",
+            r#"{"name": "alice", "tags": ["a"], "address": {}}"#,
+        )
+        .unwrap();
+
+    assert_eq!(
+        store.json_get("user:1", &["name"]).unwrap(),
+        Some(r#""alice""#.into())
+    );
+    assert_eq!(
+        store.json_get("user:1", &["tags", "0"]).unwrap(),
+        Some(r#""a""#.into())
+    );
+    assert_eq!(store.json_get("user:1", &["missing"]).unwrap(), None);
+    assert_eq!(store.json_get("user:2", &[]).unwrap(), None);
+
+    assert!(
+        store
+            .json_set("user:1", &["address", "city"], r#""Berlin""#)
+            .unwrap()
+    );
+    assert_eq!(
+        store.json_get("user:1", &["address"]).unwrap(),
+        Some(r#"{"city": "Berlin"}"#.into())
+    );
+    assert!(!store.json_set("user:2", &["name"], r#""bob""#).unwrap());
+    assert!(matches!(
+        store.json_set("user:1", &["name"], "{not json"),
+        Err(pgkv::Error::InvalidValue { .. })
+    ));
+
+    assert!(
+        store
+            .json_array_append("user:1", &["tags"], r#""b""#)
+            .unwrap()
+    );
+    assert_eq!(
+        store.json_get("user:1", &["tags"]).unwrap(),
+        Some(r#"["a", "b"]"#.into())
+    );
+    assert!(matches!(
+        store.json_array_append("user:1", &["name"], "1"),
+        Err(pgkv::Error::InvalidValue { .. })
+    ));
+    assert!(!store.json_array_append("user:2", &[], "1").unwrap());
+
+    // Shallow merge, creating the key if needed
+    store
+        .json_merge(
+            "user:1",
+            r#"{"name": "alice2", "address": {"zip": "10115"}}"#,
+        )
+        .unwrap();
+    assert_eq!(
+        store.json_get("user:1", &["address"]).unwrap(),
+        Some(r#"{"zip": "10115"}"#.into())
+    );
+    store.json_merge("user:2", r#"{"name": "bob"}"#).unwrap();
+    assert_eq!(
+        store.get_string("user:2").unwrap(),
+        Some(r#"{"name": "bob"}"#.into())
+    );
+
+    // An empty path addresses the whole value
+    store.set("list", "[]").unwrap();
+    assert!(store.json_array_append("list", &[], "1").unwrap());
+    assert!(store.json_set("list", &[], "[1, 2]").unwrap());
+    assert_eq!(store.get_string("list").unwrap(), Some("[1, 2]".into()));
+}
+
+#[test]
+fn test_json_contains_scan() {
+    let Some(store) = create_json_store("json_contains") else {
+        return;
+    };
+
+    store
+        .set("user:1", r#"{"role": "admin", "teams": ["a"]}"#)
+        .unwrap();
+    store
+        .set("user:2", r#"{"role": "member", "teams": ["a", "b"]}"#)
+        .unwrap();
+    store.set("group:1", r#"{"role": "admin"}"#).unwrap();
+
+    let admins = store
+        .keys(
+            ScanOptions::new()
+                .prefix("user:")
+                .json_contains(r#"{"role": "admin"}"#),
+        )
+        .unwrap();
+    assert_eq!(admins, vec!["user:1"]);
+
+    let team_b = ScanOptions::new().json_contains(r#"{"teams": ["b"]}"#);
+    assert_eq!(store.count(team_b).unwrap(), 1);
+
+    let mut client =
+        postgres::Client::connect(&get_database_url().unwrap(), postgres::NoTls).unwrap();
+    let row = client
+        .query_one(
+            "SELECT COUNT(*) FROM pg_indexes WHERE indexname = 'test_json_contains_value_idx'",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 1);
+
+    // JSON operations need a JSONB table
+    let Some(bytes_store) = create_test_store("json_on_bytes") else {
+        return;
+    };
+    assert!(matches!(
+        bytes_store.json_get("k", &[]),
+        Err(pgkv::Error::Config(_))
+    ));
+    assert!(matches!(
+        bytes_store.keys(ScanOptions::new().json_contains("{}")),
+        Err(pgkv::Error::Config(_))
+    ));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_typed_store_json_operations() {
+    use pgkv::TypedStoreExt;
+
+    let Some(store) = create_json_store("typed_jsonb") else {
+        return;
+    };
+
+    let profiles = store.typed::<Profile>();
+    profiles
+        .set(
+            "profile:1",
+            &Profile {
+                name: "alice".into(),
+                visits: 1,
+            },
+        )
+        .unwrap();
+
+    assert!(profiles.json_set("profile:1", &["visits"], &7).unwrap());
+    let name: Option<String> = profiles.json_get("profile:1", &["name"]).unwrap();
+    assert_eq!(name.as_deref(), Some("alice"));
+
+    profiles
+        .json_merge(
+            "profile:2",
+            &serde_json::json!({"name": "bob", "visits": 0}),
+        )
+        .unwrap();
+
+    let busy = profiles
+        .scan(ScanOptions::new().json_contains(r#"{"visits": 7}"#))
+        .unwrap();
+    assert_eq!(busy.len(), 1);
+    assert_eq!(busy[0].1.visits, 7);
+    assert_eq!(profiles.scan(ScanOptions::new()).unwrap().len(), 2);
+}
+
+// ==================== Secondary Indexes ====================
+
+#[test]
+fn test_index_json_path() {
+    let Some(store) = create_test_store("index_json_path") else {
+        return;
+    };
+
+    store
+        .create_index("by_user", IndexExpr::json_path(&["user_id"]))
+        .unwrap();
+    // Creating the same index again does nothing
+    store
+        .create_index("by_user", IndexExpr::json_path(&["user_id"]))
+        .unwrap();
+
+    store.set("session:a", r#"{"user_id": 123}"#).unwrap();
+    store.set("session:b", r#"{"user_id": 456}"#).unwrap();
+    store.set("session:c", r#"{"user_id": 123}"#).unwrap();
+    store
+        .set_ex(
+            "session:d",
+            r#"{"user_id": 123}"#,
+            Duration::from_millis(50),
+        )
+        .unwrap();
+    // Values that aren't JSON are left out of the index
+    store.set("raw", b"\xff\x00not json").unwrap();
+
+    std::thread::sleep(Duration::from_millis(100));
+
+    let found = store.find_by("by_user", "123").unwrap();
+    let keys: Vec<_> = found.iter().map(|kv| kv.key.as_str()).collect();
+    assert_eq!(keys, ["session:a", "session:c"]);
+    assert_eq!(found[0].value_str(), Some(r#"{"user_id": 123}"#));
+
+    // The index follows updates
+    store.set("session:a", r#"{"user_id": 456}"#).unwrap();
+    assert_eq!(store.find_by("by_user", "123").unwrap().len(), 1);
+    assert_eq!(store.find_by("by_user", "456").unwrap().len(), 2);
+    assert!(store.find_by("by_user", "789").unwrap().is_empty());
+
+    let mut client =
+        postgres::Client::connect(&get_database_url().unwrap(), postgres::NoTls).unwrap();
+    let row = client
+        .query_one(
+            "SELECT COUNT(*) FROM pg_indexes WHERE indexname = 'test_index_json_path_ix_by_user'",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 1);
+}
+
+#[test]
+fn test_index_shared_between_stores() {
+    let Some(store) = create_test_store("index_shared") else {
+        return;
+    };
+    let Some(other) = create_test_store("index_shared") else {
+        return;
+    };
+
+    store
+        .create_index("by_length", IndexExpr::sql("octet_length(value)"))
+        .unwrap();
+    store.set("a", "xyz").unwrap();
+    store.set("b", "hello").unwrap();
+
+    // Another store sees the index created by the first
+    let found = other.find_by("by_length", "5").unwrap();
+    assert_eq!(found, vec![KeyValue::new("b", "hello")]);
+    assert_eq!(
+        other.indexes().unwrap(),
+        vec![IndexInfo {
+            name: "by_length".into(),
+            expression: "((octet_length(value))::text)".into(),
+        }]
+    );
+
+    // A name can't be reused for a different expression
+    assert!(matches!(
+        other.create_index("by_length", IndexExpr::sql("length(key)")),
+        Err(pgkv::Error::Config(_))
+    ));
+    assert!(matches!(
+        other.create_index("By Length", IndexExpr::sql("length(key)")),
+        Err(pgkv::Error::Config(_))
+    ));
+
+    assert!(other.drop_index("by_length").unwrap());
+    assert!(!store.drop_index("by_length").unwrap());
+    assert!(store.indexes().unwrap().is_empty());
+    assert!(matches!(
+        store.find_by("by_length", "5"),
+        Err(pgkv::Error::Config(_))
+    ));
+}
+
+#[test]
+fn test_index_jsonb_values() {
+    let Some(store) = create_json_store("index_jsonb") else {
+        return;
+    };
+
+    store
+        .create_index("by_city", IndexExpr::json_path(&["address", "city"]))
+        .unwrap();
+    store
+        .set("user:1", r#"{"address": {"city": "Oslo"}}"#)
+        .unwrap();
+    store
+        .set("user:2", r#"{"address": {"city": "Lima"}}"#)
+        .unwrap();
+    store.set("user:3", r#"{"address": null}"#).unwrap();
+
+    let found = store.find_by("by_city", "Oslo").unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].key, "user:1");
+
+    // Compressed values can't be indexed
+    let config = Config::new(get_database_url().unwrap())
+        .table_name("test_index_compressed")
+        .compression(pgkv::Compression::Lz4 { min_size: 0 });
+    if let Ok(compressed) = Store::with_config(config) {
+        assert!(matches!(
+            compressed.create_index("by_user", IndexExpr::json_path(&["user_id"])),
+            Err(pgkv::Error::Config(_))
+        ));
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_typed_store_find_by() {
+    use pgkv::TypedStoreExt;
+
+    let Some(store) = create_test_store("index_typed") else {
+        return;
+    };
+    store
+        .create_index("by_name", IndexExpr::json_path(&["name"]))
+        .unwrap();
+
+    let profiles = store.typed::<Profile>();
+    for (key, name) in [("p:1", "alice"), ("p:2", "bob"), ("p:3", "alice")] {
+        profiles
+            .set(
+                key,
+                &Profile {
+                    name: name.into(),
+                    visits: 0,
+                },
+            )
+            .unwrap();
+    }
+
+    let alices = profiles.find_by("by_name", "alice").unwrap();
+    assert_eq!(alices.len(), 2);
+    assert_eq!(alices[1].0, "p:3");
+    assert_eq!(alices[1].1.name, "alice");
 }
 
 // ==================== Configuration Tests ====================